@@ -2,7 +2,13 @@ use std::{collections::hash_map::RandomState, hint::black_box};
 
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 use rand::Rng;
-use xx_bloom::{BloomBuildHasher, BloomFilter, BuildHasher128Adapter, RandomXxh3State, ASMS};
+use std::sync::Mutex;
+
+use xx_bloom::{
+    probe_all, BloomBuildHasher, BloomFilter, BufferedBloomFilter, BuildHasher128Adapter,
+    CountingBloomFilter, IndependentBloomFilter, IndexLayout, PartitionedBloomFilter,
+    RandomXxh3State, SeededXxh3State, TieredBloomFilter, ASMS,
+};
 
 // Since no way to get this value cross-platform, manually set it to larger than reasonable.
 // Most tests just reuse the same slice over and over again, but that's not representative of
@@ -17,6 +23,23 @@ fn get_random_key<'a>(large_buffer: &'a [u8], offset: &mut usize, size: usize) -
     key
 }
 
+/// Generates `len` key indices in `0..num_keys` following a Zipf
+/// distribution with exponent 1.
+fn zipf_trace<R: Rng>(rng: &mut R, num_keys: usize, len: usize) -> Vec<u64> {
+    let mut cdf = Vec::with_capacity(num_keys);
+    let mut total = 0.0f64;
+    for rank in 1..=num_keys {
+        total += 1.0 / rank as f64;
+        cdf.push(total);
+    }
+    (0..len)
+        .map(|_| {
+            let target = rng.gen::<f64>() * total;
+            cdf.partition_point(|c| *c < target) as u64
+        })
+        .collect()
+}
+
 fn benchmark(c: &mut Criterion) {
     let mut rng = rand::thread_rng();
 
@@ -41,7 +64,8 @@ fn benchmark(c: &mut Criterion) {
                     );
                     b.iter(|| {
                         let key = get_random_key(&key_buffer, &mut offset, key_size);
-                        black_box(filter.insert(&key));
+                        filter.insert(&key);
+                        black_box(&filter);
                     });
                 },
             );
@@ -50,7 +74,8 @@ fn benchmark(c: &mut Criterion) {
                 let mut filter = BloomFilter::with_rate(0.01, num_keys + 300_000);
                 b.iter(|| {
                     let key = get_random_key(&key_buffer, &mut offset, key_size);
-                    black_box(filter.insert_slice(&key));
+                    filter.insert_slice(key);
+                    black_box(&filter);
                 });
             });
         }
@@ -67,7 +92,7 @@ fn benchmark(c: &mut Criterion) {
                 &key_size,
                 |b, _| {
                     let mut offset = 0;
-                    let mut filter = BloomFilter::with_rate_and_hasher(
+                    let filter = BloomFilter::with_rate_and_hasher(
                         0.01,
                         num_keys + 300_000,
                         BuildHasher128Adapter::with_hashers(RandomState::new(), RandomState::new()),
@@ -80,10 +105,10 @@ fn benchmark(c: &mut Criterion) {
             );
             group.bench_with_input(BenchmarkId::new("xxh3", key_size), &key_size, |b, _| {
                 let mut offset = 0;
-                let mut filter = BloomFilter::with_rate(0.01, num_keys + 300_000);
+                let filter = BloomFilter::with_rate(0.01, num_keys + 300_000);
                 b.iter(|| {
                     let key = get_random_key(&key_buffer, &mut offset, key_size);
-                    black_box(filter.contains_slice(&key));
+                    black_box(filter.contains_slice(key));
                 });
             });
         }
@@ -99,13 +124,13 @@ fn benchmark(c: &mut Criterion) {
             group.bench_with_input(BenchmarkId::new("naiive", key_size), &key_size, |b, _| {
                 let mut offset = 0;
                 let builder = RandomXxh3State::new();
-                let mut filters: Vec<BloomFilter> = (0..10)
+                let filters: Vec<BloomFilter> = (0..10)
                     .map(|_| BloomFilter::with_rate_and_hasher(0.01, num_keys + 300_000, builder))
                     .collect();
                 b.iter(|| {
                     let key = get_random_key(&key_buffer, &mut offset, key_size);
                     for filter in &filters {
-                        black_box(filter.contains_slice(&key));
+                        black_box(filter.contains_slice(key));
                     }
                 });
             });
@@ -115,7 +140,7 @@ fn benchmark(c: &mut Criterion) {
                 |b, _| {
                     let mut offset = 0;
                     let builder = RandomXxh3State::new();
-                    let mut filters: Vec<BloomFilter> = (0..10)
+                    let filters: Vec<BloomFilter> = (0..10)
                         .map(|_| {
                             BloomFilter::with_rate_and_hasher(0.01, num_keys + 300_000, builder)
                         })
@@ -129,9 +154,462 @@ fn benchmark(c: &mut Criterion) {
                     });
                 },
             );
+            group.bench_with_input(
+                BenchmarkId::new("probe_all", key_size),
+                &key_size,
+                |b, _| {
+                    let mut offset = 0;
+                    let builder = RandomXxh3State::new();
+                    let filters: Vec<BloomFilter> = (0..10)
+                        .map(|_| {
+                            BloomFilter::with_rate_and_hasher(0.01, num_keys + 300_000, builder)
+                        })
+                        .collect();
+                    let refs: Vec<&BloomFilter> = filters.iter().collect();
+                    b.iter(|| {
+                        let key = get_random_key(&key_buffer, &mut offset, key_size);
+                        let fp = builder.hash_one_128(key);
+                        black_box(probe_all(&refs, fp).unwrap());
+                    });
+                },
+            );
         }
     }
 }
 
-criterion_group!(benches, benchmark);
+fn tiered_benchmark(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+    let num_keys = 10_000_000;
+    let trace = zipf_trace(&mut rng, num_keys, 1_000_000);
+
+    let mut group = c.benchmark_group("Zipf contains");
+    group.bench_function("flat", |b| {
+        let mut filter = BloomFilter::with_rate(0.01, num_keys as u32);
+        for key in 0..num_keys as u64 {
+            filter.insert(&key);
+        }
+        let mut offset = 0;
+        b.iter(|| {
+            let key = trace[offset];
+            offset = (offset + 1) % trace.len();
+            black_box(filter.contains(&key))
+        });
+    });
+    group.bench_function("tiered", |b| {
+        let mut filter = TieredBloomFilter::with_rate(0.01, num_keys as u32, 10_000);
+        for key in 0..num_keys as u64 {
+            filter.insert(&key);
+        }
+        let mut offset = 0;
+        b.iter(|| {
+            let key = trace[offset];
+            offset = (offset + 1) % trace.len();
+            black_box(filter.query(&key))
+        });
+    });
+}
+
+fn concurrent_insert_benchmark(c: &mut Criterion) {
+    let threads = 8u32;
+    let per_thread = 100_000u32;
+    let num_keys = threads * per_thread;
+
+    let mut group = c.benchmark_group("Concurrent insert");
+    group.sample_size(10);
+    group.bench_function("mutex per insert", |b| {
+        b.iter(|| {
+            let filter = Mutex::new(BloomFilter::with_rate(0.01, num_keys));
+            std::thread::scope(|s| {
+                for t in 0..threads {
+                    let filter = &filter;
+                    s.spawn(move || {
+                        for i in t * per_thread..(t + 1) * per_thread {
+                            filter.lock().unwrap().insert(&i);
+                        }
+                    });
+                }
+            });
+            black_box(filter)
+        });
+    });
+    for batch_size in [64, 1024] {
+        group.bench_with_input(
+            BenchmarkId::new("buffered", batch_size),
+            &batch_size,
+            |b, &batch_size| {
+                b.iter(|| {
+                    let filter = BufferedBloomFilter::new(
+                        BloomFilter::with_rate(0.01, num_keys),
+                        batch_size,
+                    );
+                    std::thread::scope(|s| {
+                        for t in 0..threads {
+                            let filter = &filter;
+                            s.spawn(move || {
+                                let mut writer = filter.writer();
+                                for i in t * per_thread..(t + 1) * per_thread {
+                                    writer.insert(&i);
+                                }
+                            });
+                        }
+                    });
+                    black_box(filter)
+                });
+            },
+        );
+    }
+}
+
+fn index_layout_benchmark(c: &mut Criterion) {
+    let keys: Vec<u64> = (0..1024).collect();
+
+    let mut group = c.benchmark_group("Contains index mapping");
+    group.throughput(criterion::Throughput::Elements(keys.len() as u64));
+    for layout in [IndexLayout::Modulo, IndexLayout::FastRange, IndexLayout::Mask] {
+        // Mask needs a power of two, the others get an awkward size.
+        let num_bits = match layout {
+            IndexLayout::Mask => 1 << 29,
+            _ => (1 << 29) - 12_345,
+        };
+        let mut filter =
+            BloomFilter::with_size_and_layout(num_bits, 7, RandomXxh3State::new(), layout);
+        for key in keys.iter().step_by(2) {
+            filter.insert(key);
+        }
+        let fps: Vec<_> = keys
+            .iter()
+            .map(|key| filter.hash_builder().hash_one_128(&key.to_le_bytes()))
+            .collect();
+        group.bench_function(format!("{:?}", layout), |b| {
+            b.iter(|| {
+                for fp in &fps {
+                    black_box(filter.contains_fingerprint(*fp));
+                }
+            });
+        });
+    }
+}
+
+fn small_filter_probe_benchmark(c: &mut Criterion) {
+    // Small enough to stay in L1, so the cost is the probe arithmetic
+    // rather than cache misses.
+    let keys: Vec<u64> = (0..1024).collect();
+    let hasher = RandomXxh3State::new();
+    let fps: Vec<_> = keys
+        .iter()
+        .map(|key| hasher.hash_one_128(&key.to_le_bytes()))
+        .collect();
+
+    let mut group = c.benchmark_group("Small filter probes");
+    group.throughput(criterion::Throughput::Elements(keys.len() as u64));
+    group.bench_function("insert_fingerprint", |b| {
+        let mut filter = BloomFilter::with_size_and_hasher(1 << 15, 7, hasher);
+        b.iter(|| {
+            for fp in &fps {
+                filter.insert_fingerprint(*fp);
+            }
+        });
+    });
+    group.bench_function("contains_fingerprint", |b| {
+        let mut filter = BloomFilter::with_size_and_hasher(1 << 15, 7, hasher);
+        filter.insert_fingerprints(fps.iter().step_by(2).copied());
+        b.iter(|| {
+            for fp in &fps {
+                black_box(filter.contains_fingerprint(*fp));
+            }
+        });
+    });
+}
+
+fn prefetch_benchmark(c: &mut Criterion) {
+    // Far larger than any last level cache, so every probe misses.
+    let num_bits = 8 * 1024 * 1024 * 1024;
+    let mut filter = BloomFilter::with_size(num_bits, 7);
+    // Enough keys that their probed cache lines don't stay cached
+    // between iterations.
+    let fps: Vec<_> = (0..1u64 << 18)
+        .map(|i| filter.hash_builder().hash_one_128(&i.to_le_bytes()))
+        .collect();
+    // Every key is present, so no lookup can stop early and each one
+    // costs all 7 misses.
+    filter.insert_fingerprints(fps.iter().copied());
+
+    let mut group = c.benchmark_group("Contains 1 GiB filter");
+    group.sample_size(10);
+    group.throughput(criterion::Throughput::Elements(fps.len() as u64));
+    group.bench_function("contains_fingerprint", |b| {
+        b.iter(|| {
+            for fp in &fps {
+                black_box(filter.contains_fingerprint(*fp));
+            }
+        });
+    });
+    group.bench_function("contains_fingerprint_prefetched", |b| {
+        b.iter(|| {
+            for fp in &fps {
+                black_box(filter.contains_fingerprint_prefetched(*fp));
+            }
+        });
+    });
+    group.bench_function("contains_fingerprints_prefetched", |b| {
+        let mut out = Vec::new();
+        b.iter(|| {
+            filter.contains_fingerprints_prefetched(&fps, &mut out);
+            black_box(&out);
+        });
+    });
+    group.bench_function("contains_batch_sorted", |b| {
+        let mut out = Vec::new();
+        b.iter(|| {
+            filter.contains_batch_sorted(&fps, &mut out);
+            black_box(&out);
+        });
+    });
+}
+
+fn hasher_state_benchmark(c: &mut Criterion) {
+    let keys: Vec<u64> = (0..1024).collect();
+
+    let mut group = c.benchmark_group("Small filter construction");
+    group.throughput(criterion::Throughput::Elements(1024));
+    group.bench_function("RandomXxh3State", |b| {
+        b.iter(|| {
+            for _ in 0..1024 {
+                black_box(BloomFilter::with_rate(0.01, 64));
+            }
+        });
+    });
+    group.bench_function("SeededXxh3State", |b| {
+        b.iter(|| {
+            for _ in 0..1024 {
+                black_box(BloomFilter::with_rate_and_hasher(
+                    0.01,
+                    64,
+                    SeededXxh3State::new(),
+                ));
+            }
+        });
+    });
+    drop(group);
+
+    let mut group = c.benchmark_group("Insert u64");
+    group.throughput(criterion::Throughput::Elements(keys.len() as u64));
+    group.bench_function("RandomXxh3State", |b| {
+        let mut filter = BloomFilter::with_rate(0.01, keys.len() as u32);
+        b.iter(|| {
+            for key in &keys {
+                filter.insert(key);
+            }
+        });
+    });
+    group.bench_function("SeededXxh3State", |b| {
+        let mut filter =
+            BloomFilter::with_rate_and_hasher(0.01, keys.len() as u32, SeededXxh3State::new());
+        b.iter(|| {
+            for key in &keys {
+                filter.insert(key);
+            }
+        });
+    });
+}
+
+// Double hashing derives every probe from one hash; independent hashing
+// spends a hash per probe for a false positive rate matching theory.
+// The rates, printed first, and throughputs together show the tradeoff
+// as the number of hashes grows.
+fn independent_hashing_benchmark(c: &mut Criterion) {
+    const ITEMS: u32 = 100_000;
+    let keys: Vec<u64> = (0..ITEMS as u64).collect();
+    let absent: Vec<u64> = (ITEMS as u64..2 * ITEMS as u64).collect();
+
+    let mut group = c.benchmark_group("Independent hashing");
+    group.throughput(criterion::Throughput::Elements(keys.len() as u64));
+    for rate in [0.1, 0.01, 0.001, 0.0001] {
+        let mut double = BloomFilter::with_rate_and_hasher(rate, ITEMS, RandomXxh3State::new());
+        let mut independent = IndependentBloomFilter::with_rate(rate, ITEMS);
+        for key in &keys {
+            double.insert(key);
+            independent.insert(key);
+        }
+        let k = independent.num_hashes();
+        let fpr = |hits: usize| hits as f64 / absent.len() as f64;
+        println!(
+            "rate {} (k = {}): double hashing fpr {:.6}, independent fpr {:.6}",
+            rate,
+            k,
+            fpr(absent.iter().filter(|key| double.contains(key)).count()),
+            fpr(absent.iter().filter(|key| independent.contains(key)).count()),
+        );
+
+        group.bench_function(BenchmarkId::new("double insert", k), |b| {
+            b.iter(|| keys.iter().for_each(|key| double.insert(key)));
+        });
+        group.bench_function(BenchmarkId::new("independent insert", k), |b| {
+            b.iter(|| keys.iter().for_each(|key| independent.insert(key)));
+        });
+        group.bench_function(BenchmarkId::new("double contains absent", k), |b| {
+            b.iter(|| {
+                for key in &absent {
+                    black_box(double.contains(key));
+                }
+            });
+        });
+        group.bench_function(BenchmarkId::new("independent contains absent", k), |b| {
+            b.iter(|| {
+                for key in &absent {
+                    black_box(independent.contains(key));
+                }
+            });
+        });
+    }
+}
+
+fn counting_benchmark(c: &mut Criterion) {
+    let keys: Vec<[u8; 16]> = (0..1024u128).map(|k| k.to_le_bytes()).collect();
+
+    // 3 bit counters take the general path, the others the aligned ones.
+    // The filters fit in L2 so the counter access itself dominates.
+    let mut filters: Vec<_> = [3, 4, 8, 16]
+        .into_iter()
+        .map(|bits_per_entry| {
+            let mut filter = CountingBloomFilter::with_rate(bits_per_entry, 0.01, 10_000);
+            for key in keys.iter().step_by(2) {
+                filter.insert_slice(key);
+            }
+            (bits_per_entry, filter)
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("Counting contains_slice");
+    group.throughput(criterion::Throughput::Elements(keys.len() as u64));
+    for (bits_per_entry, filter) in &filters {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(bits_per_entry),
+            filter,
+            |b, filter| {
+                b.iter(|| {
+                    for key in &keys {
+                        black_box(filter.contains_slice(key));
+                    }
+                });
+            },
+        );
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("Counting estimate_count_slice");
+    group.throughput(criterion::Throughput::Elements(keys.len() as u64));
+    for (bits_per_entry, filter) in &filters {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(bits_per_entry),
+            filter,
+            |b, filter| {
+                b.iter(|| {
+                    for key in &keys {
+                        black_box(filter.estimate_count_slice(key));
+                    }
+                });
+            },
+        );
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("Counting insert_slice and remove_slice");
+    group.throughput(criterion::Throughput::Elements(keys.len() as u64));
+    for (bits_per_entry, filter) in &mut filters {
+        group.bench_function(BenchmarkId::from_parameter(bits_per_entry), |b| {
+            b.iter(|| {
+                for key in &keys {
+                    filter.insert_slice(key);
+                    black_box(filter.remove_slice(key));
+                }
+            });
+        });
+    }
+}
+
+fn partitioned_benchmark(c: &mut Criterion) {
+    let hasher = RandomXxh3State::new();
+    let fps: Vec<_> = (0..1u64 << 16)
+        .map(|i| hasher.hash_one_128(&i.to_le_bytes()))
+        .collect();
+
+    let mut group = c.benchmark_group("Partitioned probes");
+    group.throughput(criterion::Throughput::Elements(fps.len() as u64));
+    // One filter that stays in L1 and one far larger than any cache.
+    for num_bits in [1 << 15, 1 << 30] {
+        let vectorized = PartitionedBloomFilter::with_size_and_hasher(num_bits, 8, hasher);
+        let scalar = PartitionedBloomFilter::combinable_with(&vectorized).scalar();
+        for (path, mut filter) in [("vectorized", vectorized), ("scalar", scalar)] {
+            if path == "vectorized" && !filter.is_vectorized() {
+                continue;
+            }
+            group.bench_function(BenchmarkId::new(format!("insert {}", path), num_bits), |b| {
+                b.iter(|| {
+                    for fp in &fps {
+                        filter.insert_fingerprint(*fp);
+                    }
+                });
+            });
+            group.bench_function(BenchmarkId::new(format!("contains {}", path), num_bits), |b| {
+                b.iter(|| {
+                    for fp in &fps {
+                        black_box(filter.contains_fingerprint(*fp));
+                    }
+                });
+            });
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+fn union_many_benchmark(c: &mut Criterion) {
+    let num_bits = 64 * 1024 * 1024 * 8;
+    let template = BloomFilter::with_size(num_bits, 4);
+    let sources: Vec<BloomFilter> = (0..16u32)
+        .map(|s| {
+            let mut source = BloomFilter::combinable_with(&template);
+            for i in 0..100_000 {
+                source.insert(&(s, i));
+            }
+            source
+        })
+        .collect();
+    let refs: Vec<&BloomFilter> = sources.iter().collect();
+
+    let mut group = c.benchmark_group("Union 16 x 64 MiB");
+    group.sample_size(10);
+    group.bench_function("sequential", |b| {
+        let mut dest = BloomFilter::combinable_with(&template);
+        b.iter(|| dest.union_many(&refs).unwrap());
+    });
+    for threads in [1, 2, 4, 8] {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        group.bench_with_input(BenchmarkId::new("parallel", threads), &threads, |b, _| {
+            let mut dest = BloomFilter::combinable_with(&template);
+            b.iter(|| pool.install(|| dest.par_union_many(&refs).unwrap()));
+        });
+    }
+}
+
+#[cfg(not(feature = "rayon"))]
+fn union_many_benchmark(_: &mut Criterion) {}
+
+criterion_group!(
+    benches,
+    benchmark,
+    tiered_benchmark,
+    concurrent_insert_benchmark,
+    index_layout_benchmark,
+    small_filter_probe_benchmark,
+    prefetch_benchmark,
+    counting_benchmark,
+    hasher_state_benchmark,
+    independent_hashing_benchmark,
+    partitioned_benchmark,
+    union_many_benchmark
+);
 criterion_main!(benches);
@@ -0,0 +1,86 @@
+#ifndef XXBLOOM_H
+#define XXBLOOM_H
+
+/* Generated by cbindgen from xx_bloom::ffi; don't edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The call succeeded.
+#define XXBLOOM_OK 0
+
+// A required pointer was null.
+#define XXBLOOM_ERR_NULL -1
+
+// The output buffer is too small; the needed size was stored in
+// `out_len`.
+#define XXBLOOM_ERR_BUFFER_TOO_SMALL -2
+
+// The call panicked, e.g. because the filter couldn't be allocated.
+#define XXBLOOM_ERR_PANIC -3
+
+// An opaque handle to a filter.
+typedef struct XxBloom XxBloom;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Create a filter sized for `items` items at a false positive rate of
+// `rate`, hashing with a random secret.  Returns null if `rate` isn't
+// strictly between 0 and 1 or `items` is 0.
+XxBloom *xxbloom_new(double rate, uint32_t items);
+
+// Like `xxbloom_new`, but hashing with a secret derived from `seed`,
+// so filters created with the same parameters and seed can be
+// combined, even across processes.
+XxBloom *xxbloom_new_with_seed(double rate, uint32_t items, uint64_t seed);
+
+// Insert the `len` bytes at `key`.  Returns 1 if the key wasn't
+// already (probably) present, 0 if it was, or a negative error.
+//
+// # Safety
+// `filter` must be a live handle not used by another thread, and `key`
+// must point to `len` readable bytes (or be null if `len` is 0).
+int32_t xxbloom_insert(XxBloom *filter, const uint8_t *key, uintptr_t len);
+
+// Check for the `len` bytes at `key`.  Returns 1 if the key is
+// (probably) present, 0 if it definitely isn't, or a negative error.
+//
+// # Safety
+// `filter` must be a live handle, and `key` must point to `len`
+// readable bytes (or be null if `len` is 0).
+int32_t xxbloom_contains(const XxBloom *filter, const uint8_t *key, uintptr_t len);
+
+// Serialize the filter into `out_buf`, which holds `*out_len` bytes,
+// and store the number of bytes written in `*out_len`.  If `out_buf`
+// is null or too small nothing is written, the size needed is stored
+// in `*out_len` and `XXBLOOM_ERR_BUFFER_TOO_SMALL` is returned, so
+// calling with a null buffer first finds the size to allocate.
+//
+// # Safety
+// `filter` must be a live handle, `out_len` must be valid for reads
+// and writes, and `out_buf` must be null or point to `*out_len`
+// writable bytes.
+int32_t xxbloom_serialize(const XxBloom *filter, uint8_t *out_buf, uintptr_t *out_len);
+
+// Read a filter written by `xxbloom_serialize` from the `len` bytes at
+// `buf`.  Returns null if they don't hold a valid filter.
+//
+// # Safety
+// `buf` must point to `len` readable bytes (or be null if `len` is 0).
+XxBloom *xxbloom_deserialize(const uint8_t *buf, uintptr_t len);
+
+// Release a filter.  Null is ignored.
+//
+// # Safety
+// `filter` must be null or a live handle, which mustn't be used again.
+void xxbloom_free(XxBloom *filter);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif // XXBLOOM_H
@@ -0,0 +1,67 @@
+/* Exercises the C API through the header, as a C caller would. */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "xxbloom.h"
+
+#define CHECK(cond)                                                      \
+    do {                                                                 \
+        if (!(cond)) {                                                   \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__,       \
+                    __LINE__, #cond);                                    \
+            return 1;                                                    \
+        }                                                                \
+    } while (0)
+
+static int insert(XxBloom *filter, const char *key) {
+    return xxbloom_insert(filter, (const uint8_t *)key, strlen(key));
+}
+
+static int contains(const XxBloom *filter, const char *key) {
+    return xxbloom_contains(filter, (const uint8_t *)key, strlen(key));
+}
+
+int main(void) {
+    XxBloom *filter = xxbloom_new(0.01, 1000);
+    CHECK(filter != NULL);
+    CHECK(xxbloom_new(1.5, 1000) == NULL);
+
+    CHECK(insert(filter, "apple") == 1);
+    CHECK(insert(filter, "apple") == 0);
+    CHECK(insert(filter, "banana") == 1);
+    CHECK(contains(filter, "apple") == 1);
+    CHECK(contains(filter, "banana") == 1);
+    CHECK(contains(filter, "cherry") == 0);
+    CHECK(xxbloom_contains(NULL, (const uint8_t *)"apple", 5) == XXBLOOM_ERR_NULL);
+
+    uintptr_t len = 0;
+    CHECK(xxbloom_serialize(filter, NULL, &len) == XXBLOOM_ERR_BUFFER_TOO_SMALL);
+    uint8_t *buf = malloc(len);
+    CHECK(buf != NULL);
+    CHECK(xxbloom_serialize(filter, buf, &len) == XXBLOOM_OK);
+    xxbloom_free(filter);
+
+    XxBloom *copy = xxbloom_deserialize(buf, len);
+    CHECK(copy != NULL);
+    CHECK(contains(copy, "apple") == 1);
+    CHECK(contains(copy, "banana") == 1);
+    CHECK(contains(copy, "cherry") == 0);
+    xxbloom_free(copy);
+
+    CHECK(xxbloom_deserialize(buf, 10) == NULL);
+    free(buf);
+
+    XxBloom *a = xxbloom_new_with_seed(0.01, 1000, 42);
+    XxBloom *b = xxbloom_new_with_seed(0.01, 1000, 42);
+    CHECK(insert(a, "key") == 1);
+    CHECK(contains(b, "key") == 0);
+    CHECK(insert(b, "key") == 1);
+    xxbloom_free(a);
+    xxbloom_free(b);
+    xxbloom_free(NULL);
+
+    printf("ok\n");
+    return 0;
+}
@@ -0,0 +1,56 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+// Compiles `c_api.c` against the header and the freshly built library
+// with the system C compiler (or `$CC`), then runs it.
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[cfg(unix)]
+#[test]
+fn c_program() {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // Integration tests run from the directory the library is built in.
+    let lib_dir = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+    let out_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let exe = out_dir.join("c_api");
+
+    let status = Command::new(env::var("CC").unwrap_or_else(|_| "cc".into()))
+        .arg(manifest.join("tests/c_api.c"))
+        .arg("-std=c99")
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I")
+        .arg(manifest.join("include"))
+        .arg("-L")
+        .arg(&lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .arg("-lxxbloom")
+        .arg("-o")
+        .arg(&exe)
+        .status()
+        .expect("failed to run the C compiler");
+    assert!(status.success(), "compiling c_api.c failed");
+
+    let output = Command::new(&exe).output().unwrap();
+    assert!(
+        output.status.success(),
+        "c_api failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ok\n");
+}
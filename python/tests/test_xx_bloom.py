@@ -0,0 +1,120 @@
+# Run with `maturin develop && pytest tests` from the `python` directory.
+
+import threading
+
+import pytest
+
+import xx_bloom
+
+# `BloomFilter::with_rate_seeded(0.1, 10, 7)` after
+# `insert_slice(b"written in rust")`, serialized by Rust's `to_bytes_le`.
+RUST_FILTER = bytes.fromhex(
+    "585842460500010030000000000000000300000000000000000000000000000001"
+    "000000000000000a000000000000001000024000000000"
+)
+
+
+def test_insert_contains():
+    f = xx_bloom.BloomFilter(rate=0.01, items=100)
+    assert f.insert(b"a")
+    assert not f.insert(b"a")
+    assert b"a" in f
+    assert b"b" not in f
+
+
+def test_insert_many():
+    f = xx_bloom.BloomFilter(rate=0.01, items=10_000)
+    keys = [str(i).encode() for i in range(10_000)]
+    f.insert_many(keys)
+    assert all(key in f for key in keys)
+
+
+def test_insert_many_from_threads():
+    # insert_many releases the GIL, so threads filling their own
+    # filters run in parallel; the results can then be unioned.
+    chunks = [[b"%d-%d" % (t, i) for i in range(10_000)] for t in range(4)]
+    filters = [xx_bloom.BloomFilter(rate=0.01, items=40_000, seed=1) for _ in chunks]
+    threads = [
+        threading.Thread(target=f.insert_many, args=(chunk,))
+        for f, chunk in zip(filters, chunks)
+    ]
+    for t in threads:
+        t.start()
+    for t in threads:
+        t.join()
+    merged = filters[0]
+    for f in filters[1:]:
+        merged.union(f)
+    assert all(key in merged for chunk in chunks for key in chunk)
+
+
+def test_seeded_round_trip():
+    f = xx_bloom.BloomFilter(rate=0.01, items=100, seed=3)
+    f.insert(b"key")
+    g = xx_bloom.BloomFilter.from_bytes(f.to_bytes(), seed=3)
+    assert b"key" in g
+    assert g.to_bytes() == f.to_bytes()
+    assert (g.num_bits, g.num_hashes) == (f.num_bits, f.num_hashes)
+
+
+def test_unseeded_not_written():
+    f = xx_bloom.BloomFilter(rate=0.01, items=100)
+    with pytest.raises(ValueError):
+        f.to_bytes()
+
+
+def test_reads_rust_filter():
+    f = xx_bloom.BloomFilter.from_bytes(RUST_FILTER, seed=7)
+    assert b"written in rust" in f
+    g = xx_bloom.BloomFilter(rate=0.1, items=10, seed=7)
+    g.insert(b"written in rust")
+    assert g.to_bytes() == RUST_FILTER
+
+
+def test_bad_input():
+    with pytest.raises(ValueError):
+        xx_bloom.BloomFilter(rate=1.5)
+    with pytest.raises(ValueError):
+        xx_bloom.BloomFilter.from_bytes(b"not a filter", seed=0)
+
+
+def test_union():
+    a = xx_bloom.BloomFilter(rate=0.01, items=100, seed=5)
+    b = xx_bloom.BloomFilter(rate=0.01, items=100, seed=5)
+    a.insert(b"a")
+    b.insert(b"b")
+    a.union(b)
+    assert b"a" in a and b"b" in a
+    with pytest.raises(ValueError):
+        a.union(xx_bloom.BloomFilter(rate=0.01, items=100, seed=6))
+
+
+def test_counting():
+    f = xx_bloom.CountingBloomFilter(rate=0.01, items=100, bits_per_entry=4, seed=2)
+    assert f.insert(b"a") == 0
+    assert f.insert(b"a") == 1
+    f.insert_many([b"b", b"b", b"b"])
+    assert f.count(b"a") == 2
+    assert f.count(b"b") == 3
+    assert f.remove(b"a") == 2
+    assert f.count(b"a") == 1
+    assert b"a" in f
+    assert b"c" not in f
+
+    g = xx_bloom.CountingBloomFilter(rate=0.01, items=100, bits_per_entry=4, seed=2)
+    g.insert(b"a")
+    f.union(g)
+    assert f.count(b"a") == 2
+
+
+def test_counting_round_trip():
+    # Unseeded, since the secret is written along with the counters.
+    f = xx_bloom.CountingBloomFilter(rate=0.01, items=100, bits_per_entry=4)
+    f.insert_many([b"a", b"a", b"b"])
+    g = xx_bloom.CountingBloomFilter.from_bytes(f.to_bytes())
+    assert g.count(b"a") == 2
+    assert g.count(b"b") == 1
+    assert b"c" not in g
+    assert g.to_bytes() == f.to_bytes()
+    with pytest.raises(ValueError):
+        xx_bloom.CountingBloomFilter.from_bytes(b"not a filter")
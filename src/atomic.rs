@@ -0,0 +1,759 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::bits::Bits;
+use crate::bloom::{needed_bits, optimal_num_hashes, IndexLayout};
+use crate::hashing::{fastrange, probeable, HashIter};
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher, BloomFilter, BloomFingerprint};
+
+/// A BloomFilter that can be inserted into concurrently from many
+/// threads without a lock.
+///
+/// Bits are stored in `AtomicU64` words and every operation takes
+/// `&self`: inserts set bits with a relaxed `fetch_or` and lookups use
+/// relaxed loads.  Setting a bit can never be lost to a concurrent
+/// insert, so once every inserting thread has been joined (or otherwise
+/// synchronized with) every inserted item is guaranteed to be
+/// contained.  A lookup that races with an insert of the same item may
+/// or may not see it.
+///
+/// Bits are addressed exactly like `BloomFilter`, so `freeze` produces a
+/// plain BloomFilter that answers identically.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::{ASMS,AtomicBloomFilter};
+///
+/// let filter = AtomicBloomFilter::with_rate(0.01, 1000);
+/// std::thread::scope(|s| {
+///     s.spawn(|| filter.insert(&1));
+///     s.spawn(|| filter.insert(&2));
+/// });
+/// assert!(filter.contains(&1));
+/// let frozen = filter.freeze();
+/// assert!(frozen.contains(&2));
+/// ```
+pub struct AtomicBloomFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    words: Box<[AtomicU64]>,
+    num_bits: usize,
+    num_hashes: u32,
+    hash_builder: H,
+    domain: u64,
+    layout: IndexLayout,
+}
+
+impl AtomicBloomFilter<RandomXxh3State> {
+    /// Create a new AtomicBloomFilter with the specified number of bits,
+    /// and hashes
+    pub fn with_size(num_bits: usize, num_hashes: u32) -> AtomicBloomFilter<RandomXxh3State> {
+        AtomicBloomFilter::with_size_and_hasher(num_bits, num_hashes, RandomXxh3State::new())
+    }
+
+    /// create an AtomicBloomFilter that expects to hold
+    /// `expected_num_items`.  The filter will be sized to have a
+    /// false positive rate of the value specified in `rate`.
+    pub fn with_rate(rate: f32, expected_num_items: u32) -> AtomicBloomFilter<RandomXxh3State> {
+        AtomicBloomFilter::with_rate_and_hasher(rate, expected_num_items, RandomXxh3State::new())
+    }
+}
+
+impl<H> AtomicBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Create a new AtomicBloomFilter with the specified number of bits,
+    /// hashes, and hash builder.
+    pub fn with_size_and_hasher(
+        num_bits: usize,
+        num_hashes: u32,
+        hash_builder: H,
+    ) -> AtomicBloomFilter<H> {
+        AtomicBloomFilter {
+            words: (0..num_bits.div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            num_bits,
+            num_hashes,
+            hash_builder,
+            domain: 0,
+            layout: IndexLayout::default(),
+        }
+    }
+
+    /// Create an AtomicBloomFilter that expects to hold
+    /// `expected_num_items`.  The filter will be sized to have a
+    /// false positive rate of the value specified in `rate`.  Items
+    /// will be hashed using the Hasher produced by `hash_builder`.
+    pub fn with_rate_and_hasher(
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> AtomicBloomFilter<H> {
+        let bits = needed_bits(rate, expected_num_items);
+        AtomicBloomFilter::with_size_and_hasher(
+            bits,
+            optimal_num_hashes(bits, expected_num_items),
+            hash_builder,
+        )
+    }
+
+    /// Get the number of bits this AtomicBloomFilter is using
+    #[inline(always)]
+    pub fn num_bits(&self) -> usize {
+        self.num_bits
+    }
+
+    /// Get the number of hash functions this AtomicBloomFilter is using
+    #[inline(always)]
+    pub fn num_hashes(&self) -> u32 {
+        self.num_hashes
+    }
+
+    /// Get the hash builder this AtomicBloomFilter is using
+    #[inline(always)]
+    pub fn hash_builder(&self) -> &H {
+        &self.hash_builder
+    }
+
+    #[inline(always)]
+    fn probeable(&self) -> bool {
+        probeable(self.num_bits as u64)
+    }
+
+    fn insert_hash_iter(&self, h_iter: HashIter) {
+        if !self.probeable() {
+            return;
+        }
+        h_iter.with_domain(self.domain).for_each(|h| {
+            let idx = self.layout.index(h, self.num_bits);
+            self.words[idx / 64].fetch_or(1 << (idx % 64), Ordering::Relaxed);
+        });
+    }
+
+    fn contains_hash_iter(&self, h_iter: HashIter) -> bool {
+        if !self.probeable() {
+            return false;
+        }
+        h_iter.with_domain(self.domain).all(|h| {
+            let idx = self.layout.index(h, self.num_bits);
+            self.words[idx / 64].load(Ordering::Relaxed) & (1 << (idx % 64)) != 0
+        })
+    }
+
+    /// Insert item into this AtomicBloomFilter.
+    #[inline(always)]
+    pub fn insert<T: Hash>(&self, item: &T) {
+        self.insert_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Insert item into this AtomicBloomFilter.
+    /// This is a faster-path if the item you're inserting is a byte slice.
+    #[inline(always)]
+    pub fn insert_slice(&self, item: &[u8]) {
+        self.insert_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Insert a fingerprint into this AtomicBloomFilter.
+    /// This is a faster-path if you have multiple filters that share the same
+    /// hash algorithm that you're inserting into.
+    #[inline(always)]
+    pub fn insert_fingerprint(&self, fingerprint: BloomFingerprint) {
+        self.insert_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Check if the item has been inserted into this filter.
+    /// This function can return false positives, but not false
+    /// negatives.
+    #[inline(always)]
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Check if the item has been inserted into this filter.
+    /// This is a faster-path if the item is a byte slice.
+    #[inline(always)]
+    pub fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Check if the fingerprint has been inserted into this filter.
+    #[inline(always)]
+    pub fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        self.contains_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Remove all values from this filter.  Inserts racing with the
+    /// clear may or may not survive it.
+    pub fn clear(&self) {
+        for word in self.words.iter() {
+            word.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Copy the current bits into a plain BloomFilter.
+    pub fn to_bloom_filter(&self) -> BloomFilter<H> {
+        let bits = Bits::from_words(
+            self.num_bits,
+            self.words.iter().map(|word| word.load(Ordering::Relaxed)),
+        );
+        BloomFilter::from_parts(
+            bits,
+            self.num_hashes,
+            self.hash_builder.clone(),
+            self.domain,
+            self.layout,
+        )
+        .recount()
+    }
+
+    /// Convert this filter into a plain BloomFilter once concurrent
+    /// inserting is done.
+    pub fn freeze(self) -> BloomFilter<H> {
+        self.to_bloom_filter()
+    }
+}
+
+impl<H> From<&BloomFilter<H>> for AtomicBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Copy the bits of a plain BloomFilter into a new AtomicBloomFilter.
+    fn from(filter: &BloomFilter<H>) -> Self {
+        let words = filter
+            .bits()
+            .words()
+            .iter()
+            .map(|&word| AtomicU64::new(word))
+            .collect();
+        AtomicBloomFilter {
+            words,
+            num_bits: filter.num_bits(),
+            num_hashes: filter.num_hashes(),
+            hash_builder: filter.hash_builder().clone(),
+            domain: filter.domain(),
+            layout: filter.layout(),
+        }
+    }
+}
+
+impl<H> From<AtomicBloomFilter<H>> for BloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    fn from(filter: AtomicBloomFilter<H>) -> Self {
+        filter.freeze()
+    }
+}
+
+/// A CountingBloomFilter that can be updated concurrently from many
+/// threads without a lock.
+///
+/// Counters are packed into `AtomicU64` words without straddling word
+/// boundaries, and each update is a compare-exchange loop on the word
+/// holding the counter, so a counter can never carry into or borrow from
+/// its neighbours.  Like `CountingBloomFilter`, counters saturate at
+/// their maximum value and removes then leave them there, since a
+/// saturated counter may hold more inserts than it shows.  Decrements
+/// stop at zero so a counter can never underflow even if removes race
+/// ahead of the matching inserts.
+///
+/// Every operation takes `&self`.  Addressing matches
+/// `CountingBloomFilter`, so a filter built with the same parameters and
+/// hasher reports the same estimates once all updates have finished.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::AtomicCountingBloomFilter;
+///
+/// let filter = AtomicCountingBloomFilter::with_rate(4, 0.01, 1000);
+/// std::thread::scope(|s| {
+///     s.spawn(|| filter.insert(&1));
+///     s.spawn(|| filter.insert(&1));
+/// });
+/// assert_eq!(filter.estimate_count(&1), 2);
+/// assert_eq!(filter.remove(&1), 2);
+/// assert_eq!(filter.estimate_count(&1), 1);
+/// ```
+pub struct AtomicCountingBloomFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    words: Box<[AtomicU64]>,
+    num_entries: u64,
+    bits_per_entry: u32,
+    counters_per_word: u64,
+    max_value: u64,
+    num_hashes: u32,
+    hash_builder: H,
+}
+
+impl AtomicCountingBloomFilter<RandomXxh3State> {
+    /// Create a new AtomicCountingBloomFilter that will hold
+    /// `num_entries` items, uses `bits_per_entry` per item, and
+    /// `num_hashes` hashes
+    pub fn with_size(
+        num_entries: usize,
+        bits_per_entry: usize,
+        num_hashes: u32,
+    ) -> AtomicCountingBloomFilter<RandomXxh3State> {
+        AtomicCountingBloomFilter::with_size_and_hasher(
+            num_entries,
+            bits_per_entry,
+            num_hashes,
+            RandomXxh3State::new(),
+        )
+    }
+
+    /// create an AtomicCountingBloomFilter that uses `bits_per_entry`
+    /// entries and expects to hold `expected_num_items`.  The filter
+    /// will be sized to have a false positive rate of the value
+    /// specified in `rate`.
+    pub fn with_rate(
+        bits_per_entry: usize,
+        rate: f32,
+        expected_num_items: u32,
+    ) -> AtomicCountingBloomFilter<RandomXxh3State> {
+        AtomicCountingBloomFilter::with_rate_and_hasher(
+            bits_per_entry,
+            rate,
+            expected_num_items,
+            RandomXxh3State::new(),
+        )
+    }
+}
+
+impl<H> AtomicCountingBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Create a new AtomicCountingBloomFilter with the specified number
+    /// of entries, bits per entry, hashes, and hash builder.
+    ///
+    /// # Panics
+    /// Panics if `bits_per_entry` is 0 or larger than 32.
+    pub fn with_size_and_hasher(
+        num_entries: usize,
+        bits_per_entry: usize,
+        num_hashes: u32,
+        hash_builder: H,
+    ) -> AtomicCountingBloomFilter<H> {
+        assert!(
+            (1..=32).contains(&bits_per_entry),
+            "bits_per_entry must be between 1 and 32"
+        );
+        let counters_per_word = 64 / bits_per_entry;
+        AtomicCountingBloomFilter {
+            words: (0..num_entries.div_ceil(counters_per_word))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            num_entries: num_entries as u64,
+            bits_per_entry: bits_per_entry as u32,
+            counters_per_word: counters_per_word as u64,
+            max_value: (1 << bits_per_entry) - 1,
+            num_hashes,
+            hash_builder,
+        }
+    }
+
+    /// Create an AtomicCountingBloomFilter that expects to hold
+    /// `expected_num_items`.  The filter will be sized to have a
+    /// false positive rate of the value specified in `rate`.  Items
+    /// will be hashed using the Hasher produced by `hash_builder`.
+    pub fn with_rate_and_hasher(
+        bits_per_entry: usize,
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> AtomicCountingBloomFilter<H> {
+        let entries = needed_bits(rate, expected_num_items);
+        AtomicCountingBloomFilter::with_size_and_hasher(
+            entries,
+            bits_per_entry,
+            optimal_num_hashes(entries, expected_num_items),
+            hash_builder,
+        )
+    }
+
+    /// The maximum value each counter can hold
+    #[inline(always)]
+    pub fn max_value(&self) -> u32 {
+        self.max_value as u32
+    }
+
+    /// Get the hash builder this filter is using
+    #[inline(always)]
+    pub fn hash_builder(&self) -> &H {
+        &self.hash_builder
+    }
+
+    /// Returns the word holding the counter for `h` and the shift of the
+    /// counter within it.
+    #[inline(always)]
+    fn locate(&self, h: u64) -> (&AtomicU64, u32) {
+        let idx = fastrange(h, self.num_entries);
+        let shift = (idx % self.counters_per_word) as u32 * self.bits_per_entry;
+        (&self.words[(idx / self.counters_per_word) as usize], shift)
+    }
+
+    /// Applies `update` to the counter for `h` with a compare-exchange
+    /// loop, returning the prior value.  `update` returns None to leave
+    /// the counter unchanged.
+    #[inline(always)]
+    fn update(&self, h: u64, update: impl Fn(u64) -> Option<u64>) -> u32 {
+        let (word, shift) = self.locate(h);
+        let mut current = word.load(Ordering::Relaxed);
+        loop {
+            let value = (current >> shift) & self.max_value;
+            let new_value = match update(value) {
+                Some(new_value) => new_value,
+                None => return value as u32,
+            };
+            let new = (current & !(self.max_value << shift)) | (new_value << shift);
+            match word.compare_exchange_weak(current, new, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return value as u32,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn counter(&self, h: u64) -> u32 {
+        let (word, shift) = self.locate(h);
+        ((word.load(Ordering::Relaxed) >> shift) & self.max_value) as u32
+    }
+
+    #[inline(always)]
+    fn probeable(&self) -> bool {
+        probeable(self.num_entries)
+    }
+
+    fn insert_hash_iter(&self, h_iter: HashIter) -> u32 {
+        if !self.probeable() {
+            return 0;
+        }
+        let max = self.max_value;
+        h_iter
+            .map(|h| self.update(h, |v| if v < max { Some(v + 1) } else { None }))
+            .fold(u32::MAX, |min, cur| min.min(cur))
+    }
+
+    fn remove_hash_iter(&self, h_iter: HashIter) -> u32 {
+        if !self.contains_hash_iter(h_iter) {
+            return 0;
+        }
+        let max = self.max_value;
+        h_iter
+            .map(|h| self.update(h, |v| if v > 0 && v < max { Some(v - 1) } else { None }))
+            .fold(u32::MAX, |min, cur| min.min(cur))
+    }
+
+    fn estimate_count_hash_iter(&self, h_iter: HashIter) -> u32 {
+        if !self.probeable() {
+            return 0;
+        }
+        h_iter
+            .map(|h| self.counter(h))
+            .fold(u32::MAX, |min, cur| min.min(cur))
+    }
+
+    fn contains_hash_iter(&self, mut h_iter: HashIter) -> bool {
+        self.probeable() && h_iter.all(|h| self.counter(h) != 0)
+    }
+
+    /// Inserts an item, returns the estimated count of the number of
+    /// times this item had previously been inserted (not counting
+    /// this insertion)
+    #[inline(always)]
+    pub fn insert<T: Hash>(&self, item: &T) -> u32 {
+        self.insert_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Like `insert`, but a fast-path for when the item is a byte slice.
+    #[inline(always)]
+    pub fn insert_slice(&self, item: &[u8]) -> u32 {
+        self.insert_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Like `insert`, but a fast-path that lets you amortize hashing
+    /// across multiple filters sharing the same hash algorithm.
+    #[inline(always)]
+    pub fn insert_fingerprint(&self, fingerprint: BloomFingerprint) -> u32 {
+        self.insert_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Remove an item.  Returns an upper bound of the number of times
+    /// this item had been inserted previously (i.e. the count before
+    /// this remove).  Returns 0 if item was never inserted.
+    #[inline(always)]
+    pub fn remove<T: Hash>(&self, item: &T) -> u32 {
+        self.remove_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Like `remove`, but a fast-path for when the item is a byte slice.
+    #[inline(always)]
+    pub fn remove_slice(&self, item: &[u8]) -> u32 {
+        self.remove_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Like `remove`, but a fast-path for fingerprints.
+    #[inline(always)]
+    pub fn remove_fingerprint(&self, fingerprint: BloomFingerprint) -> u32 {
+        self.remove_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Return an estimate of the number of times `item` has been
+    /// inserted into the filter.  Estimate is a upper bound on the
+    /// count, meaning the item has been inserted *at most* this many
+    /// times, but possibly fewer.
+    #[inline(always)]
+    pub fn estimate_count<T: Hash>(&self, item: &T) -> u32 {
+        self.estimate_count_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Like `estimate_count`, but a fast-path for when the item is a
+    /// byte slice.
+    #[inline(always)]
+    pub fn estimate_count_slice(&self, item: &[u8]) -> u32 {
+        self.estimate_count_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Like `estimate_count`, but a fast-path for fingerprints.
+    #[inline(always)]
+    pub fn estimate_count_fingerprint(&self, fingerprint: BloomFingerprint) -> u32 {
+        self.estimate_count_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Check if the item has been inserted into this filter.
+    #[inline(always)]
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Like `contains`, but a fast-path for when the item is a byte slice.
+    #[inline(always)]
+    pub fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Like `contains`, but a fast-path for fingerprints.
+    #[inline(always)]
+    pub fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        self.contains_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Reset all counters to 0.
+    pub fn clear(&self) {
+        for word in self.words.iter() {
+            word.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AtomicBloomFilter, AtomicCountingBloomFilter};
+    use crate::hashing::{fastrange, HashIter};
+    use crate::{BloomFilter, CountingBloomFilter, RandomXxh3State, ASMS};
+
+    #[test]
+    fn simple() {
+        let filter = AtomicBloomFilter::with_rate(0.01, 100);
+        filter.insert(&1);
+        filter.insert_slice(b"hello");
+        assert!(filter.contains(&1));
+        assert!(!filter.contains(&2));
+        assert!(filter.contains_slice(b"hello"));
+        filter.clear();
+        assert!(!filter.contains(&1));
+    }
+
+    #[test]
+    fn freeze_matches_sequential() {
+        let filter = AtomicBloomFilter::with_size(1001, 5);
+        let mut sequential = BloomFilter::with_size_and_hasher(1001, 5, *filter.hash_builder());
+        for i in 0..200 {
+            filter.insert(&i);
+            sequential.insert(&i);
+        }
+        let frozen = filter.freeze();
+        assert_eq!(frozen.raw_bits(), sequential.raw_bits());
+    }
+
+    #[test]
+    fn from_bloom_filter() {
+        let mut b = BloomFilter::with_size(1001, 5);
+        for i in 0..200 {
+            b.insert(&i);
+        }
+        let atomic = AtomicBloomFilter::from(&b);
+        assert!((0..200).all(|i| atomic.contains(&i)));
+        assert_eq!(atomic.freeze().raw_bits(), b.raw_bits());
+    }
+
+    #[test]
+    fn concurrent_inserts() {
+        let threads = 8u32;
+        let per_thread = 20_000u32;
+        let filter = AtomicBloomFilter::with_rate(0.01, threads * per_thread);
+        std::thread::scope(|s| {
+            for t in 0..threads {
+                let filter = &filter;
+                s.spawn(move || {
+                    for i in t * per_thread..(t + 1) * per_thread {
+                        filter.insert(&i);
+                    }
+                });
+            }
+        });
+        assert!((0..threads * per_thread).all(|i| filter.contains(&i)));
+        let frozen: BloomFilter = filter.into();
+        assert!((0..threads * per_thread).all(|i| frozen.contains(&i)));
+    }
+
+    #[test]
+    fn counting_neighbours() {
+        // 3 bit counters so neighbours share words and 64 isn't a
+        // multiple of the counter width.
+        let filter = AtomicCountingBloomFilter::with_size(64, 3, 1);
+        // Probe hashes map onto counters by their top bits, so `i << 58`
+        // addresses counter `i` of 64.
+        for i in 0..64u64 {
+            for _ in 0..i % 8 {
+                filter.update(i << 58, |v| if v < 7 { Some(v + 1) } else { None });
+            }
+        }
+        for i in 0..64u64 {
+            assert_eq!(filter.counter(i << 58), (i % 8) as u32);
+        }
+        for i in 0..64u64 {
+            for _ in 0..10 {
+                filter.update(i << 58, |v| if v > 0 { Some(v - 1) } else { None });
+            }
+            assert_eq!(filter.counter(i << 58), 0);
+        }
+    }
+
+    #[test]
+    fn counting_concurrent_matches_sequential() {
+        let threads = 8u32;
+        let keys = 2_000u32;
+        let hasher = RandomXxh3State::from_seed(0xdead_beef);
+        let filter = AtomicCountingBloomFilter::with_rate_and_hasher(8, 0.01, keys, hasher);
+        let mut oracle = CountingBloomFilter::with_rate_and_hasher(8, 0.01, keys, hasher);
+
+        std::thread::scope(|s| {
+            for t in 0..threads {
+                let filter = &filter;
+                s.spawn(move || {
+                    // Churn the same keys from every thread.  Each thread
+                    // only removes what it inserted so counts are never
+                    // driven negative by correct usage.
+                    for round in 0..5 {
+                        for key in 0..keys {
+                            filter.insert(&key);
+                        }
+                        for key in 0..keys {
+                            assert!(filter.remove(&key) > 0);
+                        }
+                        // Leave a known residue behind.
+                        if round == t % 5 {
+                            for key in (t..keys).step_by(threads as usize) {
+                                filter.insert(&key);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        for t in 0..threads {
+            for key in (t..keys).step_by(threads as usize) {
+                oracle.insert(&key);
+            }
+        }
+
+        for key in 0..keys * 2 {
+            assert_eq!(filter.estimate_count(&key), oracle.estimate_count(&key));
+        }
+        for key in 0..keys {
+            assert_eq!(filter.remove(&key), oracle.remove(&key));
+        }
+        assert!((0..keys).all(|key| filter.estimate_count(&key) == 0));
+    }
+
+    #[test]
+    fn saturated_remove() {
+        let hasher = RandomXxh3State::from_seed(1);
+        let max = CountingBloomFilter::bits_for_max(3);
+        let cbf = AtomicCountingBloomFilter::with_size_and_hasher(64, max, 3, hasher);
+        // Find a key sharing a counter with key 0.
+        let counters = |key: u32| -> Vec<usize> {
+            HashIter::from(key, 3, &hasher).map(|h| fastrange(h, 64) as usize).collect()
+        };
+        let shared = (1..)
+            .find(|key| counters(*key).iter().any(|c| counters(0).contains(c)))
+            .unwrap();
+
+        for _ in 0..20 {
+            cbf.insert(&0);
+        }
+        cbf.insert(&shared);
+        for _ in 0..20 {
+            cbf.remove(&0);
+        }
+        assert!(cbf.contains(&shared));
+        // Key 0 is over-counted rather than going to zero.
+        assert_eq!(cbf.estimate_count(&0), 3);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "empty filter")]
+    fn counting_no_entries() {
+        AtomicCountingBloomFilter::with_size(0, 4, 3).insert(&1);
+    }
+}
@@ -0,0 +1,409 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+//! A fixed size bit array stored in cache line aligned `u64` words.
+//!
+//! Bit `i` is bit `i % 64` (counting from the least significant bit) of
+//! word `i / 64`.  Bits past `len` in the last word are always zero, so
+//! words can be combined and counted without masking.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::slice;
+
+const WORDS_PER_LINE: usize = 8;
+
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct CacheLine([u64; WORDS_PER_LINE]);
+
+/// The in-memory bit storage `BloomFilter` uses by default, kept in
+/// cache line aligned words.
+#[derive(Clone)]
+pub struct Bits {
+    lines: Box<[CacheLine]>,
+    len: usize,
+}
+
+impl Bits {
+    /// Create a bit array of `len` zero bits.
+    pub(crate) fn new(len: usize) -> Bits {
+        let lines = len.div_ceil(64).div_ceil(WORDS_PER_LINE);
+        Bits {
+            lines: vec![CacheLine([0; WORDS_PER_LINE]); lines].into_boxed_slice(),
+            len,
+        }
+    }
+
+    /// Create a bit array of `len` bits from `words`.  Missing words are
+    /// zero and bits past `len` are ignored.
+    pub(crate) fn from_words<I: IntoIterator<Item = u64>>(len: usize, words: I) -> Bits {
+        let mut bits = Bits::new(len);
+        for (dst, src) in bits.words_mut().iter_mut().zip(words) {
+            *dst = src;
+        }
+        bits.fix_last_word();
+        bits
+    }
+
+    /// Create a bit array of `len` bits from bytes packed most
+    /// significant bit first, i.e. bit `i` is `(bytes[i / 8] >> (7 - i %
+    /// 8)) & 1`.
+    ///
+    /// # Panics
+    /// Panics if `bytes` holds fewer than `len` bits.
+    pub(crate) fn from_msb_bytes(bytes: &[u8], len: usize) -> Bits {
+        let bytes = &bytes[..len.div_ceil(8)];
+        Bits::from_words(
+            len,
+            bytes.chunks(8).map(|chunk| {
+                let mut word = [0u8; 8];
+                for (dst, src) in word.iter_mut().zip(chunk) {
+                    *dst = src.reverse_bits();
+                }
+                u64::from_le_bytes(word)
+            }),
+        )
+    }
+
+    /// Pack the bits into bytes in the layout `from_msb_bytes` reads.
+    pub(crate) fn to_msb_bytes(&self) -> Vec<u8> {
+        self.words()
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .take(self.len.div_ceil(8))
+            .map(u8::reverse_bits)
+            .collect()
+    }
+
+    #[inline(always)]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline(always)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline(always)]
+    pub(crate) fn words(&self) -> &[u64] {
+        // SAFETY: a CacheLine is exactly WORDS_PER_LINE u64s with no
+        // padding, and there are enough lines for every word.
+        unsafe { slice::from_raw_parts(self.lines.as_ptr().cast(), self.len.div_ceil(64)) }
+    }
+
+    /// Mutable access to the words.  Callers must leave the bits past
+    /// `len` zero.
+    #[inline(always)]
+    pub(crate) fn words_mut(&mut self) -> &mut [u64] {
+        // SAFETY: as for `words`.
+        unsafe { slice::from_raw_parts_mut(self.lines.as_mut_ptr().cast(), self.len.div_ceil(64)) }
+    }
+
+    fn fix_last_word(&mut self) {
+        let extra_bits = self.len % 64;
+        if extra_bits > 0 {
+            if let Some(last) = self.words_mut().last_mut() {
+                *last &= (1 << extra_bits) - 1;
+            }
+        }
+    }
+
+    /// Get bit `i`.
+    ///
+    /// # Panics
+    /// Panics if `i` is out of bounds.
+    #[inline(always)]
+    pub(crate) fn get(&self, i: usize) -> bool {
+        assert!(i < self.len, "bit index {} out of bounds", i);
+        self.words()[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    /// Get bit `i` without a bounds check in release builds.
+    ///
+    /// # Safety
+    /// `i` must be less than `len()`.
+    #[inline(always)]
+    pub(crate) unsafe fn get_unchecked(&self, i: usize) -> bool {
+        debug_assert!(i < self.len, "bit index {} out of bounds", i);
+        // SAFETY: the caller guarantees `i < len`, so word `i / 64` exists.
+        unsafe { *self.words().get_unchecked(i / 64) & (1 << (i % 64)) != 0 }
+    }
+
+    /// Set bit `i` without a bounds check in release builds.
+    ///
+    /// # Safety
+    /// `i` must be less than `len()`.
+    #[inline(always)]
+    pub(crate) unsafe fn set_unchecked(&mut self, i: usize) {
+        debug_assert!(i < self.len, "bit index {} out of bounds", i);
+        // SAFETY: as for `get_unchecked`.
+        unsafe { *self.words_mut().get_unchecked_mut(i / 64) |= 1 << (i % 64) }
+    }
+
+    /// Set bit `i` without a bounds check in release builds, returning
+    /// whether it was already set.
+    ///
+    /// # Safety
+    /// `i` must be less than `len()`.
+    #[inline(always)]
+    pub(crate) unsafe fn replace_unchecked(&mut self, i: usize) -> bool {
+        debug_assert!(i < self.len, "bit index {} out of bounds", i);
+        let mask = 1 << (i % 64);
+        // SAFETY: as for `get_unchecked`.
+        let word = unsafe { self.words_mut().get_unchecked_mut(i / 64) };
+        let old = *word;
+        *word = old | mask;
+        old & mask != 0
+    }
+
+    /// Hint that bit `i` is about to be read.  This is a no-op on
+    /// targets without a prefetch instruction we can issue on stable.
+    #[inline(always)]
+    pub(crate) fn prefetch(&self, i: usize) {
+        let word = self.words().as_ptr().wrapping_add(i / 64);
+        #[cfg(target_arch = "x86_64")]
+        // SAFETY: SSE is part of the x86_64 baseline, and prefetching
+        // never faults even for an invalid address.
+        unsafe {
+            use core::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+            _mm_prefetch::<_MM_HINT_T0>(word.cast());
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        let _ = word;
+    }
+
+    /// Set bit `i`.
+    ///
+    /// # Panics
+    /// Panics if `i` is out of bounds.
+    #[inline(always)]
+    pub(crate) fn set(&mut self, i: usize) {
+        assert!(i < self.len, "bit index {} out of bounds", i);
+        self.words_mut()[i / 64] |= 1 << (i % 64);
+    }
+
+    /// Clear bit `i`.
+    ///
+    /// # Panics
+    /// Panics if `i` is out of bounds.
+    #[inline(always)]
+    pub(crate) fn unset(&mut self, i: usize) {
+        assert!(i < self.len, "bit index {} out of bounds", i);
+        self.words_mut()[i / 64] &= !(1 << (i % 64));
+    }
+
+    /// Set every bit that is set in `other`.
+    ///
+    /// # Panics
+    /// Panics if the lengths differ.
+    pub(crate) fn or(&mut self, other: &Bits) {
+        assert_eq!(self.len, other.len, "bit arrays must be the same length");
+        for (word, other) in self.words_mut().iter_mut().zip(other.words()) {
+            *word |= other;
+        }
+    }
+
+    /// Clear every bit that isn't set in `other`.
+    ///
+    /// # Panics
+    /// Panics if the lengths differ.
+    pub(crate) fn and(&mut self, other: &Bits) {
+        assert_eq!(self.len, other.len, "bit arrays must be the same length");
+        for (word, other) in self.words_mut().iter_mut().zip(other.words()) {
+            *word &= other;
+        }
+    }
+
+    pub(crate) fn count_ones(&self) -> usize {
+        self.words()
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// The number of bits set in both `self` and `other`.
+    ///
+    /// # Panics
+    /// Panics if the lengths differ.
+    pub(crate) fn count_ones_and(&self, other: &Bits) -> usize {
+        assert_eq!(self.len, other.len, "bit arrays must be the same length");
+        self.words()
+            .iter()
+            .zip(other.words())
+            .map(|(a, b)| (a & b).count_ones() as usize)
+            .sum()
+    }
+
+    /// The number of bits set in `self` but not in `other`.
+    ///
+    /// # Panics
+    /// Panics if the lengths differ.
+    pub(crate) fn count_ones_and_not(&self, other: &Bits) -> usize {
+        assert_eq!(self.len, other.len, "bit arrays must be the same length");
+        self.words()
+            .iter()
+            .zip(other.words())
+            .map(|(a, b)| (a & !b).count_ones() as usize)
+            .sum()
+    }
+
+    pub(crate) fn any(&self) -> bool {
+        self.words().iter().any(|&word| word != 0)
+    }
+
+    /// Iterate over the indices of the set bits, in increasing order.
+    pub(crate) fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words()
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &word)| word_ones(i, word))
+    }
+
+    /// Iterate over the indices of the bits set in `self` but not in
+    /// `other`, in increasing order.
+    ///
+    /// # Panics
+    /// Panics if the lengths differ.
+    pub(crate) fn ones_and_not<'a>(&'a self, other: &'a Bits) -> impl Iterator<Item = usize> + 'a {
+        assert_eq!(self.len, other.len, "bit arrays must be the same length");
+        self.words()
+            .iter()
+            .zip(other.words())
+            .enumerate()
+            .flat_map(|(i, (a, b))| word_ones(i, a & !b))
+    }
+
+    /// Clear every bit.
+    pub(crate) fn clear(&mut self) {
+        self.words_mut().fill(0);
+    }
+
+    /// Clear bits `start..end`, a word at a time with masked edges.
+    ///
+    /// # Panics
+    /// Panics if `start > end` or `end > len()`.
+    pub(crate) fn clear_range(&mut self, start: usize, end: usize) {
+        assert!(
+            start <= end,
+            "range starts at {} but ends at {}",
+            start,
+            end
+        );
+        assert!(end <= self.len, "bit index {} out of bounds", end);
+        if start == end {
+            return;
+        }
+        let (first, last) = (start / 64, (end - 1) / 64);
+        let head = u64::MAX << (start % 64);
+        let tail = u64::MAX >> (63 - (end - 1) % 64);
+        let words = self.words_mut();
+        if first == last {
+            words[first] &= !(head & tail);
+        } else {
+            words[first] &= !head;
+            words[first + 1..last].fill(0);
+            words[last] &= !tail;
+        }
+    }
+}
+
+impl FromIterator<bool> for Bits {
+    fn from_iter<I: IntoIterator<Item = bool>>(iter: I) -> Bits {
+        let flags: Vec<bool> = iter.into_iter().collect();
+        let mut bits = Bits::new(flags.len());
+        for (i, _) in flags.iter().enumerate().filter(|(_, &b)| b) {
+            bits.set(i);
+        }
+        bits
+    }
+}
+
+/// The indices of the set bits of `word`, the `i`th word of an array.
+fn word_ones(i: usize, mut word: u64) -> impl Iterator<Item = usize> {
+    core::iter::from_fn(move || {
+        if word == 0 {
+            return None;
+        }
+        let bit = word.trailing_zeros() as usize;
+        word &= word - 1;
+        Some(i * 64 + bit)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bits;
+
+    #[test]
+    fn set_get() {
+        let mut bits = Bits::new(130);
+        assert!(bits.words().as_ptr().align_offset(64) == 0);
+        for i in [0, 1, 63, 64, 129] {
+            bits.set(i);
+        }
+        assert!(bits.get(63) && bits.get(64) && !bits.get(65));
+        assert_eq!(bits.count_ones(), 5);
+        assert_eq!(bits.ones().collect::<Vec<_>>(), vec![0, 1, 63, 64, 129]);
+        bits.unset(1);
+        assert!(!bits.get(1));
+        unsafe {
+            bits.set_unchecked(2);
+            assert!(bits.get_unchecked(2) && !bits.get_unchecked(3));
+        }
+        bits.clear();
+        assert!(!bits.any());
+    }
+
+    #[test]
+    fn clear_range() {
+        for (start, end) in [
+            (0, 0),
+            (0, 200),
+            (3, 5),
+            (10, 64),
+            (63, 65),
+            (5, 130),
+            (64, 128),
+        ] {
+            let mut bits = Bits::from_words(200, [u64::MAX; 4]);
+            bits.clear_range(start, end);
+            let expected: Vec<usize> = (0..200).filter(|i| !(start..end).contains(i)).collect();
+            assert_eq!(bits.ones().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn msb_bytes() {
+        let bytes = [0x80, 0x01, 0xff];
+        let bits = Bits::from_msb_bytes(&bytes, 20);
+        assert_eq!(bits.ones().collect::<Vec<_>>(), vec![0, 15, 16, 17, 18, 19]);
+        assert_eq!(bits.to_msb_bytes(), vec![0x80, 0x01, 0xf0]);
+    }
+
+    #[test]
+    fn combine() {
+        let mut a = Bits::from_words(70, [0b1100, 0b1]);
+        let b = Bits::from_words(70, [0b1010, u64::MAX]);
+        assert_eq!(b.words(), &[0b1010, 0x3f]);
+        let mut c = a.clone();
+        c.or(&b);
+        assert_eq!(c.words(), &[0b1110, 0x3f]);
+        a.and(&b);
+        assert_eq!(a.words(), &[0b1000, 0b1]);
+    }
+}
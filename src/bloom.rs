@@ -13,19 +13,42 @@
 // Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
 // 02110-1301, USA.
 
-extern crate bit_vec;
 extern crate core;
 extern crate xxhash_rust;
 
-use bit_vec::BitVec;
-use std::cmp::{max, min};
-use std::hash::Hash;
+#[cfg(feature = "std")]
+use std::collections::HashSet;
+use core::fmt;
+use core::hash::Hash;
+#[cfg(feature = "std")]
+use core::hash::Hasher;
+#[cfg(feature = "std")]
+use std::io;
 
+use crate::bits::Bits;
+#[cfg(feature = "roaring")]
+use crate::error::BitOutOfRange;
+use crate::error::{
+    BufferTooShort, CapacityExceeded, FoldError, IncompatibleFilters, ParameterError,
+    SaturatedError,
+};
+#[cfg(feature = "metrics")]
+use crate::metrics::BloomStats;
+use crate::metrics::OpCounters;
+use crate::recent::RecentInserts;
+use crate::storage::{BitStorage, BitStorageMut};
+#[cfg(feature = "std")]
+use crate::xxh_helper::{random_secret, SecretBasedXxh3Builder};
 use crate::xxh_helper::RandomXxh3State;
-use crate::BloomBuildHasher;
+#[cfg(feature = "std")]
+use crate::BloomHasher;
+use crate::{BloomBuildHasher, BloomFingerprint, CachedKey, FrozenBloomFilter};
 
-use super::hashing::HashIter;
+use super::hashing::{fastrange, fingerprint, probeable, HashIter};
 use super::{Intersectable, Unionable, ASMS};
+#[cfg(not(any(feature = "std", test)))]
+use crate::float::FloatExt;
+use alloc::vec::Vec;
 
 /// A standard BloomFilter.  If an item is instered then `contains`
 /// is guaranteed to return `true` for that item.  For items not
@@ -33,6 +56,12 @@ use super::{Intersectable, Unionable, ASMS};
 /// that `contains` returns `true` for an item that was not inserted
 /// is called the False Positive Rate.
 ///
+/// The bits are kept in `S`, an in-memory `Bits` unless another
+/// `BitStorage` is picked with `with_storage_and_hasher`.  Inserting
+/// needs storage implementing `BitStorageMut`; filters over read only
+/// storage, such as the memory mapped `MmapBits` from `open_mmap`, only
+/// support lookups.
+///
 /// # False Positive Rate
 /// The false positive rate is specified as a float in the range
 /// (0,1).  If indicates that out of `X` probes, `X * rate` should
@@ -42,6 +71,7 @@ use super::{Intersectable, Unionable, ASMS};
 /// # Example Usage
 ///
 /// ```rust
+/// # #[cfg(feature = "std")] {
 /// use xx_bloom::{ASMS,BloomFilter};
 ///
 /// let expected_num_items = 1000;
@@ -53,96 +83,314 @@ use super::{Intersectable, Unionable, ASMS};
 /// filter.insert(&1);
 /// filter.contains(&1); /* true */
 /// filter.contains(&2); /* false */
+/// # }
 /// ```
-pub struct BloomFilter<H = RandomXxh3State>
+pub struct BloomFilter<H = RandomXxh3State, S = Bits>
 where
     H: BloomBuildHasher,
 {
-    bits: BitVec,
+    bits: S,
     num_hashes: u32,
     hash_builder: H,
+    domain: u64,
+    layout: IndexLayout,
+    stats: OpCounters,
+    recent: RecentInserts,
+    occupancy: Occupancy,
+}
+
+// What a filter knows about how full it is.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Occupancy {
+    // Inserts that set at least one new bit, plus what was merged in.
+    pub(crate) insertions: u64,
+    // Only true while no bit can be set, so lookups can skip probing.
+    // Anything that might set bits without counting them clears it.
+    pub(crate) empty: bool,
+    // The number of set bits, while it's known.  Inserts keep it up to
+    // date; anything else that changes the bits forgets it, and
+    // `insert_within_fpr` counts them again when next called.
+    pub(crate) set_bits: Option<u64>,
+    pub(crate) capacity: Option<u64>,
+    pub(crate) capacity_limit: f64,
+}
+
+impl Occupancy {
+    // A filter whose bits are all known to be clear.
+    pub(crate) fn fresh() -> Occupancy {
+        Occupancy {
+            insertions: 0,
+            empty: true,
+            set_bits: Some(0),
+            capacity: None,
+            capacity_limit: 1.0,
+        }
+    }
+
+    // A filter whose bits came from elsewhere and haven't been counted.
+    pub(crate) fn unknown() -> Occupancy {
+        Occupancy {
+            empty: false,
+            set_bits: None,
+            ..Occupancy::fresh()
+        }
+    }
+
+    // Account for `other`'s items being merged in.
+    pub(crate) fn merge(&mut self, other: &Occupancy) {
+        self.insertions = self.insertions.saturating_add(other.insertions);
+        self.empty &= other.empty;
+        self.set_bits = None;
+    }
+}
+
+// Probe positions computed and prefetched ahead of testing them.
+// Filters using more hashes test the rest without prefetching.
+const PREFETCH_PROBES: usize = 32;
+
+/// How a filter maps each 64-bit probe hash onto a bit index.
+///
+/// The mapping determines which bits an item sets, so a filter must
+/// always be read back with the layout it was built with.  The
+/// serialization format records it; callers using `raw_bits` must
+/// track it themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexLayout {
+    /// `h % num_bits`, the mapping used by filters built before
+    /// `FastRange` was introduced.
+    Modulo,
+    /// Lemire's multiply-shift reduction `(h * num_bits) >> 64`, which
+    /// avoids a 64-bit division per probe.
+    ///
+    /// Neither this nor `Modulo` is measurably biased.  Both map 2^64
+    /// hashes onto `num_bits` indices, so some indices get one more
+    /// hash than others, a relative skew of about `num_bits / 2^64`
+    /// whatever the size.  Modulo gives the extra hashes to the lowest
+    /// indices, this spreads them evenly.
+    #[default]
+    FastRange,
+    /// `h & (num_bits - 1)`, for filters whose size is a power of two.
+    /// This is a single AND per probe.  The probe step is odd, so a
+    /// key's probes are distinct modulo any power of two, and the low
+    /// bits a mask keeps are as well spread as the high ones.
+    Mask,
+}
+
+impl IndexLayout {
+    #[inline(always)]
+    pub(crate) fn index(self, h: u64, num_bits: usize) -> usize {
+        match self {
+            IndexLayout::Modulo => (h % num_bits as u64) as usize,
+            IndexLayout::FastRange => fastrange(h, num_bits as u64) as usize,
+            IndexLayout::Mask => (h & (num_bits as u64 - 1)) as usize,
+        }
+    }
+
+    /// Returns true if this layout can address a filter of `num_bits`.
+    #[inline(always)]
+    pub(crate) fn supports(self, num_bits: usize) -> bool {
+        self != IndexLayout::Mask || num_bits.is_power_of_two()
+    }
 }
 
 impl BloomFilter<RandomXxh3State> {
     /// Create a new BloomFilter with the specified number of bits,
-    /// and hashes
+    /// and hashes.  `num_hashes` should be at most `MAX_NUM_HASHES`.
+    ///
+    /// # Panics
+    /// In debug builds, panics if `num_bits` or `num_hashes` is 0; use
+    /// `try_with_size` to check them instead.
+    #[cfg(feature = "std")]
     pub fn with_size(num_bits: usize, num_hashes: u32) -> BloomFilter<RandomXxh3State> {
-        BloomFilter {
-            bits: BitVec::from_elem(num_bits, false),
-            num_hashes,
-            hash_builder: RandomXxh3State::new(),
-        }
+        BloomFilter::with_size_and_hasher(num_bits, num_hashes, RandomXxh3State::new())
+    }
+
+    /// Like `with_size`, but returns an error if `num_bits` or
+    /// `num_hashes` is 0, e.g. when they come from a config file.
+    #[cfg(feature = "std")]
+    pub fn try_with_size(
+        num_bits: usize,
+        num_hashes: u32,
+    ) -> Result<BloomFilter<RandomXxh3State>, ParameterError> {
+        BloomFilter::try_with_size_and_hasher(num_bits, num_hashes, RandomXxh3State::new())
+    }
+
+    /// Create a new BloomFilter with `2^num_bits_log2` bits and the
+    /// specified number of hashes, using the `Mask` layout.
+    #[cfg(feature = "std")]
+    pub fn with_size_pow2(num_bits_log2: u32, num_hashes: u32) -> BloomFilter<RandomXxh3State> {
+        BloomFilter::with_size_pow2_and_hasher(num_bits_log2, num_hashes, RandomXxh3State::new())
     }
 
     /// create a BloomFilter that expects to hold
     /// `expected_num_items`.  The filter will be sized to have a
     /// false positive rate of the value specified in `rate`.
+    ///
+    /// # Panics
+    /// Panics if `rate` isn't strictly between 0 and 1 or
+    /// `expected_num_items` is 0; use `try_with_rate` to check them
+    /// instead.
+    #[cfg(feature = "std")]
     pub fn with_rate(rate: f32, expected_num_items: u32) -> BloomFilter<RandomXxh3State> {
         let bits = needed_bits(rate, expected_num_items);
         BloomFilter::with_size(bits, optimal_num_hashes(bits, expected_num_items))
     }
+
+    /// Like `with_rate`, but returns an error if `rate` isn't strictly
+    /// between 0 and 1 or `expected_num_items` is 0.
+    #[cfg(feature = "std")]
+    pub fn try_with_rate(
+        rate: f32,
+        expected_num_items: u32,
+    ) -> Result<BloomFilter<RandomXxh3State>, ParameterError> {
+        BloomFilter::try_with_rate_and_hasher(rate, expected_num_items, RandomXxh3State::new())
+    }
+
+    /// Create the BloomFilter with the lowest false positive rate for
+    /// `expected_num_items` that fits in `bytes` bytes of bits.  Returns
+    /// the filter along with that theoretical false positive rate.
+    ///
+    /// This is the inverse of `with_rate`, for when memory rather than
+    /// accuracy is the fixed constraint.
+    ///
+    /// # Panics
+    /// Panics if `bytes` or `expected_num_items` is 0.
+    #[cfg(feature = "std")]
+    pub fn with_memory_budget(
+        bytes: usize,
+        expected_num_items: u32,
+    ) -> (BloomFilter<RandomXxh3State>, f64) {
+        BloomFilter::with_memory_budget_and_hasher(bytes, expected_num_items, RandomXxh3State::new())
+    }
+
+    /// Like `with_rate`, but hashing with a secret derived from `seed`.
+    /// Filters created with the same parameters and seed hash
+    /// identically, even in different processes, so they can be
+    /// intersected and unioned with one another.
+    pub fn with_rate_seeded(
+        rate: f32,
+        expected_num_items: u32,
+        seed: u64,
+    ) -> BloomFilter<RandomXxh3State> {
+        BloomFilter::with_rate_and_hasher(
+            rate,
+            expected_num_items,
+            RandomXxh3State::from_seed(seed),
+        )
+    }
 }
 
-impl<H> BloomFilter<H>
-where
-    H: BloomBuildHasher,
-{
-    /// Create a new BloomFilter with the exact same parameters as the other.
-    /// These two filters can be safely intersected and unioned with one another.
-    /// Otherwise you have to be careful that not only the number of bits and number of hashes
-    /// is the same, but also that the hash algorithms used have the same parameters (the default
-    /// hash functions use random state).
-    pub fn combinable_with(other: &BloomFilter<H>) -> Self {
-        BloomFilter {
-            bits: BitVec::from_elem(other.num_bits(), false),
-            num_hashes: other.num_hashes,
-            hash_builder: other.hash_builder.clone(),
-        }
+#[cfg(feature = "std")]
+impl BloomFilter<SecretBasedXxh3Builder> {
+    /// Build a filter holding exactly the keys in `keys` with a false
+    /// positive rate of `rate`.
+    ///
+    /// Since every key is known up front the filter is sized exactly
+    /// for the number of keys, and a handful of random secrets are tried
+    /// keeping whichever leaves the fewest bits set (and so has the
+    /// lowest actual false positive rate).  `keys` is iterated once to
+    /// count it and once per attempt.  The chosen secret is available via
+    /// `hash_builder().secret()` so the filter can be persisted.
+    ///
+    /// # Panics
+    /// Panics if `rate` isn't strictly between 0 and 1, or the number of
+    /// bits doesn't fit in a usize.
+    pub fn build_from_slices<'a, I>(keys: I, rate: f64) -> BloomFilter<SecretBasedXxh3Builder>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+        I::IntoIter: Clone,
+    {
+        let keys = keys.into_iter();
+        let num_keys = keys.clone().count();
+        BloomFilter::build_from_slices_with_count(keys, num_keys, rate, 8)
+    }
+
+    /// Like `build_from_slices`, but with the exact number of keys
+    /// provided so counting isn't needed, and with control over how many
+    /// random secrets are tried.
+    ///
+    /// # Panics
+    /// Panics if `attempts` is 0, or `rate` isn't strictly between 0 and
+    /// 1, or the number of bits doesn't fit in a usize.
+    pub fn build_from_slices_with_count<'a, I>(
+        keys: I,
+        num_keys: usize,
+        rate: f64,
+        attempts: usize,
+    ) -> BloomFilter<SecretBasedXxh3Builder>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+        I::IntoIter: Clone,
+    {
+        assert!(attempts > 0, "at least one attempt is needed");
+        check_false_pos_rate(rate).unwrap_or_else(|e| panic!("{}", e));
+        let keys = keys.into_iter();
+        let n = num_keys.max(1) as f64;
+        let ln2 = core::f64::consts::LN_2;
+        let num_bits = (-n * rate.ln() / (ln2 * ln2)).ceil().max(1.0);
+        assert!(num_bits < usize::MAX as f64, "{}", ParameterError::TooLarge);
+        let num_bits = num_bits as usize;
+        let num_hashes = ((num_bits as f64 / n * ln2).round() as u32).clamp(1, MAX_NUM_HASHES);
+
+        (0..attempts)
+            .map(|_| {
+                let mut filter = BloomFilter::with_size_and_hasher(
+                    num_bits,
+                    num_hashes,
+                    SecretBasedXxh3Builder::with_secret(random_secret()),
+                );
+                keys.clone().for_each(|key| filter.insert_slice(key));
+                filter
+            })
+            .min_by_key(|filter| filter.count_ones())
+            .unwrap()
     }
 }
 
-impl<H> BloomFilter<H>
+impl<H, S> BloomFilter<H, S>
 where
     H: BloomBuildHasher,
+    S: BitStorage,
 {
-    /// Create a new BloomFilter with the specified number of bits,
-    /// hashes, and the two specified HashBuilders.  Note the the
-    /// HashBuilders MUST provide independent hash values.  Passing
-    /// two HashBuilders that produce the same or correlated hash
-    /// values will break the false positive guarantees of the
-    /// BloomFilter.
-    pub fn with_size_and_hasher(
-        num_bits: usize,
-        num_hashes: u32,
-        hash_builder: H,
-    ) -> BloomFilter<H> {
-        BloomFilter {
-            bits: BitVec::from_elem(num_bits, false),
+    /// Create a BloomFilter over existing `storage`, keeping whatever
+    /// bits it already has set, probing `num_hashes` times with hashes
+    /// from `hash_builder` and mapped with the default `IndexLayout`.
+    /// Use this to insert into and look up in a backend other than
+    /// `Bits`.
+    ///
+    /// # Panics
+    /// In debug builds, panics if `storage` is empty or `num_hashes` is
+    /// 0.
+    pub fn with_storage_and_hasher(storage: S, num_hashes: u32, hash_builder: H) -> Self {
+        debug_assert!(!storage.is_empty(), "can't build an empty filter");
+        debug_assert!(num_hashes > 0, "can't build a filter without hashes");
+        BloomFilter::from_parts(
+            storage,
             num_hashes,
             hash_builder,
-        }
+            0,
+            IndexLayout::default(),
+        )
+        .recount()
     }
 
-    /// Create a BloomFilter that expects to hold
-    /// `expected_num_items`.  The filter will be sized to have a
-    /// false positive rate of the value specified in `rate`.  Items
-    /// will be hashed using the Hashers produced by
-    /// `hash_builder` and `hash_builder_two`.  Note the the
-    /// HashBuilders MUST provide independent hash values.  Passing
-    /// two HashBuilders that produce the same or correlated hash
-    /// values will break the false positive guarantees of the
-    /// BloomFilter.
-    pub fn with_rate_and_hasher(
-        rate: f32,
-        expected_num_items: u32,
+    pub(crate) fn from_parts(
+        bits: S,
+        num_hashes: u32,
         hash_builder: H,
-    ) -> BloomFilter<H> {
-        let bits = needed_bits(rate, expected_num_items);
-        BloomFilter::with_size_and_hasher(
+        domain: u64,
+        layout: IndexLayout,
+    ) -> BloomFilter<H, S> {
+        BloomFilter {
             bits,
-            optimal_num_hashes(bits, expected_num_items),
+            num_hashes,
             hash_builder,
-        )
+            domain,
+            layout,
+            stats: OpCounters::default(),
+            recent: RecentInserts::default(),
+            occupancy: Occupancy::unknown(),
+        }
     }
 
     /// Get the number of bits this BloomFilter is using
@@ -157,191 +405,2818 @@ where
         self.num_hashes
     }
 
-    fn insert_hash_iter(&mut self, h_iter: HashIter) {
-        h_iter.for_each(|h| {
-            let idx: usize = (h % self.num_bits() as u64) as usize;
-            self.bits.set(idx, true);
-        });
+    /// Get the domain mixed into this BloomFilter's fingerprints
+    #[inline(always)]
+    pub fn domain(&self) -> u64 {
+        self.domain
     }
 
-    fn contains_hash_iter(&self, mut h_iter: HashIter) -> bool {
-        h_iter.all(|h| {
-            let idx: usize = (h % self.num_bits() as u64) as usize;
-            match self.bits.get(idx) {
-                Some(b) => {
-                    b
-                }
-                None => {
-                    panic!("Hash mod failed");
-                }
-            }
-        })
+    /// Get the IndexLayout this BloomFilter maps probes with
+    #[inline(always)]
+    pub fn layout(&self) -> IndexLayout {
+        self.layout
     }
-}
 
-impl<H> ASMS for BloomFilter<H>
-where
-    H: BloomBuildHasher,
-{
-    /// Insert item into this BloomFilter.
-    ///
-    /// If the BloomFilter did not have this value present, `true` is returned.
-    ///
-    /// If the BloomFilter did have this value present, `false` is returned.
+    /// Get the hash builder this BloomFilter is using
     #[inline(always)]
-    fn insert<T: Hash>(&mut self, item: &T) {
-        self.insert_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    pub fn hash_builder(&self) -> &H {
+        &self.hash_builder
     }
 
-    /// Insert item into this BloomFilter.
-    ///
-    /// If the BloomFilter did not have this value present, `true` is returned.
-    ///
-    /// If the BloomFilter did have this value present, `false` is returned.
-    /// 
-    /// This is a faster-path if the item you're inserting is a byte slice.
-    #[inline(always)]
-    fn insert_slice(&mut self, item: &[u8]) {
-        self.insert_hash_iter(HashIter::from_slice(
-            item,
-            self.num_hashes,
-            &self.hash_builder,
-        ))
+    /// Get the number of bits that are set in this BloomFilter
+    pub fn count_ones(&self) -> usize {
+        self.bits.count_ones()
     }
 
-    /// Insert a fingerprint into this BloomFilter.
-    ///
-    /// If the BloomFilter did not have this value present, `true` is returned.
-    ///
-    /// If the BloomFilter did have this value present, `false` is returned.
-    /// 
-    /// This is a faster-path if you have multiple filters that share the same
-    /// hash algorithm that you're inserting into. This will let you amortize
-    /// the cost of the hash.
-    #[inline(always)]
-    fn insert_fingerprint(&mut self, fingerprint: crate::BloomFingerprint) {
-        self.insert_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    /// The fraction of bits that are set.
+    pub fn fill_ratio(&self) -> f64 {
+        self.count_ones() as f64 / self.num_bits() as f64
     }
 
-    /// Check if the item has been inserted into this bloom filter.
-    /// This function can return false positives, but not false
-    /// negatives.
-    #[inline(always)]
-    fn contains<T: Hash>(&self, item: &T) -> bool {
-        self.contains_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    /// The false positive rate the filter currently has, estimated from
+    /// how many bits are set: the chance that all `num_hashes` probes of
+    /// an item that was never inserted land on set bits.
+    pub fn estimated_fpp(&self) -> f64 {
+        theoretical_fpr(self.count_ones(), self.num_bits(), self.num_hashes)
     }
 
-    /// Check if the item has been inserted into this bloom filter.
-    /// This function can return false positives, but not false
-    /// negatives.
-    /// This is a faster-path if the item you're inserting is a byte slice.
-    #[inline(always)]
-    fn contains_slice(&self, item: &[u8]) -> bool {
-        self.contains_hash_iter(HashIter::from_slice(
-            item,
-            self.num_hashes,
-            &self.hash_builder,
-        ))
+    /// Estimate how many distinct items have been inserted, based on how
+    /// many bits are set (Swamidass & Baldi).  Returns infinity once
+    /// every bit is set.
+    pub fn estimate_cardinality(&self) -> f64 {
+        let m = self.num_bits() as f64;
+        let k = self.num_hashes as f64;
+        let set = self.count_ones() as f64;
+        -(m / k) * (1.0 - set / m).ln()
     }
 
-    /// Check if the item has been inserted into this bloom filter.
-    /// This function can return false positives, but not false
-    /// negatives.
-    /// This is a faster-path if you have multiple filters that share the same
-    /// hash algorithm that you're checking containership for. This will let you amortize
-    /// the cost of the hash.
-    #[inline(always)]
-    fn contains_fingerprint(&self, fingerprint: crate::BloomFingerprint) -> bool {
-        self.contains_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    /// The fingerprints of the last 64 items inserted, oldest first, as
+    /// hashed by this filter's hasher before any domain is mixed in.
+    /// When `contains` misses an item believed inserted, compare its
+    /// `hash_one_128` against these to see whether it was hashed
+    /// differently, e.g. through a different hasher or by mixing
+    /// `insert` with `contains_slice`.  Recording costs a copy per
+    /// insert, so it is only compiled in with the `debug-tracking`
+    /// feature.  `clear` doesn't forget them.
+    #[cfg(feature = "debug-tracking")]
+    pub fn debug_recent(&self) -> &[BloomFingerprint] {
+        self.recent.as_slice()
     }
 
-    /// Remove all values from this BloomFilter
-    #[inline(always)]
-    fn clear(&mut self) {
-        self.bits.clear();
+    /// Roughly how many distinct items have been inserted: inserts that
+    /// set at least one new bit, so an item inserted twice (or a false
+    /// positive inserted) isn't counted again.  Unions add the other
+    /// filter's count, so overlapping items are counted twice.  For a
+    /// filter built from bits of unknown history, e.g. with
+    /// `from_raw_bits`, this starts at `estimate_cardinality`.
+    pub fn approximate_insertions(&self) -> u64 {
+        self.occupancy.insertions
     }
-}
 
-impl<H> Intersectable for BloomFilter<H> where H: BloomBuildHasher {
-    /// Calculates the intersection of two BloomFilters.  Only items inserted into both filters will still be present in `self`.
-    ///
-    /// Both BloomFilters must be using the same number of
-    /// bits. Returns true if self changed.
-    ///
-    /// # Panics
-    /// Panics if the BloomFilters are not using the same number of bits
-    fn intersect(&mut self, other: &BloomFilter<H>) {
-        self.bits.and(&other.bits);
+    /// The number of items the filter was sized for, if it was built
+    /// from an expected number of items, e.g. with `with_rate`.
+    pub fn configured_capacity(&self) -> Option<u64> {
+        self.occupancy.capacity
     }
-}
 
-impl<H> Unionable for BloomFilter<H> where H: BloomBuildHasher {
-    /// Calculates the union of two BloomFilters.  Items inserted into
-    /// either filters will be present in `self`.
-    ///
-    /// Both BloomFilters must be using the same number of
-    /// bits. Returns true if self changed.
+    /// Returns true if more items have been inserted than the filter
+    /// was sized for, so its false positive rate is above the one it
+    /// was built for.  Always false without a `configured_capacity`.
+    pub fn over_capacity(&self) -> bool {
+        self.occupancy
+            .capacity
+            .is_some_and(|capacity| self.occupancy.insertions > capacity)
+    }
+
+    /// Let `insert_checked` accept up to `multiple` times the configured
+    /// capacity before refusing new items.  The default is 1.
     ///
     /// # Panics
-    /// Panics if the BloomFilters are not using the same number of bits
-    fn union(&mut self, other: &BloomFilter<H>) {
-        self.bits.or(&other.bits);
+    /// Panics if `multiple` isn't positive.
+    pub fn with_capacity_limit(mut self, multiple: f64) -> Self {
+        assert!(multiple > 0.0, "capacity limit must be positive");
+        self.occupancy.capacity_limit = multiple;
+        self
     }
-}
 
-/// Return the optimal number of hashes to use for the given number of
-/// bits and items in a filter
-pub fn optimal_num_hashes(num_bits: usize, num_items: u32) -> u32 {
-    min(
-        max(
-            (num_bits as f32 / num_items as f32 * core::f32::consts::LN_2).round() as u32,
-            2,
-        ),
-        200,
-    )
-}
+    // The number of insertions `insert_checked` accepts.
+    fn insertion_limit(&self) -> Option<u64> {
+        let capacity = self.occupancy.capacity?;
+        Some((capacity as f64 * self.occupancy.capacity_limit) as u64)
+    }
 
-/// Return the number of bits needed to satisfy the specified false
-/// positive rate, if the filter will hold `num_items` items.
-pub fn needed_bits(false_pos_rate: f32, num_items: u32) -> usize {
-    let ln22 = core::f32::consts::LN_2 * core::f32::consts::LN_2;
-    (num_items as f32 * ((1.0 / false_pos_rate).ln() / ln22)).round() as usize
-}
+    #[cfg(feature = "rayon")]
+    #[inline(always)]
+    pub(crate) fn occupancy(&self) -> &Occupancy {
+        &self.occupancy
+    }
 
-#[cfg(test)]
-mod tests {
-    use rand::Rng;
+    #[inline(always)]
+    pub(crate) fn occupancy_mut(&mut self) -> &mut Occupancy {
+        &mut self.occupancy
+    }
 
-    use super::{needed_bits, optimal_num_hashes, BloomFilter};
-    use crate::{Intersectable, Unionable, ASMS};
-    use std::collections::HashSet;
+    // Count the bits to set the occupancy of a filter built from bits
+    // of unknown history.
+    pub(crate) fn recount(mut self) -> Self {
+        let set_bits = self.count_ones();
+        let empty = set_bits == 0;
+        self.occupancy.empty = empty;
+        self.occupancy.set_bits = Some(set_bits as u64);
+        self.occupancy.insertions = if empty {
+            0
+        } else {
+            (self.estimate_cardinality().round() as u64).max(1)
+        };
+        self
+    }
 
-    #[test]
-    fn simple() {
-        let mut b: BloomFilter = BloomFilter::with_rate(0.01, 100);
-        b.insert(&1);
-        assert!(b.contains(&1));
-        assert!(!b.contains(&2));
-        b.clear();
-        assert!(!b.contains(&1));
+    // The number of bits probes are mapped into, or None if the filter
+    // has no bits, in which case it contains nothing and inserts are
+    // dropped.  Every layout maps a hash into `0..num_bits` for the
+    // sizes it `supports`, which is checked whenever a layout is picked,
+    // so given `Some` the probe loops can skip per-bit bounds checks.
+    // A filter without hashes makes no probes, so it vacuously contains
+    // everything.
+    #[inline(always)]
+    fn probe_bits(&self) -> Option<usize> {
+        let num_bits = self.num_bits();
+        probeable(num_bits as u64).then_some(num_bits)
     }
 
-    #[test]
-    fn intersect() {
-        let mut b1 = BloomFilter::with_rate(0.01, 20);
-        b1.insert(&1);
-        b1.insert(&2);
-        let mut b2 = BloomFilter::combinable_with(&b1);
-        b2.insert(&1);
+    pub(crate) fn contains_hash_iter(&self, h_iter: HashIter) -> bool {
+        self.stats.record_contains();
+        let Some(num_bits) = self.probe_bits() else {
+            return false;
+        };
+        if self.occupancy.empty && self.num_hashes > 0 {
+            return false;
+        }
+        h_iter.with_domain(self.domain).all(|h| {
+            let idx = self.layout.index(h, num_bits);
+            // SAFETY: `probe_bits` makes `idx` in bounds.
+            unsafe { self.bits.get_unchecked(idx) }
+        })
+    }
+}
 
-        b1.intersect(&b2);
+impl<H, S> BloomFilter<H, S>
+where
+    H: BloomBuildHasher,
+    S: BitStorageMut,
+{
+    #[inline(always)]
+    fn insert_hash_iter(&mut self, h_iter: HashIter) {
+        self.insert_new_hash_iter(h_iter);
+    }
 
-        assert!(b1.contains(&1));
-        assert!(!b1.contains(&2));
+    // Like `insert_hash_iter`, but returns true if any probed bit was
+    // unset, i.e. the item wasn't already (probably) present.  Only
+    // those inserts count towards `approximate_insertions`.
+    pub(crate) fn insert_new_hash_iter(&mut self, h_iter: HashIter) -> bool {
+        self.stats.record_inserts(1);
+        self.recent.record(h_iter.fingerprint());
+        let Some(num_bits) = self.probe_bits() else {
+            return false;
+        };
+        let mut set = 0;
+        h_iter.with_domain(self.domain).for_each_unrolled(|h| {
+            let idx = self.layout.index(h, num_bits);
+            // SAFETY: `probe_bits` makes `idx` in bounds.
+            set += !unsafe { self.bits.replace_unchecked(idx) } as u64;
+        });
+        self.record_set_bits(set)
     }
 
-    #[test]
-    fn union() {
+    // Account for an insert that set `set` new bits, returning whether
+    // it set any.
+    #[inline(always)]
+    fn record_set_bits(&mut self, set: u64) -> bool {
+        if set == 0 {
+            return false;
+        }
+        self.occupancy.insertions += 1;
+        self.occupancy.empty = false;
+        if let Some(set_bits) = self.occupancy.set_bits.as_mut() {
+            *set_bits += set;
+        }
+        true
+    }
+
+    /// Insert `item` unless that would take the filter past its
+    /// capacity limit: the configured capacity times the multiple set
+    /// with `with_capacity_limit`.  Returns whether the item was newly
+    /// added.  Once the limit is reached, items the filter already
+    /// (probably) contains are still accepted, as inserting them changes
+    /// nothing, and new ones are refused with `CapacityExceeded` and not
+    /// inserted.  Filters without a `configured_capacity` never refuse.
+    pub fn insert_checked<T: Hash>(&mut self, item: &T) -> Result<bool, CapacityExceeded> {
+        let h_iter = HashIter::from(item, self.num_hashes, &self.hash_builder);
+        if let Some(limit) = self.insertion_limit() {
+            if self.occupancy.insertions >= limit && !self.contains_hash_iter(h_iter) {
+                return Err(CapacityExceeded { limit });
+            }
+        }
+        Ok(self.insert_new_hash_iter(h_iter))
+    }
+
+    /// Insert `item` unless the filter's false positive rate is already
+    /// above `max_fpr`, so a long running filter refuses new items
+    /// rather than silently degrading past usefulness.  Returns whether
+    /// the item was newly added.  As with `insert_checked`, items the
+    /// filter already (probably) contains are still accepted.
+    ///
+    /// The rate is the one `estimated_fpp` gives, from the fraction of
+    /// bits set.  Inserts keep count of the bits they set, so checking it
+    /// is cheap enough for every insert.  Anything else that changes the
+    /// bits, such as a union or `unsafe_remove`, makes the next call
+    /// count them again.
+    pub fn insert_within_fpr<T: Hash>(
+        &mut self,
+        item: &T,
+        max_fpr: f64,
+    ) -> Result<bool, SaturatedError> {
+        let h_iter = HashIter::from(item, self.num_hashes, &self.hash_builder);
+        let set_bits = match self.occupancy.set_bits {
+            Some(set_bits) => set_bits,
+            None => {
+                let set_bits = self.count_ones() as u64;
+                self.occupancy.set_bits = Some(set_bits);
+                set_bits
+            }
+        };
+        let fpr = theoretical_fpr(set_bits as usize, self.num_bits(), self.num_hashes);
+        if fpr > max_fpr && !self.contains_hash_iter(h_iter) {
+            return Err(SaturatedError { fpr, max_fpr });
+        }
+        Ok(self.insert_new_hash_iter(h_iter))
+    }
+}
+
+impl<H> BloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Create a new BloomFilter with the exact same parameters as the other,
+    /// including its configured capacity and capacity limit.
+    /// These two filters can be safely intersected and unioned with one another.
+    /// Otherwise you have to be careful that not only the number of bits and number of hashes
+    /// is the same, but also that the hash algorithms used have the same parameters (the default
+    /// hash functions use random state).
+    pub fn combinable_with(other: &BloomFilter<H>) -> Self {
+        BloomFilter {
+            bits: Bits::new(other.num_bits()),
+            num_hashes: other.num_hashes,
+            hash_builder: other.hash_builder.clone(),
+            domain: other.domain,
+            layout: other.layout,
+            stats: OpCounters::default(),
+            recent: RecentInserts::default(),
+            occupancy: Occupancy {
+                capacity: other.occupancy.capacity,
+                capacity_limit: other.occupancy.capacity_limit,
+                ..Occupancy::fresh()
+            },
+        }
+    }
+
+    /// Create a copy of this filter, with the same parameters and
+    /// hasher and already holding everything this one holds.  Unlike
+    /// `combinable_with`, which starts empty, this lets several workers
+    /// each insert their own items on top of a shared base, after which
+    /// the forks can be unioned with each other or with the base.
+    ///
+    /// Operation counters and recent inserts start fresh.  Each fork
+    /// keeps the base's `approximate_insertions`, so unioning forks
+    /// counts the base once per fork.
+    pub fn fork(&self) -> Self {
+        BloomFilter {
+            bits: self.bits.clone(),
+            num_hashes: self.num_hashes,
+            hash_builder: self.hash_builder.clone(),
+            domain: self.domain,
+            layout: self.layout,
+            stats: OpCounters::default(),
+            recent: RecentInserts::default(),
+            occupancy: self.occupancy,
+        }
+    }
+}
+
+impl<H> BloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Create a new BloomFilter with the specified number of bits,
+    /// hashes, and the two specified HashBuilders.  Note the the
+    /// HashBuilders MUST provide independent hash values.  Passing
+    /// two HashBuilders that produce the same or correlated hash
+    /// values will break the false positive guarantees of the
+    /// BloomFilter.  So will more than `MAX_NUM_HASHES` hashes.
+    ///
+    /// With one hash each item sets a single bit.  That works, but for
+    /// low false positive rates it takes many times the bits the
+    /// optimal number of hashes would, so it's only worth it when the
+    /// single position per item is wanted for its own sake.  With no
+    /// hashes nothing is ever probed: inserts set no bits and every
+    /// lookup answers true, as no probe failed.
+    ///
+    /// # Panics
+    /// In debug builds, panics if `num_bits` or `num_hashes` is 0; use
+    /// `try_with_size_and_hasher` to check them instead.
+    pub fn with_size_and_hasher(
+        num_bits: usize,
+        num_hashes: u32,
+        hash_builder: H,
+    ) -> BloomFilter<H> {
+        debug_assert!(num_bits > 0, "can't build an empty filter");
+        debug_assert!(num_hashes > 0, "can't build a filter without hashes");
+        BloomFilter {
+            bits: Bits::new(num_bits),
+            num_hashes,
+            hash_builder,
+            domain: 0,
+            layout: IndexLayout::default(),
+            stats: OpCounters::default(),
+            recent: RecentInserts::default(),
+            occupancy: Occupancy::fresh(),
+        }
+    }
+
+    /// Like `with_size_and_hasher`, but returns an error if `num_bits`
+    /// or `num_hashes` is 0, or `num_hashes` is more than
+    /// `MAX_NUM_HASHES`.
+    pub fn try_with_size_and_hasher(
+        num_bits: usize,
+        num_hashes: u32,
+        hash_builder: H,
+    ) -> Result<BloomFilter<H>, ParameterError> {
+        check_size(num_bits, num_hashes)?;
+        Ok(BloomFilter::with_size_and_hasher(num_bits, num_hashes, hash_builder))
+    }
+
+    /// Like `with_size_pow2`, but hashing with `hash_builder`.
+    ///
+    /// # Panics
+    /// Panics if `2^num_bits_log2` doesn't fit in a usize.
+    pub fn with_size_pow2_and_hasher(
+        num_bits_log2: u32,
+        num_hashes: u32,
+        hash_builder: H,
+    ) -> BloomFilter<H> {
+        assert!(num_bits_log2 < usize::BITS, "2^{} bits is too many", num_bits_log2);
+        BloomFilter::with_size_and_layout(
+            1 << num_bits_log2,
+            num_hashes,
+            hash_builder,
+            IndexLayout::Mask,
+        )
+    }
+
+    /// Like `with_size_and_hasher`, but mapping probes onto bits with
+    /// `layout` instead of the default.  This is mainly for matching
+    /// filters built with an older layout.
+    ///
+    /// # Panics
+    /// Panics if `layout` is `Mask` and `num_bits` isn't a power of two.
+    pub fn with_size_and_layout(
+        num_bits: usize,
+        num_hashes: u32,
+        hash_builder: H,
+        layout: IndexLayout,
+    ) -> BloomFilter<H> {
+        assert!(
+            layout.supports(num_bits),
+            "{:?} layout can't address {} bits",
+            layout,
+            num_bits
+        );
+        let mut filter = BloomFilter::with_size_and_hasher(num_bits, num_hashes, hash_builder);
+        filter.layout = layout;
+        filter
+    }
+
+    /// Create a BloomFilter from a raw bit buffer with no framing, for
+    /// when the parameters are already known out of band.
+    ///
+    /// Bits are packed most significant bit first: bit `i` of the
+    /// filter is `(bytes[i / 8] >> (7 - i % 8)) & 1`.  Only the first
+    /// `num_bits` bits are used; any trailing bits in the final byte
+    /// and any extra bytes are ignored.  This is the same layout
+    /// `raw_bits` produces.
+    ///
+    /// The filter uses the default `IndexLayout`; bits produced by a
+    /// filter with another layout must be read with
+    /// `from_raw_bits_with_layout`.
+    ///
+    /// Returns an error if `bytes` holds fewer than `num_bits` bits.
+    pub fn from_raw_bits(
+        bytes: &[u8],
+        num_bits: usize,
+        num_hashes: u32,
+        hash_builder: H,
+    ) -> Result<BloomFilter<H>, BufferTooShort> {
+        BloomFilter::from_raw_bits_with_layout(
+            bytes,
+            num_bits,
+            num_hashes,
+            hash_builder,
+            IndexLayout::default(),
+        )
+    }
+
+    /// Like `from_raw_bits`, but for bits produced by a filter using
+    /// `layout`.
+    ///
+    /// # Panics
+    /// Panics if `layout` is `Mask` and `num_bits` isn't a power of two.
+    pub fn from_raw_bits_with_layout(
+        bytes: &[u8],
+        num_bits: usize,
+        num_hashes: u32,
+        hash_builder: H,
+        layout: IndexLayout,
+    ) -> Result<BloomFilter<H>, BufferTooShort> {
+        if bytes.len().saturating_mul(8) < num_bits {
+            return Err(BufferTooShort {
+                num_bits,
+                num_bytes: bytes.len(),
+            });
+        }
+        assert!(
+            layout.supports(num_bits),
+            "{:?} layout can't address {} bits",
+            layout,
+            num_bits
+        );
+        Ok(BloomFilter {
+            bits: Bits::from_msb_bytes(bytes, num_bits),
+            num_hashes,
+            hash_builder,
+            domain: 0,
+            layout,
+            stats: OpCounters::default(),
+            recent: RecentInserts::default(),
+            occupancy: Occupancy::unknown(),
+        }
+        .recount())
+    }
+
+    /// The raw bits of this filter packed into bytes in the layout
+    /// accepted by `from_raw_bits`.  Unused trailing bits in the final
+    /// byte are zero.
+    pub fn raw_bits(&self) -> Vec<u8> {
+        self.bits.to_msb_bytes()
+    }
+
+    /// The positions of the set bits of this filter as a
+    /// `RoaringBitmap`, for set algebra on them with roaring's
+    /// operations.  `from_roaring` turns the result back into a filter.
+    ///
+    /// Roaring stores a sparse set of positions compactly, but a filter
+    /// near its intended capacity has about half its bits set, which
+    /// roaring holds no smaller (and handles no faster) than the bits
+    /// themselves.  This is only worth it for filters with a low fill.
+    ///
+    /// # Panics
+    /// Panics if the filter has more bits than a `u32` can index.
+    #[cfg(feature = "roaring")]
+    pub fn to_roaring(&self) -> roaring::RoaringBitmap {
+        assert!(
+            self.bits.len() as u64 <= 1 << 32,
+            "{} bits can't be indexed by a RoaringBitmap",
+            self.bits.len()
+        );
+        roaring::RoaringBitmap::from_sorted_iter(self.bits.ones().map(|i| i as u32))
+            .expect("set bits are in order")
+    }
+
+    /// Create a BloomFilter of `num_bits` bits with the positions in
+    /// `rb` set, e.g. from `to_roaring` after set algebra on the
+    /// positions of several filters.  As with `from_raw_bits`, the
+    /// filter uses the default `IndexLayout` and the parameters must
+    /// match the ones the positions were produced with.
+    ///
+    /// Returns an error if `rb` holds a position of `num_bits` or more.
+    #[cfg(feature = "roaring")]
+    pub fn from_roaring(
+        rb: &roaring::RoaringBitmap,
+        num_bits: usize,
+        num_hashes: u32,
+        hash_builder: H,
+    ) -> Result<BloomFilter<H>, BitOutOfRange> {
+        if let Some(max) = rb.max().filter(|&max| max as usize >= num_bits) {
+            return Err(BitOutOfRange {
+                bit: max as usize,
+                num_bits,
+            });
+        }
+        let mut bits = Bits::new(num_bits);
+        rb.iter().for_each(|i| bits.set(i as usize));
+        Ok(BloomFilter {
+            bits,
+            num_hashes,
+            hash_builder,
+            domain: 0,
+            layout: IndexLayout::default(),
+            stats: OpCounters::default(),
+            recent: RecentInserts::default(),
+            occupancy: Occupancy::unknown(),
+        }
+        .recount())
+    }
+
+    /// Create a BloomFilter that expects to hold
+    /// `expected_num_items`.  The filter will be sized to have a
+    /// false positive rate of the value specified in `rate`.  Items
+    /// will be hashed using the Hashers produced by
+    /// `hash_builder` and `hash_builder_two`.  Note the the
+    /// HashBuilders MUST provide independent hash values.  Passing
+    /// two HashBuilders that produce the same or correlated hash
+    /// values will break the false positive guarantees of the
+    /// BloomFilter.
+    ///
+    /// # Panics
+    /// Panics if `rate` isn't strictly between 0 and 1 or
+    /// `expected_num_items` is 0.
+    pub fn with_rate_and_hasher(
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> BloomFilter<H> {
+        let bits = needed_bits(rate, expected_num_items);
+        let mut filter = BloomFilter::with_size_and_hasher(
+            bits,
+            optimal_num_hashes(bits, expected_num_items),
+            hash_builder,
+        );
+        filter.occupancy.capacity = Some(expected_num_items.into());
+        filter
+    }
+
+    /// Like `with_rate_and_hasher`, but returns an error if `rate` isn't
+    /// strictly between 0 and 1 or `expected_num_items` is 0.
+    pub fn try_with_rate_and_hasher(
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> Result<BloomFilter<H>, ParameterError> {
+        let bits = try_needed_bits(rate, expected_num_items)?;
+        let mut filter = BloomFilter::with_size_and_hasher(
+            bits,
+            optimal_num_hashes(bits, expected_num_items),
+            hash_builder,
+        );
+        filter.occupancy.capacity = Some(expected_num_items.into());
+        Ok(filter)
+    }
+
+    /// Like `with_rate_and_hasher`, but mixing `domain` into every
+    /// fingerprint before probing.
+    ///
+    /// Filters sharing one hash builder but using different domains set
+    /// unrelated bits for the same item, so a single `RandomXxh3State`
+    /// can be handed out to many logical filters without their false
+    /// positives being correlated.  Only filters with the same domain
+    /// can be combined.  Domain 0 is what every other constructor uses.
+    pub fn with_domain(
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+        domain: u64,
+    ) -> BloomFilter<H> {
+        let mut filter = BloomFilter::with_rate_and_hasher(rate, expected_num_items, hash_builder);
+        filter.domain = domain;
+        filter
+    }
+
+    /// Like `with_memory_budget`, but hashing with `hash_builder`.
+    ///
+    /// # Panics
+    /// Panics if `bytes` or `expected_num_items` is 0.
+    pub fn with_memory_budget_and_hasher(
+        bytes: usize,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> (BloomFilter<H>, f64) {
+        assert!(bytes > 0, "memory budget must be at least one byte");
+        let bits = bytes.saturating_mul(8);
+        let num_hashes = optimal_num_hashes(bits, expected_num_items);
+        let rate = fpr_for_items(bits, num_hashes, expected_num_items.into());
+        let mut filter = BloomFilter::with_size_and_hasher(bits, num_hashes, hash_builder);
+        filter.occupancy.capacity = Some(expected_num_items.into());
+        (filter, rate)
+    }
+
+    #[inline(always)]
+    pub(crate) fn bits(&self) -> &Bits {
+        &self.bits
+    }
+
+    // Callers may set bits, so lookups can no longer assume the filter
+    // is empty.
+    #[inline(always)]
+    pub(crate) fn bits_mut(&mut self) -> &mut Bits {
+        self.occupancy.empty = false;
+        self.occupancy.set_bits = None;
+        &mut self.bits
+    }
+
+    #[cfg(feature = "rayon")]
+    pub(crate) fn into_bits(self) -> Bits {
+        self.bits
+    }
+
+    /// Get the number of bits that are set in both this BloomFilter and
+    /// `other`, i.e. the `count_ones` of their intersection, without
+    /// building the intersection.
+    ///
+    /// # Panics
+    /// Panics if the filters are not using the same number of bits
+    pub fn shared_bits(&self, other: &BloomFilter<H>) -> usize {
+        self.bits.count_ones_and(&other.bits)
+    }
+
+    /// Get the number of bits set in this BloomFilter but not in
+    /// `older`, an earlier snapshot of it, i.e. how much the filter
+    /// grew since then.  Replicas holding `older` only need these bits
+    /// to catch up.
+    ///
+    /// # Panics
+    /// Panics if the filters are not using the same number of bits, or
+    /// their hashers have different `compat_token`s.
+    pub fn bits_added_since(&self, older: &BloomFilter<H>) -> usize {
+        assert_same_hasher(&self.hash_builder, &older.hash_builder);
+        self.bits.count_ones_and_not(&older.bits)
+    }
+
+    /// Shrink an underfilled filter to `new_num_bits`, which must evenly
+    /// divide `num_bits`, by ORing together the bits that map onto the
+    /// same smaller index.
+    ///
+    /// The folded filter is an ordinary filter with the same hasher,
+    /// number of hashes, domain and layout, and needs no special query
+    /// indexing: every layout maps a probe hash onto the smaller size
+    /// exactly where the folding moved its bit.  With `Modulo`, `h %
+    /// new_num_bits` equals `(h % num_bits) % new_num_bits`, so bit `i`
+    /// folds onto `i % new_num_bits`, and masking off more low bits
+    /// does the same for `Mask`.  With `FastRange` the index scales
+    /// with the size, so runs of `num_bits / new_num_bits`
+    /// neighbouring bits fold onto one.  Everything that
+    /// was inserted is therefore still found, but the false positive
+    /// rate rises with the fraction of bits set, roughly to `(1 - (1 -
+    /// fill_ratio)^f)^num_hashes` for a fold by a factor of `f`.
+    pub fn fold_to(&self, new_num_bits: usize) -> Result<BloomFilter<H>, FoldError> {
+        let num_bits = self.num_bits();
+        if new_num_bits == 0 || !num_bits.is_multiple_of(new_num_bits) {
+            return Err(FoldError {
+                num_bits,
+                new_num_bits,
+            });
+        }
+        let factor = num_bits / new_num_bits;
+        let mut bits = Bits::new(new_num_bits);
+        for i in self.bits.ones() {
+            bits.set(match self.layout {
+                IndexLayout::Modulo | IndexLayout::Mask => i % new_num_bits,
+                IndexLayout::FastRange => i / factor,
+            });
+        }
+        let mut folded = BloomFilter::from_parts(
+            bits,
+            self.num_hashes,
+            self.hash_builder.clone(),
+            self.domain,
+            self.layout,
+        );
+        folded.occupancy.insertions = self.occupancy.insertions;
+        folded.occupancy.empty = self.occupancy.empty;
+        Ok(folded)
+    }
+
+    /// Stop inserting into this filter and get a read only view of it
+    /// for lookups.  The bits aren't copied.
+    pub fn freeze(self) -> FrozenBloomFilter<H> {
+        FrozenBloomFilter::new(self)
+    }
+
+    /// Look up a random item, almost certainly one that was never
+    /// inserted, so that a hit is a false positive.  The item's
+    /// fingerprint is drawn from `next_u64`, which can wrap any random
+    /// number generator, e.g. `|| rng.gen()`.
+    pub fn probe_random<R: FnMut() -> u64>(&self, mut next_u64: R) -> bool {
+        self.contains_fingerprint(BloomFingerprint::new(next_u64(), next_u64()))
+    }
+
+    /// The fraction of `num_probes` calls to `probe_random` that hit,
+    /// i.e. the false positive rate observed against the filter's
+    /// current contents.  Useful as an empirical check of
+    /// `estimated_fpp` without generating keys.
+    ///
+    /// # Panics
+    /// Panics if `num_probes` is 0.
+    pub fn sampled_fpp<R: FnMut() -> u64>(&self, num_probes: u32, mut next_u64: R) -> f64 {
+        assert!(num_probes > 0, "need at least one probe");
+        let hits = (0..num_probes)
+            .filter(|_| self.probe_random(&mut next_u64))
+            .count();
+        hits as f64 / num_probes as f64
+    }
+
+    /// How many times items have been inserted into and looked up in
+    /// this filter.  Counting costs a relaxed atomic increment per
+    /// operation, so it is only compiled in with the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn stats(&self) -> BloomStats {
+        self.stats.snapshot()
+    }
+
+    /// Build a smaller filter holding `items` at the false positive rate
+    /// `new_rate`, using the same hash builder as this filter.
+    ///
+    /// This is for filters that turned out to be overprovisioned (e.g.
+    /// `estimate_cardinality` is far below what the filter was sized
+    /// for).  Since the original items can't be recovered from the bits,
+    /// the caller must supply them again; `items` should be exactly the
+    /// items inserted into this filter, and the new filter is sized for
+    /// `items.len()` of them.
+    pub fn compact<T, I>(&self, items: I, new_rate: f32) -> BloomFilter<H>
+    where
+        T: Hash,
+        I: IntoIterator<Item = T>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let items = items.into_iter();
+        let mut compacted = BloomFilter::with_domain(
+            new_rate,
+            items.len().max(1) as u32,
+            self.hash_builder.clone(),
+            self.domain,
+        );
+        compacted.layout = self.layout;
+        for item in items {
+            debug_assert!(self.contains(&item), "compact given an item not in the filter");
+            compacted.insert(&item);
+        }
+        compacted
+    }
+
+    /// Insert every fingerprint in `fps` into this BloomFilter.
+    ///
+    /// This is handy when the fingerprints were computed up front, e.g.
+    /// on another thread or in a prior pass over the keys, using the same
+    /// hash algorithm as this filter.
+    pub fn insert_fingerprints<I: IntoIterator<Item = BloomFingerprint>>(&mut self, fps: I) {
+        for fp in fps {
+            self.insert_hash_iter(HashIter::from_fingerprint(fp, self.num_hashes));
+        }
+    }
+
+    /// Insert a key hashed up front with `cache_key` or `cache_slice`.
+    ///
+    /// # Panics
+    /// In debug builds, panics if the key was made by a hasher that
+    /// doesn't hash like this filter's.
+    #[inline(always)]
+    pub fn insert_cached(&mut self, key: &CachedKey) {
+        key.debug_check(&self.hash_builder);
+        self.insert_fingerprint(key.fingerprint())
+    }
+
+    /// Check if a key hashed up front with `cache_key` or
+    /// `cache_slice` is in the filter.
+    ///
+    /// # Panics
+    /// In debug builds, panics if the key was made by a hasher that
+    /// doesn't hash like this filter's.
+    #[inline(always)]
+    pub fn contains_cached(&self, key: &CachedKey) -> bool {
+        key.debug_check(&self.hash_builder);
+        self.contains_fingerprint(key.fingerprint())
+    }
+
+    /// Check if `item` is contained when hashed with `hash_builder`
+    /// instead of the filter's own hash builder.
+    ///
+    /// **This is a power-user and testing tool, not a general lookup
+    /// API.**  The answer is only meaningful if `hash_builder` hashes
+    /// identically to the one the items were inserted with; with any
+    /// other hasher the result is essentially random and false negatives
+    /// are to be expected.  It's useful for things like checking how
+    /// sensitive a filter is to its hash parameters without rebuilding it.
+    pub fn contains_with<HB: BloomBuildHasher, T: Hash>(
+        &self,
+        item: &T,
+        hash_builder: &HB,
+    ) -> bool {
+        self.contains_hash_iter(HashIter::from(item, self.num_hashes, hash_builder))
+    }
+
+    /// Insert every item of `iter` into this BloomFilter, yielding only
+    /// the ones that weren't already present.
+    ///
+    /// This deduplicates a stream in bounded memory.  Like any lookup in
+    /// the filter it's approximate: a genuinely new item that collides
+    /// with earlier ones is a false positive and gets dropped, at roughly
+    /// the filter's false positive rate.  Duplicates are never yielded.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use xx_bloom::BloomFilter;
+    ///
+    /// let mut filter = BloomFilter::with_rate(0.001, 100);
+    /// let unique: Vec<_> = filter.dedup_iter([3, 1, 3, 2, 1]).collect();
+    /// assert_eq!(unique, [3, 1, 2]);
+    /// # }
+    /// ```
+    pub fn dedup_iter<'a, I>(&'a mut self, iter: I) -> impl Iterator<Item = I::Item> + 'a
+    where
+        I: IntoIterator,
+        I::Item: Hash,
+        I::IntoIter: 'a,
+    {
+        iter.into_iter().filter(move |item| {
+            self.insert_new_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+        })
+    }
+
+    /// Insert everything `r` yields as a single item, hashing it as it is
+    /// read.  This is the same item `insert_slice` of the full contents
+    /// would insert, without holding the contents in memory.  Returns
+    /// true if the item wasn't already (probably) present.
+    #[cfg(feature = "std")]
+    pub fn insert_reader<R: io::Read>(&mut self, r: R) -> io::Result<bool> {
+        let fp = self.hash_builder.hash_reader_128(r)?;
+        Ok(self.insert_new_hash_iter(HashIter::from_fingerprint(fp, self.num_hashes)))
+    }
+
+    /// Get a handle that inserts everything written to it as a single
+    /// item once `finish` is called, e.g. after `io::copy`ing a large
+    /// object into it.  The item is hashed as it is written, so it is
+    /// never held in memory.
+    #[cfg(feature = "std")]
+    pub fn inserter(&mut self) -> BloomInserter<'_, H> {
+        BloomInserter {
+            hasher: self.hash_builder.build_hasher(),
+            filter: self,
+        }
+    }
+
+    /// Check if everything `r` yields, as a single item, is in the
+    /// filter.  Equivalent to `contains_slice` of the full contents.
+    #[cfg(feature = "std")]
+    pub fn contains_reader<R: io::Read>(&self, r: R) -> io::Result<bool> {
+        let fp = self.hash_builder.hash_reader_128(r)?;
+        Ok(self.contains_fingerprint(fp))
+    }
+
+    /// Clear bits `start_bit..end_bit`, leaving the rest alone.
+    ///
+    /// This is for segmented schemes that know which range of bits
+    /// holds data to forget, such as a ring of fixed size segments laid
+    /// out side by side, where the oldest is periodically reset.  Whole
+    /// words are zeroed, with only the edge words masked.
+    ///
+    /// Every item with a probe in the range is no longer reliably
+    /// contained afterwards.  An ordinary filter's items probe the whole
+    /// filter, so clearing any range of one gives false negatives; it's
+    /// only safe when each item's probes are known to fall in or out of
+    /// the range as a whole.
+    ///
+    /// # Panics
+    /// Panics if `start_bit > end_bit` or `end_bit > num_bits()`.
+    pub fn clear_range(&mut self, start_bit: usize, end_bit: usize) {
+        self.bits.clear_range(start_bit, end_bit);
+        self.occupancy.set_bits = None;
+    }
+
+    /// Count how many of `items` test positive with `contains_slice`,
+    /// e.g. to estimate how many candidates survive this filter as a
+    /// prefilter before an expensive exact check.
+    pub fn contains_count<'a, I: IntoIterator<Item = &'a [u8]>>(&self, items: I) -> usize {
+        items
+            .into_iter()
+            .filter(|item| self.contains_slice(item))
+            .count()
+    }
+
+    /// Insert `item` and report which probes were already set before
+    /// this insert: bit `i` of the result is set if probe `i` found its
+    /// bit already set.  A full mask means `insert` would have found the
+    /// item (probably) present; anything else is a partial match.
+    ///
+    /// # Panics
+    /// Panics if the filter uses more than 32 hashes; use
+    /// `insert_report_vec` for those.
+    pub fn insert_report<T: Hash>(&mut self, item: &T) -> u32 {
+        assert!(
+            self.num_hashes <= 32,
+            "insert_report needs at most 32 hashes, filter uses {}",
+            self.num_hashes
+        );
+        let mut mask = 0;
+        self.insert_report_hash_iter(
+            HashIter::from(item, self.num_hashes, &self.hash_builder),
+            |i, was_set| mask |= (was_set as u32) << i,
+        );
+        mask
+    }
+
+    /// Like `insert_report`, but works with any number of hashes by
+    /// returning one entry per probe.
+    pub fn insert_report_vec<T: Hash>(&mut self, item: &T) -> Vec<bool> {
+        let mut report = Vec::with_capacity(self.num_hashes as usize);
+        self.insert_report_hash_iter(
+            HashIter::from(item, self.num_hashes, &self.hash_builder),
+            |_, was_set| report.push(was_set),
+        );
+        report
+    }
+
+    /// Like `contains_fingerprint`, but computing every probe position
+    /// and prefetching it before testing any bits.
+    ///
+    /// For filters much larger than the last level cache each probe is
+    /// a cache miss, and this lets them overlap instead of happening one
+    /// after another.  For small filters it's slower, since it gives up
+    /// stopping at the first unset bit.  The answer is always the same as
+    /// `contains_fingerprint`.
+    pub fn contains_fingerprint_prefetched(&self, fingerprint: BloomFingerprint) -> bool {
+        let mut positions = [0; PREFETCH_PROBES];
+        let n = self.prefetch_fingerprint(fingerprint, &mut positions);
+        self.test_prefetched(fingerprint, &positions[..n])
+    }
+
+    /// Look up every fingerprint `fps` yields, returning the answers in
+    /// the same order.
+    ///
+    /// This is for fingerprints computed ahead of time, e.g. by hashing a
+    /// whole batch of keys in one pass, that now need to be checked
+    /// against this one filter.  Each lookup stops at its first unset
+    /// bit, which suits filters that fit in cache; for larger filters
+    /// `contains_fingerprints_prefetched` overlaps the cache misses.
+    pub fn contains_fingerprints<I>(&self, fps: I) -> Vec<bool>
+    where
+        I: IntoIterator<Item = BloomFingerprint>,
+    {
+        fps.into_iter()
+            .map(|fp| self.contains_fingerprint(fp))
+            .collect()
+    }
+
+    /// Look up every fingerprint in `fps`, replacing the contents of
+    /// `out` with the answers in the same order.
+    ///
+    /// Lookups are software pipelined: the probe positions of the next
+    /// fingerprint are prefetched while the bits of the current one are
+    /// tested.  Answers are identical to `contains_fingerprint`.
+    pub fn contains_fingerprints_prefetched(&self, fps: &[BloomFingerprint], out: &mut Vec<bool>) {
+        out.clear();
+        out.reserve(fps.len());
+        let mut current = [0; PREFETCH_PROBES];
+        let mut next = [0; PREFETCH_PROBES];
+        let mut n = match fps.first() {
+            Some(fp) => self.prefetch_fingerprint(*fp, &mut current),
+            None => return,
+        };
+        for (i, fp) in fps.iter().enumerate() {
+            let m = match fps.get(i + 1) {
+                Some(next_fp) => self.prefetch_fingerprint(*next_fp, &mut next),
+                None => 0,
+            };
+            out.push(self.test_prefetched(*fp, &current[..n]));
+            core::mem::swap(&mut current, &mut next);
+            n = m;
+        }
+    }
+
+    /// Look up every fingerprint in `fps`, replacing the contents of
+    /// `out` with the answers in the same order, testing the bits in
+    /// ascending address order.
+    ///
+    /// Every probe position is computed up front, tagged with the index
+    /// of its fingerprint, and sorted, so the bit array is read nearly
+    /// sequentially.  This costs a sort and 16 bytes per probe of
+    /// scratch, but for huge batches against a filter paged in from
+    /// disk it turns a page fault per probe into one sequential sweep.
+    /// For a filter resident in memory the sort costs more than the
+    /// cache misses it saves; prefer `contains_fingerprints_prefetched`
+    /// there.  Answers are identical
+    /// to `contains_fingerprint`.
+    ///
+    /// # Panics
+    /// In debug builds, panics if the filter has no bits and `fps`
+    /// isn't empty.  Release builds answer false for every fingerprint.
+    pub fn contains_batch_sorted(&self, fps: &[BloomFingerprint], out: &mut Vec<bool>) {
+        out.clear();
+        if fps.is_empty() {
+            return;
+        }
+        out.resize(fps.len(), self.probe_bits().is_some());
+        if !out[0] {
+            return;
+        }
+        let mut probes = Vec::with_capacity(fps.len() * self.num_hashes as usize);
+        for (i, fp) in fps.iter().enumerate() {
+            probes.extend(self.probe_positions(*fp).map(|pos| (pos, i)));
+        }
+        probes.sort_unstable_by_key(|&(pos, _)| pos);
+        for (pos, i) in probes {
+            if !self.bits.get(pos) {
+                out[i] = false;
+            }
+        }
+    }
+
+    // Computes and prefetches up to PREFETCH_PROBES probe positions for
+    // `fp`, returning how many were written to `positions`.
+    #[inline(always)]
+    fn prefetch_fingerprint(
+        &self,
+        fp: BloomFingerprint,
+        positions: &mut [usize; PREFETCH_PROBES],
+    ) -> usize {
+        let Some(num_bits) = self.probe_bits() else {
+            return 0;
+        };
+        let probes = HashIter::from_fingerprint(fp, self.num_hashes).with_domain(self.domain);
+        let mut n = 0;
+        for (slot, h) in positions.iter_mut().zip(probes) {
+            *slot = self.layout.index(h, num_bits);
+            self.bits.prefetch(*slot);
+            n += 1;
+        }
+        n
+    }
+
+    // Tests the prefetched `positions` of `fp`, then any probes past
+    // PREFETCH_PROBES.
+    #[inline(always)]
+    fn test_prefetched(&self, fp: BloomFingerprint, positions: &[usize]) -> bool {
+        self.stats.record_contains();
+        let Some(num_bits) = self.probe_bits() else {
+            return false;
+        };
+        positions.iter().all(|&idx| self.bits.get(idx))
+            && HashIter::from_fingerprint(fp, self.num_hashes)
+                .with_domain(self.domain)
+                .skip(positions.len())
+                .all(|h| self.bits.get(self.layout.index(h, num_bits)))
+    }
+
+    /// Union every filter in `others` into this one.
+    ///
+    /// Every filter must have the same number of bits, hashes, domain and
+    /// layout as this one, otherwise `IncompatibleFilters` is returned
+    /// naming the first that doesn't and this filter is left untouched.
+    /// Like `union`, the filters must also share a hasher, which is
+    /// checked via `BloomBuildHasher::compat_token`.
+    pub fn union_many(&mut self, others: &[&BloomFilter<H>]) -> Result<(), IncompatibleFilters> {
+        self.check_compatible(others)?;
+        for other in others {
+            self.bits.or(&other.bits);
+            self.occupancy.merge(&other.occupancy);
+        }
+        Ok(())
+    }
+
+    /// Union `other` into this filter when their sizes differ but one
+    /// evenly divides the other, mapping each of `other`'s set bits to
+    /// where its probes land at this filter's size.  Two power of two
+    /// sized filters always qualify.
+    ///
+    /// Both filters must otherwise have the same number of hashes,
+    /// domain, layout and hasher, or `IncompatibleFilters` is returned
+    /// and this filter is left untouched.
+    ///
+    /// Nothing inserted into either filter is lost, but how accurate the
+    /// result is depends on the direction:
+    ///
+    /// * If `other` is larger its bits are folded as by `fold_to`, which
+    ///   sets exactly the bits inserting its items here would have.
+    /// * If `other` is smaller, a bit can't be traced back to the one
+    ///   position it came from, so every position it could have come
+    ///   from is set.  Lookups probing where `other`'s items could be
+    ///   answer like `other` would, so the result's false positive rate
+    ///   is at best that of the smaller filter.
+    ///
+    /// A filter can't give back its items, so if you still hold them,
+    /// inserting them into a filter of the size you want is always
+    /// exact:
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use xx_bloom::{ASMS, BloomFilter, RandomXxh3State};
+    ///
+    /// let hasher = RandomXxh3State::new();
+    /// let items = [1, 2, 3];
+    /// let mut small = BloomFilter::with_size_and_hasher(1 << 10, 3, hasher);
+    /// let mut big = BloomFilter::with_size_and_hasher(1 << 12, 3, hasher);
+    /// for item in &items {
+    ///     small.insert(item);
+    /// }
+    /// big.insert(&4);
+    ///
+    /// // Best effort: big now answers like small for those items.
+    /// big.union_resampled(&small).unwrap();
+    /// assert!(items.iter().all(|item| big.contains(item)));
+    ///
+    /// // Exact: reinsert the held items instead.
+    /// let mut rebuilt = BloomFilter::with_size_and_hasher(1 << 12, 3, hasher);
+    /// for item in items.iter().chain(&[4]) {
+    ///     rebuilt.insert(item);
+    /// }
+    /// # }
+    /// ```
+    pub fn union_resampled(&mut self, other: &BloomFilter<H>) -> Result<(), IncompatibleFilters> {
+        let (num_bits, other_bits) = (self.num_bits(), other.num_bits());
+        if other.num_hashes != self.num_hashes
+            || other.domain != self.domain
+            || other.layout != self.layout
+            || other.hash_builder.compat_token() != self.hash_builder.compat_token()
+            || num_bits == 0
+            || other_bits == 0
+            || !(other_bits.is_multiple_of(num_bits) || num_bits.is_multiple_of(other_bits))
+        {
+            return Err(IncompatibleFilters { index: 0 });
+        }
+        if other_bits >= num_bits {
+            let factor = other_bits / num_bits;
+            for i in other.bits.ones() {
+                self.bits.set(match self.layout {
+                    IndexLayout::Modulo | IndexLayout::Mask => i % num_bits,
+                    IndexLayout::FastRange => i / factor,
+                });
+            }
+        } else {
+            // Every index that maps back onto `i` at the smaller size.
+            let factor = num_bits / other_bits;
+            for i in other.bits.ones() {
+                for k in 0..factor {
+                    self.bits.set(match self.layout {
+                        IndexLayout::Modulo | IndexLayout::Mask => i + k * other_bits,
+                        IndexLayout::FastRange => i * factor + k,
+                    });
+                }
+            }
+        }
+        self.occupancy.merge(&other.occupancy);
+        Ok(())
+    }
+
+    /// The bit positions `fingerprint` probes in this filter, in probe
+    /// order.  Filters with the same number of bits, hashes, domain and
+    /// layout probe the same positions.
+    ///
+    /// # Panics
+    /// In debug builds, panics if the filter has no bits.  Release
+    /// builds yield no positions.
+    pub fn probe_positions(
+        &self,
+        fingerprint: BloomFingerprint,
+    ) -> impl Iterator<Item = usize> + '_ {
+        self.probe_bits().into_iter().flat_map(move |num_bits| {
+            HashIter::from_fingerprint(fingerprint, self.num_hashes)
+                .with_domain(self.domain)
+                .map(move |h| self.layout.index(h, num_bits))
+        })
+    }
+
+    /// How many of the distinct bit positions `a` probes are also
+    /// probed by `b`.  Two unrelated keys should rarely share more than
+    /// `num_hashes^2 / num_bits` positions, so consistently higher
+    /// overlap between "random" keys points at a poor hasher.
+    ///
+    /// # Panics
+    /// In debug builds, panics if the filter has no bits.
+    pub fn probes_overlap<T: Hash, U: Hash>(&self, a: &T, b: &U) -> usize {
+        let mut a: Vec<usize> = self.probe_positions(fingerprint(a, &self.hash_builder)).collect();
+        let b: Vec<usize> = self.probe_positions(fingerprint(b, &self.hash_builder)).collect();
+        a.sort_unstable();
+        a.dedup();
+        a.iter().filter(|pos| b.contains(pos)).count()
+    }
+
+    pub(crate) fn check_compatible(
+        &self,
+        others: &[&BloomFilter<H>],
+    ) -> Result<(), IncompatibleFilters> {
+        let token = self.hash_builder.compat_token();
+        match others.iter().position(|other| {
+            other.num_bits() != self.num_bits()
+                || other.num_hashes != self.num_hashes
+                || other.domain != self.domain
+                || other.layout != self.layout
+                || other.hash_builder.compat_token() != token
+        }) {
+            Some(index) => Err(IncompatibleFilters { index }),
+            None => Ok(()),
+        }
+    }
+
+    /// Clear every bit `item` probes, so that `contains(item)` returns
+    /// false afterwards.
+    ///
+    /// **This breaks the usual BloomFilter guarantee.**  Any other
+    /// inserted item sharing even one of those bits will from now on
+    /// also be reported as absent, i.e. this introduces false negatives.
+    /// It is meant for best-effort uses such as cache invalidation where
+    /// an occasional spurious miss is acceptable; if removals need to be
+    /// exact, use a `CountingBloomFilter` instead.  Despite the name this
+    /// is not an `unsafe fn`: it can't cause undefined behavior.
+    #[inline(always)]
+    pub fn unsafe_remove<T: Hash>(&mut self, item: &T) {
+        self.clear_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Like `unsafe_remove`, but for a byte slice.  The same caveats
+    /// apply.
+    #[inline(always)]
+    pub fn unsafe_remove_slice(&mut self, item: &[u8]) {
+        self.clear_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Like `unsafe_remove`, but for a fingerprint.  The same caveats
+    /// apply.
+    #[inline(always)]
+    pub fn unsafe_remove_fingerprint(&mut self, fingerprint: BloomFingerprint) {
+        self.clear_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    fn clear_hash_iter(&mut self, h_iter: HashIter) {
+        let Some(num_bits) = self.probe_bits() else {
+            return;
+        };
+        h_iter.with_domain(self.domain).for_each(|h| {
+            let idx = self.layout.index(h, num_bits);
+            self.bits.unset(idx);
+        });
+        self.occupancy.set_bits = None;
+    }
+
+    // Like `insert_hash_iter`, but passes each probe's index and whether
+    // its bit was already set to `report`.
+    fn insert_report_hash_iter(&mut self, h_iter: HashIter, mut report: impl FnMut(u32, bool)) {
+        self.stats.record_inserts(1);
+        self.recent.record(h_iter.fingerprint());
+        let Some(num_bits) = self.probe_bits() else {
+            return;
+        };
+        let mut set = 0;
+        h_iter.with_domain(self.domain).zip(0..).for_each(|(h, i)| {
+            let idx = self.layout.index(h, num_bits);
+            let was_set = self.bits.get(idx);
+            report(i, was_set);
+            set += !was_set as u64;
+            self.bits.set(idx);
+        });
+        self.record_set_bits(set);
+    }
+
+}
+
+/// Streams one item into a `BloomFilter`; see `BloomFilter::inserter`.
+///
+/// Nothing is inserted until `finish`, so dropping an inserter part way
+/// through leaves the filter untouched.  The item is the same one
+/// `insert_slice` of all the bytes written would insert.
+#[cfg(feature = "std")]
+pub struct BloomInserter<'a, H>
+where
+    H: BloomBuildHasher,
+{
+    filter: &'a mut BloomFilter<H>,
+    hasher: H::Hasher,
+}
+
+#[cfg(feature = "std")]
+impl<H> BloomInserter<'_, H>
+where
+    H: BloomBuildHasher,
+{
+    /// Insert the item written so far.  Returns true if it wasn't
+    /// already (probably) present.
+    pub fn finish(self) -> bool {
+        let fp = self.hasher.finish_128();
+        self.filter
+            .insert_new_hash_iter(HashIter::from_fingerprint(fp, self.filter.num_hashes))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<H> io::Write for BloomInserter<'_, H>
+where
+    H: BloomBuildHasher,
+{
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Hasher::write(&mut self.hasher, buf);
+        Ok(buf.len())
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<H, S> ASMS for BloomFilter<H, S>
+where
+    H: BloomBuildHasher,
+    S: BitStorageMut,
+{
+    /// Insert item into this BloomFilter.
+    ///
+    /// If the BloomFilter did not have this value present, `true` is returned.
+    ///
+    /// If the BloomFilter did have this value present, `false` is returned.
+    #[inline(always)]
+    fn insert<T: Hash>(&mut self, item: &T) {
+        self.insert_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Insert item into this BloomFilter.
+    ///
+    /// If the BloomFilter did not have this value present, `true` is returned.
+    ///
+    /// If the BloomFilter did have this value present, `false` is returned.
+    /// 
+    /// This is a faster-path if the item you're inserting is a byte slice.
+    #[inline(always)]
+    fn insert_slice(&mut self, item: &[u8]) {
+        self.stats.record_bytes(item.len());
+        self.insert_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Insert a fingerprint into this BloomFilter.
+    ///
+    /// If the BloomFilter did not have this value present, `true` is returned.
+    ///
+    /// If the BloomFilter did have this value present, `false` is returned.
+    /// 
+    /// This is a faster-path if you have multiple filters that share the same
+    /// hash algorithm that you're inserting into. This will let you amortize
+    /// the cost of the hash.
+    #[inline(always)]
+    fn insert_fingerprint(&mut self, fingerprint: crate::BloomFingerprint) {
+        self.insert_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Check if the item has been inserted into this bloom filter.
+    /// This function can return false positives, but not false
+    /// negatives.
+    #[inline(always)]
+    fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Check if the item has been inserted into this bloom filter.
+    /// This function can return false positives, but not false
+    /// negatives.
+    /// This is a faster-path if the item you're inserting is a byte slice.
+    #[inline(always)]
+    fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Check if the item has been inserted into this bloom filter.
+    /// This function can return false positives, but not false
+    /// negatives.
+    /// This is a faster-path if you have multiple filters that share the same
+    /// hash algorithm that you're checking containership for. This will let you amortize
+    /// the cost of the hash.
+    #[inline(always)]
+    fn contains_fingerprint(&self, fingerprint: crate::BloomFingerprint) -> bool {
+        self.contains_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Remove all values from this BloomFilter
+    #[inline(always)]
+    fn clear(&mut self) {
+        self.bits.clear();
+        self.occupancy.insertions = 0;
+        self.occupancy.empty = true;
+        self.occupancy.set_bits = Some(0);
+    }
+}
+
+impl<H, S> fmt::Debug for BloomFilter<H, S>
+where
+    H: BloomBuildHasher,
+    S: BitStorage,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BloomFilter")
+            .field("num_bits", &self.num_bits())
+            .field("num_hashes", &self.num_hashes)
+            .field("count_ones", &self.count_ones())
+            .field("domain", &self.domain)
+            .field("layout", &self.layout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H> Intersectable for BloomFilter<H> where H: BloomBuildHasher {
+    /// Calculates the intersection of two BloomFilters.  Only items inserted into both filters will still be present in `self`.
+    ///
+    /// Both BloomFilters must be using the same number of
+    /// bits. Returns true if self changed.
+    ///
+    /// # Panics
+    /// Panics if the BloomFilters are not using the same number of bits,
+    /// or their hashers have different `compat_token`s.
+    fn intersect(&mut self, other: &BloomFilter<H>) {
+        assert_same_hasher(&self.hash_builder, &other.hash_builder);
+        self.bits.and(&other.bits);
+        self.occupancy.set_bits = None;
+    }
+}
+
+impl<H> Unionable for BloomFilter<H> where H: BloomBuildHasher {
+    /// Calculates the union of two BloomFilters.  Items inserted into
+    /// either filters will be present in `self`.
+    ///
+    /// Both BloomFilters must be using the same number of
+    /// bits. Returns true if self changed.
+    ///
+    /// # Panics
+    /// Panics if the BloomFilters are not using the same number of bits,
+    /// or their hashers have different `compat_token`s.
+    fn union(&mut self, other: &BloomFilter<H>) {
+        assert_same_hasher(&self.hash_builder, &other.hash_builder);
+        self.bits.or(&other.bits);
+        self.occupancy.merge(&other.occupancy);
+    }
+}
+
+// Combining filters whose hashers differ gives a filter that answers
+// meaningless results, so refuse to.
+#[inline]
+pub(crate) fn assert_same_hasher<H: BloomBuildHasher>(a: &H, b: &H) {
+    assert!(
+        a.compat_token() == b.compat_token(),
+        "can't combine filters built with different hashers"
+    );
+}
+
+/// Look up `fingerprint` in every filter at once, returning a mask
+/// with bit `i` set if `filters[i]` contains it.
+///
+/// The filters must all have the same number of bits, hashes, domain
+/// and layout, otherwise `IncompatibleFilters` is returned naming the
+/// first that doesn't match `filters[0]`.  The probe positions are then
+/// computed once, and each probe's bit is read from every filter still
+/// in the running before moving on to the next probe, so the memory
+/// accesses to different filters overlap.  Like `union`, the filters
+/// must also share a hasher, or `IncompatibleFilters` is returned.
+///
+/// # Panics
+/// Panics if there are more than 64 filters, or in debug builds if
+/// they have no bits.  Release builds find nothing in filters without
+/// bits.
+pub fn probe_all<H>(
+    filters: &[&BloomFilter<H>],
+    fingerprint: BloomFingerprint,
+) -> Result<u64, IncompatibleFilters>
+where
+    H: BloomBuildHasher,
+{
+    assert!(
+        filters.len() <= 64,
+        "probe_all takes at most 64 filters, got {}",
+        filters.len()
+    );
+    let Some((first, rest)) = filters.split_first() else {
+        return Ok(0);
+    };
+    first
+        .check_compatible(rest)
+        .map_err(|e| IncompatibleFilters { index: e.index + 1 })?;
+    if first.probe_bits().is_none() {
+        return Ok(0);
+    }
+
+    let mut present = u64::MAX >> (64 - filters.len());
+    for filter in filters {
+        filter.stats.record_contains();
+    }
+    for idx in first.probe_positions(fingerprint) {
+        let mut pending = present;
+        while pending != 0 {
+            let i = pending.trailing_zeros() as usize;
+            pending &= pending - 1;
+            // SAFETY: `probe_positions` keeps `idx` in bounds of `first`,
+            // and every compatible filter has as many bits.
+            if !unsafe { filters[i].bits.get_unchecked(idx) } {
+                present &= !(1 << i);
+            }
+        }
+        if present == 0 {
+            break;
+        }
+    }
+    Ok(present)
+}
+
+/// The most hashes a filter should use.
+///
+/// Every probe position is derived from the two 64-bit halves of one
+/// 128-bit fingerprint, so the probes of a key aren't independent.
+/// Past a couple hundred probes, keys whose halves are close modulo the
+/// filter size share long runs of positions. More hashes then stop
+/// lowering the false positive rate and only cost time.  Filters that
+/// really need more should use `WideBloomFilter`, which derives its
+/// probes from 256 bits.
+///
+/// `optimal_num_hashes` never exceeds this.  Building a filter with more
+/// hashes panics in debug builds on the first insert or lookup.
+pub const MAX_NUM_HASHES: u32 = 200;
+
+/// Return the optimal number of hashes to use for the given number of
+/// bits and items in a filter
+///
+/// # Panics
+/// Panics if `num_items` is 0.
+pub fn optimal_num_hashes(num_bits: usize, num_items: u32) -> u32 {
+    assert!(num_items > 0, "{}", ParameterError::ZeroItems);
+    ((num_bits as f32 / num_items as f32 * core::f32::consts::LN_2).round() as u32)
+        .clamp(2, MAX_NUM_HASHES)
+}
+
+// The false positive rate of a filter with `num_bits` and `num_hashes`
+// once `num_items` have been inserted: (1 - e^(-kn/m))^k.
+fn fpr_for_items(num_bits: usize, num_hashes: u32, num_items: u64) -> f64 {
+    let k = num_hashes as f64;
+    (1.0 - (-k * num_items as f64 / num_bits as f64).exp()).powf(k)
+}
+
+/// The false positive rate of a filter of `num_bits` bits, `set_bits` of
+/// which are set, probing `num_hashes` times: the chance that every
+/// probe of an item that was never inserted lands on a set bit,
+/// `(set_bits / num_bits)^num_hashes`.  This is what
+/// `BloomFilter::estimated_fpp` reports, as a standalone function for
+/// filters whose bit counts are reported separately.
+///
+/// # Example
+///
+/// ```rust
+/// use xx_bloom::theoretical_fpr;
+///
+/// // Half full with 7 hashes.
+/// assert_eq!(theoretical_fpr(500, 1000, 7), 0.0078125);
+/// ```
+///
+/// # Panics
+/// Panics if `num_bits` is 0 or `set_bits` is more than `num_bits`.
+pub fn theoretical_fpr(set_bits: usize, num_bits: usize, num_hashes: u32) -> f64 {
+    assert!(num_bits > 0, "{}", ParameterError::ZeroBits);
+    assert!(
+        set_bits <= num_bits,
+        "{} bits can't be set out of {}",
+        set_bits,
+        num_bits
+    );
+    (set_bits as f64 / num_bits as f64).powi(num_hashes as i32)
+}
+
+// Checks the parameters every filter needs to answer lookups.
+pub(crate) fn check_size(num_bits: usize, num_hashes: u32) -> Result<(), ParameterError> {
+    if num_bits == 0 {
+        return Err(ParameterError::ZeroBits);
+    }
+    if num_hashes == 0 {
+        return Err(ParameterError::ZeroHashes);
+    }
+    if num_hashes > MAX_NUM_HASHES {
+        return Err(ParameterError::TooManyHashes(num_hashes));
+    }
+    Ok(())
+}
+
+// Checks a false positive rate is strictly between 0 and 1.
+fn check_false_pos_rate(false_pos_rate: f64) -> Result<(), ParameterError> {
+    // Written so NaN fails too.
+    if !(false_pos_rate > 0.0 && false_pos_rate < 1.0) {
+        return Err(ParameterError::InvalidRate);
+    }
+    Ok(())
+}
+
+// Checks the inputs every rate based constructor sizes a filter from.
+pub(crate) fn check_rate(false_pos_rate: f32, num_items: u32) -> Result<(), ParameterError> {
+    check_false_pos_rate(false_pos_rate as f64)?;
+    if num_items == 0 {
+        return Err(ParameterError::ZeroItems);
+    }
+    Ok(())
+}
+
+/// Return the number of bits needed to satisfy the specified false
+/// positive rate, if the filter will hold `num_items` items.  This is
+/// at least 1, since a filter can't have zero bits.
+///
+/// # Panics
+/// Panics if `false_pos_rate` isn't strictly between 0 and 1, or
+/// `num_items` is 0, or on 32-bit platforms if the number of bits
+/// doesn't fit in a usize.
+pub fn needed_bits(false_pos_rate: f32, num_items: u32) -> usize {
+    try_needed_bits(false_pos_rate, num_items).unwrap_or_else(|e| panic!("{}", e))
+}
+
+// Like `needed_bits`, but returning what's wrong with the inputs.
+pub(crate) fn try_needed_bits(
+    false_pos_rate: f32,
+    num_items: u32,
+) -> Result<usize, ParameterError> {
+    check_rate(false_pos_rate, num_items)?;
+    let ln22 = core::f32::consts::LN_2 * core::f32::consts::LN_2;
+    let bits = (num_items as f32 * ((1.0 / false_pos_rate).ln() / ln22)).round();
+    // Casting would saturate rather than fail.
+    if bits >= usize::MAX as f32 {
+        return Err(ParameterError::TooLarge);
+    }
+    Ok((bits as usize).max(1))
+}
+
+/// The size of a filter for a target false positive rate and item
+/// count, as computed by `optimal_params`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterParams {
+    /// The number of bits the filter needs.
+    pub num_bits: usize,
+    /// The number of hash functions the filter should use.
+    pub num_hashes: u32,
+    /// The theoretical false positive rate once the expected number of
+    /// items have been inserted.  Rounding the bits and hashes to whole
+    /// numbers means this differs slightly from the requested rate.
+    pub actual_fpr: f64,
+}
+
+/// Size a filter to hold `expected_items` with a false positive rate of
+/// `rate`, in one call.
+///
+/// This is `needed_bits` followed by `optimal_num_hashes`, computed in
+/// f64 so item counts beyond `u32::MAX` work, along with the false
+/// positive rate the rounded parameters actually give.
+///
+/// # Example Usage
+///
+/// ```rust
+/// # #[cfg(feature = "std")] {
+/// use xx_bloom::{optimal_params, BloomFilter};
+///
+/// let params = optimal_params(0.01, 1000);
+/// assert_eq!(params.num_hashes, 7);
+/// assert!((params.actual_fpr - 0.01).abs() < 0.001);
+/// let filter = BloomFilter::with_size(params.num_bits, params.num_hashes);
+/// # }
+/// ```
+///
+/// # Panics
+/// Panics if `rate` isn't strictly between 0 and 1, or `expected_items`
+/// is 0, or the number of bits doesn't fit in a usize.
+pub fn optimal_params(rate: f64, expected_items: u64) -> FilterParams {
+    check_false_pos_rate(rate).unwrap_or_else(|e| panic!("{}", e));
+    assert!(expected_items > 0, "{}", ParameterError::ZeroItems);
+    let ln2 = core::f64::consts::LN_2;
+    let items = expected_items as f64;
+    let num_bits = (items * (1.0 / rate).ln() / (ln2 * ln2)).round();
+    assert!(num_bits < usize::MAX as f64, "{}", ParameterError::TooLarge);
+    let num_bits = (num_bits as usize).max(1);
+    let num_hashes = ((num_bits as f64 / items * ln2).round() as u32).clamp(2, MAX_NUM_HASHES);
+    FilterParams {
+        num_bits,
+        num_hashes,
+        actual_fpr: fpr_for_items(num_bits, num_hashes, expected_items),
+    }
+}
+
+/// Return the log2 of the smallest power of two number of bits that
+/// satisfies the specified false positive rate, if the filter will hold
+/// `num_items` items.
+///
+/// # Panics
+/// Panics under the same conditions as `needed_bits`.
+pub fn needed_bits_pow2(false_pos_rate: f32, num_items: u32) -> u32 {
+    needed_bits(false_pos_rate, num_items)
+        .next_power_of_two()
+        .trailing_zeros()
+}
+
+/// Measure the false positive rate a filter built with `with_rate(rate,
+/// count)` actually achieves on your own keys.
+///
+/// `next_item` should produce keys drawn from the same distribution as
+/// the real workload.  `count` of them are inserted into a fresh filter,
+/// then `count` more are drawn and probed; the returned rate is the
+/// fraction of probes that weren't inserted but are reported as present.
+/// Probes that collide with an inserted key are skipped, so a generator
+/// that only ever repeats inserted keys yields NaN.
+///
+/// # Example Usage
+///
+/// ```rust
+/// let mut next = 0u64;
+/// let observed = xx_bloom::measure_fpr(0.01, 10_000, || {
+///     next += 1;
+///     next
+/// });
+/// assert!(observed < 0.02);
+/// ```
+///
+/// # Panics
+/// Panics if the filter reports a false negative, which would be a bug.
+#[cfg(feature = "std")]
+pub fn measure_fpr<T, F>(rate: f32, count: u32, next_item: F) -> f64
+where
+    T: Hash + Eq,
+    F: FnMut() -> T,
+{
+    measure_fpr_with_hasher(rate, count, RandomXxh3State::new(), next_item)
+}
+
+/// Like `measure_fpr`, but hashing with `hash_builder`.
+#[cfg(feature = "std")]
+pub fn measure_fpr_with_hasher<H, T, F>(
+    rate: f32,
+    count: u32,
+    hash_builder: H,
+    mut next_item: F,
+) -> f64
+where
+    H: BloomBuildHasher,
+    T: Hash + Eq,
+    F: FnMut() -> T,
+{
+    let mut filter = BloomFilter::with_rate_and_hasher(rate, count, hash_builder);
+    let mut inserted = HashSet::with_capacity(count as usize);
+    for _ in 0..count {
+        let item = next_item();
+        filter.insert(&item);
+        inserted.insert(item);
+    }
+
+    let mut negatives = 0u64;
+    let mut false_positives = 0u64;
+    for _ in 0..count {
+        let item = next_item();
+        let present = filter.contains(&item);
+        if inserted.contains(&item) {
+            assert!(present, "false negative");
+            continue;
+        }
+        negatives += 1;
+        if present {
+            false_positives += 1;
+        }
+    }
+    false_positives as f64 / negatives as f64
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::io::{self, Write};
+
+    use rand::Rng;
+
+    use super::{
+        measure_fpr, needed_bits, needed_bits_pow2, optimal_num_hashes, optimal_params,
+        theoretical_fpr, BloomFilter, IndexLayout, MAX_NUM_HASHES,
+    };
+    use crate::bits::Bits;
+    use crate::error::{
+        BufferTooShort, CapacityExceeded, FoldError, IncompatibleFilters, ParameterError,
+    };
+    use crate::hashing::{fingerprint, HashIter};
+    use crate::{
+        BloomBuildHasher, BloomFingerprint, Intersectable, RandomXxh3State, SecretBasedXxh3Builder,
+        Unionable, ASMS,
+    };
+
+    #[test]
+    fn simple() {
+        let mut b: BloomFilter = BloomFilter::with_rate(0.01, 100);
+        b.insert(&1);
+        assert!(b.contains(&1));
+        assert!(!b.contains(&2));
+        b.clear();
+        assert!(!b.contains(&1));
+    }
+
+    #[test]
+    fn pow2() {
+        assert_eq!(needed_bits_pow2(0.01, 10_000), 17);
+        assert_eq!(needed_bits_pow2(0.5, 1), 0);
+
+        let mut b = BloomFilter::with_size_pow2_and_hasher(12, 5, RandomXxh3State::from_seed(4));
+        assert_eq!(b.num_bits(), 4096);
+        assert_eq!(b.layout(), IndexLayout::Mask);
+        for i in 0..300u32 {
+            b.insert(&i);
+        }
+        assert!((0..300u32).all(|i| b.contains(&i)));
+        // Probes cover the whole filter.
+        assert!(b.bits.ones().any(|i| i >= 2048) && b.bits.ones().any(|i| i % 2 == 1));
+        // Masking keeps the low bits, where `FastRange` keeps the high.
+        let h = 0xabcd_0000_0000_1234;
+        assert_eq!(IndexLayout::Mask.index(h, 4096), 0x234);
+        assert_eq!(IndexLayout::FastRange.index(h, 4096), 0xabc);
+
+        let mut tiny = BloomFilter::with_size_pow2(0, 2);
+        tiny.insert(&1);
+        assert!(tiny.contains(&2));
+    }
+
+    #[test]
+    #[should_panic]
+    fn mask_needs_pow2() {
+        BloomFilter::with_size_and_layout(1000, 4, RandomXxh3State::new(), IndexLayout::Mask);
+    }
+
+    #[test]
+    fn dedup_iter() {
+        let mut b = BloomFilter::with_rate_and_hasher(0.001, 1000, RandomXxh3State::from_seed(9));
+        let stream = (0..1000u32).chain(0..1000).map(|i| i * 7 % 1000);
+        let unique: Vec<u32> = b.dedup_iter(stream).collect();
+        let expected: Vec<u32> = (0..1000).map(|i| i * 7 % 1000).collect();
+        assert_eq!(unique, expected);
+        assert!(expected.iter().all(|i| b.contains(i)));
+        assert_eq!(b.dedup_iter(0..1000u32).count(), 0);
+    }
+
+    #[test]
+    fn memory_budget() {
+        let n = 10_000;
+        let (mut b, rate) = BloomFilter::with_memory_budget(12_000, n);
+        assert_eq!(b.num_bits(), 96_000);
+        assert_eq!(b.num_hashes(), 7);
+        assert!((rate - 0.01).abs() < 0.001, "{}", rate);
+        for i in 0..n {
+            b.insert(&i);
+        }
+        assert!((b.estimated_fpp() - rate).abs() < 0.002);
+
+        // A bigger budget only ever buys a lower rate.
+        let (_, tight) = BloomFilter::with_memory_budget(1_000, n);
+        let (_, loose) = BloomFilter::with_memory_budget(100_000, n);
+        assert!(tight > rate && rate > loose);
+    }
+
+    #[test]
+    fn cached_key() {
+        let mut a = BloomFilter::with_rate_and_hasher(0.01, 100, RandomXxh3State::from_seed(1));
+        let mut b = BloomFilter::combinable_with(&a);
+        let key = a.hash_builder().cache_key(&5u32);
+        a.insert_cached(&key);
+        b.insert(&5u32);
+        assert!(a.contains(&5u32) && b.contains_cached(&key));
+        assert_eq!(a.raw_bits(), b.raw_bits());
+        let slice = a.hash_builder().cache_slice(b"x");
+        assert!(!a.contains_cached(&slice));
+        a.insert_slice(b"x");
+        assert!(a.contains_cached(&slice));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "different hasher")]
+    fn cached_key_wrong_hasher() {
+        let b = BloomFilter::with_rate_and_hasher(0.01, 100, RandomXxh3State::from_seed(1));
+        b.contains_cached(&RandomXxh3State::from_seed(2).cache_key(&5u32));
+    }
+
+    #[test]
+    fn reader() {
+        let mut b = BloomFilter::with_rate_and_hasher(0.01, 100, RandomXxh3State::from_seed(1));
+        let data = vec![7u8; 100_000];
+        assert!(b.insert_reader(&data[..]).unwrap());
+        assert!(!b.insert_reader(&data[..]).unwrap());
+        assert!(b.contains_slice(&data));
+        assert!(b.contains_reader(&data[..]).unwrap());
+        b.insert_slice(b"x");
+        assert!(b.contains_reader(&b"x"[..]).unwrap());
+        assert!(!b.contains_reader(&b"y"[..]).unwrap());
+    }
+
+    #[test]
+    fn fold_to() {
+        let mut rng = rand::thread_rng();
+        for layout in [IndexLayout::Modulo, IndexLayout::FastRange, IndexLayout::Mask] {
+            // Mask needs a power of two, which modulo maps poorly onto.
+            let num_bits = match layout {
+                IndexLayout::Mask => 1 << 16,
+                _ => 60_000,
+            };
+            let hasher = RandomXxh3State::from_seed(6);
+            let mut b = BloomFilter::with_size_and_layout(num_bits, 5, hasher, layout);
+            let items: Vec<u64> = (0..1000).map(|_| rng.gen()).collect();
+            for item in &items {
+                b.insert(item);
+            }
+            for factor in [1, 2, 4, 8] {
+                let folded = b.fold_to(b.num_bits() / factor).unwrap();
+                assert_eq!(folded.num_bits(), b.num_bits() / factor);
+                assert_eq!(folded.layout(), layout);
+                assert!(items.iter().all(|item| folded.contains(item)));
+                // Folding sets exactly the bits inserting into a filter of
+                // the smaller size would have.
+                let mut direct =
+                    BloomFilter::with_size_and_layout(folded.num_bits(), 5, hasher, layout);
+                for item in &items {
+                    direct.insert(item);
+                }
+                assert_eq!(folded.bits().words(), direct.bits().words());
+                if layout == IndexLayout::Modulo {
+                    // Double hashed probes are poorly spread modulo sizes
+                    // with small factors, so the rate exceeds the estimate.
+                    continue;
+                }
+
+                let predicted = (1.0 - (1.0 - b.fill_ratio()).powi(factor as i32)).powi(5);
+                let fpr = folded.sampled_fpp(200_000, || rng.gen());
+                assert!(
+                    (fpr - predicted).abs() < 0.1 * predicted + 0.001,
+                    "{:?} / {}: {} vs {}",
+                    layout,
+                    factor,
+                    fpr,
+                    predicted
+                );
+            }
+        }
+
+        let b = BloomFilter::with_size_and_hasher(1000, 3, RandomXxh3State::from_seed(6));
+        assert!(b.fold_to(250).is_ok());
+        let err = FoldError {
+            num_bits: 1000,
+            new_num_bits: 300,
+        };
+        assert_eq!(b.fold_to(300).unwrap_err(), err);
+        assert_eq!(b.fold_to(0).unwrap_err().new_num_bits, 0);
+    }
+
+    #[test]
+    fn probe_all() {
+        let hasher = RandomXxh3State::from_seed(8);
+        let mut filters: Vec<_> = (0..10)
+            .map(|_| BloomFilter::with_rate_and_hasher(0.01, 100, hasher))
+            .collect();
+        for (i, filter) in filters.iter_mut().enumerate() {
+            for item in 0..100u32 {
+                if item % (i as u32 + 1) == 0 {
+                    filter.insert(&item);
+                }
+            }
+        }
+        let refs: Vec<_> = filters.iter().collect();
+        for item in 0..200u32 {
+            let fp = hasher.hash_one_128(&item.to_ne_bytes());
+            let expected = refs
+                .iter()
+                .enumerate()
+                .filter(|(_, f)| f.contains_fingerprint(fp))
+                .fold(0u64, |mask, (i, _)| mask | 1 << i);
+            assert_eq!(super::probe_all(&refs, fp), Ok(expected));
+        }
+        let fp = hasher.hash_one_128(&0u32.to_ne_bytes());
+        assert_eq!(super::probe_all(&refs, fp), Ok((1 << 10) - 1));
+        assert_eq!(super::probe_all::<RandomXxh3State>(&[], fp), Ok(0));
+
+        let positions: Vec<_> = filters[0].probe_positions(fp).collect();
+        assert_eq!(positions.len(), filters[0].num_hashes() as usize);
+        assert!(positions.iter().all(|&idx| filters[0].bits.get(idx)));
+
+        let other = BloomFilter::with_rate_and_hasher(0.01, 1000, hasher);
+        let mixed = [refs[0], refs[1], &other];
+        assert_eq!(super::probe_all(&mixed, fp), Err(IncompatibleFilters { index: 2 }));
+    }
+
+    #[test]
+    fn contains_count() {
+        let mut b = BloomFilter::with_rate_and_hasher(0.001, 100, RandomXxh3State::from_seed(4));
+        let keys: Vec<[u8; 4]> = (0..200u32).map(u32::to_le_bytes).collect();
+        for key in &keys[..50] {
+            b.insert_slice(key);
+        }
+        assert_eq!(b.contains_count(keys[..50].iter().map(|k| &k[..])), 50);
+        let all = b.contains_count(keys.iter().map(|k| &k[..]));
+        assert!((50..=52).contains(&all));
+        assert_eq!(b.contains_count(Vec::new()), 0);
+    }
+
+    #[test]
+    fn contains_batch_sorted() {
+        let mut b = BloomFilter::with_rate_and_hasher(0.05, 1000, RandomXxh3State::from_seed(5));
+        for i in 0..1000u32 {
+            b.insert(&i);
+        }
+        let fps: Vec<_> = (0..5000u32).map(|i| fingerprint(i, b.hash_builder())).collect();
+        let mut sorted = vec![false; 3];
+        b.contains_batch_sorted(&fps, &mut sorted);
+        let naive: Vec<bool> = fps.iter().map(|fp| b.contains_fingerprint(*fp)).collect();
+        assert_eq!(sorted, naive);
+        assert!(sorted[..1000].iter().all(|&found| found));
+        assert!(sorted[1000..].iter().any(|&found| !found));
+        b.contains_batch_sorted(&[], &mut sorted);
+        assert!(sorted.is_empty());
+    }
+
+    #[test]
+    fn union_resampled() {
+        let hasher = RandomXxh3State::from_seed(8);
+        for layout in [IndexLayout::Modulo, IndexLayout::FastRange, IndexLayout::Mask] {
+            let (big_bits, small_bits) = match layout {
+                IndexLayout::Mask => (1 << 14, 1 << 12),
+                _ => (20_000, 5_000),
+            };
+            let new = |num_bits| BloomFilter::with_size_and_layout(num_bits, 4, hasher, layout);
+
+            // Downscaling sets the same bits as inserting directly.
+            let mut big = new(big_bits);
+            let mut small = new(small_bits);
+            let mut direct = new(small_bits);
+            for i in 0..300u32 {
+                big.insert(&i);
+                direct.insert(&i);
+            }
+            small.insert(&1000u32);
+            direct.insert(&1000u32);
+            small.union_resampled(&big).unwrap();
+            assert_eq!(small.bits().words(), direct.bits().words(), "{:?}", layout);
+
+            // Upscaling keeps everything but sets a run per bit.
+            let mut big = new(big_bits);
+            big.insert(&1000u32);
+            big.union_resampled(&small).unwrap();
+            assert!((0..300u32).chain([1000]).all(|i| big.contains(&i)), "{:?}", layout);
+            assert!(big.count_ones() <= 4 + small.count_ones() * 4);
+            assert!(big.count_ones() >= small.count_ones() * 4);
+        }
+
+        let mut a = BloomFilter::with_size_and_hasher(1000, 3, hasher);
+        let sizes = BloomFilter::with_size_and_hasher(300, 3, hasher);
+        assert_eq!(a.union_resampled(&sizes), Err(IncompatibleFilters { index: 0 }));
+        let hashes = BloomFilter::with_size_and_hasher(500, 4, hasher);
+        assert_eq!(a.union_resampled(&hashes), Err(IncompatibleFilters { index: 0 }));
+        let secret = BloomFilter::with_size_and_hasher(500, 3, RandomXxh3State::from_seed(9));
+        assert_eq!(a.union_resampled(&secret), Err(IncompatibleFilters { index: 0 }));
+        assert_eq!(a.count_ones(), 0);
+    }
+
+    #[test]
+    fn contains_fingerprints() {
+        let mut b = BloomFilter::with_rate_and_hasher(0.05, 100, RandomXxh3State::from_seed(7));
+        for i in (0..200u32).step_by(2) {
+            b.insert(&i);
+        }
+        let fps: Vec<_> = (0..200u32).map(|i| fingerprint(i, b.hash_builder())).collect();
+        let found = b.contains_fingerprints(fps.iter().copied());
+        let naive: Vec<bool> = fps.iter().map(|fp| b.contains_fingerprint(*fp)).collect();
+        assert_eq!(found, naive);
+        assert!(found.iter().step_by(2).all(|&found| found));
+        assert!(found.iter().skip(1).step_by(2).any(|&found| !found));
+        assert!(b.contains_fingerprints(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn probes_overlap() {
+        let b = BloomFilter::with_size_and_hasher(1 << 20, 7, RandomXxh3State::from_seed(6));
+        assert_eq!(b.probes_overlap(&1u32, &1u32), 7);
+        let shared: usize = (0..1000u32).map(|i| b.probes_overlap(&i, &(i + 1))).sum();
+        assert!(shared <= 2, "{} shared probes", shared);
+
+        // With 2 bits every key's probes land on one of them.
+        let tiny = BloomFilter::with_size_and_hasher(2, 7, RandomXxh3State::from_seed(6));
+        assert!((1..=2).contains(&tiny.probes_overlap(&1u32, &2u32)));
+    }
+
+    #[test]
+    fn inserter() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let mut streamed = BloomFilter::with_rate_and_hasher(0.01, 100, RandomXxh3State::from_seed(1));
+        let mut whole = BloomFilter::with_rate_and_hasher(0.01, 100, RandomXxh3State::from_seed(1));
+
+        // Dropping an unfinished inserter inserts nothing.
+        streamed.inserter().write_all(&data).unwrap();
+        assert_eq!(streamed.count_ones(), 0);
+
+        let mut inserter = streamed.inserter();
+        assert_eq!(io::copy(&mut &data[..], &mut inserter).unwrap(), data.len() as u64);
+        assert!(inserter.finish());
+        whole.insert_slice(&data);
+        assert_eq!(streamed.bits().words(), whole.bits().words());
+        assert!(streamed.contains_slice(&data));
+
+        let mut inserter = streamed.inserter();
+        inserter.write_all(&data[..10]).unwrap();
+        inserter.write_all(&data[10..]).unwrap();
+        assert!(!inserter.finish());
+    }
+
+    #[test]
+    fn insert_report() {
+        let mut b = BloomFilter::with_size_and_hasher(1000, 5, RandomXxh3State::from_seed(3));
+        assert_eq!(b.insert_report(&1), 0);
+        assert_eq!(b.insert_report(&1), 0b11111);
+        assert_eq!(b.insert_report_vec(&1), vec![true; 5]);
+
+        // Set only the first of item 2's probes, through a filter that
+        // shares the hasher but probes once.
+        let mut one = BloomFilter::with_size_and_hasher(1000, 1, RandomXxh3State::from_seed(3));
+        one.insert(&2);
+        let one = BloomFilter::from_parts(one.bits().clone(), 5, RandomXxh3State::from_seed(3), 0, b.layout());
+        b.union(&one);
+        assert_eq!(b.insert_report(&2) & 1, 1);
+        assert!(b.contains(&2));
+
+        let mut wide = BloomFilter::with_size_and_hasher(1000, 40, RandomXxh3State::from_seed(3));
+        assert_eq!(wide.insert_report_vec(&1).len(), 40);
+        assert!(wide.insert_report_vec(&1).iter().all(|&set| set));
+    }
+
+    #[test]
+    #[should_panic(expected = "at most 32 hashes")]
+    fn insert_report_too_many_hashes() {
+        BloomFilter::with_size(1000, 33).insert_report(&1);
+    }
+
+    #[test]
+    fn max_num_hashes() {
+        assert_eq!(optimal_num_hashes(1 << 20, 1), MAX_NUM_HASHES);
+        let mut b = BloomFilter::with_size(1 << 20, MAX_NUM_HASHES);
+        b.insert(&1);
+        assert!(b.contains(&1));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "more than MAX_NUM_HASHES")]
+    fn too_many_hashes() {
+        BloomFilter::with_size(1 << 20, MAX_NUM_HASHES + 1).insert(&1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "empty filter")]
+    fn empty_filter() {
+        BloomFilter::with_size(0, 3).contains(&1);
+    }
+
+    #[test]
+    fn clear_range() {
+        let mut b = BloomFilter::with_size_and_hasher(1000, 3, RandomXxh3State::from_seed(2));
+        for i in 0..300u32 {
+            b.insert(&i);
+        }
+        let outside = b.bits.ones().filter(|i| !(100..700).contains(i)).count();
+        b.clear_range(100, 700);
+        assert_eq!(b.count_ones(), outside);
+        assert!(b.bits.ones().all(|i| !(100..700).contains(&i)));
+        b.clear_range(0, 1000);
+        assert_eq!(b.count_ones(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn clear_range_past_end() {
+        BloomFilter::with_size(1000, 3).clear_range(10, 1001);
+    }
+
+    #[test]
+    fn misconfigured() {
+        assert_eq!(BloomFilter::try_with_size(0, 3).err(), Some(ParameterError::ZeroBits));
+        assert_eq!(BloomFilter::try_with_size(1024, 0).err(), Some(ParameterError::ZeroHashes));
+        let mut b = BloomFilter::try_with_size(1024, 3).unwrap();
+        b.insert(&1);
+        assert!(b.contains(&1));
+    }
+
+    #[test]
+    fn invalid_rate() {
+        for rate in [0.0, 1.0, 1.5, -0.1, f32::NAN, f32::INFINITY] {
+            let b = BloomFilter::try_with_rate(rate, 100);
+            assert_eq!(b.err(), Some(ParameterError::InvalidRate), "rate {}", rate);
+        }
+        assert_eq!(BloomFilter::try_with_rate(0.01, 0).err(), Some(ParameterError::ZeroItems));
+        // A bad rate is reported before a bad item count.
+        assert_eq!(BloomFilter::try_with_rate(0.0, 0).err(), Some(ParameterError::InvalidRate));
+    }
+
+    #[test]
+    fn rate_boundaries() {
+        let mut b = BloomFilter::try_with_rate(1e-9, 1).unwrap();
+        assert_eq!(b.num_bits(), needed_bits(1e-9, 1));
+        assert!(b.num_hashes() > 1 && b.num_hashes() <= MAX_NUM_HASHES);
+        b.insert(&1);
+        assert!(b.contains(&1));
+
+        let b = BloomFilter::try_with_rate(0.999, 1).unwrap();
+        assert_eq!(b.num_bits(), 1);
+        let b = BloomFilter::try_with_rate(0.01, u32::MAX).unwrap();
+        assert_eq!(b.num_bits(), needed_bits(0.01, u32::MAX));
+    }
+
+    #[test]
+    fn optimal_params_matches() {
+        for (rate, items) in [(0.01, 1000), (0.001, 100), (0.1, 10_000), (1e-6, 123_456)] {
+            let params = optimal_params(rate, items);
+            assert_eq!(params.num_bits, needed_bits(rate as f32, items as u32));
+            assert_eq!(params.num_hashes, optimal_num_hashes(params.num_bits, items as u32));
+            assert!((params.actual_fpr / rate - 1.0).abs() < 0.1, "{:?}", params);
+        }
+
+        // Few bits round coarsely, which actual_fpr reflects.
+        let tiny = optimal_params(0.001, 1);
+        assert_eq!((tiny.num_bits, tiny.num_hashes), (14, 10));
+        assert!(tiny.actual_fpr > 0.0012);
+
+        // Too many items for the u32 functions, and bits for a 32-bit
+        // usize.
+        #[cfg(target_pointer_width = "64")]
+        {
+            let big = optimal_params(0.01, 1 << 33);
+            assert_eq!(big.num_hashes, 7);
+            assert!(big.num_bits > 9 << 33 && big.num_bits < 10 << 33);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "strictly between 0 and 1")]
+    fn optimal_params_bad_rate() {
+        optimal_params(f64::NAN, 100);
+    }
+
+    #[test]
+    fn needed_bits_large() {
+        // More than u32::MAX bits, which only fits on 64-bit platforms.
+        let bits = super::try_needed_bits(0.001, 3_000_000_000);
+        if cfg!(target_pointer_width = "64") {
+            let bits = bits.unwrap() as u64;
+            assert!(bits > 43_000_000_000 && bits < 43_200_000_000, "{}", bits);
+        } else {
+            assert_eq!(bits, Err(ParameterError::TooLarge));
+        }
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "32")]
+    fn too_large_32() {
+        let b = BloomFilter::try_with_rate(0.001, 3_000_000_000);
+        assert_eq!(b.err(), Some(ParameterError::TooLarge));
+        // Just under the limit still sizes fine.
+        assert!(super::try_needed_bits(0.5, 2_000_000_000).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "can address")]
+    #[cfg(target_pointer_width = "32")]
+    fn needed_bits_too_large_32() {
+        needed_bits(0.001, 3_000_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "strictly between 0 and 1")]
+    fn zero_rate() {
+        needed_bits(0.0, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "strictly between 0 and 1")]
+    fn rate_above_one() {
+        needed_bits(1.5, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one item")]
+    fn zero_items() {
+        BloomFilter::with_rate(0.01, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one item")]
+    fn zero_items_hashes() {
+        optimal_num_hashes(1024, 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "without hashes")]
+    fn no_hashes() {
+        BloomFilter::with_size(1024, 0).contains(&1);
+    }
+
+    // Bypassing the constructors' debug check gives a filter that
+    // never probes, so nothing is ever missing from it.
+    #[test]
+    fn no_hashes_contains_everything() {
+        let mut b = BloomFilter::from_parts(
+            Bits::new(1024),
+            0,
+            RandomXxh3State::from_seed(1),
+            0,
+            IndexLayout::default(),
+        );
+        let fp = b.hash_builder().hash_one_128(b"a");
+        assert!(b.contains(&1));
+        assert!(b.contains_fingerprint_prefetched(fp));
+        let mut out = Vec::new();
+        b.contains_batch_sorted(&[fp], &mut out);
+        assert_eq!(out, [true]);
+        assert_eq!(super::probe_all(&[&b], fp), Ok(1));
+
+        b.insert(&1);
+        assert!(b.insert_report_vec(&2).is_empty());
+        assert_eq!(b.count_ones(), 0);
+        assert_eq!(b.approximate_insertions(), 0);
+        assert_eq!(b.probe_positions(fp).count(), 0);
+        assert!(b.contains(&3));
+    }
+
+    #[test]
+    fn one_hash() {
+        let mut b = BloomFilter::with_size_and_hasher(1024, 1, RandomXxh3State::from_seed(1));
+        assert!(!b.contains(&1));
+        assert_eq!(b.insert_report_vec(&1), [false]);
+        assert_eq!(b.count_ones(), 1);
+        assert!(b.contains(&1));
+        let fp = fingerprint(1, b.hash_builder());
+        let positions: Vec<usize> = b.probe_positions(fp).collect();
+        assert_eq!(positions.len(), 1);
+        assert!(b.bits.get(positions[0]));
+        assert_eq!(b.estimated_fpp(), 1.0 / 1024.0);
+    }
+
+    #[test]
+    fn domain() {
+        let hasher = RandomXxh3State::from_seed(5);
+        let mut a = BloomFilter::with_domain(0.01, 1000, hasher, 1);
+        let mut b = BloomFilter::with_domain(0.01, 1000, hasher, 2);
+        for i in 0..1000u32 {
+            a.insert(&i);
+            b.insert(&i);
+        }
+        assert!((0..1000u32).all(|i| a.contains(&i) && b.contains(&i)));
+        assert_ne!(a.raw_bits(), b.raw_bits());
+
+        let plain = BloomFilter::with_rate_and_hasher(0.01, 1000, hasher);
+        assert_eq!(plain.domain(), 0);
+        let mut c = BloomFilter::combinable_with(&a);
+        assert_eq!(c.domain(), 1);
+        assert_eq!(c.union_many(&[&a, &b]), Err(IncompatibleFilters { index: 1 }));
+        assert_eq!(c.union_many(&[&plain]), Err(IncompatibleFilters { index: 0 }));
+        c.union_many(&[&a]).unwrap();
+        assert_eq!(c.raw_bits(), a.raw_bits());
+        assert_eq!(a.compact(0..1000u32, 0.01).domain(), 1);
+    }
+
+    fn golden_positions(layout: IndexLayout) -> Vec<usize> {
+        let hasher = RandomXxh3State::from_seed(42);
+        let mut b = BloomFilter::with_size_and_layout(1000, 4, hasher, layout);
+        for i in 0..20u32 {
+            b.insert(&i);
+        }
+        b.insert_slice(b"hello");
+        let positions: Vec<usize> = b.bits.ones().collect();
+        assert_eq!(b.count_ones(), positions.len());
+
+        let raw = b.raw_bits();
+        let restored = BloomFilter::from_raw_bits_with_layout(&raw, 1000, 4, hasher, layout);
+        assert_eq!(restored.unwrap().bits.ones().collect::<Vec<_>>(), positions);
+        positions
+    }
+
+    #[test]
+    fn bit_positions() {
+        // Positions captured when the probe sequence last changed, to
+        // check the same keys keep setting the same bits.
+        let modulo = [
+            12, 50, 69, 104, 141, 147, 155, 157, 163, 172, 173, 178, 181, 206, 209, 223, 236, 244,
+            261, 287, 294, 302, 316, 319, 326, 330, 344, 399, 415, 441, 454, 457, 470, 473, 486,
+            491, 492, 507, 512, 515, 516, 537, 543, 554, 580, 591, 608, 621, 628, 631, 637, 648,
+            672, 677, 699, 712, 734, 735, 750, 751, 756, 770, 784, 790, 809, 841, 843, 869, 902,
+            908, 909, 910, 914, 915, 920, 922, 925, 939, 961, 967, 982,
+        ];
+        assert_eq!(golden_positions(IndexLayout::Modulo), modulo);
+        let fastrange = [
+            18, 27, 84, 95, 122, 132, 136, 162, 167, 170, 217, 218, 219, 230, 232, 249, 298, 315,
+            321, 326, 339, 342, 349, 357, 360, 361, 399, 425, 429, 438, 443, 446, 465, 500, 506,
+            508, 521, 525, 545, 550, 557, 558, 601, 616, 624, 642, 646, 674, 677, 688, 702, 710,
+            730, 736, 745, 783, 795, 830, 847, 848, 849, 880, 886, 889, 892, 893, 896, 899, 902,
+            909, 913, 925, 926, 930, 933, 937, 943, 946, 953, 978, 983, 991, 994,
+        ];
+        assert_eq!(golden_positions(IndexLayout::FastRange), fastrange);
+        assert_eq!(BloomFilter::with_size(10, 1).layout(), IndexLayout::FastRange);
+
+        let hasher = RandomXxh3State::from_seed(42);
+        let modulo = BloomFilter::with_size_and_layout(1000, 4, hasher, IndexLayout::Modulo);
+        let mut fastrange = BloomFilter::with_size_and_hasher(1000, 4, hasher);
+        assert_eq!(fastrange.union_many(&[&modulo]), Err(IncompatibleFilters { index: 0 }));
+    }
+
+    #[test]
+    fn index_uniformity() {
+        // A size just above a power of two, where a reduction of a
+        // narrow hash would be most skewed.  Every probe of each
+        // fingerprint is counted, so derived probes are covered too.
+        let num_bits = (1 << 20) + 3;
+        let num_hashes = 7;
+        let mut rng = rand::thread_rng();
+        let fps: Vec<_> = (0..1_000_000)
+            .map(|_| BloomFingerprint::new(rng.gen(), rng.gen()))
+            .collect();
+        for layout in [IndexLayout::Modulo, IndexLayout::FastRange] {
+            let mut counts = vec![0u32; num_bits];
+            for &fp in &fps {
+                for h in HashIter::from_fingerprint(fp, num_hashes) {
+                    counts[layout.index(h, num_bits)] += 1;
+                }
+            }
+            let expected = (fps.len() * num_hashes as usize) as f64 / num_bits as f64;
+            let chi2: f64 = counts
+                .iter()
+                .map(|&c| (c as f64 - expected).powi(2) / expected)
+                .sum();
+            // Chi-squared with `num_bits - 1` degrees of freedom has that
+            // mean and a standard deviation of its square root doubled.
+            let df = (num_bits - 1) as f64;
+            assert!(chi2 < df + 6.0 * (2.0 * df).sqrt(), "{:?}: {}", layout, chi2);
+            let low: u64 = counts[..num_bits / 2].iter().map(|&c| c as u64).sum();
+            let share = low as f64 / (fps.len() * num_hashes as usize) as f64;
+            assert!((share - 0.5).abs() < 0.002, "{:?}: {}", layout, share);
+        }
+    }
+
+    #[test]
+    fn raw_bits() {
+        let mut b: BloomFilter = BloomFilter::with_rate(0.01, 100);
+        b.insert(&1);
+        let bytes = b.raw_bits();
+        let restored =
+            BloomFilter::from_raw_bits(&bytes, b.num_bits(), b.num_hashes(), b.hash_builder)
+                .unwrap();
+        assert_eq!(restored.num_bits(), b.num_bits());
+        assert!(restored.contains(&1));
+        assert!(!restored.contains(&2));
+
+        let first = BloomFilter::from_raw_bits(&[0x80], 3, 1, RandomXxh3State::new()).unwrap();
+        assert!(first.bits.get(0));
+        assert!(!first.bits.get(1));
+
+        let err = BloomFilter::from_raw_bits(&[0; 2], 17, 1, RandomXxh3State::new());
+        assert_eq!(
+            err.err(),
+            Some(BufferTooShort {
+                num_bits: 17,
+                num_bytes: 2
+            })
+        );
+    }
+
+    #[test]
+    fn build_from_slices_invalid_rate() {
+        for rate in [0.0, 1.0, 1.5, -0.1, f64::NAN] {
+            let built = std::panic::catch_unwind(|| {
+                BloomFilter::build_from_slices([&b"key"[..]], rate)
+            });
+            assert!(built.is_err(), "rate {}", rate);
+        }
+    }
+
+    #[test]
+    fn build_from_slices_clamps_hashes() {
+        let b = BloomFilter::build_from_slices_with_count([&b"key"[..]], 1, 1e-300, 1);
+        assert_eq!(b.num_hashes(), MAX_NUM_HASHES);
+        assert!(b.contains_slice(b"key"));
+    }
+
+    #[test]
+    fn build_from_slices() {
+        let keys: Vec<[u8; 8]> = (0..10_000u64).map(|i| i.to_le_bytes()).collect();
+        let rate = 0.01;
+        let b = BloomFilter::build_from_slices(keys.iter().map(|k| &k[..]), rate);
+        assert_eq!(b.num_bits(), 95851);
+        assert_eq!(b.num_hashes(), 7);
+        assert!(keys.iter().all(|k| b.contains_slice(k)));
+
+        let restored = BloomFilter::from_raw_bits(
+            &b.raw_bits(),
+            b.num_bits(),
+            b.num_hashes(),
+            SecretBasedXxh3Builder::with_secret(b.hash_builder().secret().try_into().unwrap()),
+        )
+        .unwrap();
+        assert!(keys.iter().all(|k| restored.contains_slice(k)));
+
+        let probes = 200_000u64;
+        let false_positives = (10_000..10_000 + probes)
+            .filter(|i| b.contains_slice(&i.to_le_bytes()))
+            .count();
+        // Allow for sampling noise of a few standard deviations.
+        let actual_rate = false_positives as f64 / probes as f64;
+        assert!(actual_rate <= rate + 3.0 * (rate / probes as f64).sqrt());
+    }
+
+    #[cfg(feature = "roaring")]
+    #[test]
+    fn roaring() {
+        let hasher = RandomXxh3State::from_seed(3);
+        let mut a = BloomFilter::with_rate_and_hasher(0.01, 1000, hasher);
+        let mut b = BloomFilter::with_rate_and_hasher(0.01, 1000, hasher);
+        for i in 0..100u32 {
+            a.insert(&i);
+            b.insert(&(i + 50));
+        }
+        let rb = a.to_roaring();
+        assert_eq!(rb.len(), a.count_ones() as u64);
+        let back = BloomFilter::from_roaring(&rb, a.num_bits(), a.num_hashes(), hasher)
+            .unwrap();
+        assert_eq!(back.raw_bits(), a.raw_bits());
+        assert_eq!(back.count_ones(), a.count_ones());
+
+        let union = rb | b.to_roaring();
+        let union = BloomFilter::from_roaring(&union, a.num_bits(), a.num_hashes(), hasher)
+            .unwrap();
+        a.union(&b);
+        assert_eq!(union.raw_bits(), a.raw_bits());
+        assert!((0..150u32).all(|i| union.contains(&i)));
+
+        let empty = BloomFilter::<RandomXxh3State>::from_roaring(
+            &Default::default(),
+            64,
+            3,
+            RandomXxh3State::from_seed(3),
+        )
+        .unwrap();
+        assert_eq!(empty.count_ones(), 0);
+
+        let rb: roaring::RoaringBitmap = [3, 64].into_iter().collect();
+        let err = BloomFilter::from_roaring(&rb, 64, 3, RandomXxh3State::from_seed(3)).unwrap_err();
+        assert_eq!(err, crate::error::BitOutOfRange { bit: 64, num_bits: 64 });
+    }
+
+    #[test]
+    fn insert_within_fpr() {
+        let mut b = BloomFilter::with_rate_and_hasher(0.01, 1000, RandomXxh3State::from_seed(4));
+        let mut accepted = 0u32;
+        let err = loop {
+            match b.insert_within_fpr(&accepted, 0.01) {
+                Ok(_) => accepted += 1,
+                Err(err) => break err,
+            }
+        };
+        assert!((900..1100).contains(&accepted), "{}", accepted);
+        assert_eq!(err.max_fpr, 0.01);
+        assert!(err.fpr > 0.01 && err.fpr < 0.0105, "{}", err.fpr);
+        assert_eq!(b.estimated_fpp(), err.fpr);
+        assert_eq!(b.occupancy.set_bits, Some(b.count_ones() as u64));
+
+        // Refused items aren't inserted, but present ones are accepted.
+        let ones = b.count_ones();
+        assert!(b.insert_within_fpr(&(accepted + 1), 0.01).is_err());
+        assert_eq!(b.count_ones(), ones);
+        assert_eq!(b.insert_within_fpr(&0u32, 0.01), Ok(false));
+        assert_eq!(b.insert_within_fpr(&(accepted + 1), 0.5), Ok(true));
+
+        // Unioning in an overlapping copy doubles approximate_insertions
+        // but sets no new bits, so doesn't make items be refused early.
+        let hasher = RandomXxh3State::from_seed(5);
+        let mut b = BloomFilter::with_rate_and_hasher(0.01, 1000, hasher);
+        for i in 0..600u32 {
+            b.insert_within_fpr(&i, 0.01).unwrap();
+        }
+        let copy = b.fork();
+        b.union(&copy);
+        assert_eq!(b.approximate_insertions(), 1200);
+        assert_eq!(b.insert_within_fpr(&600u32, 0.01), Ok(true));
+        assert_eq!(b.occupancy.set_bits, Some(b.count_ones() as u64));
+        b.unsafe_remove(&0u32);
+        assert_eq!(b.insert_within_fpr(&0u32, 0.01), Ok(true));
+        assert_eq!(b.occupancy.set_bits, Some(b.count_ones() as u64));
+    }
+
+    #[test]
+    fn insert_fingerprints() {
+        let mut b: BloomFilter = BloomFilter::with_rate(0.01, 100);
+        let fps: Vec<_> = (0..50)
+            .map(|i: i32| b.hash_builder().hash_one_128(&i.to_le_bytes()))
+            .collect();
+        b.insert_fingerprints(fps.iter().copied());
+        assert!(fps.iter().all(|fp| b.contains_fingerprint(*fp)));
+        assert!((0..50i32).all(|i| b.contains_slice(&i.to_le_bytes())));
+    }
+
+    #[test]
+    fn count_ones() {
+        let mut rng = rand::thread_rng();
+        for num_bits in [1, 63, 64, 65, 127, 1000, 4097] {
+            let mut b = BloomFilter::with_size_and_hasher(num_bits, 3, RandomXxh3State::from_seed(1));
+            for _ in 0..rng.gen_range(0, num_bits) {
+                b.insert(&rng.gen::<u64>());
+            }
+            let naive = (0..num_bits).filter(|i| b.bits.get(*i)).count();
+            assert_eq!(b.count_ones(), naive);
+            assert_eq!(b.fill_ratio(), naive as f64 / num_bits as f64);
+        }
+    }
+
+    #[test]
+    fn estimated_fpp() {
+        let mut b = BloomFilter::with_rate_and_hasher(0.01, 10_000, RandomXxh3State::from_seed(2));
+        assert_eq!(b.estimated_fpp(), 0.0);
+        for i in 0..10_000u32 {
+            b.insert(&i);
+        }
+        assert!((b.estimated_fpp() - 0.01).abs() < 0.002, "{}", b.estimated_fpp());
+        let debug = format!("{:?}", b);
+        assert!(debug.starts_with("BloomFilter { num_bits: 95851, num_hashes: 7"));
+        assert!(debug.contains(&format!("count_ones: {}", b.count_ones())));
+        assert_eq!(b.estimated_fpp(), theoretical_fpr(b.count_ones(), b.num_bits(), 7));
+    }
+
+    #[test]
+    fn theoretical_fpr_values() {
+        assert_eq!(theoretical_fpr(0, 100, 3), 0.0);
+        assert_eq!(theoretical_fpr(100, 100, 3), 1.0);
+        assert_eq!(theoretical_fpr(25, 100, 2), 0.0625);
+        // No probes vacuously hit.
+        assert_eq!(theoretical_fpr(0, 100, 0), 1.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "101 bits can't be set out of 100")]
+    fn theoretical_fpr_overfull() {
+        theoretical_fpr(101, 100, 3);
+    }
+
+    #[test]
+    fn sampled_fpp() {
+        let mut rng = rand::thread_rng();
+        let mut b = BloomFilter::with_rate_and_hasher(0.01, 10_000, RandomXxh3State::from_seed(2));
+        assert!(!b.probe_random(|| rng.gen()));
+        assert_eq!(b.sampled_fpp(1000, || rng.gen()), 0.0);
+        for i in 0..10_000u32 {
+            b.insert(&i);
+        }
+        let sampled = b.sampled_fpp(100_000, || rng.gen());
+        assert!((sampled - b.estimated_fpp()).abs() < 0.003, "{}", sampled);
+
+        let mut full = BloomFilter::with_size_and_hasher(1, 1, RandomXxh3State::from_seed(2));
+        full.insert(&0u32);
+        assert_eq!(full.sampled_fpp(10, || rng.gen()), 1.0);
+    }
+
+    #[test]
+    fn shared_bits() {
+        let hasher = RandomXxh3State::from_seed(11);
+        let mut a = BloomFilter::with_size_and_hasher(1000, 4, hasher);
+        let mut b = BloomFilter::combinable_with(&a);
+        for i in 0..100u32 {
+            a.insert(&i);
+            b.insert(&(i + 50));
+        }
+        let mut intersection = BloomFilter::combinable_with(&a);
+        intersection.union(&a);
+        intersection.intersect(&b);
+        assert_eq!(a.shared_bits(&b), intersection.count_ones());
+        assert_eq!(b.shared_bits(&a), a.shared_bits(&b));
+        assert_eq!(a.shared_bits(&a), a.count_ones());
+    }
+
+    #[test]
+    fn bits_added_since() {
+        let hasher = RandomXxh3State::from_seed(12);
+        let mut b = BloomFilter::with_size_and_hasher(1000, 4, hasher);
+        for i in 0..50u32 {
+            b.insert(&i);
+        }
+        let snapshot = b.fork();
+        assert_eq!(b.bits_added_since(&snapshot), 0);
+        for i in 50..100u32 {
+            b.insert(&i);
+        }
+        let added = b.bits_added_since(&snapshot);
+        assert_eq!(added, b.count_ones() - snapshot.count_ones());
+        assert!(added > 0);
+        assert_eq!(snapshot.bits_added_since(&b), 0);
+
+        let empty = BloomFilter::combinable_with(&b);
+        assert_eq!(b.bits_added_since(&empty), b.count_ones());
+    }
+
+    #[test]
+    #[should_panic]
+    fn bits_added_since_size_mismatch() {
+        let hasher = RandomXxh3State::from_seed(12);
+        let a = BloomFilter::with_size_and_hasher(1000, 4, hasher);
+        let b = BloomFilter::with_size_and_hasher(1001, 4, hasher);
+        a.bits_added_since(&b);
+    }
+
+    #[test]
+    #[should_panic]
+    fn bits_added_since_hasher_mismatch() {
+        let a = BloomFilter::with_size_and_hasher(1000, 4, RandomXxh3State::from_seed(12));
+        let b = BloomFilter::with_size_and_hasher(1000, 4, RandomXxh3State::from_seed(13));
+        a.bits_added_since(&b);
+    }
+
+    #[test]
+    #[should_panic]
+    fn shared_bits_size_mismatch() {
+        let hasher = RandomXxh3State::from_seed(11);
+        let a = BloomFilter::with_size_and_hasher(1000, 4, hasher);
+        let b = BloomFilter::with_size_and_hasher(1001, 4, hasher);
+        a.shared_bits(&b);
+    }
+
+    #[test]
+    fn prefetched() {
+        let hasher = RandomXxh3State::from_seed(8);
+        let filters = [
+            BloomFilter::with_rate_and_hasher(0.01, 500, hasher),
+            BloomFilter::with_domain(0.01, 500, hasher, 3),
+            BloomFilter::with_size_and_layout(4096, 40, hasher, IndexLayout::Modulo),
+            BloomFilter::with_size_pow2_and_hasher(12, 7, hasher),
+        ];
+        let fps: Vec<_> = (0..2000u32)
+            .map(|i| hasher.hash_one_128(&i.to_le_bytes()))
+            .collect();
+        let mut out = vec![true; 3];
+        for mut b in filters {
+            b.insert_fingerprints(fps[..500].iter().copied());
+            let expected: Vec<bool> = fps.iter().map(|fp| b.contains_fingerprint(*fp)).collect();
+            assert!(expected[..500].iter().all(|c| *c) && expected[500..].iter().any(|c| !c));
+            let single: Vec<bool> = fps
+                .iter()
+                .map(|fp| b.contains_fingerprint_prefetched(*fp))
+                .collect();
+            assert_eq!(single, expected);
+            b.contains_fingerprints_prefetched(&fps, &mut out);
+            assert_eq!(out, expected);
+        }
+        BloomFilter::with_size(10, 2).contains_fingerprints_prefetched(&[], &mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn contains_with() {
+        let mut b = BloomFilter::with_rate_seeded(0.01, 100, 1);
+        for i in 0..100 {
+            b.insert(&i);
+        }
+        let same = RandomXxh3State::from_seed(1);
+        let other = RandomXxh3State::from_seed(2);
+        assert!((0..100).all(|i| b.contains_with(&i, &same)));
+        assert!((0..100).filter(|i| b.contains_with(i, &other)).count() < 10);
+    }
+
+    #[test]
+    fn compact() {
+        let mut b = BloomFilter::with_rate(0.01, 100_000);
+        let items: Vec<u32> = (0..1000).collect();
+        for i in &items {
+            b.insert(i);
+        }
+        let estimate = b.estimate_cardinality();
+        assert!((950.0..1050.0).contains(&estimate));
+
+        let compacted = b.compact(&items, 0.01);
+        assert!(compacted.num_bits() < b.num_bits() / 50);
+        assert!(items.iter().all(|i| compacted.contains(i)));
+        assert_eq!(
+            compacted.hash_builder().secret(),
+            b.hash_builder().secret()
+        );
+    }
+
+    #[test]
+    fn intersect() {
+        let mut b1 = BloomFilter::with_rate(0.01, 20);
+        b1.insert(&1);
+        b1.insert(&2);
+        let mut b2 = BloomFilter::combinable_with(&b1);
+        b2.insert(&1);
+
+        b1.intersect(&b2);
+
+        assert!(b1.contains(&1));
+        assert!(!b1.contains(&2));
+    }
+
+    #[test]
+    fn union() {
         let mut b1 = BloomFilter::with_rate(0.01, 20);
         b1.insert(&1);
         let mut b2 = BloomFilter::combinable_with(&b1);
@@ -353,48 +3228,324 @@ mod tests {
         assert!(b1.contains(&2));
     }
 
+    #[test]
+    fn fork() {
+        let hasher = RandomXxh3State::from_seed(3);
+        let mut base = BloomFilter::with_domain(0.01, 1000, hasher, 9);
+        (0..100u32).for_each(|i| base.insert(&i));
+        let mut workers: Vec<_> = (0..3).map(|_| base.fork()).collect();
+        for (w, worker) in workers.iter_mut().enumerate() {
+            assert_eq!(worker.raw_bits(), base.raw_bits());
+            assert_eq!(worker.domain(), 9);
+            assert_eq!(worker.approximate_insertions(), base.approximate_insertions());
+            assert_eq!(worker.configured_capacity(), Some(1000));
+            (0..100u32).for_each(|i| worker.insert(&(1000 * (w as u32 + 1) + i)));
+        }
+        assert!(!base.contains(&1000));
+
+        let mut merged = base.fork();
+        for worker in &workers {
+            merged.union(worker);
+        }
+        assert!((0..100u32).all(|i| merged.contains(&i)));
+        assert!((1000..1100u32).chain(3000..3100).all(|i| merged.contains(&i)));
+    }
+
+    #[test]
+    fn different_secrets() {
+        let mut a = BloomFilter::with_rate(0.01, 20);
+        let b = BloomFilter::with_rate(0.01, 20);
+        assert_eq!((a.num_bits(), a.num_hashes()), (b.num_bits(), b.num_hashes()));
+        assert_eq!(a.union_many(&[&b]), Err(IncompatibleFilters { index: 0 }));
+        let fp = a.hash_builder().hash_one_128(b"a");
+        assert_eq!(super::probe_all(&[&a, &b], fp), Err(IncompatibleFilters { index: 1 }));
+    }
+
+    #[test]
+    #[should_panic(expected = "different hashers")]
+    fn union_different_secrets() {
+        let mut a = BloomFilter::with_rate(0.01, 20);
+        a.union(&BloomFilter::with_rate(0.01, 20));
+    }
+
+    #[test]
+    #[should_panic(expected = "different hashers")]
+    fn intersect_different_secrets() {
+        let mut a = BloomFilter::with_rate_seeded(0.01, 20, 1);
+        a.intersect(&BloomFilter::with_rate_seeded(0.01, 20, 2));
+    }
+
+    #[test]
+    fn union_many() {
+        let mut b = BloomFilter::with_rate(0.01, 100);
+        let others: Vec<_> = (0..4)
+            .map(|i| {
+                let mut other = BloomFilter::combinable_with(&b);
+                other.insert(&i);
+                other
+            })
+            .collect();
+        let refs: Vec<_> = others.iter().collect();
+        b.union_many(&refs).unwrap();
+        assert!((0..4).all(|i| b.contains(&i)));
+
+        let wrong = BloomFilter::with_size_and_hasher(7, b.num_hashes(), *b.hash_builder());
+        let mut refs = refs;
+        refs.insert(2, &wrong);
+        let mut c = BloomFilter::combinable_with(&b);
+        assert_eq!(c.union_many(&refs), Err(IncompatibleFilters { index: 2 }));
+        assert_eq!(c.count_ones(), 0);
+    }
+
+    #[test]
+    fn fill_ratio() {
+        let mut b = BloomFilter::with_size(100, 2);
+        assert_eq!(b.fill_ratio(), 0.0);
+        b.insert(&1);
+        assert!(b.fill_ratio() > 0.0 && b.fill_ratio() <= 0.02);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn stats() {
+        use crate::BloomStats;
+
+        let mut b = BloomFilter::with_rate(0.01, 100);
+        assert_eq!(b.stats(), BloomStats::default());
+        b.insert(&1);
+        b.insert_slice(b"hello");
+        b.insert_fingerprints([b.hash_builder().hash_one_128(b"x")]);
+        b.contains(&1);
+        b.contains_slice(b"nope");
+        assert_eq!(
+            b.stats(),
+            BloomStats {
+                inserts: 3,
+                contains: 2,
+                bytes_inserted: 5,
+            }
+        );
+    }
+
+    #[cfg(feature = "debug-tracking")]
+    #[test]
+    fn debug_recent() {
+        let mut b = BloomFilter::with_rate(0.01, 1000);
+        assert!(b.debug_recent().is_empty());
+        b.insert(&1u32);
+        b.insert_slice(b"hello");
+        assert_eq!(
+            b.debug_recent(),
+            [fingerprint(1u32, b.hash_builder()), b.hash_builder().hash_one_128(b"hello")]
+        );
+
+        // Only the latest 64 are kept.
+        for i in 0..100u32 {
+            b.insert(&i);
+        }
+        let expected: Vec<_> = (36..100u32).map(|i| fingerprint(i, b.hash_builder())).collect();
+        assert_eq!(b.debug_recent(), expected);
+
+        // Inserted as a str, looked up as a slice: the recorded
+        // fingerprint shows they hash differently.
+        b.insert(&"seven");
+        let fp = b.hash_builder().hash_one_128(b"seven");
+        assert!(!b.contains_fingerprint(fp));
+        assert_ne!(b.debug_recent().last(), Some(&fp));
+    }
+
+    #[cfg(not(feature = "debug-tracking"))]
+    #[test]
+    fn debug_recent_zero_sized() {
+        assert_eq!(core::mem::size_of::<crate::recent::RecentInserts>(), 0);
+    }
+
+    #[test]
+    fn approximate_insertions() {
+        let hasher = RandomXxh3State::from_seed(9);
+        let mut b = BloomFilter::with_rate_and_hasher(0.01, 100, hasher);
+        assert_eq!(b.configured_capacity(), Some(100));
+        assert!(!b.contains(&1));
+        for i in 0..100u32 {
+            b.insert(&i);
+            b.insert(&i);
+        }
+        // Repeats don't count, and only the odd false positive is missed.
+        assert!(b.approximate_insertions() > 95 && b.approximate_insertions() <= 100);
+        assert!(!b.over_capacity());
+
+        let mut other = BloomFilter::with_rate_and_hasher(0.01, 100, hasher);
+        other.insert(&1000);
+        let before = b.approximate_insertions();
+        b.union(&other);
+        assert_eq!(b.approximate_insertions(), before + 1);
+        assert!(b.over_capacity() == (before == 100));
+
+        b.clear();
+        assert_eq!(b.approximate_insertions(), 0);
+        assert!(!b.contains(&1));
+        b.insert(&1);
+        assert!(b.contains(&1));
+        assert_eq!(b.approximate_insertions(), 1);
+
+        // A union into a fresh filter is no longer empty.
+        let mut fresh = BloomFilter::with_rate_and_hasher(0.01, 100, hasher);
+        fresh.union_many(&[&other]).unwrap();
+        assert!(fresh.contains(&1000));
+        assert_eq!(BloomFilter::with_size(100, 3).configured_capacity(), None);
+    }
+
+    #[test]
+    fn insert_checked() {
+        let hasher = RandomXxh3State::from_seed(9);
+        let mut b = BloomFilter::with_rate_and_hasher(0.01, 10, hasher).with_capacity_limit(2.0);
+        let mut added = 0;
+        let mut refused = None;
+        for i in 0..100u32 {
+            match b.insert_checked(&i) {
+                Ok(new) => added += new as u32,
+                Err(err) => {
+                    refused = Some((i, err));
+                    break;
+                }
+            }
+        }
+        let (i, err) = refused.unwrap();
+        assert_eq!(err, CapacityExceeded { limit: 20 });
+        assert_eq!(added, 20);
+        assert!(!b.contains(&i));
+        assert!(b.over_capacity());
+        // Items already present are still accepted.
+        assert_eq!(b.insert_checked(&0), Ok(false));
+
+        // A combinable filter starts empty under the same limit.
+        let mut copy = BloomFilter::combinable_with(&b);
+        assert_eq!(copy.configured_capacity(), Some(10));
+        let err = (0..100u32).find_map(|i| copy.insert_checked(&i).err());
+        assert_eq!(err, Some(CapacityExceeded { limit: 20 }));
+
+        let mut unbounded = BloomFilter::with_size_and_hasher(64, 3, hasher);
+        assert!((0..100u32).all(|i| unbounded.insert_checked(&i).is_ok()));
+    }
+
+    #[test]
+    fn unsafe_remove() {
+        let mut b = BloomFilter::with_rate(0.01, 100);
+        b.insert(&1);
+        b.insert_slice(b"hello");
+        b.unsafe_remove(&1);
+        assert!(!b.contains(&1));
+        b.unsafe_remove_slice(b"hello");
+        assert!(!b.contains_slice(b"hello"));
+        assert_eq!(b.count_ones(), 0);
+
+        // Removing one item takes out any other sharing one of its bits.
+        let mut b = BloomFilter::with_size(1, 1);
+        b.insert(&1);
+        b.insert(&2);
+        b.unsafe_remove(&1);
+        assert!(!b.contains(&2));
+    }
+
     #[test]
     fn fpr_test() {
         let cnt = 500000;
-        let rate = 0.01 as f32;
+        let rate = 0.01_f32;
 
         let bits = needed_bits(rate, cnt);
         assert_eq!(bits, 4792529);
         let hashes = optimal_num_hashes(bits, cnt);
         assert_eq!(hashes, 7);
 
-        let mut b: BloomFilter = BloomFilter::with_rate(rate, cnt);
-        let mut set: HashSet<i32> = HashSet::new();
         let mut rng = rand::thread_rng();
+        let actual_rate = measure_fpr(rate, cnt, || rng.gen::<i32>());
 
-        let mut i = 0;
+        // make sure we're not too far off
+        assert!(actual_rate > (rate - 0.001) as f64);
+        assert!(actual_rate < (rate + 0.001) as f64);
+    }
 
-        while i < cnt {
-            let v = rng.gen::<i32>();
-            set.insert(v);
-            b.insert(&v);
-            i += 1;
-        }
+    #[test]
+    fn measure_fpr_repeated_keys() {
+        let mut keys = [1, 2, 3].iter().cycle();
+        assert!(measure_fpr(0.01, 3, || keys.next()).is_nan());
+    }
+
+    // Drives every lookup and update path over filters with extreme
+    // sizes, hash counts and keys, checking that none panics and that
+    // nothing inserted goes missing.
+    #[test]
+    fn robustness() {
+        let mut rng = rand::thread_rng();
+        let mut keys: Vec<Vec<u8>> = [0, 1, 16, 17, 64, 128, 240, 241]
+            .iter()
+            .map(|&len| (0..len as u8).collect())
+            .collect();
+        keys.push(vec![0xa5; 1 << 20]);
+        let sizes = [
+            (1, 1),
+            (1, MAX_NUM_HASHES),
+            (2, 1),
+            (63, 2),
+            (64, MAX_NUM_HASHES),
+            (65, 7),
+            (1000, 1),
+            (4096, MAX_NUM_HASHES),
+        ];
+        for (num_bits, num_hashes) in sizes {
+            for layout in [IndexLayout::Modulo, IndexLayout::FastRange, IndexLayout::Mask] {
+                if !layout.supports(num_bits) {
+                    continue;
+                }
+                let hasher = RandomXxh3State::from_seed(rng.gen());
+                let mut b = BloomFilter::with_size_and_layout(num_bits, num_hashes, hasher, layout);
+                let random: Vec<BloomFingerprint> = (0..100)
+                    .map(|_| BloomFingerprint::new(rng.gen(), rng.gen()))
+                    .collect();
+                let mut fps = random[..50].to_vec();
+                for key in &keys {
+                    b.insert_slice(key);
+                    b.insert(key);
+                    fps.push(hasher.hash_one_128(key));
+                    fps.push(fingerprint(key, &hasher));
+                }
+                b.insert_fingerprints(random[..50].iter().copied());
+                assert!(b.insert_checked(&u64::MAX).is_ok());
+                assert_eq!(b.insert_report_vec(&"report").len(), num_hashes as usize);
+
+                assert!(keys.iter().all(|key| b.contains_slice(key) && b.contains(key)));
+                assert!(b.contains_fingerprints(fps.iter().copied()).iter().all(|&hit| hit));
+                let mut out = Vec::new();
+                b.contains_fingerprints_prefetched(&fps, &mut out);
+                assert!(out.iter().all(|&hit| hit));
+                b.contains_batch_sorted(&fps, &mut out);
+                assert!(out.iter().all(|&hit| hit));
+                for &fp in &random {
+                    assert_eq!(b.contains_fingerprint_prefetched(fp), b.contains_fingerprint(fp));
+                    assert!(b.probe_positions(fp).all(|pos| pos < num_bits));
+                }
+                assert!(b.probes_overlap(&keys[0], &keys[8]) <= num_hashes as usize);
+                assert!((0.0..=1.0).contains(&b.fill_ratio()));
+                assert!((0.0..=1.0).contains(&b.estimated_fpp()));
+                assert!(!b.estimate_cardinality().is_nan());
+                assert!((0.0..=1.0).contains(&b.sampled_fpp(10, || rng.gen())));
 
-        i = 0;
-        let mut false_positives = 0;
-        while i < cnt {
-            let v = rng.gen::<i32>();
-            match (b.contains(&v), set.contains(&v)) {
-                (true, false) => {
-                    false_positives += 1;
+                for bytes in [b.to_bytes_le(), b.to_bytes_be()] {
+                    let read = BloomFilter::from_bytes(&bytes, hasher).unwrap();
+                    assert_eq!(read.raw_bits(), b.raw_bits());
+                    let mut other = BloomFilter::combinable_with(&b);
+                    other.union(&read);
+                    assert!(other.union_from_bytes(&bytes).is_ok());
+                    assert!(keys.iter().all(|key| other.contains_slice(key)));
                 }
-                (false, true) => {
-                    assert!(false);
-                } // should never happen
-                _ => {}
+                assert!(b.fold_to(1).unwrap().contains_slice(&keys[0]));
+
+                b.unsafe_remove_slice(&keys[8]);
+                assert!(!b.contains_slice(&keys[8]));
+                b.clear_range(0, num_bits);
+                assert_eq!(b.count_ones(), 0);
+                assert!(!b.contains_slice(&keys[0]));
             }
-            i += 1;
         }
-
-        // make sure we're not too far off
-        let actual_rate = false_positives as f32 / cnt as f32;
-        assert!(actual_rate > (rate - 0.001));
-        assert!(actual_rate < (rate + 0.001));
     }
 }
@@ -0,0 +1,276 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+use core::mem;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+use crate::hashing::fingerprint;
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher, BloomFilter, BloomFingerprint, ASMS};
+
+type Buffer = Mutex<Vec<BloomFingerprint>>;
+
+/// A BloomFilter shared between threads where inserts go through
+/// per-thread write buffers.
+///
+/// Each inserting thread gets a `BufferedBloomWriter` that hashes items
+/// locally and collects their fingerprints, only taking the filter's
+/// write lock to apply a whole batch at once.  Items therefore become
+/// visible to `contains` after a delay: once their writer has buffered
+/// `batch_size` items, when it is explicitly flushed or dropped, or when
+/// `flush_all` is called.  Lookups only take a read lock.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::{BloomFilter,BufferedBloomFilter};
+///
+/// let shared = BufferedBloomFilter::new(BloomFilter::with_rate(0.01, 1000), 64);
+/// std::thread::scope(|s| {
+///     for t in 0..4u32 {
+///         let shared = &shared;
+///         s.spawn(move || {
+///             let mut writer = shared.writer();
+///             for i in 0..100 {
+///                 writer.insert(&(t * 100 + i));
+///             }
+///         });
+///     }
+/// });
+/// assert!(shared.contains(&42u32));
+/// ```
+pub struct BufferedBloomFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    filter: RwLock<BloomFilter<H>>,
+    hash_builder: H,
+    batch_size: usize,
+    buffers: Mutex<Vec<Weak<Buffer>>>,
+}
+
+/// A handle that buffers inserts into a `BufferedBloomFilter`.  Any
+/// buffered items are flushed when the writer is dropped.
+pub struct BufferedBloomWriter<'a, H>
+where
+    H: BloomBuildHasher,
+{
+    shared: &'a BufferedBloomFilter<H>,
+    buffer: Arc<Buffer>,
+}
+
+impl<H> BufferedBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Share `filter` between threads, with writers applying their
+    /// inserts every `batch_size` items.
+    ///
+    /// # Panics
+    /// Panics if `batch_size` is 0.
+    pub fn new(filter: BloomFilter<H>, batch_size: usize) -> BufferedBloomFilter<H> {
+        assert!(batch_size > 0, "batch_size must be at least 1");
+        BufferedBloomFilter {
+            hash_builder: filter.hash_builder().clone(),
+            filter: RwLock::new(filter),
+            batch_size,
+            buffers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The number of items a writer buffers before applying them.
+    #[inline(always)]
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Create a new writer.  Typically each inserting thread holds one.
+    pub fn writer(&self) -> BufferedBloomWriter<'_, H> {
+        let buffer = Arc::new(Mutex::new(Vec::with_capacity(self.batch_size)));
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.retain(|b| b.strong_count() > 0);
+        buffers.push(Arc::downgrade(&buffer));
+        BufferedBloomWriter {
+            shared: self,
+            buffer,
+        }
+    }
+
+    /// Apply the buffered inserts of every live writer, so that every
+    /// item inserted before this call is visible.
+    pub fn flush_all(&self) {
+        let pending: Vec<Vec<BloomFingerprint>> = self
+            .buffers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|buffer| mem::take(&mut *buffer.lock().unwrap()))
+            .collect();
+        let mut filter = self.filter.write().unwrap();
+        for fps in pending {
+            filter.insert_fingerprints(fps);
+        }
+    }
+
+    /// Check if the item has been inserted and flushed.
+    #[inline(always)]
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_fingerprint(fingerprint(item, &self.hash_builder))
+    }
+
+    /// Check if the byte slice has been inserted and flushed.
+    #[inline(always)]
+    pub fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_fingerprint(self.hash_builder.hash_one_128(item))
+    }
+
+    /// Check if the fingerprint has been inserted and flushed.
+    pub fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        self.filter
+            .read()
+            .unwrap()
+            .contains_fingerprint(fingerprint)
+    }
+
+    /// Flush every writer and return the underlying filter.
+    pub fn into_inner(self) -> BloomFilter<H> {
+        self.flush_all();
+        self.filter.into_inner().unwrap()
+    }
+
+    fn apply(&self, fps: Vec<BloomFingerprint>) {
+        if !fps.is_empty() {
+            self.filter.write().unwrap().insert_fingerprints(fps);
+        }
+    }
+}
+
+impl<H> BufferedBloomWriter<'_, H>
+where
+    H: BloomBuildHasher,
+{
+    /// Buffer `item` for insertion.
+    #[inline(always)]
+    pub fn insert<T: Hash>(&mut self, item: &T) {
+        self.insert_fingerprint(fingerprint(item, &self.shared.hash_builder))
+    }
+
+    /// Buffer the byte slice `item` for insertion.
+    #[inline(always)]
+    pub fn insert_slice(&mut self, item: &[u8]) {
+        self.insert_fingerprint(self.shared.hash_builder.hash_one_128(item))
+    }
+
+    /// Buffer a fingerprint for insertion, applying the whole buffer
+    /// once it holds `batch_size` fingerprints.
+    pub fn insert_fingerprint(&mut self, fingerprint: BloomFingerprint) {
+        let full = {
+            let mut buffer = self.buffer.lock().unwrap();
+            buffer.push(fingerprint);
+            if buffer.len() >= self.shared.batch_size {
+                mem::replace(&mut *buffer, Vec::with_capacity(self.shared.batch_size))
+            } else {
+                return;
+            }
+        };
+        self.shared.apply(full);
+    }
+
+    /// The number of items buffered but not yet applied.
+    pub fn pending(&self) -> usize {
+        self.buffer.lock().unwrap().len()
+    }
+
+    /// Apply this writer's buffered inserts.
+    pub fn flush(&mut self) {
+        let fps = mem::take(&mut *self.buffer.lock().unwrap());
+        self.shared.apply(fps);
+    }
+}
+
+impl<H> Drop for BufferedBloomWriter<'_, H>
+where
+    H: BloomBuildHasher,
+{
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferedBloomFilter;
+    use crate::{BloomFilter, ASMS};
+
+    #[test]
+    fn visible_after_flush() {
+        let shared = BufferedBloomFilter::new(BloomFilter::with_rate(0.01, 1000), 10);
+        let mut writer = shared.writer();
+        writer.insert(&1);
+        writer.insert_slice(b"hello");
+        assert_eq!(writer.pending(), 2);
+        assert!(!shared.contains(&1));
+        writer.flush();
+        assert_eq!(writer.pending(), 0);
+        assert!(shared.contains(&1));
+        assert!(shared.contains_slice(b"hello"));
+    }
+
+    #[test]
+    fn flushes_full_batches() {
+        let shared = BufferedBloomFilter::new(BloomFilter::with_rate(0.01, 1000), 10);
+        let mut writer = shared.writer();
+        for i in 0..25 {
+            writer.insert(&i);
+        }
+        assert_eq!(writer.pending(), 5);
+        assert!((0..20).all(|i| shared.contains(&i)));
+        drop(writer);
+        assert!((0..25).all(|i| shared.contains(&i)));
+    }
+
+    #[test]
+    fn flush_all() {
+        let shared = BufferedBloomFilter::new(BloomFilter::with_rate(0.01, 1000), 1000);
+        let mut a = shared.writer();
+        let mut b = shared.writer();
+        a.insert(&1);
+        b.insert(&2);
+        shared.flush_all();
+        assert!(shared.contains(&1));
+        assert!(shared.contains(&2));
+        assert_eq!(a.pending() + b.pending(), 0);
+    }
+
+    #[test]
+    fn concurrent_writers() {
+        let shared = BufferedBloomFilter::new(BloomFilter::with_rate(0.01, 80_000), 256);
+        std::thread::scope(|s| {
+            for t in 0..8u32 {
+                let shared = &shared;
+                s.spawn(move || {
+                    let mut writer = shared.writer();
+                    for i in t * 10_000..(t + 1) * 10_000 {
+                        writer.insert(&i);
+                    }
+                });
+            }
+        });
+        let filter = shared.into_inner();
+        assert!((0..80_000u32).all(|i| filter.contains(&i)));
+    }
+}
@@ -0,0 +1,173 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use crate::bloom::{needed_bits, needed_bits_pow2, optimal_num_hashes};
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher, BloomFilter, IndexLayout};
+
+/// Configures a BloomFilter from a target false positive rate and
+/// item count, with optional tuning knobs.
+///
+/// `plan` reports the size the filter will be built with before any
+/// memory is allocated, so the cost of an option like `round_to_pow2`
+/// can be checked up front.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::{ASMS,BloomFilterBuilder,IndexLayout};
+///
+/// let builder = BloomFilterBuilder::new(0.01, 1000).round_to_pow2(true);
+/// let plan = builder.plan();
+/// assert_eq!(plan.num_bits, 16384);
+/// assert_eq!(plan.layout, IndexLayout::Mask);
+/// assert!(plan.overhead_ratio() > 0.0);
+///
+/// let mut filter = builder.build();
+/// filter.insert(&1);
+/// assert!(filter.contains(&1));
+/// ```
+#[derive(Clone)]
+pub struct BloomFilterBuilder<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    rate: f32,
+    expected_num_items: u32,
+    hash_builder: H,
+    round_to_pow2: bool,
+}
+
+/// The parameters a `BloomFilterBuilder` will build a filter with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilterPlan {
+    /// The number of bits the filter will use.
+    pub num_bits: usize,
+    /// The number of hash functions the filter will use.
+    pub num_hashes: u32,
+    /// How probes will be mapped onto bits.
+    pub layout: IndexLayout,
+    /// The number of bits exact sizing for the rate and item count
+    /// needs.
+    pub exact_bits: usize,
+}
+
+impl FilterPlan {
+    /// The bits allocated beyond what exact sizing needs.
+    pub fn overhead_bits(&self) -> usize {
+        self.num_bits - self.exact_bits
+    }
+
+    /// The extra memory relative to exact sizing, e.g. 0.5 for a filter
+    /// 50% larger than it strictly needs to be.
+    pub fn overhead_ratio(&self) -> f64 {
+        self.overhead_bits() as f64 / self.exact_bits.max(1) as f64
+    }
+}
+
+impl BloomFilterBuilder<RandomXxh3State> {
+    /// Start configuring a filter that expects to hold
+    /// `expected_num_items` with a false positive rate of `rate`.
+    pub fn new(rate: f32, expected_num_items: u32) -> BloomFilterBuilder<RandomXxh3State> {
+        BloomFilterBuilder::with_hasher(rate, expected_num_items, RandomXxh3State::new())
+    }
+}
+
+impl<H> BloomFilterBuilder<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Like `new`, but hashing items with `hash_builder`.
+    pub fn with_hasher(rate: f32, expected_num_items: u32, hash_builder: H) -> Self {
+        BloomFilterBuilder {
+            rate,
+            expected_num_items,
+            hash_builder,
+            round_to_pow2: false,
+        }
+    }
+
+    /// Round the number of bits up to the next power of two and map
+    /// probes with the `Mask` layout, trading memory for cheaper
+    /// lookups.  The extra bits also lower the false positive rate.
+    pub fn round_to_pow2(mut self, round_to_pow2: bool) -> Self {
+        self.round_to_pow2 = round_to_pow2;
+        self
+    }
+
+    /// The parameters `build` will use.
+    pub fn plan(&self) -> FilterPlan {
+        let exact_bits = needed_bits(self.rate, self.expected_num_items);
+        let (num_bits, layout) = if self.round_to_pow2 {
+            let log2 = needed_bits_pow2(self.rate, self.expected_num_items);
+            (1 << log2, IndexLayout::Mask)
+        } else {
+            (exact_bits, IndexLayout::default())
+        };
+        FilterPlan {
+            num_bits,
+            num_hashes: optimal_num_hashes(num_bits, self.expected_num_items),
+            layout,
+            exact_bits,
+        }
+    }
+
+    /// Build the filter.
+    pub fn build(self) -> BloomFilter<H> {
+        let plan = self.plan();
+        BloomFilter::with_size_and_layout(
+            plan.num_bits,
+            plan.num_hashes,
+            self.hash_builder,
+            plan.layout,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BloomFilterBuilder;
+    use crate::{BloomFilter, IndexLayout, RandomXxh3State, ASMS};
+
+    #[test]
+    fn exact_plan() {
+        let plan = BloomFilterBuilder::new(0.01, 10_000).plan();
+        let filter = BloomFilter::with_rate(0.01, 10_000);
+        assert_eq!(plan.num_bits, filter.num_bits());
+        assert_eq!(plan.num_hashes, filter.num_hashes());
+        assert_eq!(plan.layout, IndexLayout::FastRange);
+        assert_eq!(plan.overhead_bits(), 0);
+    }
+
+    #[test]
+    fn pow2() {
+        let hasher = RandomXxh3State::from_seed(3);
+        let builder = BloomFilterBuilder::with_hasher(0.01, 10_000, hasher).round_to_pow2(true);
+        let plan = builder.plan();
+        assert_eq!(plan.num_bits, 131_072);
+        assert_eq!(plan.exact_bits, 95_851);
+        assert_eq!(plan.overhead_bits(), 131_072 - 95_851);
+
+        let mut filter = builder.build();
+        assert_eq!(filter.num_bits(), plan.num_bits);
+        assert_eq!(filter.layout(), IndexLayout::Mask);
+        for i in 0..10_000u32 {
+            filter.insert(&i);
+        }
+        assert!((0..10_000u32).all(|i| filter.contains(&i)));
+        let false_positives = (10_000..110_000u32).filter(|i| filter.contains(i)).count();
+        assert!(false_positives < 1000, "{}", false_positives);
+    }
+}
@@ -1,52 +1,212 @@
+use crate::bloom::{check_size, optimal_num_hashes, try_needed_bits};
+use crate::error::{IncompatibleFilters, NotPresentError, ParameterError};
 use crate::xxh_helper::RandomXxh3State;
 use crate::BloomBuildHasher;
 use crate::BloomFingerprint;
+use crate::CachedKey;
+use crate::{Map, Set};
+#[cfg(not(any(feature = "std", test)))]
+use crate::float::FloatExt;
 
-use super::hashing::HashIter;
+use super::hashing::{fastrange, probeable, HashIter};
 use super::ValueVec;
 use super::ASMS;
-use std::hash::Hash;
+use alloc::vec::Vec;
+use core::hash::Hash;
 
 /// A standard counting bloom filter that uses a fixed number of bits
 /// per counter, supports remove, and estimating the count of the
 /// number of items inserted.
+///
+/// # Overflow spill
+/// Once a counter reaches `max_value()` it normally stops counting, so
+/// estimates for heavily inserted items are stuck at the maximum.  It
+/// also stays there: removes don't decrement a saturated counter, since
+/// it may hold more inserts than it can show and draining it could give
+/// false negatives for other items sharing it.  Items whose counters
+/// saturated are therefore over-counted, and may stay contained, after
+/// being removed.  Calling `with_overflow_spill`
+/// keeps an exact overflow count for each saturated counter in a side
+/// `HashMap`.  The packed counters stay small for the long tail of
+/// rarely inserted items while heavy hitters still count correctly.
+/// Every saturated counter costs a map entry (roughly 16 bytes plus
+/// hash table overhead), so this is a good trade for skewed workloads
+/// but a poor one if most counters saturate - use wider counters
+/// instead.
+///
+/// # Key spill
+/// Alternatively `with_spill` tracks the hottest keys themselves.  Once
+/// an insert saturates all of a key's counters its fingerprint is moved
+/// into a bounded side table that holds an exact count for it, and
+/// further inserts and removes of that key only touch the table.  Once
+/// the table holds `capacity` keys, further saturating keys fall back to
+/// the normal saturating behaviour.  A key removed until its count fits
+/// the counters again goes back to them, still saturated, so it can't
+/// be removed further.
+///
+/// # Health
+/// Saturation is silent: a filter with too narrow counters keeps
+/// answering, but removes stop draining it and its false positive rate
+/// creeps up.  `stats` reports how many counters are saturated and
+/// `is_degraded` flags a filter that should be rebuilt with wider
+/// counters or given an overflow spill.
 pub struct CountingBloomFilter<H = RandomXxh3State> {
-    counters: ValueVec,
-    num_entries: u64,
-    num_hashes: u32,
-    hash_builder: H,
+    pub(crate) counters: ValueVec,
+    pub(crate) overflow: Option<Map<usize, u32>>,
+    pub(crate) spill: Option<KeySpill>,
+    pub(crate) total_increments: u64,
+    pub(crate) num_entries: u64,
+    pub(crate) num_hashes: u32,
+    pub(crate) hash_builder: H,
+}
+
+/// The fraction of nonzero counters that may be saturated before
+/// `CountingBloomFilter::is_degraded` reports the filter as degraded.
+pub const DEGRADED_SATURATION: f64 = 0.01;
+
+/// A snapshot of how full a `CountingBloomFilter`'s counters are, as
+/// returned by `CountingBloomFilter::stats`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CountingStats {
+    /// The total number of counters.
+    pub num_counters: u64,
+    /// The number of counters that are not zero.
+    pub nonzero_counters: u64,
+    /// The number of counters stuck at `max_value()`.  Counters whose
+    /// overflow is kept by `with_overflow_spill` still count exactly and
+    /// aren't included.
+    pub saturated_counters: u64,
+    /// The largest count held by any counter, including spilled overflow.
+    pub max_counter: u32,
+    /// The number of inserts made into the filter since it was created
+    /// or last cleared, including repeated inserts of the same item.
+    /// Removes don't lower it.
+    pub total_increments: u64,
+    /// An estimate of how many distinct items the filter holds, based on
+    /// how many counters are nonzero (Swamidass & Baldi).  Infinity once
+    /// every counter is nonzero.
+    pub estimated_items: f64,
+}
+
+impl CountingStats {
+    /// The fraction of nonzero counters that are saturated, or 0 for an
+    /// empty filter.
+    pub fn saturation(&self) -> f64 {
+        if self.nonzero_counters == 0 {
+            return 0.0;
+        }
+        self.saturated_counters as f64 / self.nonzero_counters as f64
+    }
+}
+
+pub(crate) struct KeySpill {
+    pub(crate) capacity: usize,
+    pub(crate) counts: Map<BloomFingerprint, u64>,
 }
 
 impl CountingBloomFilter<RandomXxh3State> {
     /// Create a new CountingBloomFilter that will hold `num_entries`
     /// items, uses `bits_per_entry` per item, and `num_hashes` hashes
+    ///
+    /// # Panics
+    /// In debug builds, panics if `num_entries` or `num_hashes` is 0;
+    /// use `try_with_size` to check them instead.
+    #[cfg(feature = "std")]
     pub fn with_size(
         num_entries: usize,
         bits_per_entry: usize,
         num_hashes: u32,
     ) -> CountingBloomFilter<RandomXxh3State> {
-        CountingBloomFilter {
-            counters: ValueVec::new(bits_per_entry, num_entries),
-            num_entries: num_entries as u64,
-            num_hashes: num_hashes,
-            hash_builder: RandomXxh3State::new(),
-        }
+        CountingBloomFilter::with_size_and_hasher(
+            num_entries,
+            bits_per_entry,
+            num_hashes,
+            RandomXxh3State::new(),
+        )
+    }
+
+    /// Like `with_size`, but returns an error if `num_entries` or
+    /// `num_hashes` is 0, or the counters need more bits than a usize
+    /// can count.
+    #[cfg(feature = "std")]
+    pub fn try_with_size(
+        num_entries: usize,
+        bits_per_entry: usize,
+        num_hashes: u32,
+    ) -> Result<CountingBloomFilter<RandomXxh3State>, ParameterError> {
+        CountingBloomFilter::try_with_size_and_hasher(
+            num_entries,
+            bits_per_entry,
+            num_hashes,
+            RandomXxh3State::new(),
+        )
     }
 
     /// create a CountingBloomFilter that uses `bits_per_entry`
     /// entries and expects to hold `expected_num_items`.  The filter
     /// will be sized to have a false positive rate of the value
     /// specified in `rate`.
+    ///
+    /// The rate is that of membership queries (`contains`), not of
+    /// `estimate_count` being exact.  A counter is nonzero exactly when
+    /// the matching bit of a plain `BloomFilter` would be set, so the
+    /// filter gets one counter per bit that filter would need, whatever
+    /// `bits_per_entry` is.  Removes keep the rate as long as counters
+    /// don't saturate.  Saturated counters never drain (see the type
+    /// level documentation), so with counters too narrow for the churn,
+    /// e.g. 1 or 2 bits with items inserted and removed at twice the
+    /// expected load, the rate drifts towards that of the peak load.
+    ///
+    /// # Panics
+    /// Panics if `rate` isn't strictly between 0 and 1 or
+    /// `expected_num_items` is 0; use `try_with_rate` to check them
+    /// instead.
+    #[cfg(feature = "std")]
     pub fn with_rate(
         bits_per_entry: usize,
         rate: f32,
         expected_num_items: u32,
     ) -> CountingBloomFilter<RandomXxh3State> {
-        let entries = super::bloom::needed_bits(rate, expected_num_items);
-        CountingBloomFilter::with_size(
-            entries,
+        CountingBloomFilter::with_rate_and_hasher(
             bits_per_entry,
-            super::bloom::optimal_num_hashes(entries, expected_num_items),
+            rate,
+            expected_num_items,
+            RandomXxh3State::new(),
+        )
+    }
+
+    /// Like `with_rate`, but returns an error if `rate` isn't strictly
+    /// between 0 and 1 or `expected_num_items` is 0.
+    #[cfg(feature = "std")]
+    pub fn try_with_rate(
+        bits_per_entry: usize,
+        rate: f32,
+        expected_num_items: u32,
+    ) -> Result<CountingBloomFilter<RandomXxh3State>, ParameterError> {
+        CountingBloomFilter::try_with_rate_and_hasher(
+            bits_per_entry,
+            rate,
+            expected_num_items,
+            RandomXxh3State::new(),
+        )
+    }
+
+    /// Like `with_rate`, but hashing with a secret derived from `seed`.
+    /// Filters created with the same parameters and seed hash
+    /// identically, even in different processes, so their counters can
+    /// be meaningfully combined.
+    #[cfg(feature = "std")]
+    pub fn with_rate_seeded(
+        bits_per_entry: usize,
+        rate: f32,
+        expected_num_items: u32,
+        seed: u64,
+    ) -> CountingBloomFilter<RandomXxh3State> {
+        CountingBloomFilter::with_rate_and_hasher(
+            bits_per_entry,
+            rate,
+            expected_num_items,
+            RandomXxh3State::from_seed(seed),
         )
     }
 
@@ -56,12 +216,14 @@ impl CountingBloomFilter<RandomXxh3State> {
     /// # Example
     ///
     /// ```rust
+    /// # #[cfg(feature = "std")] {
     /// use xx_bloom::CountingBloomFilter;
     /// // Create a CountingBloomFilter that can count up to 10 on each entry, and with 1000
     /// // items will have a false positive rate of 0.01
     /// let cfb = CountingBloomFilter::with_rate(CountingBloomFilter::bits_for_max(10),
     ///                                          0.01,
     ///                                          1000);
+    /// # }
     /// ```
     pub fn bits_for_max(max: u32) -> usize {
         let mut bits_per_val = 0;
@@ -84,20 +246,53 @@ where
     /// Passing two HashBuilders that produce the same or correlated
     /// hash values will break the false positive guarantees of the
     /// CountingBloomFilter.
+    ///
+    /// As with `BloomFilter`, a filter with no hashes probes nothing:
+    /// inserts change no counters and every item is contained, with an
+    /// estimated count of `u32::MAX`.
+    ///
+    /// # Panics
+    /// In debug builds, panics if `num_entries` or `num_hashes` is 0;
+    /// use `try_with_size_and_hasher` to check them instead.
     pub fn with_size_and_hasher(
         num_entries: usize,
         bits_per_entry: usize,
         num_hashes: u32,
         hash_builder: H,
     ) -> CountingBloomFilter<H> {
+        debug_assert!(num_entries > 0, "can't build an empty filter");
+        debug_assert!(num_hashes > 0, "can't build a filter without hashes");
         CountingBloomFilter {
             counters: ValueVec::new(bits_per_entry, num_entries),
+            overflow: None,
+            spill: None,
+            total_increments: 0,
             num_entries: num_entries as u64,
             num_hashes,
             hash_builder,
         }
     }
 
+    /// Like `with_size_and_hasher`, but returns an error if
+    /// `num_entries` or `num_hashes` is 0, `num_hashes` is more than
+    /// `MAX_NUM_HASHES`, or the counters need more bits than a usize
+    /// can count.
+    pub fn try_with_size_and_hasher(
+        num_entries: usize,
+        bits_per_entry: usize,
+        num_hashes: u32,
+        hash_builder: H,
+    ) -> Result<CountingBloomFilter<H>, ParameterError> {
+        check_size(num_entries, num_hashes)?;
+        check_counter_bits(num_entries, bits_per_entry)?;
+        Ok(CountingBloomFilter::with_size_and_hasher(
+            num_entries,
+            bits_per_entry,
+            num_hashes,
+            hash_builder,
+        ))
+    }
+
     /// Create a CountingBloomFilter that expects to hold
     /// `expected_num_items`.  The filter will be sized to have a
     /// false positive rate of the value specified in `rate`.  Items
@@ -106,7 +301,11 @@ where
     /// HashBuilders MUST provide independent hash values.  Passing
     /// two HashBuilders that produce the same or correlated hash
     /// values will break the false positive guarantees of the
-    /// CountingBloomFilter.
+    /// CountingBloomFilter.  See `with_rate` for what the rate covers.
+    ///
+    /// # Panics
+    /// Panics if `rate` isn't strictly between 0 and 1 or
+    /// `expected_num_items` is 0.
     pub fn with_rate_and_hasher(
         bits_per_entry: usize,
         rate: f32,
@@ -122,20 +321,336 @@ where
         )
     }
 
+    /// Like `with_rate_and_hasher`, but returns an error if `rate` isn't
+    /// strictly between 0 and 1 or `expected_num_items` is 0.
+    pub fn try_with_rate_and_hasher(
+        bits_per_entry: usize,
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> Result<CountingBloomFilter<H>, ParameterError> {
+        let entries = try_needed_bits(rate, expected_num_items)?;
+        check_counter_bits(entries, bits_per_entry)?;
+        Ok(CountingBloomFilter::with_size_and_hasher(
+            entries,
+            bits_per_entry,
+            optimal_num_hashes(entries, expected_num_items),
+            hash_builder,
+        ))
+    }
+
+    /// Like `with_rate_and_hasher`, but with counters just wide enough
+    /// to count up to `max_count` (see `bits_for_max`).
+    pub fn with_max_count_rate_and_hasher(
+        max_count: u32,
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> CountingBloomFilter<H> {
+        CountingBloomFilter::with_rate_and_hasher(
+            CountingBloomFilter::<RandomXxh3State>::bits_for_max(max_count),
+            rate,
+            expected_num_items,
+            hash_builder,
+        )
+    }
+
+    /// Keep exact counts for saturated counters in a side table instead
+    /// of letting them stick at `max_value()`.  See the type level
+    /// documentation for the memory tradeoff.
+    pub fn with_overflow_spill(mut self) -> Self {
+        self.overflow.get_or_insert_with(Map::new);
+        self
+    }
+
+    /// Returns true if saturated counters spill into an overflow table.
+    #[inline(always)]
+    pub fn has_overflow_spill(&self) -> bool {
+        self.overflow.is_some()
+    }
+
+    /// The number of counters that currently have spilled overflow.
+    pub fn num_spilled_counters(&self) -> usize {
+        self.overflow.as_ref().map_or(0, |o| o.len())
+    }
+
+    /// Keep exact counts for up to `capacity` keys whose counters have
+    /// saturated.  This has no effect on a filter that also uses
+    /// `with_overflow_spill` since its counters never saturate.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use xx_bloom::{ASMS,CountingBloomFilter};
+    /// let mut cbf: CountingBloomFilter = CountingBloomFilter::with_rate(4, 0.01, 100).with_spill(16);
+    /// for _ in 0..100 {
+    ///     cbf.insert(&1);
+    /// }
+    /// assert_eq!(cbf.estimate_count(&1), 100);
+    /// # }
+    /// ```
+    pub fn with_spill(mut self, capacity: usize) -> Self {
+        self.spill = Some(KeySpill {
+            capacity,
+            counts: Map::new(),
+        });
+        self
+    }
+
+    /// The number of keys that currently have exact spilled counts.
+    pub fn num_spilled_keys(&self) -> usize {
+        self.spill.as_ref().map_or(0, |s| s.counts.len())
+    }
+
+    /// Scan the counters and report how full they are.  This walks every
+    /// counter, so it's meant for periodic health checks rather than the
+    /// hot path.
+    pub fn stats(&self) -> CountingStats {
+        let max = self.counters.max_value();
+        let stuck = self.overflow.is_none();
+        let mut nonzero_counters = 0;
+        let mut saturated_counters = 0;
+        let mut max_counter = 0;
+        for idx in 0..self.num_entries as usize {
+            let cur = self.counters.get(idx);
+            if cur == 0 {
+                continue;
+            }
+            nonzero_counters += 1;
+            if cur == max && stuck {
+                saturated_counters += 1;
+            }
+            max_counter = max_counter.max(self.counter(idx));
+        }
+        let m = self.num_entries as f64;
+        let k = self.num_hashes as f64;
+        CountingStats {
+            num_counters: self.num_entries,
+            nonzero_counters,
+            saturated_counters,
+            max_counter,
+            total_increments: self.total_increments,
+            estimated_items: -(m / k) * (1.0 - nonzero_counters as f64 / m).ln(),
+        }
+    }
+
+    /// Returns true if more than `DEGRADED_SATURATION` of the nonzero
+    /// counters are saturated.  Removes no longer drain such a filter
+    /// reliably, so it over-counts and its false positive rate drifts up;
+    /// rebuild it with wider counters or `with_overflow_spill`.
+    pub fn is_degraded(&self) -> bool {
+        self.stats().saturation() > DEGRADED_SATURATION
+    }
+
+    #[inline(always)]
+    fn spilled_count(&self, fp: BloomFingerprint) -> Option<u32> {
+        let count = self.spill.as_ref()?.counts.get(&fp)?;
+        Some((*count).min(u32::MAX as u64) as u32)
+    }
+
+    /// Bumps the exact count of a spilled key, returning the prior count
+    /// if `fp` was spilled.
+    #[inline(always)]
+    fn spill_increment(&mut self, fp: BloomFingerprint) -> Option<u32> {
+        let count = self.spill.as_mut()?.counts.get_mut(&fp)?;
+        let prior = (*count).min(u32::MAX as u64) as u32;
+        *count += 1;
+        Some(prior)
+    }
+
+    /// Drops the exact count of a spilled key, returning the prior count
+    /// if `fp` was spilled.  Once the count drains back to what the
+    /// counters can represent the key is handed back to the counters.
+    #[inline(always)]
+    fn spill_decrement(&mut self, fp: BloomFingerprint) -> Option<u32> {
+        let max = self.counters.max_value() as u64;
+        let spill = self.spill.as_mut()?;
+        let count = spill.counts.get_mut(&fp)?;
+        let prior = (*count).min(u32::MAX as u64) as u32;
+        *count -= 1;
+        if *count <= max {
+            spill.counts.remove(&fp);
+        }
+        Some(prior)
+    }
+
+    /// Moves `fp` into the spill table if the insert that just happened
+    /// saturated its counters.
+    #[inline(always)]
+    fn maybe_spill(&mut self, fp: BloomFingerprint, prior: u32) {
+        if self.overflow.is_some() || prior.saturating_add(1) < self.counters.max_value() {
+            return;
+        }
+        if let Some(spill) = self.spill.as_mut() {
+            if spill.counts.len() < spill.capacity {
+                spill.counts.insert(fp, prior as u64 + 1);
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn counter(&self, idx: usize) -> u32 {
+        let cur = self.counters.get(idx);
+        if cur == self.counters.max_value() {
+            if let Some(extra) = self.overflow.as_ref().and_then(|o| o.get(&idx)) {
+                return cur.saturating_add(*extra);
+            }
+        }
+        cur
+    }
+
+    /// Increments the counter at `idx`, returning its prior value.
+    #[inline(always)]
+    fn increment(&mut self, idx: usize) -> u32 {
+        let cur = self.counters.get(idx);
+        if cur < self.counters.max_value() {
+            self.counters.set(idx, cur + 1);
+            return cur;
+        }
+        match self.overflow.as_mut() {
+            Some(o) => {
+                let extra = o.entry(idx).or_insert(0);
+                let prior = cur.saturating_add(*extra);
+                *extra = extra.saturating_add(1);
+                prior
+            }
+            None => cur,
+        }
+    }
+
+    /// Decrements the counter at `idx`, returning its prior value.
+    /// Without overflow spill a saturated counter has lost count of how
+    /// many inserts it holds, so it's left saturated rather than letting
+    /// removes drain it to zero under items that are still present.
+    #[inline(always)]
+    fn decrement(&mut self, idx: usize) -> u32 {
+        let cur = self.counters.get(idx);
+        if cur == self.counters.max_value() {
+            let Some(o) = self.overflow.as_mut() else {
+                return cur;
+            };
+            if let Some(extra) = o.get_mut(&idx) {
+                let prior = cur.saturating_add(*extra);
+                *extra -= 1;
+                if *extra == 0 {
+                    o.remove(&idx);
+                }
+                return prior;
+            }
+        }
+        // Removing a false positive whose probes hit the same counter
+        // more than once can drain it before the last of those probes,
+        // which then leave it at zero.
+        if cur > 0 {
+            self.counters.set(idx, cur - 1);
+        }
+        cur
+    }
+
+    // Sets the counter at `idx` to `val`, keeping whatever doesn't fit in
+    // the overflow table if there is one and saturating otherwise.
+    fn set_counter(&mut self, idx: usize, val: u32) {
+        let max = self.counters.max_value();
+        self.counters.set(idx, val.min(max));
+        if let Some(o) = self.overflow.as_mut() {
+            if val > max {
+                o.insert(idx, val - max);
+            } else {
+                o.remove(&idx);
+            }
+        }
+    }
+
+    /// Merge `other` into this filter by taking the larger of each pair
+    /// of counters.
+    ///
+    /// Use this when both filters are independent estimates of the
+    /// *same* counts, e.g. replicas that were fed the same stream.
+    /// Summing those would count every item once per replica.  When the
+    /// filters counted disjoint streams use `merge_sum` instead.
+    ///
+    /// Both filters must have the same number of entries, bits per entry
+    /// and hashes, and share a hasher (see
+    /// `BloomBuildHasher::compat_token`), otherwise `IncompatibleFilters`
+    /// is returned and this filter is left untouched.
+    pub fn merge_max(&mut self, other: &CountingBloomFilter<H>) -> Result<(), IncompatibleFilters> {
+        self.merge_with(other, u32::max, u64::max)
+    }
+
+    /// Merge `other` into this filter by adding each pair of counters,
+    /// saturating at `max_value()` unless the filter spills overflow.
+    ///
+    /// Afterwards the filter counts every item as if it had been fed
+    /// both streams, which is right when the filters counted disjoint
+    /// streams.  If they're estimates of the same counts use `merge_max`.
+    /// The same compatibility rules as `merge_max` apply.
+    pub fn merge_sum(&mut self, other: &CountingBloomFilter<H>) -> Result<(), IncompatibleFilters> {
+        self.merge_with(other, u32::saturating_add, u64::saturating_add)
+    }
+
+    fn merge_with(
+        &mut self,
+        other: &CountingBloomFilter<H>,
+        merge: impl Fn(u32, u32) -> u32,
+        merge_spilled: impl Fn(u64, u64) -> u64,
+    ) -> Result<(), IncompatibleFilters> {
+        if other.num_entries != self.num_entries
+            || other.counters.bits_per_val() != self.counters.bits_per_val()
+            || other.num_hashes != self.num_hashes
+            || other.hash_builder.compat_token() != self.hash_builder.compat_token()
+        {
+            return Err(IncompatibleFilters { index: 0 });
+        }
+        self.total_increments = merge_spilled(self.total_increments, other.total_increments);
+
+        // Keys spilled by either filter get their merged exact count,
+        // computed before the counters change.
+        let count = |filter: &CountingBloomFilter<H>, fp| {
+            filter
+                .spill
+                .as_ref()
+                .and_then(|s| s.counts.get(&fp).copied())
+                .unwrap_or_else(|| filter.estimate_count_fingerprint(fp) as u64)
+        };
+        let spilled: Vec<_> = [&*self, other]
+            .into_iter()
+            .filter_map(|filter| filter.spill.as_ref())
+            .flat_map(|s| s.counts.keys().copied())
+            .collect::<Set<_>>()
+            .into_iter()
+            .map(|fp| (fp, merge_spilled(count(self, fp), count(other, fp))))
+            .collect();
+
+        for idx in 0..self.num_entries as usize {
+            let val = merge(self.counter(idx), other.counter(idx));
+            self.set_counter(idx, val);
+        }
+
+        let max = self.counters.max_value() as u64;
+        if let Some(spill) = self.spill.as_mut() {
+            for (fp, count) in spilled {
+                if count <= max {
+                    spill.counts.remove(&fp);
+                } else if spill.counts.len() < spill.capacity || spill.counts.contains_key(&fp) {
+                    spill.counts.insert(fp, count);
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn remove_hash_iter(&mut self, h_iter: HashIter) -> u32 {
+        if let Some(prior) = self.spill_decrement(h_iter.fingerprint()) {
+            return prior;
+        }
         if !(self as &CountingBloomFilter<H>).contains_hash_iter(h_iter) {
             return 0;
         }
         h_iter.map(|h| {
-            let idx = (h % self.num_entries) as usize;
-            let cur = self.counters.get(idx);
-            if cur > 0 {
-                self.counters.set(idx, cur - 1);
-            } else {
-                panic!("Contains returned true but a counter is 0");
-            }
-            cur
-        }).fold(u32::max_value(), |min, cur| min.min(cur))
+            let idx = fastrange(h, self.num_entries) as usize;
+            self.decrement(idx)
+        }).fold(u32::MAX, |min, cur| min.min(cur))
     }
     /// Remove an item.  Returns an upper bound of the number of times
     /// this item had been inserted previously (i.e. the count before
@@ -169,11 +684,62 @@ where
         self.remove_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
     }
 
+    fn try_remove_hash_iter(&mut self, h_iter: HashIter) -> Result<u32, NotPresentError> {
+        match self.remove_hash_iter(h_iter) {
+            0 => Err(NotPresentError),
+            prior => Ok(prior),
+        }
+    }
+
+    /// Remove an item.  Returns an upper bound of the number of times
+    /// this item had been inserted previously (i.e. the count before
+    /// this remove), or `NotPresentError` if the filter doesn't contain
+    /// the item and nothing was removed.
+    #[inline(always)]
+    pub fn try_remove<T: Hash>(&mut self, item: &T) -> Result<u32, NotPresentError> {
+        self.try_remove_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Like `try_remove`, but a fast path when the items you're dealing
+    /// with are byte slices.
+    #[inline(always)]
+    pub fn try_remove_slice(&mut self, item: &[u8]) -> Result<u32, NotPresentError> {
+        self.try_remove_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Like `try_remove`, but a fast path when you have a set of filters
+    /// that share the same BloomBuildHasher.
+    #[inline(always)]
+    pub fn try_remove_fingerprint(
+        &mut self,
+        fingerprint: BloomFingerprint,
+    ) -> Result<u32, NotPresentError> {
+        self.try_remove_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    // A filter without hashes makes no probes, so like a `BloomFilter`
+    // it vacuously contains everything, with an estimated count of
+    // `u32::MAX`, and inserts change nothing.
+    #[inline(always)]
+    fn probeable(&self) -> bool {
+        probeable(self.num_entries)
+    }
+
     fn estimate_count_hash_iter(&self, h_iter: HashIter) -> u32 {
-        h_iter.map(|h| {
-            let idx = (h % self.num_entries) as usize;
-            self.counters.get(idx)
-        }).fold(u32::max_value(), |min, cur| min.min(cur))
+        if !self.probeable() {
+            return 0;
+        }
+        if let Some(count) = self.spilled_count(h_iter.fingerprint()) {
+            return count;
+        }
+        let num_entries = self.num_entries;
+        h_iter
+            .map(|h| self.counter(fastrange(h, num_entries) as usize))
+            .fold(u32::MAX, |min, cur| min.min(cur))
     }
 
     /// Return an estimate of the number of times `item` has been
@@ -206,15 +772,56 @@ where
         self.estimate_count_hash_iter(HashIter::from_fingerprint(fp, self.num_hashes))
     }
 
+    /// Return `estimate_count_slice` of each of `items`, in the same
+    /// order, e.g. for a frequency analysis pass over many keys.
+    pub fn estimate_counts<'a, I: IntoIterator<Item = &'a [u8]>>(&self, items: I) -> Vec<u32> {
+        items
+            .into_iter()
+            .map(|item| self.estimate_count_slice(item))
+            .collect()
+    }
+
+    /// Insert a key hashed up front with `cache_key` or `cache_slice`.
+    ///
+    /// # Panics
+    /// In debug builds, panics if the key was made by a hasher that
+    /// doesn't hash like this filter's.
+    #[inline(always)]
+    pub fn insert_cached(&mut self, key: &CachedKey) {
+        key.debug_check(&self.hash_builder);
+        self.insert_fingerprint(key.fingerprint())
+    }
+
+    /// Check if a key hashed up front with `cache_key` or
+    /// `cache_slice` is in the filter.
+    ///
+    /// # Panics
+    /// In debug builds, panics if the key was made by a hasher that
+    /// doesn't hash like this filter's.
+    #[inline(always)]
+    pub fn contains_cached(&self, key: &CachedKey) -> bool {
+        key.debug_check(&self.hash_builder);
+        self.contains_fingerprint(key.fingerprint())
+    }
+
     fn insert_get_count_hash_iter(&mut self, h_iter: HashIter) -> u32 {
-        h_iter.map(|h| {
-            let idx = (h % self.num_entries) as usize;
-            let cur = self.counters.get(idx);
-            if cur < self.counters.max_value() {
-                self.counters.set(idx, cur + 1);
-            }
-            cur
-        }).fold(u32::MAX, |min, cur| min.min(cur))
+        if !self.probeable() {
+            return 0;
+        }
+        if self.num_hashes == 0 {
+            return u32::MAX;
+        }
+        let fp = h_iter.fingerprint();
+        self.total_increments += 1;
+        if let Some(prior) = self.spill_increment(fp) {
+            return prior;
+        }
+        let prior = h_iter.map(|h| {
+            let idx = fastrange(h, self.num_entries) as usize;
+            self.increment(idx)
+        }).fold(u32::MAX, |min, cur| min.min(cur));
+        self.maybe_spill(fp, prior);
+        prior
     }
 
     /// Inserts an item, returns the estimated count of the number of
@@ -245,21 +852,35 @@ where
     }
 
     fn insert_hash_iter(&mut self, h_iter: HashIter) {
+        if !self.probeable() || self.num_hashes == 0 {
+            return;
+        }
+        if self.spill.is_some() {
+            self.insert_get_count_hash_iter(h_iter);
+            return;
+        }
+        self.total_increments += 1;
         h_iter.for_each(|h| {
-            let idx = (h % self.num_entries) as usize;
-            let cur = self.counters.get(idx);
-            if cur < self.counters.max_value() {
-                self.counters.set(idx, cur + 1);
-            }
+            let idx = fastrange(h, self.num_entries) as usize;
+            self.increment(idx);
         });
     }
 
     fn contains_hash_iter(&self, mut h_iter: HashIter) -> bool {
-        h_iter.all(|h| {
-            let idx = (h % self.num_entries) as usize;
-            let cur = self.counters.get(idx);
-            cur != 0
-        })
+        if !self.probeable() {
+            return false;
+        }
+        let num_entries = self.num_entries;
+        h_iter.all(|h| self.counters.get(fastrange(h, num_entries) as usize) != 0)
+    }
+}
+
+// On 32-bit platforms a few hundred million wide counters already need
+// more bits than a usize can count.
+fn check_counter_bits(num_entries: usize, bits_per_entry: usize) -> Result<(), ParameterError> {
+    match num_entries.checked_mul(bits_per_entry) {
+        Some(_) => Ok(()),
+        None => Err(ParameterError::TooLarge),
     }
 }
 
@@ -298,7 +919,6 @@ where
 
     #[inline(always)]
     fn contains_slice(&self, item: &[u8]) -> bool {
-        // TODO: optimize
         self.contains_hash_iter(HashIter::from_slice(
             item,
             self.num_hashes,
@@ -314,13 +934,23 @@ where
     /// Remove all values from this CountingBloomFilter
     fn clear(&mut self) {
         self.counters.clear();
+        self.total_increments = 0;
+        if let Some(o) = self.overflow.as_mut() {
+            o.clear();
+        }
+        if let Some(s) = self.spill.as_mut() {
+            s.counts.clear();
+        }
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::CountingBloomFilter;
-    use crate::ASMS;
+    use crate::BloomFingerprint;
+    use crate::hashing::{fastrange, HashIter};
+    use crate::error::{IncompatibleFilters, NotPresentError, ParameterError};
+    use crate::{needed_bits, BloomBuildHasher, RandomXxh3State, ASMS, MAX_NUM_HASHES};
 
     #[test]
     fn simple() {
@@ -331,6 +961,112 @@ mod tests {
         assert!(!cbf.contains(&2));
     }
 
+    #[test]
+    fn merge() {
+        let mut a: CountingBloomFilter = CountingBloomFilter::with_rate(4, 0.01, 100);
+        let mut b = CountingBloomFilter::with_rate_and_hasher(4, 0.01, 100, a.hash_builder);
+        for _ in 0..3 {
+            a.insert(&1);
+        }
+        for _ in 0..2 {
+            b.insert(&1);
+        }
+        b.insert(&2);
+
+        let mut max = CountingBloomFilter::with_rate_and_hasher(4, 0.01, 100, a.hash_builder);
+        max.merge_max(&a).unwrap();
+        max.merge_max(&b).unwrap();
+        assert_eq!(max.estimate_count(&1), 3);
+        assert_eq!(max.estimate_count(&2), 1);
+
+        a.merge_sum(&b).unwrap();
+        assert_eq!(a.estimate_count(&1), 5);
+        assert_eq!(a.estimate_count(&2), 1);
+        for _ in 0..5 {
+            a.merge_sum(&b).unwrap();
+        }
+        assert_eq!(a.estimate_count(&1), 15, "saturates");
+
+        let other: CountingBloomFilter = CountingBloomFilter::with_rate(8, 0.01, 100);
+        assert_eq!(a.merge_max(&other), Err(IncompatibleFilters { index: 0 }));
+        // Same shape, different secret.
+        let other: CountingBloomFilter = CountingBloomFilter::with_rate(4, 0.01, 100);
+        assert_eq!(a.merge_sum(&other), Err(IncompatibleFilters { index: 0 }));
+    }
+
+    #[test]
+    fn merge_spilled() {
+        // Seeded, so that no two probes of an item share a counter and
+        // inflate its count.
+        let hasher = RandomXxh3State::from_seed(1);
+        let mut a =
+            CountingBloomFilter::with_rate_and_hasher(2, 0.01, 100, hasher).with_overflow_spill();
+        let mut b =
+            CountingBloomFilter::with_rate_and_hasher(2, 0.01, 100, hasher).with_overflow_spill();
+        for _ in 0..10 {
+            a.insert(&1);
+        }
+        for _ in 0..6 {
+            b.insert(&1);
+        }
+        a.merge_sum(&b).unwrap();
+        assert_eq!(a.estimate_count(&1), 16);
+        a.merge_max(&b).unwrap();
+        assert_eq!(a.estimate_count(&1), 16);
+
+        let mut a = CountingBloomFilter::with_rate_and_hasher(2, 0.01, 100, hasher).with_spill(4);
+        let mut b = CountingBloomFilter::with_rate_and_hasher(2, 0.01, 100, hasher).with_spill(4);
+        for _ in 0..10 {
+            a.insert(&1);
+        }
+        for _ in 0..6 {
+            b.insert(&1);
+        }
+        b.insert(&2);
+        let mut max =
+            CountingBloomFilter::with_rate_and_hasher(2, 0.01, 100, hasher).with_spill(4);
+        max.merge_max(&b).unwrap();
+        assert_eq!(max.estimate_count(&1), 6);
+        max.merge_max(&a).unwrap();
+        assert_eq!(max.estimate_count(&1), 10);
+        a.merge_sum(&b).unwrap();
+        assert_eq!(a.estimate_count(&1), 16);
+        assert_eq!(a.estimate_count(&2), 1);
+    }
+
+    #[test]
+    fn aligned_counters() {
+        for bits_per_entry in [3, 4, 8] {
+            let mut cbf = CountingBloomFilter::with_rate(bits_per_entry, 0.01, 1000);
+            for i in 0..1000 {
+                cbf.insert(&(i % 500));
+            }
+            for i in 0..500 {
+                assert!(cbf.contains(&i));
+                assert!(cbf.estimate_count(&i) >= 2);
+            }
+        }
+    }
+
+    #[test]
+    fn cached_key() {
+        let mut cbf: CountingBloomFilter = CountingBloomFilter::with_rate(4, 0.01, 100);
+        let key = cbf.hash_builder.cache_key(&1);
+        cbf.insert_cached(&key);
+        cbf.insert(&1);
+        assert!(cbf.contains_cached(&key));
+        assert_eq!(cbf.estimate_count(&1), 2);
+        assert!(!cbf.contains_cached(&cbf.hash_builder.cache_key(&2)));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "different hasher")]
+    fn cached_key_wrong_hasher() {
+        let mut cbf: CountingBloomFilter = CountingBloomFilter::with_rate(4, 0.01, 100);
+        cbf.insert_cached(&crate::RandomXxh3State::new().cache_key(&1));
+    }
+
     #[test]
     fn remove() {
         let mut cbf: CountingBloomFilter =
@@ -345,6 +1081,29 @@ mod tests {
         assert!(!cbf.contains(&2));
     }
 
+    #[test]
+    fn try_remove() {
+        let mut cbf: CountingBloomFilter = CountingBloomFilter::with_rate(4, 0.01, 100);
+        cbf.insert(&1);
+        cbf.insert(&1);
+        assert_eq!(cbf.try_remove(&2), Err(NotPresentError));
+        assert_eq!(cbf.try_remove(&1), Ok(2));
+        assert_eq!(cbf.try_remove_slice(b"missing"), Err(NotPresentError));
+        assert_eq!(cbf.try_remove(&1), Ok(1));
+        assert_eq!(cbf.try_remove(&1), Err(NotPresentError));
+    }
+
+    #[test]
+    fn seeded() {
+        let mut a: CountingBloomFilter = CountingBloomFilter::with_rate_seeded(4, 0.01, 100, 7);
+        let b: CountingBloomFilter = CountingBloomFilter::with_rate_seeded(4, 0.01, 100, 7);
+        a.insert(&1);
+        let fp = a.hash_builder.hash_one_128(b"x");
+        assert_eq!(fp, b.hash_builder.hash_one_128(b"x"));
+        a.insert_fingerprint(fp);
+        assert_eq!(a.estimate_count_fingerprint(fp), 1);
+    }
+
     #[test]
     fn estimate_count() {
         let mut cbf: CountingBloomFilter = CountingBloomFilter::with_rate(4, 0.01, 100);
@@ -355,4 +1114,342 @@ mod tests {
         assert_eq!(cbf.insert_get_count(&1), 1);
         assert_eq!(cbf.estimate_count(&1), 2);
     }
+
+    #[test]
+    fn misconfigured() {
+        let zero_bits = CountingBloomFilter::try_with_size(0, 4, 3);
+        assert_eq!(zero_bits.err(), Some(ParameterError::ZeroBits));
+        let zero_hashes = CountingBloomFilter::try_with_size(1024, 4, 0);
+        assert_eq!(zero_hashes.err(), Some(ParameterError::ZeroHashes));
+        let mut cbf = CountingBloomFilter::try_with_size(1024, 4, 3).unwrap();
+        cbf.insert(&1);
+        assert_eq!(cbf.estimate_count(&1), 1);
+    }
+
+    #[test]
+    fn too_large() {
+        let wide = CountingBloomFilter::try_with_size(usize::MAX / 2, 4, 3);
+        assert_eq!(wide.err(), Some(ParameterError::TooLarge));
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "32")]
+    fn too_large_32() {
+        // 2^30 4-bit counters is 2^32 bits.
+        let cbf = CountingBloomFilter::try_with_size(1 << 30, 4, 3);
+        assert_eq!(cbf.err(), Some(ParameterError::TooLarge));
+        let cbf = CountingBloomFilter::try_with_rate(4, 0.01, 500_000_000);
+        assert_eq!(cbf.err(), Some(ParameterError::TooLarge));
+    }
+
+    #[test]
+    fn invalid_rate() {
+        for rate in [0.0, 1.0, 1.5, -0.1, f32::NAN, f32::INFINITY] {
+            let cbf = CountingBloomFilter::try_with_rate(4, rate, 100);
+            assert_eq!(cbf.err(), Some(ParameterError::InvalidRate), "rate {}", rate);
+        }
+        let no_items = CountingBloomFilter::try_with_rate(4, 0.01, 0);
+        assert_eq!(no_items.err(), Some(ParameterError::ZeroItems));
+
+        // Seeded: with this few counters, an unlucky hasher can send two
+        // of the item's probes to the same counter.
+        let hasher = RandomXxh3State::from_seed(2);
+        let mut cbf = CountingBloomFilter::try_with_rate_and_hasher(4, 1e-9, 1, hasher).unwrap();
+        cbf.insert(&1);
+        assert_eq!(cbf.estimate_count(&1), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one item")]
+    fn zero_items() {
+        CountingBloomFilter::with_rate(4, 0.01, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "strictly between 0 and 1")]
+    fn zero_rate() {
+        CountingBloomFilter::with_max_count_rate_and_hasher(10, 0.0, 100, RandomXxh3State::new());
+    }
+
+    // Bypassing the constructors' debug check gives a filter that
+    // never probes, so nothing is ever missing from it.
+    #[test]
+    fn no_hashes_contains_everything() {
+        let hasher = RandomXxh3State::from_seed(1);
+        let mut cbf = CountingBloomFilter::with_size_and_hasher(1024, 4, 3, hasher);
+        cbf.num_hashes = 0;
+        assert!(cbf.contains(&1));
+        assert_eq!(cbf.estimate_count(&1), u32::MAX);
+
+        cbf.insert(&1);
+        assert_eq!(cbf.insert_get_count(&2), u32::MAX);
+        assert_eq!(cbf.total_increments, 0);
+        assert!((0..1024).all(|i| cbf.counters.get(i) == 0));
+        assert_eq!(cbf.remove(&3), u32::MAX);
+        assert!(cbf.contains(&3));
+        assert_eq!(cbf.estimate_count_slice(b"a"), u32::MAX);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "empty filter")]
+    fn no_entries() {
+        CountingBloomFilter::with_size(0, 4, 3).insert(&1);
+    }
+
+    // Counts and removes extreme keys in filters with extreme sizes,
+    // counter widths and hash counts, with and without spilling,
+    // checking that nothing panics or goes missing while inserted.
+    #[test]
+    fn robustness() {
+        let mut keys: Vec<Vec<u8>> = [0, 1, 16, 240, 241]
+            .iter()
+            .map(|&len| (0..len as u8).collect())
+            .collect();
+        keys.push(vec![0xa5; 1 << 20]);
+        let sizes = [(1, 1, 1), (1, 32, MAX_NUM_HASHES), (7, 4, MAX_NUM_HASHES), (1000, 2, 1)];
+        for (num_entries, bits_per_entry, num_hashes) in sizes {
+            for spill in 0..3 {
+                let hasher = RandomXxh3State::from_seed(num_entries as u64);
+                let cbf = CountingBloomFilter::with_size_and_hasher(
+                    num_entries,
+                    bits_per_entry,
+                    num_hashes,
+                    hasher,
+                );
+                let mut cbf = match spill {
+                    0 => cbf,
+                    1 => cbf.with_overflow_spill(),
+                    _ => cbf.with_spill(2),
+                };
+                for key in &keys {
+                    cbf.insert_slice(key);
+                    cbf.insert(key);
+                    assert!(cbf.insert_get_count_slice(key) >= 1);
+                    assert!(cbf.contains_slice(key) && cbf.contains(key));
+                    assert!(cbf.estimate_count_slice(key) >= 1);
+                }
+                cbf.stats();
+                // Each key was inserted twice as a slice and once hashed
+                // with its length.
+                for key in &keys {
+                    assert!(cbf.remove_slice(key) >= 1);
+                    assert!(cbf.try_remove_slice(key).is_ok());
+                    assert!(cbf.remove(key) >= 1);
+                }
+                // Removing false positives, which these tiny filters are
+                // full of, drains counters however often they're probed.
+                for i in 0..100u64 {
+                    cbf.remove(&i);
+                    let _ = cbf.try_remove_fingerprint(BloomFingerprint::new(i, !i));
+                }
+                cbf.clear();
+                assert!(keys.iter().all(|key| !cbf.contains_slice(key)));
+            }
+        }
+    }
+
+    #[test]
+    fn with_max_count() {
+        let hasher = RandomXxh3State::from_seed(1);
+        let mut cbf = CountingBloomFilter::with_max_count_rate_and_hasher(10, 0.01, 1000, hasher);
+        let manual = CountingBloomFilter::with_rate_and_hasher(4, 0.01, 1000, hasher);
+        assert_eq!(cbf.counters.max_value(), 15);
+        assert_eq!(cbf.num_entries, manual.num_entries);
+        assert_eq!(cbf.num_hashes, manual.num_hashes);
+        for _ in 0..12 {
+            cbf.insert(&1);
+        }
+        assert_eq!(cbf.estimate_count(&1), 12);
+    }
+
+    #[test]
+    fn estimate_counts() {
+        let mut cbf =
+            CountingBloomFilter::with_rate_and_hasher(4, 0.01, 100, RandomXxh3State::from_seed(1));
+        for (item, times) in [(&b"a"[..], 3), (b"b", 1), (b"c", 2)] {
+            for _ in 0..times {
+                cbf.insert_slice(item);
+            }
+        }
+        let items: [&[u8]; 5] = [b"c", b"a", b"missing", b"b", b"a"];
+        assert_eq!(cbf.estimate_counts(items), vec![2, 3, 0, 1, 3]);
+        assert_eq!(cbf.estimate_counts(Vec::new()), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn overflow_spill() {
+        // Seeded so that 1 doesn't probe any counter twice, which would
+        // double its counts.
+        let mut saturating: CountingBloomFilter =
+            CountingBloomFilter::with_rate_seeded(2, 0.01, 100, 1);
+        let mut spilling: CountingBloomFilter =
+            CountingBloomFilter::with_rate_seeded(2, 0.01, 100, 1).with_overflow_spill();
+        assert!(spilling.has_overflow_spill());
+        for _ in 0..10 {
+            saturating.insert(&1);
+            spilling.insert(&1);
+        }
+        spilling.insert(&2);
+        assert_eq!(saturating.estimate_count(&1), 3);
+        assert_eq!(spilling.estimate_count(&1), 10);
+        assert!(spilling.num_spilled_counters() > 0);
+
+        assert_eq!(spilling.remove(&1), 10);
+        for _ in 0..9 {
+            spilling.remove(&1);
+        }
+        assert!(!spilling.contains(&1));
+        assert!(spilling.contains(&2));
+        assert_eq!(spilling.num_spilled_counters(), 0);
+
+        spilling.insert_get_count(&1);
+        spilling.clear();
+        assert_eq!(spilling.num_spilled_counters(), 0);
+    }
+
+    #[test]
+    fn key_spill() {
+        let mut cbf: CountingBloomFilter =
+            CountingBloomFilter::with_rate_seeded(4, 0.01, 100, 1).with_spill(4);
+        for _ in 0..10_000 {
+            cbf.insert(&1);
+        }
+        cbf.insert(&2);
+        assert_eq!(cbf.num_spilled_keys(), 1);
+        assert_eq!(cbf.estimate_count(&1), 10_000);
+        assert_eq!(cbf.estimate_count(&2), 1);
+        assert_eq!(cbf.insert_get_count(&1), 10_000);
+
+        for expected in (16..=10_001).rev() {
+            assert_eq!(cbf.remove(&1), expected);
+        }
+        // Handed back to the counters, which are saturated and so can't
+        // be drained any further.
+        assert_eq!(cbf.num_spilled_keys(), 0);
+        assert_eq!(cbf.remove(&1), 15);
+        assert!(cbf.contains(&1));
+        assert!(cbf.contains(&2));
+    }
+
+    #[test]
+    fn saturated_remove() {
+        let hasher = RandomXxh3State::from_seed(1);
+        let max = CountingBloomFilter::bits_for_max(3);
+        let mut cbf = CountingBloomFilter::with_size_and_hasher(64, max, 3, hasher);
+        // Find a key sharing a counter with key 0.
+        let counters = |key: u32| -> Vec<usize> {
+            HashIter::from(key, 3, &hasher).map(|h| fastrange(h, 64) as usize).collect()
+        };
+        let shared = (1..)
+            .find(|key| counters(*key).iter().any(|c| counters(0).contains(c)))
+            .unwrap();
+
+        for _ in 0..20 {
+            cbf.insert(&0);
+        }
+        cbf.insert(&shared);
+        for _ in 0..20 {
+            cbf.remove(&0);
+        }
+        assert!(cbf.contains(&shared));
+        // Key 0 is over-counted rather than going to zero.
+        assert_eq!(cbf.estimate_count(&0), 3);
+    }
+
+    #[test]
+    fn key_spill_capacity() {
+        let mut cbf: CountingBloomFilter =
+            CountingBloomFilter::with_rate_seeded(4, 0.01, 100, 1).with_spill(1);
+        for _ in 0..100 {
+            cbf.insert(&1);
+            cbf.insert(&2);
+        }
+        assert_eq!(cbf.num_spilled_keys(), 1);
+        assert_eq!(cbf.estimate_count(&1), 100);
+        assert_eq!(cbf.estimate_count(&2), 15);
+    }
+
+    #[test]
+    fn fpr_test() {
+        let cnt = 500_000u32;
+        let rate = 0.01_f32;
+        let mut cbf = CountingBloomFilter::with_rate(4, rate, cnt);
+        assert_eq!(cbf.num_entries as usize, needed_bits(rate, cnt));
+        assert_eq!(cbf.num_hashes, 7);
+
+        // Keys 0..cnt stay, cnt..2 * cnt are inserted then removed, and
+        // everything from 2 * cnt on is never inserted.
+        let measure = |cbf: &CountingBloomFilter| {
+            let probes = 2 * cnt as u64..3 * cnt as u64;
+            probes.filter(|i| cbf.contains(i)).count() as f64 / cnt as f64
+        };
+        for i in 0..cnt as u64 {
+            cbf.insert(&i);
+        }
+        let fresh = measure(&cbf);
+        assert!(fresh > (rate - 0.001) as f64 && fresh < (rate + 0.001) as f64, "{}", fresh);
+
+        for i in cnt as u64..2 * cnt as u64 {
+            cbf.insert(&i);
+        }
+        for i in cnt as u64..2 * cnt as u64 {
+            cbf.remove(&i);
+        }
+        assert!((0..cnt as u64).all(|i| cbf.contains(&i)));
+        let churned = measure(&cbf);
+        assert!(churned > (rate - 0.001) as f64 && churned < (rate + 0.001) as f64, "{}", churned);
+    }
+
+    #[test]
+    fn stats() {
+        let mut cbf: CountingBloomFilter = CountingBloomFilter::with_rate(2, 0.01, 100);
+        let fresh = cbf.stats();
+        assert_eq!(fresh.nonzero_counters, 0);
+        assert_eq!(fresh.max_counter, 0);
+        assert_eq!(fresh.estimated_items, 0.0);
+        assert!(!cbf.is_degraded());
+
+        for i in 0..100u32 {
+            cbf.insert(&i);
+        }
+        let light = cbf.stats();
+        assert_eq!(light.total_increments, 100);
+        assert!(light.estimated_items > 90.0 && light.estimated_items < 110.0);
+
+        // Hammering a handful of keys saturates their 2-bit counters, and
+        // removing them again doesn't drain those.
+        for _ in 0..10 {
+            for i in 0..10u32 {
+                cbf.insert(&i);
+            }
+        }
+        for _ in 0..10 {
+            for i in 0..10u32 {
+                cbf.remove(&i);
+            }
+        }
+        let heavy = cbf.stats();
+        assert_eq!(heavy.total_increments, 200);
+        assert_eq!(heavy.max_counter, 3);
+        assert!(heavy.saturated_counters >= 10 * cbf.num_hashes as u64 / 2);
+        assert!(cbf.is_degraded());
+
+        cbf.clear();
+        assert_eq!(cbf.stats().total_increments, 0);
+        assert!(!cbf.is_degraded());
+
+        // The same load with the overflow counted exactly isn't degraded.
+        let mut spilled: CountingBloomFilter =
+            CountingBloomFilter::with_rate(2, 0.01, 100).with_overflow_spill();
+        for _ in 0..10 {
+            for i in 0..10u32 {
+                spilled.insert(&i);
+            }
+        }
+        let stats = spilled.stats();
+        assert_eq!(stats.saturated_counters, 0);
+        assert!(stats.max_counter >= 10);
+        assert!(!spilled.is_degraded());
+    }
 }
@@ -0,0 +1,307 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+use alloc::sync::Arc;
+
+use crate::hashing::{fastrange, probeable, HashIter};
+use crate::xxh_helper::RandomXxh3State;
+use crate::{needed_bits, optimal_num_hashes, BloomBuildHasher, BloomFingerprint, ASMS};
+
+// 64 KiB of bits per chunk.
+const CHUNK_WORDS: usize = 64 * 1024 / 8;
+
+/// A BloomFilter whose bits are split into reference counted 64 KiB
+/// chunks, so that snapshots are cheap.
+///
+/// `snapshot` only bumps the reference count of every chunk.  After
+/// that, the first insert touching a chunk copies just that chunk, so a
+/// snapshot of a large filter taken while inserts keep going costs
+/// memory in proportion to how many chunks have been written since,
+/// rather than the whole filter.  Snapshots are themselves
+/// `CowBloomFilter`s and never change unless inserted into.
+///
+/// Bits are addressed like `BloomFilter`, so both answer identically for
+/// the same size, number of hashes and hasher.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::{ASMS,CowBloomFilter};
+///
+/// let mut live = CowBloomFilter::with_rate(0.01, 1000);
+/// live.insert(&1);
+/// let snapshot = live.snapshot();
+/// live.insert(&2);
+/// assert!(snapshot.contains(&1));
+/// assert!(!snapshot.contains(&2));
+/// assert!(live.contains(&2));
+/// ```
+#[derive(Clone)]
+pub struct CowBloomFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    chunks: Vec<Arc<[u64]>>,
+    num_bits: usize,
+    num_hashes: u32,
+    hash_builder: H,
+}
+
+impl CowBloomFilter<RandomXxh3State> {
+    /// Create a new CowBloomFilter with the specified number of bits,
+    /// and hashes
+    pub fn with_size(num_bits: usize, num_hashes: u32) -> CowBloomFilter<RandomXxh3State> {
+        CowBloomFilter::with_size_and_hasher(num_bits, num_hashes, RandomXxh3State::new())
+    }
+
+    /// create a CowBloomFilter that expects to hold
+    /// `expected_num_items`.  The filter will be sized to have a
+    /// false positive rate of the value specified in `rate`.
+    pub fn with_rate(rate: f32, expected_num_items: u32) -> CowBloomFilter<RandomXxh3State> {
+        CowBloomFilter::with_rate_and_hasher(rate, expected_num_items, RandomXxh3State::new())
+    }
+}
+
+impl<H> CowBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Create a new CowBloomFilter with the specified number of bits,
+    /// hashes, and HashBuilder.
+    pub fn with_size_and_hasher(
+        num_bits: usize,
+        num_hashes: u32,
+        hash_builder: H,
+    ) -> CowBloomFilter<H> {
+        let words = num_bits.div_ceil(64);
+        let chunks = (0..words.div_ceil(CHUNK_WORDS))
+            .map(|c| {
+                let len = (words - c * CHUNK_WORDS).min(CHUNK_WORDS);
+                vec![0u64; len].into()
+            })
+            .collect();
+        CowBloomFilter {
+            chunks,
+            num_bits,
+            num_hashes,
+            hash_builder,
+        }
+    }
+
+    /// Create a CowBloomFilter that expects to hold
+    /// `expected_num_items` with a false positive rate of `rate`,
+    /// hashing items with `hash_builder`.
+    pub fn with_rate_and_hasher(
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> CowBloomFilter<H> {
+        let bits = needed_bits(rate, expected_num_items);
+        CowBloomFilter::with_size_and_hasher(
+            bits,
+            optimal_num_hashes(bits, expected_num_items),
+            hash_builder,
+        )
+    }
+
+    /// Take a snapshot of the current contents.  This is O(number of
+    /// chunks) and doesn't copy any bits.
+    pub fn snapshot(&self) -> CowBloomFilter<H> {
+        self.clone()
+    }
+
+    /// Get the number of bits this CowBloomFilter is using
+    #[inline(always)]
+    pub fn num_bits(&self) -> usize {
+        self.num_bits
+    }
+
+    /// Get the number of hash functions this CowBloomFilter is using
+    #[inline(always)]
+    pub fn num_hashes(&self) -> u32 {
+        self.num_hashes
+    }
+
+    /// Get the hash builder this CowBloomFilter is using
+    #[inline(always)]
+    pub fn hash_builder(&self) -> &H {
+        &self.hash_builder
+    }
+
+    /// The number of chunks the bits are split into.
+    #[inline(always)]
+    pub fn num_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// The number of chunks this filter shares with `other`, e.g. a
+    /// snapshot of it.  Every chunk that isn't shared is a separate
+    /// 64 KiB copy.
+    pub fn num_shared_chunks(&self, other: &CowBloomFilter<H>) -> usize {
+        self.chunks
+            .iter()
+            .zip(&other.chunks)
+            .filter(|(a, b)| Arc::ptr_eq(a, b))
+            .count()
+    }
+
+    /// Get the number of bits that are set in this CowBloomFilter
+    pub fn count_ones(&self) -> usize {
+        self.chunks
+            .iter()
+            .flat_map(|chunk| chunk.iter())
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    #[inline(always)]
+    fn locate(&self, h: u64) -> (usize, usize, u64) {
+        let idx = fastrange(h, self.num_bits as u64) as usize;
+        let word = idx / 64;
+        (word / CHUNK_WORDS, word % CHUNK_WORDS, 1 << (idx % 64))
+    }
+
+    #[inline(always)]
+    fn probeable(&self) -> bool {
+        probeable(self.num_bits as u64)
+    }
+
+    fn insert_hash_iter(&mut self, h_iter: HashIter) {
+        if !self.probeable() {
+            return;
+        }
+        for h in h_iter {
+            let (chunk, word, mask) = self.locate(h);
+            // Skip the copy if the bit is already set.
+            if self.chunks[chunk][word] & mask == 0 {
+                Arc::make_mut(&mut self.chunks[chunk])[word] |= mask;
+            }
+        }
+    }
+
+    fn contains_hash_iter(&self, mut h_iter: HashIter) -> bool {
+        if !self.probeable() {
+            return false;
+        }
+        h_iter.all(|h| {
+            let (chunk, word, mask) = self.locate(h);
+            self.chunks[chunk][word] & mask != 0
+        })
+    }
+}
+
+impl<H> ASMS for CowBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Insert item into this CowBloomFilter, copying any chunks it
+    /// touches that are shared with a snapshot.
+    #[inline(always)]
+    fn insert<T: Hash>(&mut self, item: &T) {
+        self.insert_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    #[inline(always)]
+    fn insert_slice(&mut self, item: &[u8]) {
+        self.insert_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    #[inline(always)]
+    fn insert_fingerprint(&mut self, fingerprint: BloomFingerprint) {
+        self.insert_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    #[inline(always)]
+    fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    #[inline(always)]
+    fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    #[inline(always)]
+    fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        self.contains_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Remove all values from this CowBloomFilter.  Chunks shared with
+    /// a snapshot are replaced rather than copied.
+    fn clear(&mut self) {
+        for chunk in self.chunks.iter_mut() {
+            match Arc::get_mut(chunk) {
+                Some(words) => words.fill(0),
+                None => *chunk = vec![0u64; chunk.len()].into(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CowBloomFilter;
+    use crate::{BloomFilter, RandomXxh3State, ASMS};
+
+    #[test]
+    fn matches_bloom_filter() {
+        let hasher = RandomXxh3State::from_seed(4);
+        let mut cow = CowBloomFilter::with_size_and_hasher(10_007, 5, hasher);
+        let mut plain = BloomFilter::with_size_and_hasher(10_007, 5, hasher);
+        for i in 0..1000 {
+            cow.insert(&i);
+            plain.insert(&i);
+        }
+        assert_eq!(cow.count_ones(), plain.count_ones());
+        for i in 0..10_000 {
+            assert_eq!(cow.contains(&i), plain.contains(&i));
+        }
+    }
+
+    #[test]
+    fn snapshots_are_stable() {
+        // 256 chunks, i.e. 16 MiB of bits.
+        let num_bits = 256 * 64 * 1024 * 8;
+        let mut live = CowBloomFilter::with_size(num_bits, 4);
+        for i in 0..100_000u32 {
+            live.insert(&i);
+        }
+        let snapshot = live.snapshot();
+        let before: Vec<bool> = (0..200_000u32).map(|i| snapshot.contains(&i)).collect();
+        assert_eq!(live.num_shared_chunks(&snapshot), live.num_chunks());
+
+        for i in 100_000..100_010u32 {
+            live.insert(&i);
+        }
+        let after: Vec<bool> = (0..200_000u32).map(|i| snapshot.contains(&i)).collect();
+        assert_eq!(before, after);
+        assert!((100_000..100_010u32).all(|i| live.contains(&i)));
+        // At most 40 of the 256 chunks were copied.
+        assert!(live.num_shared_chunks(&snapshot) >= live.num_chunks() - 40);
+
+        live.clear();
+        assert!(!live.contains(&0u32));
+        assert!(snapshot.contains(&0u32));
+    }
+}
@@ -0,0 +1,470 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+
+use crate::error::InsertError;
+use crate::hashing::fingerprint;
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher, BloomFingerprint, ValueVec};
+
+const DEFAULT_BUCKET_SIZE: usize = 4;
+const DEFAULT_FINGERPRINT_BITS: usize = 16;
+// Fraction of slots `with_capacity` and `with_rate` expect to fill.
+// Inserts with 4 slot buckets start failing at around 95% load.
+const TARGET_LOAD: f64 = 0.9;
+const MAX_KICKS: usize = 500;
+
+/// A cuckoo filter, which supports removal at a lower memory cost than a
+/// `CountingBloomFilter` for the same false positive rate.
+///
+/// Each item is reduced to a small fingerprint stored in one slot of one
+/// of two candidate buckets.  The second candidate bucket is derived
+/// from the first and the fingerprint alone, so a fingerprint can be
+/// moved between its buckets without knowing the item.  When both
+/// buckets are full, inserting evicts a random fingerprint to its other
+/// bucket, and so on until a free slot turns up.  The false positive
+/// rate is roughly `2 * bucket_size / 2^fingerprint_bits`.
+///
+/// Inserting the same item twice stores two copies of its fingerprint,
+/// and `delete` removes one copy.  Only delete items that were actually
+/// inserted: deleting anything else may remove the fingerprint of a
+/// different item that happens to collide with it.
+///
+/// # Insertion failure
+/// Once the filter gets close to full (around 95% of slots for the
+/// default 4 slot buckets), an insert can fail to find a free slot within
+/// a bounded number of evictions.  `insert` then returns
+/// `InsertError::BucketFull` and leaves the filter exactly as it was, so
+/// every previously inserted item is still present.  Inserting more than
+/// `2 * bucket_size` copies of one item always fails.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::CuckooFilter;
+///
+/// let mut filter = CuckooFilter::with_capacity(1000);
+/// filter.insert(&1).unwrap();
+/// assert!(filter.contains(&1));
+/// assert!(filter.delete(&1));
+/// assert!(!filter.contains(&1));
+/// ```
+pub struct CuckooFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    fingerprints: ValueVec,
+    num_buckets: usize,
+    bucket_size: usize,
+    len: usize,
+    kick_state: u64,
+    hash_builder: H,
+}
+
+impl CuckooFilter<RandomXxh3State> {
+    /// Create a CuckooFilter with at least `num_buckets` buckets (rounded
+    /// up to a power of two) holding `bucket_size` fingerprints of
+    /// `fingerprint_bits` bits each.
+    pub fn with_size(
+        num_buckets: usize,
+        bucket_size: usize,
+        fingerprint_bits: usize,
+    ) -> CuckooFilter<RandomXxh3State> {
+        CuckooFilter::with_size_and_hasher(
+            num_buckets,
+            bucket_size,
+            fingerprint_bits,
+            RandomXxh3State::new(),
+        )
+    }
+
+    /// Create a CuckooFilter that can hold `capacity` items, using 4
+    /// slot buckets and 16 bit fingerprints for a false positive rate of
+    /// about 0.0001.
+    pub fn with_capacity(capacity: usize) -> CuckooFilter<RandomXxh3State> {
+        CuckooFilter::with_capacity_and_hasher(capacity, RandomXxh3State::new())
+    }
+
+    /// Create a CuckooFilter that can hold `expected_num_items` items
+    /// with a false positive rate of `rate`.
+    pub fn with_rate(rate: f32, expected_num_items: u32) -> CuckooFilter<RandomXxh3State> {
+        CuckooFilter::with_rate_and_hasher(rate, expected_num_items, RandomXxh3State::new())
+    }
+}
+
+impl<H> CuckooFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Create a CuckooFilter with the given geometry whose items are
+    /// hashed using `hash_builder`.
+    ///
+    /// # Panics
+    /// Panics if `num_buckets` or `bucket_size` is 0 or if
+    /// `fingerprint_bits` isn't between 1 and 32.
+    pub fn with_size_and_hasher(
+        num_buckets: usize,
+        bucket_size: usize,
+        fingerprint_bits: usize,
+        hash_builder: H,
+    ) -> CuckooFilter<H> {
+        assert!(
+            num_buckets > 0 && bucket_size > 0,
+            "CuckooFilter dimensions must be non-zero"
+        );
+        assert!(
+            (1..=32).contains(&fingerprint_bits),
+            "fingerprint_bits must be between 1 and 32"
+        );
+        // A power of two so that the alternate bucket (an xor) stays in
+        // range and is reversible.
+        let num_buckets = num_buckets.next_power_of_two();
+        CuckooFilter {
+            fingerprints: ValueVec::new(fingerprint_bits, num_buckets * bucket_size),
+            num_buckets,
+            bucket_size,
+            len: 0,
+            kick_state: 0x9E37_79B9_7F4A_7C15,
+            hash_builder,
+        }
+    }
+
+    /// Create a CuckooFilter that can hold `capacity` items, hashing
+    /// items using `hash_builder`.  See `CuckooFilter::with_capacity`.
+    pub fn with_capacity_and_hasher(capacity: usize, hash_builder: H) -> CuckooFilter<H> {
+        CuckooFilter::with_size_and_hasher(
+            buckets_for(capacity, DEFAULT_BUCKET_SIZE),
+            DEFAULT_BUCKET_SIZE,
+            DEFAULT_FINGERPRINT_BITS,
+            hash_builder,
+        )
+    }
+
+    /// Create a CuckooFilter that can hold `expected_num_items` items
+    /// with a false positive rate of `rate`, hashing items using
+    /// `hash_builder`.
+    pub fn with_rate_and_hasher(
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> CuckooFilter<H> {
+        let fingerprint_bits = (2.0 * DEFAULT_BUCKET_SIZE as f64 / rate as f64)
+            .log2()
+            .ceil()
+            .clamp(1.0, 32.0) as usize;
+        CuckooFilter::with_size_and_hasher(
+            buckets_for(expected_num_items as usize, DEFAULT_BUCKET_SIZE),
+            DEFAULT_BUCKET_SIZE,
+            fingerprint_bits,
+            hash_builder,
+        )
+    }
+
+    /// The number of buckets.  Always a power of two.
+    #[inline(always)]
+    pub fn num_buckets(&self) -> usize {
+        self.num_buckets
+    }
+
+    /// The number of fingerprints each bucket holds.
+    #[inline(always)]
+    pub fn bucket_size(&self) -> usize {
+        self.bucket_size
+    }
+
+    /// The number of bits stored per fingerprint.
+    #[inline(always)]
+    pub fn fingerprint_bits(&self) -> usize {
+        self.fingerprints.bits_per_val()
+    }
+
+    /// The total number of fingerprint slots.
+    #[inline(always)]
+    pub fn capacity(&self) -> usize {
+        self.num_buckets * self.bucket_size
+    }
+
+    /// The number of fingerprints stored.
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if nothing is stored.
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The fraction of slots in use.
+    pub fn load_factor(&self) -> f64 {
+        self.len as f64 / self.capacity() as f64
+    }
+
+    /// Get the hash builder this filter is using
+    #[inline(always)]
+    pub fn hash_builder(&self) -> &H {
+        &self.hash_builder
+    }
+
+    /// The first candidate bucket and the stored fingerprint for `fp`.
+    #[inline(always)]
+    fn index_and_tag(&self, fp: BloomFingerprint) -> (usize, u32) {
+        // 0 marks an empty slot so stored fingerprints are in 1..=max.
+        let max = self.fingerprints.max_value() as u64;
+        let tag = (fp.h2 % max) as u32 + 1;
+        ((fp.h1 as usize) & (self.num_buckets - 1), tag)
+    }
+
+    /// The other candidate bucket of `tag` when it is in `bucket`.
+    #[inline(always)]
+    fn alt_index(&self, bucket: usize, tag: u32) -> usize {
+        let mixed = (tag as u64).wrapping_mul(0xC6A4_A793_5BD1_E995);
+        (bucket ^ (mixed >> 32) as usize) & (self.num_buckets - 1)
+    }
+
+    #[inline(always)]
+    fn slots(&self, bucket: usize) -> core::ops::Range<usize> {
+        bucket * self.bucket_size..(bucket + 1) * self.bucket_size
+    }
+
+    fn find(&self, bucket: usize, tag: u32) -> Option<usize> {
+        self.slots(bucket)
+            .find(|&slot| self.fingerprints.get(slot) == tag)
+    }
+
+    fn try_place(&mut self, bucket: usize, tag: u32) -> bool {
+        match self.find(bucket, 0) {
+            Some(slot) => {
+                self.fingerprints.set(slot, tag);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert_fingerprint_impl(&mut self, fp: BloomFingerprint) -> Result<(), InsertError> {
+        let (i1, tag) = self.index_and_tag(fp);
+        let i2 = self.alt_index(i1, tag);
+        if self.try_place(i1, tag) || self.try_place(i2, tag) {
+            self.len += 1;
+            return Ok(());
+        }
+
+        // Both buckets are full, so evict fingerprints to their other
+        // bucket until one fits, remembering each swap so they can be
+        // undone if none does.
+        let mut path = Vec::new();
+        let mut bucket = if self.next_kick() & 1 == 0 { i1 } else { i2 };
+        let mut tag = tag;
+        for _ in 0..MAX_KICKS {
+            let slot = bucket * self.bucket_size + self.next_kick() as usize % self.bucket_size;
+            let victim = self.fingerprints.get(slot);
+            self.fingerprints.set(slot, tag);
+            path.push(slot);
+            tag = victim;
+            bucket = self.alt_index(bucket, tag);
+            if self.try_place(bucket, tag) {
+                self.len += 1;
+                return Ok(());
+            }
+        }
+        for slot in path.into_iter().rev() {
+            let displaced = self.fingerprints.get(slot);
+            self.fingerprints.set(slot, tag);
+            tag = displaced;
+        }
+        Err(InsertError::BucketFull)
+    }
+
+    #[inline(always)]
+    fn next_kick(&mut self) -> u64 {
+        // xorshift64
+        self.kick_state ^= self.kick_state << 13;
+        self.kick_state ^= self.kick_state >> 7;
+        self.kick_state ^= self.kick_state << 17;
+        self.kick_state
+    }
+
+    /// Insert an item.
+    ///
+    /// Returns `InsertError::BucketFull`, leaving the filter unchanged,
+    /// if no free slot could be found for it.
+    #[inline(always)]
+    pub fn insert<T: Hash>(&mut self, item: &T) -> Result<(), InsertError> {
+        self.insert_fingerprint_impl(fingerprint(item, &self.hash_builder))
+    }
+
+    /// Insert a byte slice.
+    /// This is a fast path when the items you're dealing with are byte slices.
+    #[inline(always)]
+    pub fn insert_slice(&mut self, item: &[u8]) -> Result<(), InsertError> {
+        self.insert_fingerprint_impl(self.hash_builder.hash_one_128(item))
+    }
+
+    /// Insert a fingerprint.
+    /// This is a fast path when you have a set of filters that share the same
+    /// BloomBuildHasher where you can amortize the key hash across all your
+    /// filters.
+    #[inline(always)]
+    pub fn insert_fingerprint(&mut self, fp: BloomFingerprint) -> Result<(), InsertError> {
+        self.insert_fingerprint_impl(fp)
+    }
+
+    /// Check if the item has been inserted into this filter.  This
+    /// function can return false positives, but not false negatives.
+    #[inline(always)]
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_fingerprint(fingerprint(item, &self.hash_builder))
+    }
+
+    /// Check if the byte slice has been inserted into this filter.
+    #[inline(always)]
+    pub fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_fingerprint(self.hash_builder.hash_one_128(item))
+    }
+
+    /// Check if the fingerprint has been inserted into this filter.
+    pub fn contains_fingerprint(&self, fp: BloomFingerprint) -> bool {
+        let (i1, tag) = self.index_and_tag(fp);
+        self.find(i1, tag).is_some() || self.find(self.alt_index(i1, tag), tag).is_some()
+    }
+
+    fn delete_fingerprint_impl(&mut self, fp: BloomFingerprint) -> bool {
+        let (i1, tag) = self.index_and_tag(fp);
+        let slot = self
+            .find(i1, tag)
+            .or_else(|| self.find(self.alt_index(i1, tag), tag));
+        match slot {
+            Some(slot) => {
+                self.fingerprints.set(slot, 0);
+                self.len -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Delete one copy of an item.  Returns false if the item wasn't
+    /// present.
+    #[inline(always)]
+    pub fn delete<T: Hash>(&mut self, item: &T) -> bool {
+        self.delete_fingerprint_impl(fingerprint(item, &self.hash_builder))
+    }
+
+    /// Delete a byte slice.  See `delete`.
+    #[inline(always)]
+    pub fn delete_slice(&mut self, item: &[u8]) -> bool {
+        self.delete_fingerprint_impl(self.hash_builder.hash_one_128(item))
+    }
+
+    /// Delete a fingerprint.  See `delete`.
+    #[inline(always)]
+    pub fn delete_fingerprint(&mut self, fp: BloomFingerprint) -> bool {
+        self.delete_fingerprint_impl(fp)
+    }
+
+    /// Remove all values from this filter.
+    pub fn clear(&mut self) {
+        self.fingerprints.clear();
+        self.len = 0;
+    }
+}
+
+/// The number of buckets of `bucket_size` slots needed to hold
+/// `capacity` items at the target load.
+fn buckets_for(capacity: usize, bucket_size: usize) -> usize {
+    ((capacity.max(1) as f64 / TARGET_LOAD / bucket_size as f64).ceil() as usize).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CuckooFilter;
+    use crate::error::InsertError;
+    use crate::RandomXxh3State;
+
+    #[test]
+    fn simple() {
+        let mut filter = CuckooFilter::with_capacity(100);
+        assert!(filter.is_empty());
+        filter.insert(&1).unwrap();
+        filter.insert_slice(b"hello").unwrap();
+        assert!(filter.contains(&1));
+        assert!(filter.contains_slice(b"hello"));
+        assert!(!filter.contains(&2));
+        assert_eq!(filter.len(), 2);
+        assert!(filter.delete(&1));
+        assert!(!filter.delete(&1));
+        assert!(!filter.contains(&1));
+        filter.clear();
+        assert!(!filter.contains_slice(b"hello"));
+    }
+
+    #[test]
+    fn duplicates() {
+        // Seeded so that 7's two candidate buckets are distinct.
+        let mut filter =
+            CuckooFilter::with_size_and_hasher(16, 2, 8, RandomXxh3State::from_seed(1));
+        for _ in 0..4 {
+            filter.insert(&7).unwrap();
+        }
+        assert_eq!(filter.insert(&7), Err(InsertError::BucketFull));
+        for _ in 0..4 {
+            assert!(filter.delete(&7));
+        }
+        assert!(!filter.contains(&7));
+    }
+
+    #[test]
+    fn capacity() {
+        let n = 100_000u32;
+        let mut filter =
+            CuckooFilter::with_capacity_and_hasher(n as usize, RandomXxh3State::from_seed(2));
+        for i in 0..n {
+            filter.insert(&i).unwrap();
+        }
+        assert!((0..n).all(|i| filter.contains(&i)));
+        let false_positives = (n..2 * n).filter(|i| filter.contains(i)).count();
+        assert!((false_positives as f64 / n as f64) < 0.001);
+
+        for i in (0..n).step_by(2) {
+            assert!(filter.delete(&i));
+        }
+        assert_eq!(filter.len(), n as usize / 2);
+        assert!((1..n).step_by(2).all(|i| filter.contains(&i)));
+    }
+
+    #[test]
+    fn failed_insert_loses_nothing() {
+        let mut filter =
+            CuckooFilter::with_size_and_hasher(64, 4, 12, RandomXxh3State::from_seed(3));
+        let mut inserted = Vec::new();
+        let mut failed = false;
+        for i in 0..1000u32 {
+            match filter.insert(&i) {
+                Ok(()) => inserted.push(i),
+                Err(InsertError::BucketFull) => {
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        assert!(failed);
+        assert!(filter.load_factor() > 0.8);
+        assert_eq!(filter.len(), inserted.len());
+        assert!(inserted.iter().all(|i| filter.contains(i)));
+    }
+}
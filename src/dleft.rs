@@ -0,0 +1,501 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+
+use crate::error::InsertError;
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher, BloomFingerprint, ValueVec};
+
+const DEFAULT_SUBTABLES: usize = 4;
+const DEFAULT_BUCKET_SIZE: usize = 8;
+const DEFAULT_COUNTER_BITS: usize = 4;
+// Target fraction of cells in use when holding the expected number of items.
+const TARGET_LOAD: f64 = 0.75;
+
+/// A d-left counting Bloom filter.
+///
+/// Rather than spreading `k` wide counters across a bit array, each item
+/// is stored once as a (fingerprint, counter) cell in one of `d`
+/// candidate buckets, one per subtable.  New items go into the least
+/// loaded candidate bucket (leftmost on ties), which keeps bucket loads
+/// very even and lets this structure support removal with roughly half
+/// the memory of a `CountingBloomFilter` for the same false positive
+/// rate.
+///
+/// Each item is reduced to a single "true fingerprint" which is mapped to
+/// a (bucket, fingerprint) pair in every subtable by a different
+/// permutation.  Two items that collide in one subtable therefore have
+/// the same true fingerprint and collide everywhere, so they always
+/// share one cell and removing one can never orphan the other.  False
+/// positives happen when a different item has the same fingerprint and
+/// shares a candidate bucket, so the false positive rate is roughly
+/// `d * bucket_size * load / 2^fingerprint_bits`.
+///
+/// Counters saturate at the maximum value `counter_bits` can hold.  A
+/// saturated cell is never decremented or freed since its true count is
+/// unknown, and `estimate_count` is only a lower bound for it.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::DlcFilter;
+///
+/// let mut filter = DlcFilter::with_rate(0.01, 1000);
+/// filter.insert(&1).unwrap();
+/// filter.insert(&1).unwrap();
+/// assert!(filter.contains(&1));
+/// assert_eq!(filter.estimate_count(&1), 2);
+/// assert_eq!(filter.remove(&1), 2);
+/// assert_eq!(filter.estimate_count(&1), 1);
+/// ```
+pub struct DlcFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    fingerprints: ValueVec,
+    counters: ValueVec,
+    // Affine permutation (multiplier, offset) of the true fingerprint
+    // space for each subtable.
+    permutations: Vec<(u128, u128)>,
+    num_subtables: usize,
+    buckets_per_subtable: usize,
+    bucket_size: usize,
+    num_occupied: usize,
+    hash_builder: H,
+}
+
+impl DlcFilter<RandomXxh3State> {
+    /// Create a DlcFilter with `num_subtables` subtables of
+    /// `buckets_per_subtable` buckets each holding up to `bucket_size`
+    /// cells, storing `fingerprint_bits` fingerprints and
+    /// `counter_bits` counters per cell.
+    pub fn with_size(
+        num_subtables: usize,
+        buckets_per_subtable: usize,
+        bucket_size: usize,
+        fingerprint_bits: usize,
+        counter_bits: usize,
+    ) -> DlcFilter<RandomXxh3State> {
+        DlcFilter::with_size_and_hasher(
+            num_subtables,
+            buckets_per_subtable,
+            bucket_size,
+            fingerprint_bits,
+            counter_bits,
+            RandomXxh3State::new(),
+        )
+    }
+
+    /// Create a DlcFilter that expects to hold `expected_num_items`
+    /// distinct items with a false positive rate of `rate`, using 4
+    /// subtables, 8 cells per bucket and 4 bit counters.
+    pub fn with_rate(rate: f32, expected_num_items: u32) -> DlcFilter<RandomXxh3State> {
+        DlcFilter::with_rate_and_hasher(rate, expected_num_items, RandomXxh3State::new())
+    }
+}
+
+impl<H> DlcFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Create a DlcFilter with the given geometry whose items are hashed
+    /// using `hash_builder`.
+    ///
+    /// # Panics
+    /// Panics if any dimension is 0 or if `fingerprint_bits` or
+    /// `counter_bits` is larger than 31.
+    pub fn with_size_and_hasher(
+        num_subtables: usize,
+        buckets_per_subtable: usize,
+        bucket_size: usize,
+        fingerprint_bits: usize,
+        counter_bits: usize,
+        hash_builder: H,
+    ) -> DlcFilter<H> {
+        assert!(
+            num_subtables > 0 && buckets_per_subtable > 0 && bucket_size > 0,
+            "DlcFilter dimensions must be non-zero"
+        );
+        assert!(
+            (1..32).contains(&fingerprint_bits),
+            "fingerprint_bits must be between 1 and 31"
+        );
+        assert!(
+            (1..32).contains(&counter_bits),
+            "counter_bits must be between 1 and 31"
+        );
+        let cells = num_subtables * buckets_per_subtable * bucket_size;
+        let fingerprints = ValueVec::new(fingerprint_bits, cells);
+        let space = buckets_per_subtable as u128 * fingerprints.max_value() as u128;
+        let permutations = (1..=num_subtables as u128)
+            .map(|i| {
+                // Any multiplier coprime with the space gives a bijection.
+                let mut multiplier = (0x9E37_79B9_7F4A_7C15u128 * i) % space;
+                while gcd(multiplier, space) != 1 {
+                    multiplier = (multiplier + 1) % space;
+                }
+                (multiplier, (0xC2B2_AE3D_27D4_EB4Fu128 * i) % space)
+            })
+            .collect();
+        DlcFilter {
+            fingerprints,
+            counters: ValueVec::new(counter_bits, cells),
+            permutations,
+            num_subtables,
+            buckets_per_subtable,
+            bucket_size,
+            num_occupied: 0,
+            hash_builder,
+        }
+    }
+
+    /// Create a DlcFilter that expects to hold `expected_num_items`
+    /// distinct items with a false positive rate of `rate`, hashing
+    /// items using `hash_builder`.
+    pub fn with_rate_and_hasher(
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> DlcFilter<H> {
+        let cells_per_row = (DEFAULT_SUBTABLES * DEFAULT_BUCKET_SIZE) as f64;
+        let cells = (expected_num_items.max(1) as f64 / TARGET_LOAD).ceil();
+        let buckets_per_subtable = (cells / cells_per_row).ceil() as usize;
+        let fingerprint_bits = (cells_per_row * TARGET_LOAD / rate as f64)
+            .log2()
+            .ceil()
+            .clamp(1.0, 31.0) as usize;
+        DlcFilter::with_size_and_hasher(
+            DEFAULT_SUBTABLES,
+            buckets_per_subtable,
+            DEFAULT_BUCKET_SIZE,
+            fingerprint_bits,
+            DEFAULT_COUNTER_BITS,
+            hash_builder,
+        )
+    }
+
+    /// The number of subtables (`d`).
+    #[inline(always)]
+    pub fn num_subtables(&self) -> usize {
+        self.num_subtables
+    }
+
+    /// The number of buckets in each subtable.
+    #[inline(always)]
+    pub fn buckets_per_subtable(&self) -> usize {
+        self.buckets_per_subtable
+    }
+
+    /// The number of cells in each bucket.
+    #[inline(always)]
+    pub fn bucket_size(&self) -> usize {
+        self.bucket_size
+    }
+
+    /// The number of bits stored per fingerprint.
+    #[inline(always)]
+    pub fn fingerprint_bits(&self) -> usize {
+        self.fingerprints.bits_per_val()
+    }
+
+    /// The number of bits stored per counter.
+    #[inline(always)]
+    pub fn counter_bits(&self) -> usize {
+        self.counters.bits_per_val()
+    }
+
+    /// The number of cells currently holding an item.
+    #[inline(always)]
+    pub fn num_occupied(&self) -> usize {
+        self.num_occupied
+    }
+
+    /// The total number of bits used by fingerprints and counters.
+    pub fn num_bits(&self) -> usize {
+        self.fingerprints.len() + self.counters.len()
+    }
+
+    /// Returns the first cell of the candidate bucket in each subtable
+    /// along with the fingerprint stored for `fp` in that bucket.
+    #[inline(always)]
+    fn candidates(&self, fp: BloomFingerprint) -> impl Iterator<Item = (usize, u32)> + '_ {
+        // 0 marks an empty cell so stored fingerprints are in 1..=max.
+        let max = self.fingerprints.max_value() as u128;
+        let space = self.buckets_per_subtable as u128 * max;
+        let true_fp = ((fp.h1 as u128) << 64 | fp.h2 as u128) % space;
+        self.permutations
+            .iter()
+            .enumerate()
+            .map(move |(subtable, (multiplier, offset))| {
+                let permuted = (true_fp * multiplier + offset) % space;
+                let bucket = subtable * self.buckets_per_subtable + (permuted / max) as usize;
+                (bucket * self.bucket_size, (permuted % max) as u32 + 1)
+            })
+    }
+
+    fn find_cell(&self, fp: BloomFingerprint) -> Option<usize> {
+        self.candidates(fp).find_map(|(start, remainder)| {
+            (start..start + self.bucket_size).find(|&cell| self.fingerprints.get(cell) == remainder)
+        })
+    }
+
+    fn insert_fingerprint_impl(&mut self, fp: BloomFingerprint) -> Result<(), InsertError> {
+        if let Some(cell) = self.find_cell(fp) {
+            let cur = self.counters.get(cell);
+            if cur < self.counters.max_value() {
+                self.counters.set(cell, cur + 1);
+            }
+            return Ok(());
+        }
+
+        let mut best: Option<(usize, usize, u32)> = None;
+        for (start, remainder) in self.candidates(fp) {
+            let mut load = 0;
+            let mut empty = None;
+            for cell in start..start + self.bucket_size {
+                if self.fingerprints.get(cell) == 0 {
+                    empty.get_or_insert(cell);
+                } else {
+                    load += 1;
+                }
+            }
+            if let Some(empty) = empty {
+                if best.is_none_or(|(best_load, _, _)| load < best_load) {
+                    best = Some((load, empty, remainder));
+                }
+            }
+        }
+
+        let (_, cell, remainder) = best.ok_or(InsertError::BucketFull)?;
+        self.fingerprints.set(cell, remainder);
+        self.counters.set(cell, 1);
+        self.num_occupied += 1;
+        Ok(())
+    }
+
+    /// Insert an item.
+    ///
+    /// Returns `InsertError::BucketFull` if the item isn't already
+    /// present and every one of its candidate buckets is full.
+    #[inline(always)]
+    pub fn insert<T: Hash>(&mut self, item: &T) -> Result<(), InsertError> {
+        self.insert_fingerprint_impl(crate::hashing::fingerprint(item, &self.hash_builder))
+    }
+
+    /// Insert a byte slice.
+    /// This is a fast path when the items you're dealing with are byte slices.
+    #[inline(always)]
+    pub fn insert_slice(&mut self, item: &[u8]) -> Result<(), InsertError> {
+        self.insert_fingerprint_impl(self.hash_builder.hash_one_128(item))
+    }
+
+    /// Insert a fingerprint.
+    /// This is a fast path when you have a set of filters that share the same
+    /// BloomBuildHasher where you can amortize the key hash across all your
+    /// filters.
+    #[inline(always)]
+    pub fn insert_fingerprint(&mut self, fp: BloomFingerprint) -> Result<(), InsertError> {
+        self.insert_fingerprint_impl(fp)
+    }
+
+    /// Check if the item has been inserted into this filter.  This
+    /// function can return false positives, but not false negatives.
+    #[inline(always)]
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_fingerprint(crate::hashing::fingerprint(item, &self.hash_builder))
+    }
+
+    /// Check if the byte slice has been inserted into this filter.
+    #[inline(always)]
+    pub fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_fingerprint(self.hash_builder.hash_one_128(item))
+    }
+
+    /// Check if the fingerprint has been inserted into this filter.
+    #[inline(always)]
+    pub fn contains_fingerprint(&self, fp: BloomFingerprint) -> bool {
+        self.find_cell(fp).is_some()
+    }
+
+    fn remove_fingerprint_impl(&mut self, fp: BloomFingerprint) -> u32 {
+        let cell = match self.find_cell(fp) {
+            Some(cell) => cell,
+            None => return 0,
+        };
+        let cur = self.counters.get(cell);
+        if cur == self.counters.max_value() {
+            return cur;
+        }
+        if cur <= 1 {
+            self.fingerprints.set(cell, 0);
+            self.counters.set(cell, 0);
+            self.num_occupied -= 1;
+        } else {
+            self.counters.set(cell, cur - 1);
+        }
+        cur
+    }
+
+    /// Remove an item.  Returns the number of times this item had been
+    /// inserted previously (i.e. the count before this remove).  Returns
+    /// 0 if item was never inserted.  Saturated counters are left as is.
+    #[inline(always)]
+    pub fn remove<T: Hash>(&mut self, item: &T) -> u32 {
+        self.remove_fingerprint_impl(crate::hashing::fingerprint(item, &self.hash_builder))
+    }
+
+    /// Remove a byte slice.  See `remove`.
+    #[inline(always)]
+    pub fn remove_slice(&mut self, item: &[u8]) -> u32 {
+        self.remove_fingerprint_impl(self.hash_builder.hash_one_128(item))
+    }
+
+    /// Remove a fingerprint.  See `remove`.
+    #[inline(always)]
+    pub fn remove_fingerprint(&mut self, fp: BloomFingerprint) -> u32 {
+        self.remove_fingerprint_impl(fp)
+    }
+
+    /// Return an estimate of the number of times `item` has been
+    /// inserted into the filter.
+    #[inline(always)]
+    pub fn estimate_count<T: Hash>(&self, item: &T) -> u32 {
+        self.estimate_count_fingerprint(crate::hashing::fingerprint(item, &self.hash_builder))
+    }
+
+    /// Return an estimate of the number of times the byte slice has been
+    /// inserted into the filter.
+    #[inline(always)]
+    pub fn estimate_count_slice(&self, item: &[u8]) -> u32 {
+        self.estimate_count_fingerprint(self.hash_builder.hash_one_128(item))
+    }
+
+    /// Return an estimate of the number of times the fingerprint has been
+    /// inserted into the filter.
+    #[inline(always)]
+    pub fn estimate_count_fingerprint(&self, fp: BloomFingerprint) -> u32 {
+        self.find_cell(fp).map_or(0, |cell| self.counters.get(cell))
+    }
+
+    /// Remove all values from this filter.
+    pub fn clear(&mut self) {
+        self.fingerprints.clear();
+        self.counters.clear();
+        self.num_occupied = 0;
+    }
+}
+
+fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use std::collections::HashMap;
+
+    use super::DlcFilter;
+    use crate::error::InsertError;
+
+    #[test]
+    fn simple() {
+        let mut filter = DlcFilter::with_rate(0.01, 100);
+        assert!(!filter.contains(&1));
+        filter.insert(&1).unwrap();
+        filter.insert_slice(b"hello").unwrap();
+        assert!(filter.contains(&1));
+        assert!(filter.contains_slice(b"hello"));
+        assert_eq!(filter.num_occupied(), 2);
+        assert_eq!(filter.remove(&1), 1);
+        assert_eq!(filter.remove(&1), 0);
+        assert!(!filter.contains(&1));
+        filter.clear();
+        assert!(!filter.contains_slice(b"hello"));
+        assert_eq!(filter.num_occupied(), 0);
+    }
+
+    #[test]
+    fn bucket_full() {
+        let mut filter = DlcFilter::with_size(2, 1, 1, 16, 4);
+        let mut inserted = 0;
+        let mut full = false;
+        for i in 0..10 {
+            match filter.insert(&i) {
+                Ok(()) => inserted += 1,
+                Err(e) => {
+                    assert_eq!(e, InsertError::BucketFull);
+                    full = true;
+                }
+            }
+        }
+        assert!(full);
+        assert!(inserted <= 2);
+    }
+
+    #[test]
+    fn interleaved_stress() {
+        let mut rng = rand::thread_rng();
+        let mut filter = DlcFilter::with_rate(0.001, 20_000);
+        let mut live: HashMap<u32, u32> = HashMap::new();
+
+        for _ in 0..200_000 {
+            let key = rng.gen_range(0, 10_000u32);
+            if rng.gen::<bool>() {
+                filter.insert(&key).unwrap();
+                *live.entry(key).or_insert(0) += 1;
+            } else if let Some(count) = live.get_mut(&key) {
+                assert!(filter.remove(&key) >= 1);
+                *count -= 1;
+                if *count == 0 {
+                    live.remove(&key);
+                }
+            }
+        }
+
+        for (key, count) in &live {
+            assert!(filter.contains(key));
+            assert!(filter.estimate_count(key) >= (*count).min(15));
+        }
+    }
+
+    #[test]
+    fn saturated_cells_stick() {
+        let mut filter = DlcFilter::with_size(4, 16, 4, 16, 2);
+        for _ in 0..10 {
+            filter.insert(&1).unwrap();
+        }
+        assert_eq!(filter.estimate_count(&1), 3);
+        for _ in 0..10 {
+            assert_eq!(filter.remove(&1), 3);
+        }
+        assert!(filter.contains(&1));
+    }
+
+    #[test]
+    fn colliding_keys_share_a_cell() {
+        // With 1 bit fingerprints and a single bucket per subtable every
+        // key has the same true fingerprint.
+        let mut filter = DlcFilter::with_size(2, 1, 2, 1, 4);
+        filter.insert(&1).unwrap();
+        filter.insert(&2).unwrap();
+        assert_eq!(filter.num_occupied(), 1);
+        assert_eq!(filter.remove(&1), 2);
+        assert!(filter.contains(&2));
+    }
+}
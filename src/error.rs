@@ -0,0 +1,279 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+//! Error types returned by the fallible parts of this crate.
+
+use core::error::Error;
+use core::fmt;
+
+use crate::MAX_NUM_HASHES;
+
+/// Returned when a raw bit buffer is too short to hold the requested
+/// number of bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferTooShort {
+    /// The number of bits the caller asked for.
+    pub num_bits: usize,
+    /// The number of bytes that were actually provided.
+    pub num_bytes: usize,
+}
+
+impl fmt::Display for BufferTooShort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes cannot hold {} bits",
+            self.num_bytes, self.num_bits
+        )
+    }
+}
+
+impl Error for BufferTooShort {}
+
+/// Returned when a set of bit positions includes one past the end of
+/// the filter being built from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitOutOfRange {
+    /// The largest position, which doesn't fit.
+    pub bit: usize,
+    /// The number of bits the filter has.
+    pub num_bits: usize,
+}
+
+impl fmt::Display for BitOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bit {} is out of range for {} bits", self.bit, self.num_bits)
+    }
+}
+
+impl Error for BitOutOfRange {}
+
+/// Returned when an item could not be inserted into a filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertError {
+    /// Every bucket the item could be placed in is already full.
+    BucketFull,
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InsertError::BucketFull => write!(f, "all candidate buckets are full"),
+        }
+    }
+}
+
+impl Error for InsertError {}
+
+/// Returned when a filter is asked for with parameters it can't work
+/// with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParameterError {
+    /// The filter would have no bits (or counters) to set.
+    ZeroBits,
+    /// The filter would use no hashes, so it would contain everything.
+    ZeroHashes,
+    /// The filter would use more than `MAX_NUM_HASHES` hashes, whose
+    /// probes would be correlated.
+    TooManyHashes(u32),
+    /// The false positive rate isn't strictly between 0 and 1.
+    InvalidRate,
+    /// The filter is sized for zero items.
+    ZeroItems,
+    /// The filter needs more bits (or counter bits) than a usize can
+    /// count, which can happen on 32-bit platforms.
+    TooLarge,
+}
+
+impl fmt::Display for ParameterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParameterError::ZeroBits => write!(f, "a filter needs at least one bit"),
+            ParameterError::ZeroHashes => write!(f, "a filter needs at least one hash"),
+            ParameterError::TooManyHashes(n) => {
+                write!(
+                    f,
+                    "{} hashes is more than MAX_NUM_HASHES ({})",
+                    n, MAX_NUM_HASHES
+                )
+            }
+            ParameterError::InvalidRate => {
+                write!(f, "false positive rate must be strictly between 0 and 1")
+            }
+            ParameterError::ZeroItems => write!(f, "a filter needs to expect at least one item"),
+            ParameterError::TooLarge => {
+                write!(
+                    f,
+                    "the filter needs more bits than this platform can address"
+                )
+            }
+        }
+    }
+}
+
+impl Error for ParameterError {}
+
+/// Returned when removing an item that the filter doesn't contain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotPresentError;
+
+impl fmt::Display for NotPresentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "item is not present in the filter")
+    }
+}
+
+impl Error for NotPresentError {}
+
+/// Returned by `BloomFilter::insert_checked` when inserting a new item
+/// would take the filter past its capacity limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapacityExceeded {
+    /// The number of insertions the filter accepts.
+    pub limit: u64,
+}
+
+impl fmt::Display for CapacityExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "filter already holds its limit of {} items", self.limit)
+    }
+}
+
+impl Error for CapacityExceeded {}
+
+/// Returned by `BloomFilter::insert_within_fpr` when the filter's
+/// false positive rate has already passed the limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SaturatedError {
+    /// The filter's estimated false positive rate.
+    pub fpr: f64,
+    /// The limit it was checked against.
+    pub max_fpr: f64,
+}
+
+impl fmt::Display for SaturatedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "false positive rate {} is already past the limit of {}",
+            self.fpr, self.max_fpr
+        )
+    }
+}
+
+impl Error for SaturatedError {}
+
+/// Returned when a buffer can't be decoded as a serialized filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeserializeError {
+    /// The buffer doesn't start with the expected magic bytes.
+    BadMagic,
+    /// The format version isn't one this crate understands.
+    UnsupportedVersion(u8),
+    /// The byte order flag in the header is neither little nor big
+    /// endian.
+    UnknownEndianness(u8),
+    /// The index layout flag in the header isn't a known layout.
+    UnknownLayout(u8),
+    /// The header selects the mask layout for a number of bits that
+    /// isn't a power of two.
+    NotPowerOfTwo(u64),
+    /// The header describes a filter that couldn't be built, such as
+    /// one without bits or hashes.
+    InvalidParameters(ParameterError),
+    /// The buffer is shorter than its header says it should be.
+    Truncated {
+        /// The number of bytes the header calls for.
+        expected: usize,
+        /// The number of bytes that were actually provided.
+        actual: usize,
+    },
+    /// The serialized filter's number of bits, hashes, domain or layout
+    /// differ from those of the filter it's being merged into.
+    Incompatible,
+    /// A serialized delta sets a bit past the end of the filter.
+    BitOutOfRange(BitOutOfRange),
+}
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeserializeError::BadMagic => write!(f, "not a serialized filter"),
+            DeserializeError::UnsupportedVersion(v) => {
+                write!(f, "unsupported format version {}", v)
+            }
+            DeserializeError::UnknownEndianness(e) => write!(f, "unknown byte order flag {}", e),
+            DeserializeError::UnknownLayout(l) => write!(f, "unknown index layout flag {}", l),
+            DeserializeError::NotPowerOfTwo(n) => {
+                write!(f, "mask layout needs a power of two bits, not {}", n)
+            }
+            DeserializeError::InvalidParameters(e) => write!(f, "invalid header: {}", e),
+            DeserializeError::Truncated { expected, actual } => {
+                write!(f, "expected {} bytes but only got {}", expected, actual)
+            }
+            DeserializeError::Incompatible => {
+                write!(
+                    f,
+                    "serialized filter has a different size, number of hashes, domain or layout"
+                )
+            }
+            DeserializeError::BitOutOfRange(e) => write!(f, "invalid delta: {}", e),
+        }
+    }
+}
+
+impl Error for DeserializeError {}
+
+/// Returned when combining filters whose sizes, number of hashes,
+/// domains, index layouts or hashers differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IncompatibleFilters {
+    /// The position of the first mismatched filter among those passed.
+    pub index: usize,
+}
+
+impl fmt::Display for IncompatibleFilters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "filter {} has a different size, number of hashes, domain, layout or hasher",
+            self.index
+        )
+    }
+}
+
+impl Error for IncompatibleFilters {}
+
+/// Returned when folding a filter to a number of bits that doesn't
+/// evenly divide its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FoldError {
+    /// The number of bits of the filter being folded.
+    pub num_bits: usize,
+    /// The number of bits the caller asked to fold it to.
+    pub new_num_bits: usize,
+}
+
+impl fmt::Display for FoldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "can't fold {} bits to {} bits, which doesn't divide it",
+            self.num_bits, self.new_num_bits
+        )
+    }
+}
+
+impl Error for FoldError {}
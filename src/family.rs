@@ -0,0 +1,414 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+
+use crate::bits::Bits;
+use crate::hashing::fingerprint;
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher, BloomFilter, BloomFingerprint, CountingBloomFilter, ASMS};
+
+/// A single filter owned by a `FilterFamily`.
+pub enum FamilyMember<H>
+where
+    H: BloomBuildHasher,
+{
+    Bloom(BloomFilter<H>),
+    Counting(CountingBloomFilter<H>),
+}
+
+impl<H> FamilyMember<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Returns the member as a BloomFilter if that's what it is.
+    pub fn as_bloom(&self) -> Option<&BloomFilter<H>> {
+        match self {
+            FamilyMember::Bloom(f) => Some(f),
+            FamilyMember::Counting(_) => None,
+        }
+    }
+
+    /// Returns the member as a CountingBloomFilter if that's what it is.
+    pub fn as_counting(&self) -> Option<&CountingBloomFilter<H>> {
+        match self {
+            FamilyMember::Bloom(_) => None,
+            FamilyMember::Counting(f) => Some(f),
+        }
+    }
+
+    /// Returns the member as a mutable CountingBloomFilter if that's what it is.
+    pub fn as_counting_mut(&mut self) -> Option<&mut CountingBloomFilter<H>> {
+        match self {
+            FamilyMember::Bloom(_) => None,
+            FamilyMember::Counting(f) => Some(f),
+        }
+    }
+}
+
+impl<H> ASMS for FamilyMember<H>
+where
+    H: BloomBuildHasher,
+{
+    #[inline(always)]
+    fn insert<T: Hash>(&mut self, item: &T) {
+        match self {
+            FamilyMember::Bloom(f) => f.insert(item),
+            FamilyMember::Counting(f) => f.insert(item),
+        }
+    }
+
+    #[inline(always)]
+    fn insert_slice(&mut self, item: &[u8]) {
+        match self {
+            FamilyMember::Bloom(f) => f.insert_slice(item),
+            FamilyMember::Counting(f) => f.insert_slice(item),
+        }
+    }
+
+    #[inline(always)]
+    fn insert_fingerprint(&mut self, fingerprint: BloomFingerprint) {
+        match self {
+            FamilyMember::Bloom(f) => f.insert_fingerprint(fingerprint),
+            FamilyMember::Counting(f) => f.insert_fingerprint(fingerprint),
+        }
+    }
+
+    #[inline(always)]
+    fn contains<T: Hash>(&self, item: &T) -> bool {
+        match self {
+            FamilyMember::Bloom(f) => f.contains(item),
+            FamilyMember::Counting(f) => f.contains(item),
+        }
+    }
+
+    #[inline(always)]
+    fn contains_slice(&self, item: &[u8]) -> bool {
+        match self {
+            FamilyMember::Bloom(f) => f.contains_slice(item),
+            FamilyMember::Counting(f) => f.contains_slice(item),
+        }
+    }
+
+    #[inline(always)]
+    fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        match self {
+            FamilyMember::Bloom(f) => f.contains_fingerprint(fingerprint),
+            FamilyMember::Counting(f) => f.contains_fingerprint(fingerprint),
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            FamilyMember::Bloom(f) => f.clear(),
+            FamilyMember::Counting(f) => f.clear(),
+        }
+    }
+}
+
+/// The result of probing every member of a `FilterFamily` for a key.
+/// Bit `i` is set if member `i` (probably) contains the key.
+pub struct ProbeResult {
+    matched: Bits,
+}
+
+impl ProbeResult {
+    /// Returns true if member `idx` matched the key.
+    #[inline(always)]
+    pub fn matched(&self, idx: usize) -> bool {
+        idx < self.matched.len() && self.matched.get(idx)
+    }
+
+    /// Returns true if any member matched the key.
+    pub fn any(&self) -> bool {
+        self.matched.any()
+    }
+
+    /// The number of members that matched the key.
+    pub fn count(&self) -> usize {
+        self.matched.count_ones()
+    }
+
+    /// The number of members that were probed.
+    pub fn len(&self) -> usize {
+        self.matched.len()
+    }
+
+    /// Returns true if the family had no members when probed.
+    pub fn is_empty(&self) -> bool {
+        self.matched.is_empty()
+    }
+
+    /// Iterate over the indices of the members that matched the key.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.matched.ones()
+    }
+}
+
+/// A set of filters that all share one `BloomBuildHasher`.
+///
+/// Every member is created by the family so they're guaranteed to hash
+/// identically, which means a key only needs to be hashed once no matter
+/// how many members are probed.  Members created with the same parameters
+/// can also be safely intersected and unioned with one another.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::FilterFamily;
+///
+/// let mut family = FilterFamily::new();
+/// let l0 = family.new_filter(0.01, 100);
+/// let l1 = family.new_filter(0.01, 1000);
+/// family.insert_into(l1, &"key");
+/// assert!(family.contains_in_any(&"key"));
+/// let probe = family.contains_in(&"key");
+/// assert!(!probe.matched(l0));
+/// assert!(probe.matched(l1));
+/// ```
+pub struct FilterFamily<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    hash_builder: H,
+    members: Vec<FamilyMember<H>>,
+}
+
+impl FilterFamily<RandomXxh3State> {
+    /// Create an empty family using a random xxh3 secret.
+    pub fn new() -> Self {
+        Self::with_hasher(RandomXxh3State::new())
+    }
+}
+
+impl Default for FilterFamily<RandomXxh3State> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> FilterFamily<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Create an empty family whose members will all use `hash_builder`.
+    pub fn with_hasher(hash_builder: H) -> Self {
+        Self {
+            hash_builder,
+            members: Vec::new(),
+        }
+    }
+
+    /// The hasher shared by every member of this family.
+    #[inline(always)]
+    pub fn hash_builder(&self) -> &H {
+        &self.hash_builder
+    }
+
+    /// Add a BloomFilter sized for `expected_num_items` at the false
+    /// positive rate `rate`.  Returns the index of the new member.
+    pub fn new_filter(&mut self, rate: f32, expected_num_items: u32) -> usize {
+        self.push(FamilyMember::Bloom(BloomFilter::with_rate_and_hasher(
+            rate,
+            expected_num_items,
+            self.hash_builder.clone(),
+        )))
+    }
+
+    /// Add a CountingBloomFilter that uses `bits_per_entry` per
+    /// counter, sized for `expected_num_items` at the false positive
+    /// rate `rate`.  Returns the index of the new member.
+    pub fn new_counting(
+        &mut self,
+        bits_per_entry: usize,
+        rate: f32,
+        expected_num_items: u32,
+    ) -> usize {
+        self.push(FamilyMember::Counting(
+            CountingBloomFilter::with_rate_and_hasher(
+                bits_per_entry,
+                rate,
+                expected_num_items,
+                self.hash_builder.clone(),
+            ),
+        ))
+    }
+
+    fn push(&mut self, member: FamilyMember<H>) -> usize {
+        self.members.push(member);
+        self.members.len() - 1
+    }
+
+    /// The number of members in this family.
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Returns true if this family has no members.
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Get the member at `idx`.
+    #[inline(always)]
+    pub fn get(&self, idx: usize) -> Option<&FamilyMember<H>> {
+        self.members.get(idx)
+    }
+
+    /// Get the member at `idx` mutably.
+    #[inline(always)]
+    pub fn get_mut(&mut self, idx: usize) -> Option<&mut FamilyMember<H>> {
+        self.members.get_mut(idx)
+    }
+
+    /// Iterate over all members in index order.
+    pub fn iter(&self) -> impl Iterator<Item = &FamilyMember<H>> {
+        self.members.iter()
+    }
+
+    /// Compute the fingerprint of `item` as every member would.
+    #[inline(always)]
+    pub fn fingerprint<T: Hash>(&self, item: &T) -> BloomFingerprint {
+        fingerprint(item, &self.hash_builder)
+    }
+
+    /// Compute the fingerprint of a byte slice as every member would.
+    #[inline(always)]
+    pub fn fingerprint_slice(&self, item: &[u8]) -> BloomFingerprint {
+        self.hash_builder.hash_one_128(item)
+    }
+
+    /// Insert `item` into the member at `idx`.
+    ///
+    /// # Panics
+    /// Panics if `idx` is out of bounds.
+    #[inline(always)]
+    pub fn insert_into<T: Hash>(&mut self, idx: usize, item: &T) {
+        let fp = self.fingerprint(item);
+        self.members[idx].insert_fingerprint(fp);
+    }
+
+    /// Insert a byte slice into the member at `idx`.
+    ///
+    /// # Panics
+    /// Panics if `idx` is out of bounds.
+    #[inline(always)]
+    pub fn insert_slice_into(&mut self, idx: usize, item: &[u8]) {
+        let fp = self.fingerprint_slice(item);
+        self.members[idx].insert_fingerprint(fp);
+    }
+
+    /// Check whether any member contains `item`.  Stops probing at the
+    /// first member that matches.
+    #[inline(always)]
+    pub fn contains_in_any<T: Hash>(&self, item: &T) -> bool {
+        self.contains_fingerprint_in_any(self.fingerprint(item))
+    }
+
+    /// Check whether any member contains the byte slice `item`.
+    #[inline(always)]
+    pub fn contains_slice_in_any(&self, item: &[u8]) -> bool {
+        self.contains_fingerprint_in_any(self.fingerprint_slice(item))
+    }
+
+    /// Check whether any member contains `fp`.
+    pub fn contains_fingerprint_in_any(&self, fp: BloomFingerprint) -> bool {
+        self.members.iter().any(|m| m.contains_fingerprint(fp))
+    }
+
+    /// Probe every member for `item` and report which ones matched.
+    #[inline(always)]
+    pub fn contains_in<T: Hash>(&self, item: &T) -> ProbeResult {
+        self.contains_fingerprint_in(self.fingerprint(item))
+    }
+
+    /// Probe every member for the byte slice `item` and report which
+    /// ones matched.
+    #[inline(always)]
+    pub fn contains_slice_in(&self, item: &[u8]) -> ProbeResult {
+        self.contains_fingerprint_in(self.fingerprint_slice(item))
+    }
+
+    /// Probe every member for `fp` and report which ones matched.
+    pub fn contains_fingerprint_in(&self, fp: BloomFingerprint) -> ProbeResult {
+        ProbeResult {
+            matched: self
+                .members
+                .iter()
+                .map(|m| m.contains_fingerprint(fp))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FilterFamily;
+    use crate::ASMS;
+
+    #[test]
+    fn matches_individual_filters() {
+        let mut family = FilterFamily::new();
+        let members: Vec<usize> = (0..5).map(|_| family.new_filter(0.01, 100)).collect();
+        let counting = family.new_counting(4, 0.01, 100);
+
+        for i in 0..500u32 {
+            family.insert_into(members[(i % 5) as usize], &i);
+            if i % 3 == 0 {
+                family.insert_into(counting, &i);
+            }
+        }
+
+        for i in 0..1000u32 {
+            let probe = family.contains_in(&i);
+            assert_eq!(probe.len(), family.len());
+            let mut any = false;
+            for (idx, member) in family.iter().enumerate() {
+                let individual = member.contains(&i);
+                assert_eq!(probe.matched(idx), individual);
+                any |= individual;
+            }
+            assert_eq!(family.contains_in_any(&i), any);
+            assert_eq!(probe.any(), any);
+            assert_eq!(probe.iter().count(), probe.count());
+        }
+    }
+
+    #[test]
+    fn slices() {
+        let mut family = FilterFamily::new();
+        let a = family.new_filter(0.01, 100);
+        let b = family.new_counting(4, 0.01, 100);
+        family.insert_slice_into(a, b"hello");
+        family.insert_slice_into(b, b"world");
+
+        let probe = family.contains_slice_in(b"hello");
+        assert!(probe.matched(a));
+        assert_eq!(
+            probe.matched(b),
+            family.get(b).unwrap().contains_slice(b"hello")
+        );
+        assert!(family.contains_slice_in_any(b"world"));
+        assert!(family.get(b).unwrap().as_counting().is_some());
+        assert!(family
+            .get(a)
+            .unwrap()
+            .as_bloom()
+            .unwrap()
+            .contains_slice(b"hello"));
+    }
+}
@@ -0,0 +1,262 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+//! A C API over `BloomFilter`, enabled with the `ffi` feature.  The
+//! `ffi` directory builds it into a cdylib and holds the C header.
+//!
+//! Filters are handed out as opaque `XxBloom` pointers from
+//! `xxbloom_new`, `xxbloom_new_with_seed` or `xxbloom_deserialize` and
+//! must be released with `xxbloom_free`.  Keys are byte strings, hashed
+//! like `insert_slice`.  No panic unwinds into the caller: functions
+//! that would panic return `XXBLOOM_ERR_PANIC`, or null if they return
+//! a handle.
+//!
+//! Serialized filters are the hasher's secret followed by the filter
+//! in the `serialize` format, little endian, so a filter read back
+//! hashes exactly like the one written, even if its secret was random.
+
+use core::ptr;
+use core::slice;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::hashing::HashIter;
+use crate::xxh_helper::DEFAULT_SECRET_SIZE;
+use crate::{BloomFilter, RandomXxh3State, ASMS};
+
+/// The call succeeded.
+pub const XXBLOOM_OK: i32 = 0;
+/// A required pointer was null.
+pub const XXBLOOM_ERR_NULL: i32 = -1;
+/// The output buffer is too small; the needed size was stored in
+/// `out_len`.
+pub const XXBLOOM_ERR_BUFFER_TOO_SMALL: i32 = -2;
+/// The call panicked, e.g. because the filter couldn't be allocated.
+pub const XXBLOOM_ERR_PANIC: i32 = -3;
+
+/// An opaque handle to a filter.
+pub struct XxBloom(BloomFilter<RandomXxh3State>);
+
+// Runs `f`, turning a panic into `err` rather than unwinding into C.
+fn guard<T>(err: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(err)
+}
+
+// The key `key` and `len` describe; a null `key` is only allowed for an
+// empty key.
+unsafe fn key<'a>(key: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (key.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        // SAFETY: the caller guarantees `key` points to `len` bytes.
+        (false, _) => Some(unsafe { slice::from_raw_parts(key, len) }),
+    }
+}
+
+fn into_handle(filter: Option<BloomFilter<RandomXxh3State>>) -> *mut XxBloom {
+    filter.map_or(ptr::null_mut(), |filter| Box::into_raw(Box::new(XxBloom(filter))))
+}
+
+/// Create a filter sized for `items` items at a false positive rate of
+/// `rate`, hashing with a random secret.  Returns null if `rate` isn't
+/// strictly between 0 and 1 or `items` is 0.
+#[no_mangle]
+pub extern "C" fn xxbloom_new(rate: f64, items: u32) -> *mut XxBloom {
+    guard(ptr::null_mut(), || {
+        into_handle(BloomFilter::try_with_rate(rate as f32, items).ok())
+    })
+}
+
+/// Like `xxbloom_new`, but hashing with a secret derived from `seed`,
+/// so filters created with the same parameters and seed can be
+/// combined, even across processes.
+#[no_mangle]
+pub extern "C" fn xxbloom_new_with_seed(rate: f64, items: u32, seed: u64) -> *mut XxBloom {
+    guard(ptr::null_mut(), || {
+        let hash_builder = RandomXxh3State::from_seed(seed);
+        into_handle(BloomFilter::try_with_rate_and_hasher(rate as f32, items, hash_builder).ok())
+    })
+}
+
+/// Insert the `len` bytes at `key`.  Returns 1 if the key wasn't
+/// already (probably) present, 0 if it was, or a negative error.
+///
+/// # Safety
+/// `filter` must be a live handle not used by another thread, and `key`
+/// must point to `len` readable bytes (or be null if `len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn xxbloom_insert(filter: *mut XxBloom, key: *const u8, len: usize) -> i32 {
+    // SAFETY: the caller guarantees `filter` is live and unaliased.
+    let (Some(filter), Some(key)) = (unsafe { filter.as_mut() }, unsafe { self::key(key, len) })
+    else {
+        return XXBLOOM_ERR_NULL;
+    };
+    guard(XXBLOOM_ERR_PANIC, || {
+        let h_iter = HashIter::from_slice(key, filter.0.num_hashes(), filter.0.hash_builder());
+        filter.0.insert_new_hash_iter(h_iter) as i32
+    })
+}
+
+/// Check for the `len` bytes at `key`.  Returns 1 if the key is
+/// (probably) present, 0 if it definitely isn't, or a negative error.
+///
+/// # Safety
+/// `filter` must be a live handle, and `key` must point to `len`
+/// readable bytes (or be null if `len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn xxbloom_contains(
+    filter: *const XxBloom,
+    key: *const u8,
+    len: usize,
+) -> i32 {
+    // SAFETY: the caller guarantees `filter` is live.
+    let (Some(filter), Some(key)) = (unsafe { filter.as_ref() }, unsafe { self::key(key, len) })
+    else {
+        return XXBLOOM_ERR_NULL;
+    };
+    guard(XXBLOOM_ERR_PANIC, || filter.0.contains_slice(key) as i32)
+}
+
+/// Serialize the filter into `out_buf`, which holds `*out_len` bytes,
+/// and store the number of bytes written in `*out_len`.  If `out_buf`
+/// is null or too small nothing is written, the size needed is stored
+/// in `*out_len` and `XXBLOOM_ERR_BUFFER_TOO_SMALL` is returned, so
+/// calling with a null buffer first finds the size to allocate.
+///
+/// # Safety
+/// `filter` must be a live handle, `out_len` must be valid for reads
+/// and writes, and `out_buf` must be null or point to `*out_len`
+/// writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn xxbloom_serialize(
+    filter: *const XxBloom,
+    out_buf: *mut u8,
+    out_len: *mut usize,
+) -> i32 {
+    // SAFETY: the caller guarantees both pointers are valid.
+    let (Some(filter), Some(out_len)) = (unsafe { filter.as_ref() }, unsafe { out_len.as_mut() })
+    else {
+        return XXBLOOM_ERR_NULL;
+    };
+    guard(XXBLOOM_ERR_PANIC, || {
+        let bytes = filter.0.to_bytes_le();
+        let needed = DEFAULT_SECRET_SIZE + bytes.len();
+        let capacity = *out_len;
+        *out_len = needed;
+        if out_buf.is_null() || capacity < needed {
+            return XXBLOOM_ERR_BUFFER_TOO_SMALL;
+        }
+        // SAFETY: the caller guarantees `out_buf` holds `capacity`
+        // bytes, which is at least `needed`.
+        let out = unsafe { slice::from_raw_parts_mut(out_buf, needed) };
+        let (secret, rest) = out.split_at_mut(DEFAULT_SECRET_SIZE);
+        secret.copy_from_slice(filter.0.hash_builder().secret());
+        rest.copy_from_slice(&bytes);
+        XXBLOOM_OK
+    })
+}
+
+/// Read a filter written by `xxbloom_serialize` from the `len` bytes at
+/// `buf`.  Returns null if they don't hold a valid filter.
+///
+/// # Safety
+/// `buf` must point to `len` readable bytes (or be null if `len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn xxbloom_deserialize(buf: *const u8, len: usize) -> *mut XxBloom {
+    // SAFETY: the caller guarantees `buf` points to `len` bytes.
+    let Some(bytes) = (unsafe { key(buf, len) }) else {
+        return ptr::null_mut();
+    };
+    guard(ptr::null_mut(), || {
+        if bytes.len() < DEFAULT_SECRET_SIZE {
+            return ptr::null_mut();
+        }
+        let (secret, bytes) = bytes.split_at(DEFAULT_SECRET_SIZE);
+        let hash_builder = RandomXxh3State {
+            secret: secret.try_into().unwrap(),
+        };
+        into_handle(BloomFilter::from_bytes(bytes, hash_builder).ok())
+    })
+}
+
+/// Release a filter.  Null is ignored.
+///
+/// # Safety
+/// `filter` must be null or a live handle, which mustn't be used again.
+#[no_mangle]
+pub unsafe extern "C" fn xxbloom_free(filter: *mut XxBloom) {
+    if !filter.is_null() {
+        // SAFETY: the caller guarantees `filter` came from
+        // `into_handle` and is released only once.
+        drop(unsafe { Box::from_raw(filter) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ptr;
+
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        unsafe {
+            assert!(xxbloom_new(0.0, 100).is_null());
+            assert!(xxbloom_new(0.01, 0).is_null());
+            let filter = xxbloom_new(0.01, 100);
+            assert_eq!(xxbloom_insert(filter, b"key".as_ptr(), 3), 1);
+            assert_eq!(xxbloom_insert(filter, b"key".as_ptr(), 3), 0);
+            assert_eq!(xxbloom_insert(filter, ptr::null(), 0), 1);
+            assert_eq!(xxbloom_insert(filter, ptr::null(), 1), XXBLOOM_ERR_NULL);
+            assert_eq!(xxbloom_contains(filter, b"key".as_ptr(), 3), 1);
+            assert_eq!(xxbloom_contains(filter, b"other".as_ptr(), 5), 0);
+
+            let mut len = 0;
+            let err = xxbloom_serialize(filter, ptr::null_mut(), &mut len);
+            assert_eq!(err, XXBLOOM_ERR_BUFFER_TOO_SMALL);
+            let mut buf = vec![0u8; len];
+            let mut short = len - 1;
+            let err = xxbloom_serialize(filter, buf.as_mut_ptr(), &mut short);
+            assert_eq!(err, XXBLOOM_ERR_BUFFER_TOO_SMALL);
+            assert_eq!(short, len);
+            assert_eq!(xxbloom_serialize(filter, buf.as_mut_ptr(), &mut len), XXBLOOM_OK);
+            assert_eq!(len, buf.len());
+            xxbloom_free(filter);
+
+            let copy = xxbloom_deserialize(buf.as_ptr(), buf.len());
+            assert_eq!(xxbloom_contains(copy, b"key".as_ptr(), 3), 1);
+            assert_eq!(xxbloom_contains(copy, ptr::null(), 0), 1);
+            assert_eq!(xxbloom_contains(copy, b"other".as_ptr(), 5), 0);
+            xxbloom_free(copy);
+
+            assert!(xxbloom_deserialize(buf.as_ptr(), DEFAULT_SECRET_SIZE).is_null());
+            assert!(xxbloom_deserialize(ptr::null(), 0).is_null());
+            assert_eq!(xxbloom_contains(ptr::null(), b"key".as_ptr(), 3), XXBLOOM_ERR_NULL);
+            xxbloom_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn seeded() {
+        unsafe {
+            let a = xxbloom_new_with_seed(0.01, 100, 7);
+            let b = xxbloom_new_with_seed(0.01, 100, 7);
+            xxbloom_insert(a, b"key".as_ptr(), 3);
+            xxbloom_insert(b, b"key".as_ptr(), 3);
+            assert_eq!((*a).0.raw_bits(), (*b).0.raw_bits());
+            xxbloom_free(a);
+            xxbloom_free(b);
+        }
+    }
+}
@@ -0,0 +1,281 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+
+use crate::bits::Bits;
+use crate::hashing::{fastrange, fingerprint, probe, HashIter};
+use crate::xxh_helper::SecretBasedXxh3Builder;
+use crate::{BloomBuildHasher, BloomFilter, BloomFingerprint, IndexLayout, ASMS};
+
+/// A BloomFilter with `WORDS * 64` bits stored inline, which can be
+/// built in a `const` or `static` initializer.
+///
+/// With a `SecretBasedXxh3Builder`, `new` and `with_slice` are `const
+/// fn`s, so a table of known keys can be hashed into a filter at
+/// compile time.  Bits are addressed exactly like a `BloomFilter` of
+/// the same size and hasher, and `to_bloom_filter` converts to one.
+///
+/// Nothing is heap allocated, so a small one also makes cheap per
+/// request scratch space, e.g. a `FixedBloomFilter<32>` (2048 bits) for
+/// deduplicating a few hundred items, created and dropped on the stack.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::{ASMS,FixedBloomFilter,SecretBasedXxh3Builder};
+///
+/// const SECRET: [u8; xx_bloom::XXH3_SECRET_SIZE] = [0x5a; xx_bloom::XXH3_SECRET_SIZE];
+/// static KEYWORDS: FixedBloomFilter<4> =
+///     FixedBloomFilter::new(3, SecretBasedXxh3Builder::with_secret(SECRET))
+///         .with_slice(b"fn")
+///         .with_slice(b"let")
+///         .with_slice(b"match");
+///
+/// assert!(KEYWORDS.contains_slice(b"let"));
+/// assert!(!KEYWORDS.contains_slice(b"banana"));
+/// ```
+#[derive(Clone)]
+pub struct FixedBloomFilter<const WORDS: usize, H = SecretBasedXxh3Builder>
+where
+    H: BloomBuildHasher,
+{
+    words: [u64; WORDS],
+    num_hashes: u32,
+    hash_builder: H,
+}
+
+impl<const WORDS: usize, H> FixedBloomFilter<WORDS, H>
+where
+    H: BloomBuildHasher,
+{
+    const NUM_BITS: u64 = WORDS as u64 * 64;
+
+    /// Create an empty filter using `num_hashes` hashes and hashing with
+    /// `hash_builder`.
+    pub const fn new(num_hashes: u32, hash_builder: H) -> Self {
+        FixedBloomFilter {
+            words: [0; WORDS],
+            num_hashes,
+            hash_builder,
+        }
+    }
+
+    /// Get the number of bits this FixedBloomFilter is using
+    #[inline(always)]
+    pub const fn num_bits(&self) -> usize {
+        WORDS * 64
+    }
+
+    /// Get the number of hash functions this FixedBloomFilter is using
+    #[inline(always)]
+    pub const fn num_hashes(&self) -> u32 {
+        self.num_hashes
+    }
+
+    /// Get the hash builder this FixedBloomFilter is using
+    #[inline(always)]
+    pub fn hash_builder(&self) -> &H {
+        &self.hash_builder
+    }
+
+    /// Copy this filter into a heap allocated BloomFilter.
+    pub fn to_bloom_filter(&self) -> BloomFilter<H> {
+        BloomFilter::from_parts(
+            Bits::from_words(self.num_bits(), self.words),
+            self.num_hashes,
+            self.hash_builder.clone(),
+            0,
+            IndexLayout::FastRange,
+        )
+        .recount()
+    }
+
+    #[inline(always)]
+    const fn set_fingerprint(&mut self, fp: BloomFingerprint) {
+        let mut i = 0;
+        while i < self.num_hashes {
+            let idx = fastrange(probe(fp, i), Self::NUM_BITS);
+            self.words[(idx / 64) as usize] |= 1 << (idx % 64);
+            i += 1;
+        }
+    }
+
+    fn contains_hash_iter(&self, mut h_iter: HashIter) -> bool {
+        h_iter.all(|h| {
+            let idx = fastrange(h, Self::NUM_BITS);
+            self.words[(idx / 64) as usize] & (1 << (idx % 64)) != 0
+        })
+    }
+}
+
+impl<const WORDS: usize> FixedBloomFilter<WORDS, SecretBasedXxh3Builder> {
+    /// Return this filter with the byte slice `item` inserted.  Unlike
+    /// `insert_slice` this is usable in const contexts.
+    pub const fn with_slice(mut self, item: &[u8]) -> Self {
+        let h = xxhash_rust::const_xxh3::xxh3_128_with_secret(item, &self.hash_builder.secret);
+        self.set_fingerprint(BloomFingerprint::new_128(h));
+        self
+    }
+}
+
+impl<const WORDS: usize, H> ASMS for FixedBloomFilter<WORDS, H>
+where
+    H: BloomBuildHasher,
+{
+    /// Insert item into this FixedBloomFilter.
+    #[inline(always)]
+    fn insert<T: Hash>(&mut self, item: &T) {
+        self.set_fingerprint(fingerprint(item, &self.hash_builder));
+    }
+
+    /// Insert the byte slice `item` into this FixedBloomFilter.
+    #[inline(always)]
+    fn insert_slice(&mut self, item: &[u8]) {
+        self.set_fingerprint(self.hash_builder.hash_one_128(item));
+    }
+
+    /// Insert a fingerprint into this FixedBloomFilter.
+    #[inline(always)]
+    fn insert_fingerprint(&mut self, fingerprint: BloomFingerprint) {
+        self.set_fingerprint(fingerprint);
+    }
+
+    /// Check if the item has been inserted into this FixedBloomFilter.
+    #[inline(always)]
+    fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Check if the byte slice `item` has been inserted into this
+    /// FixedBloomFilter.
+    #[inline(always)]
+    fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Check if the fingerprint has been inserted into this
+    /// FixedBloomFilter.
+    #[inline(always)]
+    fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        self.contains_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Remove all values from this FixedBloomFilter
+    #[inline(always)]
+    fn clear(&mut self) {
+        self.words = [0; WORDS];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use core::cell::Cell;
+
+    use super::FixedBloomFilter;
+    use crate::{BloomFilter, SecretBasedXxh3Builder, ASMS, XXH3_SECRET_SIZE};
+
+    // Counts allocations made by the current thread, so tests running
+    // concurrently don't disturb each other's counts.
+    struct CountingAlloc;
+
+    thread_local!(static ALLOCATIONS: Cell<usize> = const { Cell::new(0) });
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAlloc = CountingAlloc;
+
+    const fn secret() -> [u8; XXH3_SECRET_SIZE] {
+        let mut secret = [0u8; XXH3_SECRET_SIZE];
+        let mut i = 0;
+        while i < XXH3_SECRET_SIZE {
+            secret[i] = (i as u8).wrapping_mul(151).wrapping_add(7);
+            i += 1;
+        }
+        secret
+    }
+
+    const HASHER: SecretBasedXxh3Builder = SecretBasedXxh3Builder::with_secret(secret());
+
+    static TABLE: FixedBloomFilter<16> = FixedBloomFilter::new(4, HASHER)
+        .with_slice(b"alpha")
+        .with_slice(b"beta")
+        .with_slice(b"gamma");
+
+    #[test]
+    fn const_table() {
+        assert!(TABLE.contains_slice(b"alpha"));
+        assert!(TABLE.contains_slice(b"gamma"));
+        assert!(!TABLE.contains_slice(b"delta"));
+        assert_eq!(TABLE.num_bits(), 1024);
+    }
+
+    #[test]
+    fn matches_bloom_filter() {
+        let mut heap = BloomFilter::with_size_and_hasher(1024, 4, HASHER);
+        for key in [&b"alpha"[..], b"beta", b"gamma"] {
+            heap.insert_slice(key);
+        }
+        assert_eq!(TABLE.to_bloom_filter().raw_bits(), heap.raw_bits());
+
+        let mut runtime = FixedBloomFilter::<16>::new(4, HASHER);
+        for i in 0..50u32 {
+            runtime.insert(&i);
+            heap.insert(&i);
+        }
+        runtime.insert_slice(b"alpha");
+        runtime.insert_slice(b"beta");
+        runtime.insert_slice(b"gamma");
+        assert_eq!(runtime.to_bloom_filter().raw_bits(), heap.raw_bits());
+        assert!((0..50u32).all(|i| runtime.contains(&i)));
+        runtime.clear();
+        assert!(!runtime.contains(&1u32));
+    }
+
+    #[test]
+    fn scratch_without_allocating() {
+        let keys: Vec<[u8; 8]> = (0..300u64).map(|i| (i % 200).to_le_bytes()).collect();
+        let before = ALLOCATIONS.with(Cell::get);
+        let mut dups = 0;
+        for _ in 0..1000 {
+            // 256 bytes of bits.
+            let mut seen = FixedBloomFilter::<32>::new(4, HASHER);
+            for key in &keys {
+                dups += seen.contains_slice(key) as u32;
+                seen.insert_slice(key);
+            }
+        }
+        assert_eq!(ALLOCATIONS.with(Cell::get), before);
+        assert!(dups >= 1000 * 100);
+        // The heap backed filter is counted.
+        drop(BloomFilter::with_size_and_hasher(2048, 4, HASHER));
+        assert!(ALLOCATIONS.with(Cell::get) > before);
+    }
+}
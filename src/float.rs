@@ -0,0 +1,107 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+//! The float functions sizing a filter needs.  With std these are the
+//! inherent `f32` and `f64` methods.  Without it `FloatExt` provides them from
+//! libm under the same names, so callers only need to import it.  Test
+//! builds link std even without the feature, so they use the inherent
+//! methods too.
+
+#[cfg(not(feature = "std"))]
+pub(crate) trait FloatExt {
+    fn ceil(self) -> Self;
+    fn round(self) -> Self;
+    fn sqrt(self) -> Self;
+    fn ln(self) -> Self;
+    fn exp(self) -> Self;
+    fn powi(self, n: i32) -> Self;
+    fn powf(self, n: Self) -> Self;
+}
+
+#[cfg(not(feature = "std"))]
+impl FloatExt for f64 {
+    #[inline(always)]
+    fn ceil(self) -> f64 {
+        libm::ceil(self)
+    }
+
+    #[inline(always)]
+    fn round(self) -> f64 {
+        libm::round(self)
+    }
+
+    #[inline(always)]
+    fn sqrt(self) -> f64 {
+        libm::sqrt(self)
+    }
+
+    #[inline(always)]
+    fn ln(self) -> f64 {
+        libm::log(self)
+    }
+
+    #[inline(always)]
+    fn exp(self) -> f64 {
+        libm::exp(self)
+    }
+
+    #[inline(always)]
+    fn powi(self, n: i32) -> f64 {
+        libm::pow(self, n as f64)
+    }
+
+    #[inline(always)]
+    fn powf(self, n: f64) -> f64 {
+        libm::pow(self, n)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl FloatExt for f32 {
+    #[inline(always)]
+    fn ceil(self) -> f32 {
+        libm::ceilf(self)
+    }
+
+    #[inline(always)]
+    fn round(self) -> f32 {
+        libm::roundf(self)
+    }
+
+    #[inline(always)]
+    fn sqrt(self) -> f32 {
+        libm::sqrtf(self)
+    }
+
+    #[inline(always)]
+    fn ln(self) -> f32 {
+        libm::logf(self)
+    }
+
+    #[inline(always)]
+    fn exp(self) -> f32 {
+        libm::expf(self)
+    }
+
+    #[inline(always)]
+    fn powi(self, n: i32) -> f32 {
+        libm::powf(self, n as f32)
+    }
+
+    #[inline(always)]
+    fn powf(self, n: f32) -> f32 {
+        libm::powf(self, n)
+    }
+}
@@ -0,0 +1,184 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::fmt;
+use core::hash::Hash;
+
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher, BloomFilter, BloomFingerprint, CachedKey, ASMS};
+use alloc::vec::Vec;
+
+/// A BloomFilter that is done being inserted into, made with
+/// `BloomFilter::freeze`.
+///
+/// Only lookups are available, so handing out a `FrozenBloomFilter`
+/// makes it clear that the set is fixed for the rest of a read-only
+/// phase.  The bits are kept exactly as they were, in one cache line
+/// aligned allocation with no spare capacity, so every lookup answers
+/// as the filter did before freezing.  `thaw` gives back the mutable
+/// filter.
+///
+/// # Example Usage
+///
+/// ```rust
+/// # #[cfg(feature = "std")] {
+/// use xx_bloom::{ASMS,BloomFilter};
+///
+/// let mut filter = BloomFilter::with_rate(0.01, 1000);
+/// filter.insert(&1);
+/// let frozen = filter.freeze();
+/// assert!(frozen.contains(&1));
+/// assert!(!frozen.contains(&2));
+///
+/// let mut filter = frozen.thaw();
+/// filter.insert(&2);
+/// assert!(filter.contains(&2));
+/// # }
+/// ```
+pub struct FrozenBloomFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    filter: BloomFilter<H>,
+}
+
+impl<H> FrozenBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    pub(crate) fn new(filter: BloomFilter<H>) -> FrozenBloomFilter<H> {
+        FrozenBloomFilter { filter }
+    }
+
+    /// Check if the item has been inserted into this filter.  This
+    /// function can return false positives, but not false negatives.
+    #[inline(always)]
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.filter.contains(item)
+    }
+
+    /// Check if the byte slice has been inserted into this filter.
+    #[inline(always)]
+    pub fn contains_slice(&self, item: &[u8]) -> bool {
+        self.filter.contains_slice(item)
+    }
+
+    /// Check if the fingerprint has been inserted into this filter.
+    #[inline(always)]
+    pub fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        self.filter.contains_fingerprint(fingerprint)
+    }
+
+    /// Check if the item `key` was made from has been inserted into
+    /// this filter.
+    #[inline(always)]
+    pub fn contains_cached(&self, key: &CachedKey) -> bool {
+        self.filter.contains_cached(key)
+    }
+
+    /// Look up every fingerprint in `fps`, replacing the contents of
+    /// `out` with the answers in the same order.  See
+    /// `BloomFilter::contains_fingerprints_prefetched`.
+    pub fn contains_fingerprints_prefetched(&self, fps: &[BloomFingerprint], out: &mut Vec<bool>) {
+        self.filter.contains_fingerprints_prefetched(fps, out)
+    }
+
+    /// Get the number of bits this filter is using
+    #[inline(always)]
+    pub fn num_bits(&self) -> usize {
+        self.filter.num_bits()
+    }
+
+    /// Get the number of hash functions this filter is using
+    #[inline(always)]
+    pub fn num_hashes(&self) -> u32 {
+        self.filter.num_hashes()
+    }
+
+    /// Get the hash builder this filter is using
+    #[inline(always)]
+    pub fn hash_builder(&self) -> &H {
+        self.filter.hash_builder()
+    }
+
+    /// The false positive rate the filter has, estimated from how many
+    /// bits are set.
+    pub fn estimated_fpp(&self) -> f64 {
+        self.filter.estimated_fpp()
+    }
+
+    /// The frozen filter, for the read only operations that aren't
+    /// repeated here, such as serializing it or counting its bits.
+    #[inline(always)]
+    pub fn as_bloom_filter(&self) -> &BloomFilter<H> {
+        &self.filter
+    }
+
+    /// Make the filter mutable again.  This doesn't copy any bits.
+    pub fn thaw(self) -> BloomFilter<H> {
+        self.filter
+    }
+}
+
+impl<H> fmt::Debug for FrozenBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("FrozenBloomFilter")
+            .field(&self.filter)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BloomBuildHasher, BloomFilter, RandomXxh3State, ASMS};
+
+    #[test]
+    fn freeze_and_thaw() {
+        let hasher = RandomXxh3State::from_seed(5);
+        let filled = || {
+            let mut filter = BloomFilter::with_rate_and_hasher(0.01, 1000, hasher);
+            for i in 0..1000u32 {
+                filter.insert(&i);
+            }
+            filter
+        };
+        let before = filled();
+        let frozen = filled().freeze();
+        assert_eq!(frozen.num_bits(), before.num_bits());
+        assert_eq!(frozen.num_hashes(), before.num_hashes());
+        assert_eq!(frozen.estimated_fpp(), before.estimated_fpp());
+        assert_eq!(
+            frozen.as_bloom_filter().bits().words(),
+            before.bits().words()
+        );
+        for i in 0..5000u32 {
+            assert_eq!(frozen.contains(&i), before.contains(&i));
+            let bytes = i.to_le_bytes();
+            assert_eq!(frozen.contains_slice(&bytes), before.contains_slice(&bytes));
+            assert_eq!(
+                frozen.contains_cached(&hasher.cache_key(&i)),
+                before.contains(&i)
+            );
+        }
+        assert!(format!("{:?}", frozen).starts_with("FrozenBloomFilter(BloomFilter {"));
+
+        let mut thawed = frozen.thaw();
+        thawed.insert(&5000u32);
+        assert!(thawed.contains(&5000u32));
+    }
+}
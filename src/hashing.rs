@@ -1,8 +1,75 @@
-use std::hash::Hash;
+use core::hash::Hash;
 
-use crate::{BloomBuildHasher, BloomFingerprint, BloomHasher};
+use crate::bloom::MAX_NUM_HASHES;
+use crate::{
+    BloomBuildHasher, BloomBuildHasher256, BloomFingerprint, BloomFingerprint256, BloomHasher,
+    BloomHasher256,
+};
 // utilities for hashing
 
+/// Compute the fingerprint of a Hash-able item with the given builder.
+#[inline(always)]
+pub fn fingerprint<T: Hash, H: BloomBuildHasher>(item: T, build_hasher: &H) -> BloomFingerprint {
+    let mut hasher = build_hasher.build_hasher();
+    item.hash(&mut hasher);
+    hasher.finish_128()
+}
+
+/// Compute the 256-bit fingerprint of a Hash-able item with the given
+/// builder.
+#[inline(always)]
+pub fn fingerprint_256<T: Hash, H: BloomBuildHasher256>(
+    item: T,
+    build_hasher: &H,
+) -> BloomFingerprint256 {
+    let mut hasher = build_hasher.build_hasher_256();
+    item.hash(&mut hasher);
+    hasher.finish_256()
+}
+
+/// Map `h` onto `0..n` with Lemire's multiply-shift reduction, which
+/// costs a multiply rather than a 64-bit division.
+#[inline(always)]
+pub(crate) const fn fastrange(h: u64, n: u64) -> u64 {
+    ((h as u128 * n as u128) >> 64) as u64
+}
+
+/// Whether a filter of `len` bits or counters has anything for probes
+/// to land on.  A filter without any holds nothing and drops inserts.
+/// Only the unchecked constructors can build one, and debug builds
+/// refuse to probe it.
+#[inline(always)]
+pub(crate) fn probeable(len: u64) -> bool {
+    debug_assert!(len > 0, "can't probe an empty filter");
+    len > 0
+}
+
+/// The `i`-th probe hash `HashIter` yields for `fp`, usable in const
+/// contexts.
+///
+/// This is Kirsch-Mitzenmacher double hashing, `h1 + i * step`, with
+/// the step derived from `h2` by `probe_step`.
+#[inline(always)]
+pub(crate) const fn probe(fp: BloomFingerprint, i: u32) -> u64 {
+    fp.h1
+        .wrapping_add((i as u64).wrapping_mul(probe_step(fp.h2)))
+}
+
+/// The distance between consecutive probes for a fingerprint whose
+/// second half is `h2`.
+///
+/// It's forced odd, so the probes cycle through every residue when the
+/// modulo or mask layout is used with a power of two size, rather than
+/// only a fraction of them when `h2` is even.  The xor first moves the zero
+/// and other tiny values that weak hashers produce, which would put
+/// every probe on the same bit (the top bits barely change), to large
+/// ones.  Some `h2` is still a poor step, but no more likely than any
+/// other.
+#[inline(always)]
+const fn probe_step(h2: u64) -> u64 {
+    (h2 ^ 0x9E37_79B9_7F4A_7C15) | 1
+}
+
 #[derive(Copy, Clone)]
 pub struct HashIter {
     fp: BloomFingerprint,
@@ -17,14 +84,7 @@ impl Iterator for HashIter {
         if self.i == self.count {
             return None;
         }
-        let r = match self.i {
-            0 => self.fp.h1,
-            1 => self.fp.h2,
-            _ => {
-                let p1 = self.fp.h1.wrapping_add(self.i as u64);
-                p1.wrapping_mul(self.fp.h2)
-            }
-        };
+        let r = probe(self.fp, self.i);
         self.i += 1;
         Some(r)
     }
@@ -33,26 +93,306 @@ impl Iterator for HashIter {
 impl HashIter {
     #[inline(always)]
     pub fn from<T: Hash, H: BloomBuildHasher>(item: T, count: u32, build_hasher: &H) -> Self {
-        let mut hasher = build_hasher.build_hasher();
-        item.hash(&mut hasher);
-        Self {
-            fp: hasher.finish_128(),
-            i: 0,
+        Self::from_fingerprint(fingerprint(item, build_hasher), count)
+    }
+
+    #[inline(always)]
+    pub fn from_slice<H: BloomBuildHasher>(item: &[u8], count: u32, build_hasher: &H) -> Self {
+        Self::from_fingerprint(build_hasher.hash_one_128(item), count)
+    }
+
+    #[inline(always)]
+    pub fn from_fingerprint(fp: BloomFingerprint, count: u32) -> Self {
+        debug_assert!(
+            count <= MAX_NUM_HASHES,
+            "{} hashes is more than MAX_NUM_HASHES ({}), probes would be correlated",
             count,
+            MAX_NUM_HASHES
+        );
+        Self { fp, i: 0, count }
+    }
+
+    #[inline(always)]
+    pub fn fingerprint(&self) -> BloomFingerprint {
+        self.fp
+    }
+
+    /// Separate the probes of filters sharing a hasher by mixing
+    /// `domain` into both halves of the fingerprint.  Domain 0 leaves
+    /// the fingerprint untouched, so filters without a domain keep
+    /// their existing bit positions.
+    #[inline(always)]
+    pub fn with_domain(mut self, domain: u64) -> Self {
+        if domain != 0 {
+            self.fp.h1 = fmix64(self.fp.h1 ^ domain);
+            self.fp.h2 = fmix64(self.fp.h2 ^ domain.wrapping_mul(0x9E37_79B9_7F4A_7C15));
         }
+        self
     }
 
+    /// The probe hashes for a count known at compile time, so loops
+    /// over them can be fully unrolled.
     #[inline(always)]
-    pub fn from_slice<H: BloomBuildHasher>(item: &[u8], count: u32, build_hasher: &H) -> Self {
+    fn probes<const K: u32>(&self) -> impl Iterator<Item = u64> {
+        let fp = self.fp;
+        (0..K).map(move |i| probe(fp, i))
+    }
+
+    /// Like `Iterator::for_each`, but for the common counts of 2 to 8
+    /// probes every hash is computed straight from the fingerprint in
+    /// an unrolled loop, visiting the same hashes in the same order.
+    #[inline(always)]
+    pub(crate) fn for_each_unrolled(self, f: impl FnMut(u64)) {
+        debug_assert_eq!(self.i, 0);
+        match self.count {
+            2 => self.probes::<2>().for_each(f),
+            3 => self.probes::<3>().for_each(f),
+            4 => self.probes::<4>().for_each(f),
+            5 => self.probes::<5>().for_each(f),
+            6 => self.probes::<6>().for_each(f),
+            7 => self.probes::<7>().for_each(f),
+            8 => self.probes::<8>().for_each(f),
+            _ => self.for_each(f),
+        }
+    }
+}
+
+/// The murmur3 64-bit finalizer, a bijection that avalanches every
+/// input bit.
+#[inline(always)]
+fn fmix64(mut k: u64) -> u64 {
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    k ^ (k >> 33)
+}
+
+/// Probe positions derived from all four lanes of a 256-bit
+/// fingerprint.  The i-th value is the cubic
+/// `l0 + i*l1 + C(i,2)*l2 + C(i,3)*l3`, evaluated incrementally with
+/// forward differences.  Two keys only share every probe if all four
+/// lanes agree (modulo the filter size), rather than just two as with
+/// `HashIter`.
+#[derive(Copy, Clone)]
+pub struct HashIter256 {
+    diffs: [u64; 4],
+    i: u32,
+    count: u32,
+}
+
+impl Iterator for HashIter256 {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.i == self.count {
+            return None;
+        }
+        let r = self.diffs[0];
+        self.diffs[0] = self.diffs[0].wrapping_add(self.diffs[1]);
+        self.diffs[1] = self.diffs[1].wrapping_add(self.diffs[2]);
+        self.diffs[2] = self.diffs[2].wrapping_add(self.diffs[3]);
+        self.i += 1;
+        Some(r)
+    }
+}
+
+impl HashIter256 {
+    #[inline(always)]
+    pub fn from<T: Hash, H: BloomBuildHasher256>(item: T, count: u32, build_hasher: &H) -> Self {
+        Self::from_fingerprint(fingerprint_256(item, build_hasher), count)
+    }
+
+    #[inline(always)]
+    pub fn from_slice<H: BloomBuildHasher256>(item: &[u8], count: u32, build_hasher: &H) -> Self {
+        Self::from_fingerprint(build_hasher.hash_one_256(item), count)
+    }
+
+    #[inline(always)]
+    pub fn from_fingerprint(fp: BloomFingerprint256, count: u32) -> Self {
         Self {
-            fp: build_hasher.hash_one_128(item),
+            diffs: fp.lanes,
             i: 0,
             count,
         }
     }
+}
 
-    #[inline(always)]
-    pub fn from_fingerprint(fp: BloomFingerprint, count: u32) -> Self {
-        Self { fp, i: 0, count }
+#[cfg(test)]
+mod tests {
+    use super::{HashIter, HashIter256};
+    use crate::{
+        BloomBuildHasher, BloomBuildHasher256, BloomFingerprint, BloomFingerprint256, IndexLayout,
+        RandomXxh3State,
+    };
+
+    const NUM_BITS: u64 = 1000;
+    const NUM_HASHES: u32 = 50;
+
+    fn probe_set<I: Iterator<Item = u64>>(iter: I) -> Vec<u64> {
+        let mut set = vec![0u64; NUM_BITS.div_ceil(64) as usize];
+        for h in iter {
+            let idx = h % NUM_BITS;
+            set[(idx / 64) as usize] |= 1 << (idx % 64);
+        }
+        set
+    }
+
+    // Number of key pairs sharing at least half of their probe positions.
+    fn correlated_pairs(sets: &[Vec<u64>]) -> usize {
+        let mut pairs = 0;
+        for (i, a) in sets.iter().enumerate() {
+            for b in &sets[i + 1..] {
+                let shared: u32 = a.iter().zip(b).map(|(x, y)| (x & y).count_ones()).sum();
+                if shared >= NUM_HASHES / 2 {
+                    pairs += 1;
+                }
+            }
+        }
+        pairs
+    }
+
+    #[test]
+    fn domains_decorrelate() {
+        let hasher = RandomXxh3State::from_seed(3);
+        let fps: Vec<_> = (0..400u32)
+            .map(|i| hasher.hash_one_128(&i.to_le_bytes()))
+            .collect();
+        let plain = HashIter::from_fingerprint(fps[0], NUM_HASHES);
+        assert!(plain.with_domain(0).eq(plain));
+
+        // The same key in two domains should share about as many probes
+        // as two unrelated keys do.
+        let shared = |d1: u64, d2: u64| -> u32 {
+            fps.iter()
+                .map(|fp| {
+                    let a = probe_set(HashIter::from_fingerprint(*fp, NUM_HASHES).with_domain(d1));
+                    let b = probe_set(HashIter::from_fingerprint(*fp, NUM_HASHES).with_domain(d2));
+                    a.iter()
+                        .zip(&b)
+                        .map(|(x, y)| (x & y).count_ones())
+                        .sum::<u32>()
+                })
+                .sum()
+        };
+        let unrelated: u32 = fps
+            .windows(2)
+            .map(|pair| {
+                let a = probe_set(HashIter::from_fingerprint(pair[0], NUM_HASHES));
+                let b = probe_set(HashIter::from_fingerprint(pair[1], NUM_HASHES));
+                a.iter()
+                    .zip(&b)
+                    .map(|(x, y)| (x & y).count_ones())
+                    .sum::<u32>()
+            })
+            .sum();
+        let across = shared(1, 2);
+        assert!(across < unrelated * 3 / 2, "{} vs {}", across, unrelated);
+        assert!(shared(0, 1) < unrelated * 3 / 2);
+    }
+
+    #[test]
+    fn unrolled_matches_iterator() {
+        let hasher = RandomXxh3State::from_seed(4);
+        let edges = [(0, 0), (u64::MAX, u64::MAX), (1, 0), (0, 1)];
+        let fps = (0..10_000u32)
+            .map(|i| hasher.hash_one_128(&i.to_le_bytes()))
+            .chain(edges.map(|(h1, h2)| BloomFingerprint::new(h1, h2)));
+        for fp in fps {
+            for count in 0..=12 {
+                let iter = HashIter::from_fingerprint(fp, count);
+                let mut unrolled = Vec::new();
+                iter.for_each_unrolled(|h| unrolled.push(h));
+                assert_eq!(unrolled, iter.collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_h2() {
+        // Zero, tiny and even second halves used to collapse the probes
+        // onto one or a few bits.
+        for (h1, h2) in [
+            (0, 0),
+            (12345, 0),
+            (u64::MAX, 0),
+            (7, 1),
+            (7, 2),
+            (7, 1 << 32),
+        ] {
+            let fp = BloomFingerprint::new(h1, h2);
+            for layout in [
+                IndexLayout::Modulo,
+                IndexLayout::FastRange,
+                IndexLayout::Mask,
+            ] {
+                let mut positions: Vec<usize> = HashIter::from_fingerprint(fp, 8)
+                    .map(|h| layout.index(h, 1024))
+                    .collect();
+                positions.sort_unstable();
+                positions.dedup();
+                assert!(
+                    positions.len() >= 6,
+                    "{:?} {:?}: {:?}",
+                    fp,
+                    layout,
+                    positions
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn cubic_probes() {
+        let fp = BloomFingerprint256::new([5, 7, 11, 13]);
+        let probes: Vec<u64> = HashIter256::from_fingerprint(fp, 6).collect();
+        let expected: Vec<u64> = (0..6u64)
+            .map(|i| {
+                5 + i * 7
+                    + i * i.saturating_sub(1) / 2 * 11
+                    + i * i.saturating_sub(1) * i.saturating_sub(2) / 6 * 13
+            })
+            .collect();
+        assert_eq!(probes, expected);
+    }
+
+    #[test]
+    fn less_correlated_than_128() {
+        // With double hashing, keys whose h2 agree modulo the filter size
+        // and whose h1 are close probe mostly the same positions, since
+        // one sequence is a shift of the other.  Using all four lanes
+        // makes that vanishingly unlikely.
+        let hasher = RandomXxh3State::from_seed(7);
+        let fps: Vec<_> = (0..400u32)
+            .map(|i| hasher.hash_one_256(&i.to_le_bytes()))
+            .collect();
+        let narrow: Vec<_> = fps
+            .iter()
+            .map(|fp| probe_set(HashIter::from_fingerprint(fp.truncate(), NUM_HASHES)))
+            .collect();
+        let wide: Vec<_> = fps
+            .iter()
+            .map(|fp| probe_set(HashIter256::from_fingerprint(*fp, NUM_HASHES)))
+            .collect();
+
+        let narrow_pairs = correlated_pairs(&narrow);
+        let wide_pairs = correlated_pairs(&wide);
+        assert!(narrow_pairs > 0);
+        assert_eq!(wide_pairs, 0);
+    }
+
+    #[test]
+    fn upper_lanes_matter() {
+        let a = BloomFingerprint256::new([1, 2, 3, 4]);
+        let b = BloomFingerprint256::new([1, 2, 5, 6]);
+        assert_eq!(
+            HashIter::from_fingerprint(a.truncate(), NUM_HASHES).collect::<Vec<_>>(),
+            HashIter::from_fingerprint(b.truncate(), NUM_HASHES).collect::<Vec<_>>()
+        );
+        let shared = correlated_pairs(&[
+            probe_set(HashIter256::from_fingerprint(a, NUM_HASHES)),
+            probe_set(HashIter256::from_fingerprint(b, NUM_HASHES)),
+        ]);
+        assert_eq!(shared, 0);
     }
 }
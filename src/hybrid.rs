@@ -0,0 +1,242 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher, BloomFingerprint, CountingBloomFilter, ASMS};
+
+// Counters saturate at 3, so anything inserted at most twice never
+// needs the side table.
+const BITS_PER_ENTRY: usize = 2;
+
+/// A counting filter for skewed workloads: 2-bit counters for the long
+/// tail of items seen once or twice, and exact counts for the heavy
+/// hitters.
+///
+/// Once inserting an item saturates all of its counters, its
+/// fingerprint moves into a side table holding its exact count, and
+/// further inserts and removes of it only touch the table.  When removes
+/// bring the count back down to what the counters can hold, the item
+/// goes back to the counters.  Those are still saturated, and saturated
+/// counters are never decremented, so from then on it counts as 3 (and
+/// is contained) however many more times it's removed.  This is a
+/// `CountingBloomFilter` with 2-bit counters and an unbounded
+/// `with_spill` table.
+///
+/// # When to use it
+/// Compared to a `CountingBloomFilter` with `b`-bit counters, this saves
+/// `b - 2` bits per counter and spends roughly 40 bytes (fingerprint,
+/// count and hash table overhead) per heavy hitter.  With the usual
+/// sizing of about 10 counters per item, 4-bit counters cost 2.5 bytes
+/// more per expected item than this filter, so it is smaller as long as
+/// fewer than about 1 in 16 items are inserted three or more times.
+/// It is also exact for the heavy hitters at any count, where fixed
+/// width counters saturate.  If most items repeat, use a
+/// `CountingBloomFilter` wide enough for the typical count instead.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::HybridCountingFilter;
+///
+/// let mut filter = HybridCountingFilter::with_rate(0.01, 1000);
+/// for _ in 0..1000 {
+///     filter.insert(&"hot");
+/// }
+/// filter.insert(&"cold");
+/// assert_eq!(filter.estimate_count(&"hot"), 1000);
+/// assert_eq!(filter.estimate_count(&"cold"), 1);
+/// assert_eq!(filter.num_heavy_hitters(), 1);
+/// ```
+pub struct HybridCountingFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    counts: CountingBloomFilter<H>,
+}
+
+impl HybridCountingFilter<RandomXxh3State> {
+    /// Create a HybridCountingFilter that expects to hold
+    /// `expected_num_items` distinct items.  The filter will be sized
+    /// to have a false positive rate of the value specified in `rate`.
+    pub fn with_rate(rate: f32, expected_num_items: u32) -> HybridCountingFilter<RandomXxh3State> {
+        HybridCountingFilter::with_rate_and_hasher(rate, expected_num_items, RandomXxh3State::new())
+    }
+}
+
+impl<H> HybridCountingFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Create a HybridCountingFilter with `num_entries` counters and
+    /// `num_hashes` hashes, hashing with `hash_builder`.
+    pub fn with_size_and_hasher(
+        num_entries: usize,
+        num_hashes: u32,
+        hash_builder: H,
+    ) -> HybridCountingFilter<H> {
+        HybridCountingFilter {
+            counts: CountingBloomFilter::with_size_and_hasher(
+                num_entries,
+                BITS_PER_ENTRY,
+                num_hashes,
+                hash_builder,
+            )
+            .with_spill(usize::MAX),
+        }
+    }
+
+    /// Like `with_rate`, but hashing with `hash_builder`.
+    pub fn with_rate_and_hasher(
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> HybridCountingFilter<H> {
+        HybridCountingFilter {
+            counts: CountingBloomFilter::with_rate_and_hasher(
+                BITS_PER_ENTRY,
+                rate,
+                expected_num_items,
+                hash_builder,
+            )
+            .with_spill(usize::MAX),
+        }
+    }
+
+    /// The number of items currently counted exactly in the side table.
+    pub fn num_heavy_hitters(&self) -> usize {
+        self.counts.num_spilled_keys()
+    }
+
+    /// The underlying counting filter.
+    #[inline(always)]
+    pub fn counting_filter(&self) -> &CountingBloomFilter<H> {
+        &self.counts
+    }
+
+    /// Insert an item.
+    #[inline(always)]
+    pub fn insert<T: Hash>(&mut self, item: &T) {
+        self.counts.insert(item)
+    }
+
+    /// Insert a byte slice.
+    #[inline(always)]
+    pub fn insert_slice(&mut self, item: &[u8]) {
+        self.counts.insert_slice(item)
+    }
+
+    /// Insert a fingerprint.
+    #[inline(always)]
+    pub fn insert_fingerprint(&mut self, fp: BloomFingerprint) {
+        self.counts.insert_fingerprint(fp)
+    }
+
+    /// Return an upper bound on the number of times `item` has been
+    /// inserted.  Exact for heavy hitters.
+    #[inline(always)]
+    pub fn estimate_count<T: Hash>(&self, item: &T) -> u32 {
+        self.counts.estimate_count(item)
+    }
+
+    /// Like `estimate_count`, but for a byte slice.
+    #[inline(always)]
+    pub fn estimate_count_slice(&self, item: &[u8]) -> u32 {
+        self.counts.estimate_count_slice(item)
+    }
+
+    /// Like `estimate_count`, but for a fingerprint.
+    #[inline(always)]
+    pub fn estimate_count_fingerprint(&self, fp: BloomFingerprint) -> u32 {
+        self.counts.estimate_count_fingerprint(fp)
+    }
+
+    /// Remove an item.  Returns an upper bound of the number of times
+    /// this item had been inserted previously (i.e. the count before
+    /// this remove).  Returns 0 if item was never inserted.
+    #[inline(always)]
+    pub fn remove<T: Hash>(&mut self, item: &T) -> u32 {
+        self.counts.remove(item)
+    }
+
+    /// Like `remove`, but for a byte slice.
+    #[inline(always)]
+    pub fn remove_slice(&mut self, item: &[u8]) -> u32 {
+        self.counts.remove_slice(item)
+    }
+
+    /// Like `remove`, but for a fingerprint.
+    #[inline(always)]
+    pub fn remove_fingerprint(&mut self, fp: BloomFingerprint) -> u32 {
+        self.counts.remove_fingerprint(fp)
+    }
+
+    /// Remove all items.
+    pub fn clear(&mut self) {
+        self.counts.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HybridCountingFilter;
+    use crate::RandomXxh3State;
+
+    #[test]
+    fn skewed() {
+        let mut filter =
+            HybridCountingFilter::with_rate_and_hasher(0.01, 2000, RandomXxh3State::from_seed(7));
+        for i in 0..2000u32 {
+            for _ in 0..=i % 2 {
+                filter.insert(&i);
+            }
+        }
+        let heavy = [(10_000u32, 50u32), (10_001, 300), (10_002, 3)];
+        for (item, times) in heavy {
+            for _ in 0..times {
+                filter.insert(&item);
+            }
+        }
+
+        for i in 0..2000u32 {
+            assert!(filter.estimate_count(&i) > i % 2);
+        }
+        for (item, times) in heavy {
+            assert_eq!(filter.estimate_count(&item), times);
+        }
+        assert!(filter.num_heavy_hitters() >= heavy.len());
+        assert!(
+            filter.num_heavy_hitters() < 50,
+            "{}",
+            filter.num_heavy_hitters()
+        );
+
+        // Draining a heavy hitter hands it back to the saturated
+        // counters, which then stay put.
+        let before = filter.num_heavy_hitters();
+        for expected in (4..=300).rev() {
+            assert_eq!(filter.remove(&10_001u32), expected);
+        }
+        assert_eq!(filter.num_heavy_hitters(), before - 1);
+        assert_eq!(filter.remove(&10_001u32), 3);
+        assert_eq!(filter.estimate_count(&10_001u32), 3);
+        assert_eq!(filter.estimate_count(&10_000u32), 50);
+
+        filter.clear();
+        assert_eq!(filter.num_heavy_hitters(), 0);
+        assert_eq!(filter.estimate_count_slice(b"x"), 0);
+    }
+}
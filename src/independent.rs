@@ -0,0 +1,276 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::{Hash, Hasher};
+
+use crate::bits::Bits;
+use crate::bloom::{needed_bits, optimal_num_hashes};
+use crate::hashing::{fastrange, probeable};
+use crate::xxh_helper::MultiSecretXxh3State;
+use crate::{Intersectable, Unionable};
+
+/// A BloomFilter probing with fully independent hashes, one per lane
+/// of a `MultiSecretXxh3State`.
+///
+/// `BloomFilter` derives every probe from one 128-bit hash, so an
+/// insert costs a single hash however many probes it makes.  This
+/// filter hashes the item once per probe instead, with a different
+/// secret each time.  Its false positive rate follows the theoretical
+/// one, which double hashing can drift above, but inserts cost
+/// `num_hashes` times the hashing.  Lookups hash lazily and stop at the
+/// first unset bit, so for absent items they usually cost only a hash
+/// or two.  The benchmarks compare the two.
+///
+/// The number of hashes is the hasher's number of lanes.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::IndependentBloomFilter;
+///
+/// let mut filter = IndependentBloomFilter::with_rate(0.01, 1000);
+/// filter.insert(&1);
+/// assert!(filter.contains(&1));
+/// ```
+pub struct IndependentBloomFilter {
+    bits: Bits,
+    hash_builder: MultiSecretXxh3State,
+}
+
+impl IndependentBloomFilter {
+    /// Create a new IndependentBloomFilter with the specified number of
+    /// bits, probing once for each lane of `hash_builder`.
+    pub fn with_size_and_hasher(
+        num_bits: usize,
+        hash_builder: MultiSecretXxh3State,
+    ) -> IndependentBloomFilter {
+        IndependentBloomFilter {
+            bits: Bits::new(num_bits),
+            hash_builder,
+        }
+    }
+
+    /// Create an IndependentBloomFilter that expects to hold
+    /// `expected_num_items` with a false positive rate of `rate`,
+    /// hashing with random secrets.
+    pub fn with_rate(rate: f32, expected_num_items: u32) -> IndependentBloomFilter {
+        let bits = needed_bits(rate, expected_num_items);
+        let hash_builder = MultiSecretXxh3State::new(optimal_num_hashes(bits, expected_num_items));
+        IndependentBloomFilter::with_size_and_hasher(bits, hash_builder)
+    }
+
+    /// Like `with_rate`, but hashing with secrets derived from `seed`.
+    /// Filters created with the same parameters and seed can be
+    /// intersected and unioned with one another.
+    pub fn with_rate_seeded(
+        rate: f32,
+        expected_num_items: u32,
+        seed: u64,
+    ) -> IndependentBloomFilter {
+        let bits = needed_bits(rate, expected_num_items);
+        let num_hashes = optimal_num_hashes(bits, expected_num_items);
+        let hash_builder = MultiSecretXxh3State::from_seed(seed, num_hashes);
+        IndependentBloomFilter::with_size_and_hasher(bits, hash_builder)
+    }
+
+    /// Create a new IndependentBloomFilter with the exact same
+    /// parameters as the other, so the two can be intersected and
+    /// unioned.
+    pub fn combinable_with(other: &IndependentBloomFilter) -> Self {
+        IndependentBloomFilter::with_size_and_hasher(other.num_bits(), other.hash_builder.clone())
+    }
+
+    /// Get the number of bits this IndependentBloomFilter is using
+    #[inline(always)]
+    pub fn num_bits(&self) -> usize {
+        self.bits.len()
+    }
+
+    /// Get the number of hash functions this IndependentBloomFilter is
+    /// using
+    #[inline(always)]
+    pub fn num_hashes(&self) -> u32 {
+        self.hash_builder.num_lanes()
+    }
+
+    /// Get the hash builder this IndependentBloomFilter is using
+    #[inline(always)]
+    pub fn hash_builder(&self) -> &MultiSecretXxh3State {
+        &self.hash_builder
+    }
+
+    /// Get the number of bits that are set in this filter
+    pub fn count_ones(&self) -> usize {
+        self.bits.count_ones()
+    }
+
+    /// Insert item into this IndependentBloomFilter.
+    #[inline(always)]
+    pub fn insert<T: Hash>(&mut self, item: &T) {
+        if !self.probeable() {
+            return;
+        }
+        for lane in 0..self.num_hashes() {
+            let idx = self.index(self.hash_item(item, lane));
+            self.bits.set(idx);
+        }
+    }
+
+    /// Insert a byte slice into this IndependentBloomFilter.
+    #[inline(always)]
+    pub fn insert_slice(&mut self, item: &[u8]) {
+        if !self.probeable() {
+            return;
+        }
+        for lane in 0..self.num_hashes() {
+            let idx = self.index(self.hash_builder.hash_lane(item, lane));
+            self.bits.set(idx);
+        }
+    }
+
+    /// Check if the item has been inserted into this filter.  This
+    /// function can return false positives, but not false negatives.
+    #[inline(always)]
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.probeable()
+            && (0..self.num_hashes())
+                .all(|lane| self.bits.get(self.index(self.hash_item(item, lane))))
+    }
+
+    /// Check if the byte slice has been inserted into this filter.
+    #[inline(always)]
+    pub fn contains_slice(&self, item: &[u8]) -> bool {
+        self.probeable()
+            && (0..self.num_hashes())
+                .all(|lane| self.bits.get(self.index(self.hash_builder.hash_lane(item, lane))))
+    }
+
+    /// Remove all values from this IndependentBloomFilter
+    #[inline(always)]
+    pub fn clear(&mut self) {
+        self.bits.clear();
+    }
+
+    #[inline(always)]
+    fn hash_item<T: Hash>(&self, item: &T, lane: u32) -> u64 {
+        let mut hasher = self.hash_builder.lane_hasher(lane);
+        item.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[inline(always)]
+    fn index(&self, h: u64) -> usize {
+        fastrange(h, self.num_bits() as u64) as usize
+    }
+
+    #[inline(always)]
+    fn probeable(&self) -> bool {
+        probeable(self.num_bits() as u64)
+    }
+}
+
+impl Intersectable for IndependentBloomFilter {
+    /// Calculates the intersection of two IndependentBloomFilters.
+    ///
+    /// # Panics
+    /// Panics if the filters are not using the same number of bits
+    fn intersect(&mut self, other: &IndependentBloomFilter) {
+        self.bits.and(&other.bits);
+    }
+}
+
+impl Unionable for IndependentBloomFilter {
+    /// Calculates the union of two IndependentBloomFilters.
+    ///
+    /// # Panics
+    /// Panics if the filters are not using the same number of bits
+    fn union(&mut self, other: &IndependentBloomFilter) {
+        self.bits.or(&other.bits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IndependentBloomFilter;
+    use crate::{
+        theoretical_fpr, BloomBuildHasher, BloomFilter, MultiSecretXxh3State, RandomXxh3State,
+        Unionable, ASMS,
+    };
+
+    #[test]
+    fn insert_contains() {
+        let mut filter = IndependentBloomFilter::with_rate_seeded(0.01, 1000, 3);
+        assert_eq!(filter.num_hashes(), 7);
+        for i in 0..1000u32 {
+            filter.insert(&i);
+        }
+        assert!((0..1000u32).all(|i| filter.contains(&i)));
+        filter.insert_slice(b"bytes");
+        assert!(filter.contains_slice(b"bytes"));
+        filter.clear();
+        assert!(!filter.contains(&0u32));
+    }
+
+    #[test]
+    fn union() {
+        let mut a = IndependentBloomFilter::with_rate(0.01, 100);
+        let mut b = IndependentBloomFilter::combinable_with(&a);
+        a.insert(&1);
+        b.insert(&2);
+        a.union(&b);
+        assert!(a.contains(&1));
+        assert!(a.contains(&2));
+    }
+
+    #[test]
+    fn lanes() {
+        let hasher = MultiSecretXxh3State::from_seed(5, 4);
+        assert!(hasher == MultiSecretXxh3State::from_seed(5, 4));
+        assert_eq!(hasher.num_lanes(), 4);
+        let lanes: Vec<u64> = (0..4).map(|lane| hasher.hash_lane(b"key", lane)).collect();
+        for (i, a) in lanes.iter().enumerate() {
+            assert!(lanes[i + 1..].iter().all(|b| a != b));
+        }
+        // The first lane is keyed like `from_seed`, so as a
+        // `BloomBuildHasher` it matches it.
+        let single = RandomXxh3State::from_seed(5);
+        assert_eq!(hasher.hash_one_128(b"key"), single.hash_one_128(b"key"));
+        let mut filter = BloomFilter::with_rate_and_hasher(0.01, 100, hasher);
+        filter.insert_slice(b"key");
+        assert!(filter.contains_slice(b"key"));
+    }
+
+    // With independent probes the false positive rate should match the
+    // theoretical rate for the bits that ended up set.
+    #[test]
+    fn fpr_matches_theory() {
+        let mut filter = IndependentBloomFilter::with_rate_seeded(0.01, 10_000, 7);
+        for i in 0..10_000u64 {
+            filter.insert(&i);
+        }
+        let expected = theoretical_fpr(filter.count_ones(), filter.num_bits(), filter.num_hashes());
+        let probes = 200_000u64;
+        let false_positives = (10_000..10_000 + probes).filter(|i| filter.contains(i)).count();
+        let actual = false_positives as f64 / probes as f64;
+        assert!((actual - expected).abs() < 4.0 * (expected / probes as f64).sqrt());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "empty filter")]
+    fn empty_filter() {
+        IndependentBloomFilter::with_size_and_hasher(0, MultiSecretXxh3State::new(3)).contains(&1);
+    }
+}
@@ -0,0 +1,440 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+use core::iter;
+
+use crate::bloom::{assert_same_hasher, Occupancy};
+use crate::bits::Bits;
+use crate::error::DeserializeError;
+use crate::serialize::{write_filter, Endianness, Header, HEADER_LEN};
+use crate::xxh_helper::RandomXxh3State;
+use crate::{
+    needed_bits, optimal_num_hashes, BloomBuildHasher, BloomFilter, BloomFingerprint, IndexLayout,
+    Intersectable, Unionable, ASMS,
+};
+
+/// A BloomFilter that doesn't allocate its bits until the first insert.
+///
+/// Configuring many filters up front, most of which stay empty, only
+/// costs the size of the configuration for each empty one.  Lookups in
+/// a filter that was never inserted into answer false without touching
+/// any bits, and `clear` frees the bits again.  Otherwise it behaves
+/// exactly like a `BloomFilter` of the same size, number of hashes and
+/// hasher.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::{ASMS,LazyBloomFilter};
+///
+/// let mut filter = LazyBloomFilter::with_rate(0.01, 1000);
+/// assert!(!filter.contains(&1));
+/// assert!(!filter.is_allocated());
+/// filter.insert(&1);
+/// assert!(filter.is_allocated());
+/// assert!(filter.contains(&1));
+/// ```
+pub struct LazyBloomFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    storage: Storage<H>,
+}
+
+enum Storage<H>
+where
+    H: BloomBuildHasher,
+{
+    Unallocated {
+        num_bits: usize,
+        num_hashes: u32,
+        hash_builder: H,
+        domain: u64,
+        layout: IndexLayout,
+        capacity: Option<u64>,
+    },
+    Allocated(BloomFilter<H>),
+}
+
+impl LazyBloomFilter<RandomXxh3State> {
+    /// Create a new LazyBloomFilter with the specified number of bits,
+    /// and hashes
+    pub fn with_size(num_bits: usize, num_hashes: u32) -> LazyBloomFilter<RandomXxh3State> {
+        LazyBloomFilter::with_size_and_hasher(num_bits, num_hashes, RandomXxh3State::new())
+    }
+
+    /// create a LazyBloomFilter that expects to hold
+    /// `expected_num_items`.  The filter will be sized to have a
+    /// false positive rate of the value specified in `rate`.
+    pub fn with_rate(rate: f32, expected_num_items: u32) -> LazyBloomFilter<RandomXxh3State> {
+        LazyBloomFilter::with_rate_and_hasher(rate, expected_num_items, RandomXxh3State::new())
+    }
+}
+
+impl<H> LazyBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Create a new LazyBloomFilter with the specified number of bits,
+    /// hashes, and HashBuilder.
+    pub fn with_size_and_hasher(
+        num_bits: usize,
+        num_hashes: u32,
+        hash_builder: H,
+    ) -> LazyBloomFilter<H> {
+        LazyBloomFilter {
+            storage: Storage::Unallocated {
+                num_bits,
+                num_hashes,
+                hash_builder,
+                domain: 0,
+                layout: IndexLayout::default(),
+                capacity: None,
+            },
+        }
+    }
+
+    /// Create a LazyBloomFilter that expects to hold
+    /// `expected_num_items` with a false positive rate of `rate`,
+    /// hashing items with `hash_builder`.
+    pub fn with_rate_and_hasher(
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> LazyBloomFilter<H> {
+        let bits = needed_bits(rate, expected_num_items);
+        let mut filter = LazyBloomFilter::with_size_and_hasher(
+            bits,
+            optimal_num_hashes(bits, expected_num_items),
+            hash_builder,
+        );
+        if let Storage::Unallocated { capacity, .. } = &mut filter.storage {
+            *capacity = Some(expected_num_items.into());
+        }
+        filter
+    }
+
+    /// Whether the bits have been allocated, i.e. something was
+    /// inserted since the filter was created or last cleared.
+    #[inline(always)]
+    pub fn is_allocated(&self) -> bool {
+        matches!(self.storage, Storage::Allocated(_))
+    }
+
+    /// The underlying filter, if the bits have been allocated.
+    #[inline(always)]
+    pub fn as_bloom_filter(&self) -> Option<&BloomFilter<H>> {
+        match &self.storage {
+            Storage::Allocated(filter) => Some(filter),
+            Storage::Unallocated { .. } => None,
+        }
+    }
+
+    /// Get the number of bits this LazyBloomFilter is configured with,
+    /// whether or not they have been allocated.
+    #[inline(always)]
+    pub fn num_bits(&self) -> usize {
+        match &self.storage {
+            Storage::Allocated(filter) => filter.num_bits(),
+            Storage::Unallocated { num_bits, .. } => *num_bits,
+        }
+    }
+
+    /// Get the number of hash functions this LazyBloomFilter is using
+    #[inline(always)]
+    pub fn num_hashes(&self) -> u32 {
+        match &self.storage {
+            Storage::Allocated(filter) => filter.num_hashes(),
+            Storage::Unallocated { num_hashes, .. } => *num_hashes,
+        }
+    }
+
+    /// Get the hash builder this LazyBloomFilter is using
+    #[inline(always)]
+    pub fn hash_builder(&self) -> &H {
+        match &self.storage {
+            Storage::Allocated(filter) => filter.hash_builder(),
+            Storage::Unallocated { hash_builder, .. } => hash_builder,
+        }
+    }
+
+    /// Serialize this filter in the same format as
+    /// `BloomFilter::to_bytes`.  A filter without bits is written as
+    /// all zero bits, without allocating them.
+    pub fn to_bytes(&self, endianness: Endianness) -> Vec<u8> {
+        match &self.storage {
+            Storage::Allocated(filter) => filter.to_bytes(endianness),
+            Storage::Unallocated {
+                num_bits,
+                num_hashes,
+                domain,
+                layout,
+                capacity,
+                ..
+            } => {
+                let header = Header {
+                    endianness,
+                    layout: *layout,
+                    num_bits: *num_bits,
+                    num_hashes: *num_hashes,
+                    domain: *domain,
+                    insertions: Some(0),
+                    capacity: *capacity,
+                    len: HEADER_LEN,
+                };
+                write_filter(&header, iter::repeat_n(0, num_bits.div_ceil(64)))
+            }
+        }
+    }
+
+    /// Read a filter written by `to_bytes` or `BloomFilter::to_bytes`,
+    /// hashing with `hash_builder`.  If no bits are set, the bits read
+    /// are freed again.
+    pub fn from_bytes(
+        bytes: &[u8],
+        hash_builder: H,
+    ) -> Result<LazyBloomFilter<H>, DeserializeError> {
+        let mut filter = LazyBloomFilter {
+            storage: Storage::Allocated(BloomFilter::from_bytes(bytes, hash_builder)?),
+        };
+        if filter
+            .as_bloom_filter()
+            .is_some_and(|f| f.count_ones() == 0)
+        {
+            filter.clear();
+        }
+        Ok(filter)
+    }
+
+    // The filter, allocating its bits if they aren't yet.
+    fn allocate(&mut self) -> &mut BloomFilter<H> {
+        if let Storage::Unallocated {
+            num_bits,
+            num_hashes,
+            hash_builder,
+            domain,
+            layout,
+            capacity,
+        } = &self.storage
+        {
+            let mut filter = BloomFilter::from_parts(
+                Bits::new(*num_bits),
+                *num_hashes,
+                hash_builder.clone(),
+                *domain,
+                *layout,
+            );
+            *filter.occupancy_mut() = Occupancy {
+                capacity: *capacity,
+                ..Occupancy::fresh()
+            };
+            self.storage = Storage::Allocated(filter);
+        }
+        match &mut self.storage {
+            Storage::Allocated(filter) => filter,
+            Storage::Unallocated { .. } => unreachable!(),
+        }
+    }
+}
+
+impl<H> ASMS for LazyBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Insert item into this LazyBloomFilter, allocating its bits if
+    /// this is the first insert.
+    #[inline(always)]
+    fn insert<T: Hash>(&mut self, item: &T) {
+        self.allocate().insert(item)
+    }
+
+    /// Insert a byte slice into this LazyBloomFilter, allocating its
+    /// bits if this is the first insert.
+    #[inline(always)]
+    fn insert_slice(&mut self, item: &[u8]) {
+        self.allocate().insert_slice(item)
+    }
+
+    /// Insert a fingerprint into this LazyBloomFilter, allocating its
+    /// bits if this is the first insert.
+    #[inline(always)]
+    fn insert_fingerprint(&mut self, fingerprint: BloomFingerprint) {
+        self.allocate().insert_fingerprint(fingerprint)
+    }
+
+    /// Check if the item has been inserted into this LazyBloomFilter.
+    /// Always false if the bits haven't been allocated.
+    #[inline(always)]
+    fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.as_bloom_filter().is_some_and(|f| f.contains(item))
+    }
+
+    /// Check if the byte slice has been inserted into this
+    /// LazyBloomFilter.  Always false if the bits haven't been
+    /// allocated.
+    #[inline(always)]
+    fn contains_slice(&self, item: &[u8]) -> bool {
+        self.as_bloom_filter()
+            .is_some_and(|f| f.contains_slice(item))
+    }
+
+    /// Check if the fingerprint has been inserted into this
+    /// LazyBloomFilter.  Always false if the bits haven't been
+    /// allocated.
+    #[inline(always)]
+    fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        self.as_bloom_filter()
+            .is_some_and(|f| f.contains_fingerprint(fingerprint))
+    }
+
+    /// Remove all values from this LazyBloomFilter, freeing its bits.
+    fn clear(&mut self) {
+        if let Storage::Allocated(filter) = &self.storage {
+            self.storage = Storage::Unallocated {
+                num_bits: filter.num_bits(),
+                num_hashes: filter.num_hashes(),
+                hash_builder: filter.hash_builder().clone(),
+                domain: filter.domain(),
+                layout: filter.layout(),
+                capacity: filter.configured_capacity(),
+            };
+        }
+    }
+}
+
+impl<H> Unionable for LazyBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Calculates the union of two LazyBloomFilters.  Items inserted
+    /// into either filters will be present in `self`.  Bits are only
+    /// allocated if `other` has them.
+    ///
+    /// # Panics
+    /// Panics if the filters are not using the same number of bits, or
+    /// their hashers have different `compat_token`s.
+    fn union(&mut self, other: &LazyBloomFilter<H>) {
+        assert_eq!(self.num_bits(), other.num_bits(), "filters differ in size");
+        assert_same_hasher(self.hash_builder(), other.hash_builder());
+        if let Some(other) = other.as_bloom_filter() {
+            self.allocate().union(other);
+        }
+    }
+}
+
+impl<H> Intersectable for LazyBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Calculates the intersection of two LazyBloomFilters.  Only items
+    /// inserted into both filters will still be present in `self`.  If
+    /// `other` has no bits, `self` frees its own.
+    ///
+    /// # Panics
+    /// Panics if the filters are not using the same number of bits, or
+    /// their hashers have different `compat_token`s.
+    fn intersect(&mut self, other: &LazyBloomFilter<H>) {
+        assert_eq!(self.num_bits(), other.num_bits(), "filters differ in size");
+        assert_same_hasher(self.hash_builder(), other.hash_builder());
+        match (&mut self.storage, other.as_bloom_filter()) {
+            (Storage::Allocated(filter), Some(other)) => filter.intersect(other),
+            (_, None) => self.clear(),
+            (Storage::Unallocated { .. }, Some(_)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LazyBloomFilter;
+    use crate::serialize::Endianness;
+    use crate::{BloomFilter, Intersectable, RandomXxh3State, Unionable, ASMS};
+
+    fn lazy(seed: u64) -> LazyBloomFilter {
+        LazyBloomFilter::with_rate_and_hasher(0.01, 1000, RandomXxh3State::from_seed(seed))
+    }
+
+    #[test]
+    fn query_before_allocation() {
+        let mut filter = lazy(1);
+        let eager = BloomFilter::with_rate_and_hasher(0.01, 1000, RandomXxh3State::from_seed(1));
+        assert_eq!(filter.num_bits(), eager.num_bits());
+        assert_eq!(filter.num_hashes(), eager.num_hashes());
+        assert!(!filter.contains(&1));
+        assert!(!filter.contains_slice(b"1"));
+        assert!(!filter.is_allocated());
+        assert!(filter.as_bloom_filter().is_none());
+
+        filter.insert_slice(b"1");
+        assert!(filter.is_allocated());
+        assert!(filter.contains_slice(b"1"));
+        filter.clear();
+        assert!(!filter.is_allocated());
+        assert!(!filter.contains_slice(b"1"));
+        assert_eq!(filter.num_bits(), eager.num_bits());
+    }
+
+    #[test]
+    fn union_unallocated() {
+        let mut a = lazy(2);
+        let b = lazy(2);
+        a.insert(&1);
+        a.union(&b);
+        assert!(a.contains(&1));
+
+        let mut c = lazy(2);
+        c.union(&b);
+        assert!(!c.is_allocated());
+        c.union(&a);
+        assert!(c.contains(&1));
+
+        c.intersect(&b);
+        assert!(!c.is_allocated());
+        a.intersect(&lazy(2));
+        assert!(!a.is_allocated());
+    }
+
+    #[test]
+    #[should_panic(expected = "filters differ in size")]
+    fn union_sizes() {
+        let mut a = lazy(3);
+        a.union(&LazyBloomFilter::with_size(10, 2));
+    }
+
+    #[test]
+    fn serialize_empty() {
+        let filter = lazy(4);
+        let eager = BloomFilter::with_rate_and_hasher(0.01, 1000, RandomXxh3State::from_seed(4));
+        for endianness in [Endianness::Little, Endianness::Big] {
+            let bytes = filter.to_bytes(endianness);
+            assert_eq!(bytes, eager.to_bytes(endianness));
+            let read = LazyBloomFilter::from_bytes(&bytes, RandomXxh3State::from_seed(4)).unwrap();
+            assert!(!read.is_allocated());
+            assert_eq!(read.num_bits(), filter.num_bits());
+            assert_eq!(read.num_hashes(), filter.num_hashes());
+        }
+
+        let mut filter = filter;
+        filter.insert(&1);
+        let read = LazyBloomFilter::from_bytes(
+            &filter.to_bytes(Endianness::Little),
+            RandomXxh3State::from_seed(4),
+        )
+        .unwrap();
+        assert!(read.is_allocated());
+        assert!(read.contains(&1));
+    }
+}
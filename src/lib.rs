@@ -56,6 +56,7 @@
 //! # Example Usage
 //!
 //! ```rust
+//! # #[cfg(feature = "std")] {
 //! use xx_bloom::{ASMS,BloomFilter};
 //!
 //! let expected_num_items = 1000;
@@ -67,6 +68,7 @@
 //! filter.insert(&1);
 //! filter.contains(&1); /* true */
 //! filter.contains(&2); /* probably false */
+//! # }
 //! ```
 //!
 //! # Counting Bloom Filters
@@ -83,6 +85,7 @@
 //! # Example Usage
 //!
 //! ```rust
+//! # #[cfg(feature = "std")] {
 //! use xx_bloom::{ASMS,CountingBloomFilter};
 //! // Create a counting filter that uses 4 bits per element and has a false positive rate
 //! // of 0.01 when 100 items have been inserted
@@ -95,37 +98,234 @@
 //! assert_eq!(cbf.estimate_count(&1),2);
 //! assert_eq!(cbf.remove(&1),2);
 //! assert_eq!(cbf.estimate_count(&1),1);
+//! # }
+//! ```
+//!
+//! # no_std
+//!
+//! With `default-features = false` the crate only needs `alloc`.
+//! `BloomFilter`, `CountingBloomFilter`, `FrozenBloomFilter` and the
+//! byte serialization are available, but there's no randomness to seed
+//! a hasher with, so filters are built with an explicit one such as a
+//! `SecretBasedXxh3Builder` or `RandomXxh3State::from_seed`.  The other
+//! filters and the `io` based helpers need the `std` feature.
+//!
+//! ```rust
+//! use xx_bloom::{BloomFilter, SecretBasedXxh3Builder, ASMS, XXH3_SECRET_SIZE};
+//!
+//! let hasher = SecretBasedXxh3Builder::with_secret([7; XXH3_SECRET_SIZE]);
+//! let mut filter = BloomFilter::with_size_and_hasher(1 << 12, 4, hasher);
+//! filter.insert(&1);
+//! assert!(filter.contains(&1));
 //! ```
 
 #![crate_name = "xx_bloom"]
 #![crate_type = "rlib"]
-#![cfg_attr(feature = "do-bench", feature(test))]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+// Helpers shared with the std-only filters go unused without std.
+#![cfg_attr(not(feature = "std"), allow(dead_code))]
 
-extern crate bit_vec;
+extern crate alloc;
 extern crate core;
-use std::hash::{Hash, Hasher};
+use core::hash::{Hash, Hasher};
+#[cfg(feature = "std")]
+use std::io;
 
+mod bits;
+mod float;
 mod hashing;
+#[cfg(feature = "std")]
 mod std_hasher;
 mod xxh_helper;
 
 pub mod bloom;
-pub use crate::bloom::{needed_bits, optimal_num_hashes, BloomFilter};
+pub use crate::bloom::{
+    needed_bits, needed_bits_pow2, optimal_num_hashes, optimal_params, probe_all,
+    theoretical_fpr, BloomFilter, FilterParams, IndexLayout, MAX_NUM_HASHES,
+};
+#[cfg(feature = "std")]
+pub use crate::bloom::{measure_fpr, measure_fpr_with_hasher, BloomInserter};
+
+#[cfg(feature = "std")]
+pub mod atomic;
+#[cfg(feature = "std")]
+pub use crate::atomic::{AtomicBloomFilter, AtomicCountingBloomFilter};
+
+#[cfg(feature = "std")]
+pub mod buffered;
+#[cfg(feature = "std")]
+pub use crate::buffered::{BufferedBloomFilter, BufferedBloomWriter};
+
+#[cfg(feature = "std")]
+pub mod builder;
+#[cfg(feature = "std")]
+pub use crate::builder::{BloomFilterBuilder, FilterPlan};
 
 pub mod counting;
-pub use crate::counting::CountingBloomFilter;
+pub use crate::counting::{CountingBloomFilter, CountingStats};
+
+#[cfg(feature = "std")]
+pub mod cow;
+#[cfg(feature = "std")]
+pub use crate::cow::CowBloomFilter;
+
+#[cfg(feature = "std")]
+pub mod cuckoo;
+#[cfg(feature = "std")]
+pub use crate::cuckoo::CuckooFilter;
+
+pub mod error;
+
+#[cfg(feature = "std")]
+pub mod dleft;
+#[cfg(feature = "std")]
+pub use crate::dleft::DlcFilter;
+
+mod metrics;
+#[cfg(feature = "metrics")]
+pub use crate::metrics::BloomStats;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+#[cfg(feature = "std")]
+pub mod family;
+#[cfg(feature = "std")]
+pub use crate::family::{FamilyMember, FilterFamily, ProbeResult};
+
+#[cfg(feature = "std")]
+pub mod fixed;
+#[cfg(feature = "std")]
+pub use crate::fixed::FixedBloomFilter;
+
+pub mod frozen;
+pub use crate::frozen::FrozenBloomFilter;
+
+#[cfg(feature = "std")]
+pub mod hybrid;
+#[cfg(feature = "std")]
+pub use crate::hybrid::HybridCountingFilter;
+
+#[cfg(feature = "std")]
+pub mod independent;
+#[cfg(feature = "std")]
+pub use crate::independent::IndependentBloomFilter;
+
+#[cfg(feature = "std")]
+pub mod lazy;
+#[cfg(feature = "std")]
+pub use crate::lazy::LazyBloomFilter;
+
+#[cfg(feature = "memmap2")]
+pub mod mmap;
+#[cfg(feature = "memmap2")]
+pub use crate::mmap::MmapBits;
+
+#[cfg(feature = "rayon")]
+mod parallel;
+
+#[cfg(feature = "std")]
+pub mod partitioned;
+#[cfg(feature = "std")]
+pub use crate::partitioned::{PartitionedBloomFilter, MAX_PARTITIONS};
+
+#[cfg(feature = "python")]
+pub mod python;
+
+mod recent;
+
+#[cfg(feature = "serde")]
+mod serde_impls;
+
+pub mod serialize;
+pub use crate::serialize::Endianness;
+
+#[cfg(feature = "std")]
+pub mod sharded;
+#[cfg(feature = "std")]
+pub use crate::sharded::{ShardHandle, ShardedBloomFilter};
+
+#[cfg(feature = "std")]
+pub mod sparse;
+#[cfg(feature = "std")]
+pub use crate::sparse::SparseBloomFilter;
+
+pub mod storage;
+pub use crate::storage::{BitStorage, BitStorageMut, Bits};
+
+#[cfg(feature = "std")]
+pub mod striped;
+#[cfg(feature = "std")]
+pub use crate::striped::StripedCountingBloomFilter;
+
+#[cfg(feature = "std")]
+pub mod swappable;
+#[cfg(feature = "std")]
+pub use crate::swappable::{FilterGuard, SwappableFilter};
+
+#[cfg(feature = "std")]
+pub mod tiered;
+#[cfg(feature = "std")]
+pub use crate::tiered::TieredBloomFilter;
+
+#[cfg(feature = "std")]
+pub mod tombstone;
+#[cfg(feature = "std")]
+pub use crate::tombstone::TombstoneBloomFilter;
+
+#[cfg(feature = "std")]
+pub mod wide;
+#[cfg(feature = "std")]
+pub use crate::wide::WideBloomFilter;
 
 pub mod valuevec;
 pub use crate::valuevec::ValueVec;
+#[cfg(feature = "std")]
 pub use std_hasher::*;
 pub use xxh_helper::*;
 pub const XXH3_SECRET_SIZE: usize = xxh_helper::DEFAULT_SECRET_SIZE;
 
+// std's hash maps need std, so without it the few maps the core filters
+// keep off their hot paths are alloc's ordered maps instead.
+#[cfg(feature = "std")]
+pub(crate) use std::collections::{HashMap as Map, HashSet as Set};
+#[cfg(not(feature = "std"))]
+pub(crate) use alloc::collections::{BTreeMap as Map, BTreeSet as Set};
+
+/// A byte slice key whose `Hash` impl feeds the raw bytes to the hasher
+/// without the length prefix that `Hash for [u8]` adds.
+///
+/// `insert_slice` and friends hash the raw bytes with the crate's xxh3
+/// hashers, so wrapping a slice in `ByteKey` lets the generic `Hash` API
+/// agree with the slice fast path on the same data.  A
+/// `BuildHasher128Adapter` hashes slices through `Hash` instead, so with
+/// it the slice itself agrees with the slice methods and `ByteKey`
+/// doesn't.
+///
+/// ```rust
+/// # #[cfg(feature = "std")] {
+/// use xx_bloom::{ASMS,BloomFilter,ByteKey};
+///
+/// let mut filter = BloomFilter::with_rate(0.01, 100);
+/// filter.insert_slice(b"x");
+/// assert!(filter.contains(&ByteKey(b"x")));
+/// # }
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ByteKey<'a>(pub &'a [u8]);
+
+impl Hash for ByteKey<'_> {
+    #[inline(always)]
+    fn hash<S: Hasher>(&self, state: &mut S) {
+        state.write(self.0);
+    }
+}
+
 /// This is an opaque container of the raw underlying information for a key.
 /// If you have a bunch of filters with the exact BloomBuildHasher being used,
 /// then you can quickly check the fingerprint in all of them without needing to
 /// rehash your key constantly.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct BloomFingerprint {
     pub(crate) h1: u64,
     pub(crate) h2: u64,
@@ -133,16 +333,66 @@ pub struct BloomFingerprint {
 
 impl BloomFingerprint {
     #[inline(always)]
-    pub fn new(h1: u64, h2: u64) -> Self {
+    pub const fn new(h1: u64, h2: u64) -> Self {
         Self { h1, h2 }
     }
 
     #[inline(always)]
-    pub fn new_128(h: u128) -> Self {
+    pub const fn new_128(h: u128) -> Self {
         Self::new((h >> 64) as u64, h as u64)
     }
 }
 
+/// A key's fingerprint computed once, so the key can be looked up in
+/// several filters without rehashing it each time.
+///
+/// Unlike a bare `BloomFingerprint` it remembers which hasher made it,
+/// via `BloomBuildHasher::compat_token`, and the `*_cached` methods on
+/// the filters check in debug builds that they hash the same way.
+///
+/// ```rust
+/// # #[cfg(feature = "std")] {
+/// use xx_bloom::{ASMS,BloomBuildHasher,BloomFilter};
+///
+/// let mut a = BloomFilter::with_rate(0.01, 100);
+/// let mut b = BloomFilter::combinable_with(&a);
+/// let key = a.hash_builder().cache_key(&"key");
+/// a.insert_cached(&key);
+/// assert!(a.contains_cached(&key));
+/// assert!(!b.contains_cached(&key));
+/// # }
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CachedKey {
+    fingerprint: BloomFingerprint,
+    token: u64,
+}
+
+impl CachedKey {
+    /// The key's fingerprint.
+    #[inline(always)]
+    pub fn fingerprint(&self) -> BloomFingerprint {
+        self.fingerprint
+    }
+
+    /// The `compat_token` of the hasher that made this key.
+    #[inline(always)]
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// Panics in debug builds if this key wasn't made by a hasher that
+    /// hashes like `hash_builder`.
+    #[inline(always)]
+    pub(crate) fn debug_check<H: BloomBuildHasher>(&self, hash_builder: &H) {
+        debug_assert_eq!(
+            self.token,
+            hash_builder.compat_token(),
+            "CachedKey was made by a different hasher than the filter's"
+        );
+    }
+}
+
 /// Extends Hasher so that we can get the full underlying 128-bit digest if
 /// it's implemented natively as such.
 pub trait BloomHasher: Hasher {
@@ -156,9 +406,105 @@ pub trait BloomBuildHasher: Clone {
 
     fn build_hasher(&self) -> Self::Hasher;
     fn hash_one_128(&self, k: &[u8]) -> BloomFingerprint;
+
+    /// Hash everything `r` yields, feeding it to the hasher in chunks so
+    /// the contents never have to be in memory all at once.  The result
+    /// is the same as `hash_one_128` of the full contents.
+    #[cfg(feature = "std")]
+    fn hash_reader_128<R: io::Read>(&self, mut r: R) -> io::Result<BloomFingerprint> {
+        let mut hasher = self.build_hasher();
+        let mut buf = [0; 16 * 1024];
+        loop {
+            match r.read(&mut buf) {
+                Ok(0) => return Ok(hasher.finish_128()),
+                Ok(n) => hasher.write(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// A token identifying how this builder hashes.  Builders that
+    /// hash the same way have the same token and different ones almost
+    /// certainly don't, since it's derived from the hash of a fixed key.
+    #[inline(always)]
+    fn compat_token(&self) -> u64 {
+        self.hash_one_128(b"xx-bloom compat token").h1
+    }
+
+    /// Hash `item` once into a key that can be looked up in any filter
+    /// using this hasher.
+    #[inline(always)]
+    fn cache_key<T: Hash>(&self, item: &T) -> CachedKey {
+        CachedKey {
+            fingerprint: hashing::fingerprint(item, self),
+            token: self.compat_token(),
+        }
+    }
+
+    /// Like `cache_key`, but for a byte slice.
+    #[inline(always)]
+    fn cache_slice(&self, item: &[u8]) -> CachedKey {
+        CachedKey {
+            fingerprint: self.hash_one_128(item),
+            token: self.compat_token(),
+        }
+    }
+}
+
+/// A 256-bit fingerprint made of four 64-bit lanes.  Filters using
+/// many hash functions (e.g. for very low false positive rates) derive
+/// their probe positions from all four lanes, since the two halves of a
+/// `BloomFingerprint` produce correlated positions once `num_hashes`
+/// gets large.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BloomFingerprint256 {
+    pub(crate) lanes: [u64; 4],
+}
+
+impl BloomFingerprint256 {
+    #[inline(always)]
+    pub fn new(lanes: [u64; 4]) -> Self {
+        Self { lanes }
+    }
+
+    /// Build a fingerprint from two independent 128-bit hashes.  The
+    /// first two lanes come from `h1` in the same order `new_128` uses.
+    #[inline(always)]
+    pub fn new_256(h1: u128, h2: u128) -> Self {
+        Self::new([(h1 >> 64) as u64, h1 as u64, (h2 >> 64) as u64, h2 as u64])
+    }
+
+    /// The 128-bit fingerprint made of the first two lanes.
+    #[inline(always)]
+    pub fn truncate(&self) -> BloomFingerprint {
+        BloomFingerprint::new(self.lanes[0], self.lanes[1])
+    }
+}
+
+/// Extends BloomHasher for hashers that can produce a 256-bit digest.
+pub trait BloomHasher256: BloomHasher {
+    fn finish_256(&self) -> BloomFingerprint256;
+}
+
+/// Like BloomBuildHasher, but for builders producing 256-bit
+/// fingerprints.
+pub trait BloomBuildHasher256: Clone {
+    type Hasher256: BloomHasher256;
+
+    fn build_hasher_256(&self) -> Self::Hasher256;
+    fn hash_one_256(&self, k: &[u8]) -> BloomFingerprint256;
 }
 
 /// Stanard filter functions
+///
+/// The `_slice` methods hash with `hash_one_128`, which for the crate's
+/// xxh3 hashers is the raw bytes.  The generic methods go through
+/// `Hash`, which prefixes slices, `Vec<u8>` and arrays with their length
+/// and terminates strings, so with those hashers `insert(&bytes)` and
+/// `insert_slice(&bytes)` never find each other's items, even for empty
+/// keys.  Wrap bytes in `ByteKey` to hash them through `Hash` the way
+/// the slice methods do.
 pub trait ASMS {
     fn insert<T: Hash>(&mut self, item: &T);
     fn insert_slice(&mut self, item: &[u8]);
@@ -175,8 +521,9 @@ pub trait ASMS {
 ///
 /// Both filters MUST be the same size and be using the same hash
 /// functions for this to work.  Will panic if the filters are not the
-/// same size, but will simply produce incorrect (meaningless) results
-/// if the filters are using different hash functions.
+/// same size.  Filters hashing with a `BloomBuildHasher` also panic if
+/// their hashers' `compat_token`s differ; others will simply produce
+/// incorrect (meaningless) results if they use different hash functions.
 pub trait Intersectable {
     fn intersect(&mut self, other: &Self);
 }
@@ -187,8 +534,9 @@ pub trait Intersectable {
 ///
 /// Both filters MUST be the same size and be using the same hash
 /// functions for this to work.  Will panic if the filters are not the
-/// same size, but will simply produce incorrect (meaningless) results
-/// if the filters are using different hash functions.
+/// same size.  Filters hashing with a `BloomBuildHasher` also panic if
+/// their hashers' `compat_token`s differ; others will simply produce
+/// incorrect (meaningless) results if they use different hash functions.
 pub trait Unionable {
     fn union(&mut self, other: &Self);
 }
@@ -196,3 +544,204 @@ pub trait Unionable {
 /// Filters than are Combineable can be unioned and intersected
 pub trait Combineable: Intersectable + Unionable {}
 impl<T> Combineable for T where T: Intersectable + Unionable {}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::hashing::{fingerprint, fingerprint_256, HashIter};
+    use crate::{
+        BloomBuildHasher, BloomBuildHasher256, BloomFilter, BloomHasher, BloomHasher256,
+        BuildHasher128Adapter, ByteKey, RandomXxh3State, SecretBasedXxh3Builder, SeededXxh3State,
+        ASMS, XXH3_SECRET_SIZE,
+    };
+    use core::hash::Hasher;
+    use std::io;
+
+    fn check_byte_key<H: BloomBuildHasher>(hash_builder: H) {
+        for key in [&b""[..], b"x", b"hello world", &[0u8; 300]] {
+            assert_eq!(
+                fingerprint(ByteKey(key), &hash_builder),
+                hash_builder.hash_one_128(key)
+            );
+        }
+    }
+
+    #[test]
+    fn cached_key() {
+        let a = RandomXxh3State::from_seed(1);
+        let token = a.compat_token();
+        assert_eq!(token, RandomXxh3State::from_seed(1).compat_token());
+        assert_ne!(token, RandomXxh3State::from_seed(2).compat_token());
+        assert_eq!(a.cache_key(&ByteKey(b"x")), a.cache_slice(b"x"));
+        assert_eq!(a.cache_slice(b"x").fingerprint(), a.hash_one_128(b"x"));
+    }
+
+    #[test]
+    fn byte_key_matches_slice() {
+        check_byte_key(RandomXxh3State::new());
+        check_byte_key(SecretBasedXxh3Builder::with_secret([5; XXH3_SECRET_SIZE]));
+        check_byte_key(SeededXxh3State::from_seed(1));
+    }
+
+    // The adapter hashes slices through `Hash`, so it's the slice itself
+    // rather than `ByteKey` that agrees with its slice methods.
+    #[test]
+    fn adapter_hashes_slices() {
+        let hash_builder = BuildHasher128Adapter::new();
+        for key in [&b""[..], b"x", b"hello world", &[0u8; 300]] {
+            let expected = hash_builder.hash_one_128(key);
+            assert_eq!(fingerprint(key, &hash_builder), expected);
+            assert_ne!(fingerprint(ByteKey(key), &hash_builder), expected);
+            assert_eq!(HashIter::from_slice(key, 3, &hash_builder).fingerprint(), expected);
+            assert_eq!(hash_builder.cache_slice(key).fingerprint(), expected);
+        }
+        let mut filter = BloomFilter::with_size_and_hasher(1 << 12, 4, hash_builder);
+        filter.insert_slice(b"x");
+        assert!(filter.contains(&&b"x"[..]));
+    }
+
+    // Empty and one byte keys, and keys either side of where xxh3
+    // switches algorithms (16, 128 and 240 bytes), fills a stripe (64
+    // bytes) and where `SeededXxh3Hasher` starts streaming (64 bytes).
+    const KEY_LENS: [usize; 18] =
+        [0, 1, 2, 3, 4, 8, 9, 15, 16, 17, 63, 64, 65, 128, 129, 240, 241, 1025];
+
+    // Every way of hashing raw bytes must agree with `hash_one_128`:
+    // `ByteKey`, `HashIter`, cached keys, and streaming the bytes in
+    // one write, a byte at a time, or split anywhere.
+    fn check_key_paths<H: BloomBuildHasher>(hash_builder: H) {
+        for len in KEY_LENS {
+            let key: Vec<u8> = (0..len).map(|i| (i * 7 + 3) as u8).collect();
+            let expected = hash_builder.hash_one_128(&key);
+            assert_eq!(fingerprint(ByteKey(&key), &hash_builder), expected, "{}", len);
+            assert_eq!(HashIter::from_slice(&key, 3, &hash_builder).fingerprint(), expected);
+            assert_eq!(HashIter::from(ByteKey(&key), 3, &hash_builder).fingerprint(), expected);
+            assert_eq!(hash_builder.cache_slice(&key).fingerprint(), expected);
+            assert_eq!(hash_builder.cache_key(&ByteKey(&key)).fingerprint(), expected);
+
+            let mut bytewise = hash_builder.build_hasher();
+            key.iter().for_each(|b| bytewise.write(&[*b]));
+            assert_eq!(bytewise.finish_128(), expected, "{} bytes one at a time", len);
+            for split in 0..=len {
+                let mut hasher = hash_builder.build_hasher();
+                hasher.write(&key[..split]);
+                hasher.write(&[]);
+                hasher.write(&key[split..]);
+                assert_eq!(hasher.finish_128(), expected, "{} bytes split at {}", len, split);
+            }
+        }
+    }
+
+    #[test]
+    fn key_paths_agree() {
+        check_key_paths(RandomXxh3State::from_seed(1));
+        check_key_paths(SecretBasedXxh3Builder::with_secret([5; XXH3_SECRET_SIZE]));
+        check_key_paths(SeededXxh3State::from_seed(1));
+    }
+
+    #[test]
+    fn key_paths_agree_256() {
+        let hash_builder = RandomXxh3State::from_seed(1);
+        for len in KEY_LENS {
+            let key = vec![0xa5; len];
+            let expected = hash_builder.hash_one_256(&key);
+            assert_eq!(fingerprint_256(ByteKey(&key), &hash_builder), expected, "{}", len);
+            let mut hasher = hash_builder.build_hasher_256();
+            hasher.write(&key[..len / 2]);
+            hasher.write(&key[len / 2..]);
+            assert_eq!(hasher.finish_256(), expected, "{}", len);
+        }
+    }
+
+    // `Hash` length prefixes slices, `Vec`s and arrays alike, so the
+    // generic methods agree with each other but never with the slice
+    // methods, not even for an empty key.  This is by design; see the
+    // `ASMS` docs.
+    #[test]
+    fn hash_trait_diverges_from_slices() {
+        let hash_builder = RandomXxh3State::from_seed(1);
+        let mut filter = BloomFilter::with_size_and_hasher(1 << 20, 7, hash_builder);
+        for len in KEY_LENS {
+            let key: Vec<u8> = vec![0x5a; len];
+            let slice = hash_builder.hash_one_128(&key);
+            let hashed = fingerprint(&key, &hash_builder);
+            assert_eq!(fingerprint(&key[..], &hash_builder), hashed);
+            assert_ne!(hashed, slice, "{} byte key", len);
+            if len == 4 {
+                assert_eq!(fingerprint([0x5au8; 4], &hash_builder), hashed);
+            }
+
+            filter.insert(&key);
+            assert!(filter.contains(&key) && filter.contains(&&key[..]));
+            assert!(!filter.contains_slice(&key));
+            assert!(!filter.contains(&ByteKey(&key)));
+        }
+        let empty = String::new();
+        assert_ne!(fingerprint(&empty, &hash_builder), hash_builder.hash_one_128(b""));
+        assert_ne!(fingerprint(&empty, &hash_builder), fingerprint(&b""[..], &hash_builder));
+    }
+
+    // Hands out at most `chunk` bytes per read, like a pipe or socket.
+    struct Chunked<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl io::Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    fn check_reader<H: BloomBuildHasher>(hash_builder: H, data: &[u8]) {
+        for chunk in [1, 7, 4093, 65537] {
+            let reader = Chunked { data, chunk };
+            assert_eq!(
+                hash_builder.hash_reader_128(reader).unwrap(),
+                hash_builder.hash_one_128(data),
+                "chunk size {}",
+                chunk
+            );
+        }
+    }
+
+    #[test]
+    fn hash_reader_matches_slice() {
+        let data: Vec<u8> = (0..3 * 1024 * 1024 + 17)
+            .map(|i: u32| ((i * 31) >> 3) as u8)
+            .collect();
+        check_reader(RandomXxh3State::from_seed(1), &data);
+        check_reader(BuildHasher128Adapter::new(), &data[..100_000]);
+        check_reader(RandomXxh3State::from_seed(1), &[]);
+    }
+
+    // Run with `wasm-pack test --node -- --features wasm`.
+    #[cfg(target_arch = "wasm32")]
+    mod wasm {
+        use wasm_bindgen_test::wasm_bindgen_test;
+
+        use crate::{BloomFilter, RandomXxh3State, ASMS, XXH3_SECRET_SIZE};
+
+        #[wasm_bindgen_test]
+        fn insert_and_query() {
+            let mut filter = BloomFilter::with_rate(0.01, 1000);
+            (0..1000u32).for_each(|i| filter.insert(&i));
+            assert!((0..1000u32).all(|i| filter.contains(&i)));
+            assert!((1000..2000u32).filter(|i| filter.contains(i)).count() < 50);
+        }
+
+        #[wasm_bindgen_test]
+        fn explicit_secret() {
+            let hash_builder = RandomXxh3State::from_entropy_or([7; XXH3_SECRET_SIZE]);
+            let mut filter = BloomFilter::with_rate_and_hasher(0.01, 100, hash_builder);
+            filter.insert_slice(b"key");
+            assert!(filter.contains_slice(b"key"));
+            let seeded = RandomXxh3State::from_seed(1);
+            let mut filter = BloomFilter::with_rate_and_hasher(0.01, 100, seeded);
+            filter.insert(&"key");
+            assert!(filter.contains(&"key"));
+        }
+    }
+}
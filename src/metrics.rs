@@ -0,0 +1,74 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+//! Operation counters, enabled with the `metrics` feature.  Without the
+//! feature the counters are zero sized and recording is a no-op.
+
+#[cfg(feature = "metrics")]
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A snapshot of how a filter has been used, as returned by
+/// `BloomFilter::stats`.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BloomStats {
+    /// The number of items inserted, through any insert method.
+    pub inserts: u64,
+    /// The number of membership checks, through any contains method.
+    pub contains: u64,
+    /// The total length of the byte slices passed to `insert_slice`.
+    /// Items inserted any other way aren't counted here.
+    pub bytes_inserted: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct OpCounters {
+    #[cfg(feature = "metrics")]
+    inserts: AtomicU64,
+    #[cfg(feature = "metrics")]
+    contains: AtomicU64,
+    #[cfg(feature = "metrics")]
+    bytes_inserted: AtomicU64,
+}
+
+impl OpCounters {
+    #[inline(always)]
+    pub(crate) fn record_inserts(&self, _count: u64) {
+        #[cfg(feature = "metrics")]
+        self.inserts.fetch_add(_count, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub(crate) fn record_bytes(&self, _len: usize) {
+        #[cfg(feature = "metrics")]
+        self.bytes_inserted
+            .fetch_add(_len as u64, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub(crate) fn record_contains(&self) {
+        #[cfg(feature = "metrics")]
+        self.contains.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn snapshot(&self) -> BloomStats {
+        BloomStats {
+            inserts: self.inserts.load(Ordering::Relaxed),
+            contains: self.contains.load(Ordering::Relaxed),
+            bytes_inserted: self.bytes_inserted.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,213 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+//! Lookups served straight from a memory mapped serialized filter,
+//! enabled with the `memmap2` feature.
+
+use std::fs::File;
+use core::hash::Hash;
+use std::io;
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::error::DeserializeError;
+use crate::hashing::HashIter;
+use crate::serialize::{Endianness, Header};
+use crate::storage::BitStorage;
+use crate::{BloomBuildHasher, BloomFilter, BloomFingerprint};
+
+/// The bits of a filter written by `BloomFilter::to_bytes`, read in
+/// place from a memory mapped file.  See `BloomFilter::open_mmap`.
+///
+/// Only the pages lookups touch are read from disk, so filters much
+/// larger than memory can be queried.  The storage is read only.
+pub struct MmapBits {
+    map: Mmap,
+    // Where the words start.
+    offset: usize,
+    len: usize,
+    endianness: Endianness,
+}
+
+impl BitStorage for MmapBits {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline(always)]
+    fn get(&self, i: usize) -> bool {
+        assert!(i < self.len, "bit index {} out of bounds", i);
+        let word = self
+            .endianness
+            .read_u64(&self.map[self.offset + i / 64 * 8..]);
+        word & (1 << (i % 64)) != 0
+    }
+
+    // Bits past `len` in the last word are masked off, in case whatever
+    // wrote the file didn't zero them.
+    fn count_ones(&self) -> usize {
+        let words = self.len.div_ceil(64);
+        (0..words)
+            .map(|w| {
+                let word = self.endianness.read_u64(&self.map[self.offset + w * 8..]);
+                let live = (self.len - w * 64).min(64);
+                (word & (u64::MAX >> (64 - live))).count_ones() as usize
+            })
+            .sum()
+    }
+}
+
+impl<H> BloomFilter<H, MmapBits>
+where
+    H: BloomBuildHasher,
+{
+    /// Open a filter written by `to_bytes`, in either byte order, by
+    /// memory mapping the file at `path` instead of reading it in.
+    /// Lookups read bits directly from the mapping, hashing with
+    /// `hash_builder`, which must be the one the filter was built with.
+    ///
+    /// The header is validated up front and errors are returned as
+    /// `io::ErrorKind::InvalidData` wrapping a `DeserializeError`.  The
+    /// filter can't be inserted into; read it with `from_bytes` for
+    /// that.
+    ///
+    /// # Safety
+    /// As with `memmap2::Mmap::map`, the file must not be modified or
+    /// truncated, by this or any other process, while the filter is
+    /// alive.  Writing to it changes memory the filter reads as
+    /// immutable, and truncating it makes lookups fault.
+    pub unsafe fn open_mmap<P: AsRef<Path>>(path: P, hash_builder: H) -> io::Result<Self> {
+        let invalid = |err: DeserializeError| io::Error::new(io::ErrorKind::InvalidData, err);
+        let file = File::open(path)?;
+        // SAFETY: the caller guarantees the file isn't changed while the
+        // mapping, owned by the returned filter, is alive.
+        let map = unsafe { Mmap::map(&file)? };
+        let header = Header::parse(&map).map_err(invalid)?;
+        header.payload(&map).map_err(invalid)?;
+        let mut filter = BloomFilter::from_parts(
+            MmapBits {
+                map,
+                offset: header.len,
+                len: header.num_bits,
+                endianness: header.endianness,
+            },
+            header.num_hashes,
+            hash_builder,
+            header.domain,
+            header.layout,
+        );
+        // Counting the bits would read the whole file, so the filter is
+        // never assumed empty.
+        filter.occupancy_mut().insertions = header.insertions.unwrap_or(0);
+        filter.occupancy_mut().capacity = header.capacity;
+        Ok(filter)
+    }
+
+    /// Check if the item has been inserted into this filter.  This
+    /// function can return false positives, but not false negatives.
+    #[inline(always)]
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_hash_iter(HashIter::from(item, self.num_hashes(), self.hash_builder()))
+    }
+
+    /// Check if the byte slice has been inserted into this filter.
+    #[inline(always)]
+    pub fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes(),
+            self.hash_builder(),
+        ))
+    }
+
+    /// Check if the fingerprint has been inserted into this filter.
+    #[inline(always)]
+    pub fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        self.contains_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+
+    use crate::serialize::Endianness;
+    use crate::{BloomBuildHasher, BloomFilter, RandomXxh3State, ASMS};
+
+    // A file in the temp directory that's removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &[u8]) -> TempFile {
+            let path =
+                std::env::temp_dir().join(format!("xx-bloom-{}-{}", std::process::id(), name));
+            fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn open_mmap() {
+        let hasher = RandomXxh3State::from_seed(11);
+        let mut filter = BloomFilter::with_rate_and_hasher(0.01, 1000, hasher);
+        for i in 0..1000u32 {
+            filter.insert(&i);
+        }
+        filter.insert_slice(b"slice");
+
+        for endianness in [Endianness::Little, Endianness::Big] {
+            let file = TempFile::new(&format!("{:?}", endianness), &filter.to_bytes(endianness));
+            // SAFETY: the test owns the file and doesn't change it.
+            let mapped = unsafe { BloomFilter::open_mmap(&file.0, hasher) }.unwrap();
+            assert_eq!(mapped.num_bits(), filter.num_bits());
+            assert_eq!(mapped.num_hashes(), filter.num_hashes());
+            assert_eq!(mapped.layout(), filter.layout());
+            assert_eq!(mapped.count_ones(), filter.count_ones());
+            for i in 0..10_000u32 {
+                assert_eq!(mapped.contains(&i), filter.contains(&i));
+            }
+            assert!(mapped.contains_slice(b"slice"));
+            let fp = hasher.hash_one_128(b"slice");
+            assert!(mapped.contains_fingerprint(fp));
+        }
+    }
+
+    #[test]
+    fn open_mmap_errors() {
+        let bytes = BloomFilter::with_size(1000, 3).to_bytes_le();
+        let truncated = TempFile::new("truncated", &bytes[..bytes.len() - 1]);
+        // SAFETY: the test owns the files and doesn't change them.
+        let err = unsafe { BloomFilter::open_mmap(&truncated.0, RandomXxh3State::new()) }
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let missing = std::env::temp_dir().join("xx-bloom-does-not-exist");
+        let err = unsafe { BloomFilter::open_mmap(missing, RandomXxh3State::new()) }
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,158 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+//! Rayon powered bulk operations, enabled with the `rayon` feature.
+
+use rayon::prelude::*;
+
+use crate::error::IncompatibleFilters;
+use crate::{AtomicBloomFilter, BloomBuildHasher, BloomFilter};
+
+// Words of bits each task ORs across every source (256 KiB).
+const UNION_CHUNK_WORDS: usize = 32 * 1024;
+
+impl<H> BloomFilter<H>
+where
+    H: BloomBuildHasher + Sync,
+{
+    /// Insert every key in `keys`, hashing and setting bits in parallel
+    /// on the rayon thread pool.
+    ///
+    /// The bits are copied into an atomic backing for the duration of
+    /// the insert, so this is only worthwhile when `keys` is large
+    /// relative to the filter.  The resulting bits are identical to
+    /// inserting every key sequentially with `insert_slice`.
+    pub fn par_extend_slices(&mut self, keys: &[&[u8]]) {
+        let atomic = AtomicBloomFilter::from(&*self);
+        keys.par_iter().for_each(|key| atomic.insert_slice(key));
+        *self.bits_mut() = atomic.freeze().into_bits();
+        // Which keys were new isn't known, so count them all.
+        let occupancy = self.occupancy_mut();
+        occupancy.insertions = occupancy.insertions.saturating_add(keys.len() as u64);
+    }
+
+    /// Like `union_many`, but splitting the bits into ranges that are
+    /// each ORed across every source in parallel on the rayon thread
+    /// pool.  The result is bit-identical to `union_many`.
+    pub fn par_union_many(
+        &mut self,
+        others: &[&BloomFilter<H>],
+    ) -> Result<(), IncompatibleFilters> {
+        self.check_compatible(others)?;
+        let sources: Vec<&[u64]> = others.iter().map(|other| other.bits().words()).collect();
+        // Every source has the same length and zeroed trailing bits, so
+        // ORing them in keeps the trailing bits zero.
+        self.bits_mut()
+            .words_mut()
+            .par_chunks_mut(UNION_CHUNK_WORDS)
+            .enumerate()
+            .for_each(|(i, chunk)| {
+                let start = i * UNION_CHUNK_WORDS;
+                for source in &sources {
+                    let source = &source[start..start + chunk.len()];
+                    for (word, other) in chunk.iter_mut().zip(source) {
+                        *word |= other;
+                    }
+                }
+            });
+        for other in others {
+            self.occupancy_mut().merge(other.occupancy());
+        }
+        Ok(())
+    }
+
+    /// Create a BloomFilter that expects to hold `expected_num_items`
+    /// with a false positive rate of `rate`, and insert every key in
+    /// `keys` in parallel on the rayon thread pool.
+    ///
+    /// The resulting bits are identical to inserting every key
+    /// sequentially into `with_rate_and_hasher`.
+    pub fn from_par_iter_with_rate<'a, I>(
+        keys: I,
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> BloomFilter<H>
+    where
+        I: IntoParallelIterator<Item = &'a [u8]>,
+    {
+        let atomic =
+            AtomicBloomFilter::with_rate_and_hasher(rate, expected_num_items, hash_builder);
+        keys.into_par_iter()
+            .for_each(|key| atomic.insert_slice(key));
+        let mut filter = atomic.freeze();
+        filter.occupancy_mut().capacity = Some(expected_num_items.into());
+        filter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::IncompatibleFilters;
+    use crate::{BloomFilter, RandomXxh3State, ASMS};
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let num_keys = 1_000_000u32;
+        let keys: Vec<[u8; 4]> = (0..num_keys).map(|i| i.to_le_bytes()).collect();
+        let key_slices: Vec<&[u8]> = keys.iter().map(|k| &k[..]).collect();
+        let hasher = RandomXxh3State::new();
+
+        let mut sequential = BloomFilter::with_rate_and_hasher(0.01, num_keys, hasher);
+        for key in &key_slices {
+            sequential.insert_slice(key);
+        }
+
+        let mut extended = BloomFilter::with_rate_and_hasher(0.01, num_keys, hasher);
+        extended.insert_slice(key_slices[0]);
+        extended.par_extend_slices(&key_slices);
+        assert_eq!(extended.raw_bits(), sequential.raw_bits());
+
+        let built =
+            BloomFilter::from_par_iter_with_rate(key_slices.clone(), 0.01, num_keys, hasher);
+        assert_eq!(built.raw_bits(), sequential.raw_bits());
+        assert_eq!(built.configured_capacity(), Some(num_keys.into()));
+    }
+
+    #[test]
+    fn par_union_many_matches_sequential() {
+        // Not a multiple of the chunk size, so the last range is short.
+        let template = BloomFilter::with_size(3_000_001, 4);
+        let sources: Vec<BloomFilter> = (0..16u32)
+            .map(|s| {
+                let mut source = BloomFilter::combinable_with(&template);
+                for i in 0..20_000 {
+                    source.insert(&(s, i));
+                }
+                source
+            })
+            .collect();
+        let refs: Vec<&BloomFilter> = sources.iter().collect();
+
+        let mut sequential = BloomFilter::combinable_with(&template);
+        sequential.insert(&"base");
+        let mut parallel = BloomFilter::combinable_with(&template);
+        parallel.insert(&"base");
+        sequential.union_many(&refs).unwrap();
+        parallel.par_union_many(&refs).unwrap();
+        assert_eq!(parallel.raw_bits(), sequential.raw_bits());
+
+        let wrong = BloomFilter::with_size(10, 4);
+        assert_eq!(
+            parallel.par_union_many(&[&wrong]),
+            Err(IncompatibleFilters { index: 0 })
+        );
+    }
+}
@@ -0,0 +1,490 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+
+use crate::bloom::{needed_bits, optimal_num_hashes};
+use crate::error::ParameterError;
+use crate::hashing::{fastrange, fingerprint};
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher, BloomFingerprint, Intersectable, Unionable};
+
+/// The most partitions a block can have: as many 32-bit lanes as a
+/// 256-bit vector register holds.
+pub const MAX_PARTITIONS: u32 = 8;
+
+/// Odd multipliers, one per partition, that turn a key into a
+/// different bit of each lane.  These are the salts Parquet's split
+/// block filters use.
+const SALTS: [u32; MAX_PARTITIONS as usize] = [
+    0x47b6_137b,
+    0x4497_4d91,
+    0x8824_ad5b,
+    0xa2b7_289d,
+    0x7054_95c7,
+    0x2df1_424b,
+    0x9efc_4947,
+    0x5c6b_fb31,
+];
+
+/// A BloomFilter whose probes for an item all land in one small block.
+///
+/// The bits are split into blocks of `num_hashes` 32-bit partitions,
+/// and every item sets exactly one bit in each partition of a single
+/// block.  An insert or lookup therefore touches one cache line
+/// instead of `num_hashes` of them, which makes this filter much
+/// faster than `BloomFilter` once it outgrows the cache.  The price is
+/// a somewhat higher false positive rate at the same size, since items
+/// crowd into blocks unevenly.
+///
+/// With the `simd` feature, filters with `MAX_PARTITIONS` hashes
+/// compute and test all of a block's bits with AVX2 on x86_64 or NEON
+/// on aarch64, when the CPU running them has it.  Other filters and
+/// CPUs take the scalar path, which sets the same bits.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::PartitionedBloomFilter;
+///
+/// let mut filter = PartitionedBloomFilter::with_rate(0.01, 1000);
+/// filter.insert(&1);
+/// assert!(filter.contains(&1));
+/// ```
+pub struct PartitionedBloomFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    words: Vec<u32>,
+    num_hashes: u32,
+    vectorized: bool,
+    hash_builder: H,
+}
+
+impl PartitionedBloomFilter<RandomXxh3State> {
+    /// Create a new PartitionedBloomFilter with the specified number
+    /// of bits, and hashes.  See `with_size_and_hasher`.
+    pub fn with_size(num_bits: usize, num_hashes: u32) -> PartitionedBloomFilter<RandomXxh3State> {
+        PartitionedBloomFilter::with_size_and_hasher(num_bits, num_hashes, RandomXxh3State::new())
+    }
+
+    /// Create a PartitionedBloomFilter that expects to hold
+    /// `expected_num_items`.  See `with_rate_and_hasher`.
+    pub fn with_rate(
+        rate: f32,
+        expected_num_items: u32,
+    ) -> PartitionedBloomFilter<RandomXxh3State> {
+        PartitionedBloomFilter::with_rate_and_hasher(
+            rate,
+            expected_num_items,
+            RandomXxh3State::new(),
+        )
+    }
+}
+
+impl<H> PartitionedBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Create a new PartitionedBloomFilter with the specified number
+    /// of bits, hashes, and HashBuilder.  The bits are rounded up to a
+    /// whole number of blocks, and there is always at least one.
+    ///
+    /// # Panics
+    /// Panics if `num_hashes` is 0 or more than `MAX_PARTITIONS`.
+    pub fn with_size_and_hasher(
+        num_bits: usize,
+        num_hashes: u32,
+        hash_builder: H,
+    ) -> PartitionedBloomFilter<H> {
+        assert!(num_hashes > 0, "{}", ParameterError::ZeroHashes);
+        assert!(
+            num_hashes <= MAX_PARTITIONS,
+            "{} hashes is more than MAX_PARTITIONS ({})",
+            num_hashes,
+            MAX_PARTITIONS
+        );
+        let block_bits = 32 * num_hashes as usize;
+        let num_blocks = num_bits.div_ceil(block_bits).max(1);
+        PartitionedBloomFilter {
+            words: vec![0; num_blocks * num_hashes as usize],
+            num_hashes,
+            vectorized: simd_available(num_hashes),
+            hash_builder,
+        }
+    }
+
+    /// Create a PartitionedBloomFilter that expects to hold
+    /// `expected_num_items` with a false positive rate of about
+    /// `rate`, hashing items with `hash_builder`.  It gets as many bits
+    /// as a `BloomFilter` would, and at most `MAX_PARTITIONS` hashes,
+    /// so its actual rate comes out somewhat higher.
+    pub fn with_rate_and_hasher(
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> PartitionedBloomFilter<H> {
+        let bits = needed_bits(rate, expected_num_items);
+        PartitionedBloomFilter::with_size_and_hasher(
+            bits,
+            optimal_num_hashes(bits, expected_num_items).min(MAX_PARTITIONS),
+            hash_builder,
+        )
+    }
+
+    /// Create a new PartitionedBloomFilter with the exact same
+    /// parameters as the other, so the two can be intersected and
+    /// unioned.
+    pub fn combinable_with(other: &PartitionedBloomFilter<H>) -> Self {
+        PartitionedBloomFilter {
+            words: vec![0; other.words.len()],
+            num_hashes: other.num_hashes,
+            vectorized: other.vectorized,
+            hash_builder: other.hash_builder.clone(),
+        }
+    }
+
+    /// Make this filter take the scalar path even where the vector one
+    /// is available.  Both set the same bits, so this only matters for
+    /// comparing their speed.
+    pub fn scalar(mut self) -> Self {
+        self.vectorized = false;
+        self
+    }
+
+    /// Whether this filter probes its blocks with vector instructions.
+    #[inline(always)]
+    pub fn is_vectorized(&self) -> bool {
+        self.vectorized
+    }
+
+    /// Get the number of bits this PartitionedBloomFilter is using
+    #[inline(always)]
+    pub fn num_bits(&self) -> usize {
+        self.words.len() * 32
+    }
+
+    /// Get the number of hash functions this PartitionedBloomFilter is
+    /// using, which is also the number of partitions in each block.
+    #[inline(always)]
+    pub fn num_hashes(&self) -> u32 {
+        self.num_hashes
+    }
+
+    /// Get the hash builder this PartitionedBloomFilter is using
+    #[inline(always)]
+    pub fn hash_builder(&self) -> &H {
+        &self.hash_builder
+    }
+
+    /// Insert item into this PartitionedBloomFilter.
+    #[inline(always)]
+    pub fn insert<T: Hash>(&mut self, item: &T) {
+        self.insert_fingerprint(fingerprint(item, &self.hash_builder))
+    }
+
+    /// Insert a byte slice into this PartitionedBloomFilter.
+    #[inline(always)]
+    pub fn insert_slice(&mut self, item: &[u8]) {
+        self.insert_fingerprint(self.hash_builder.hash_one_128(item))
+    }
+
+    /// Insert a fingerprint into this PartitionedBloomFilter.
+    #[inline]
+    pub fn insert_fingerprint(&mut self, fingerprint: BloomFingerprint) {
+        let (start, key) = self.locate(fingerprint);
+        let block = &mut self.words[start..start + self.num_hashes as usize];
+        #[cfg(all(feature = "simd", any(target_arch = "x86_64", target_arch = "aarch64")))]
+        if self.vectorized {
+            // SAFETY: `vectorized` is only set when the CPU has the
+            // vector path and blocks have `MAX_PARTITIONS` lanes.
+            unsafe { simd::insert(block, key) };
+            return;
+        }
+        for (word, salt) in block.iter_mut().zip(SALTS) {
+            *word |= lane_bit(key, salt);
+        }
+    }
+
+    /// Check if the item has been inserted into this filter.  This
+    /// function can return false positives, but not false negatives.
+    #[inline(always)]
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_fingerprint(fingerprint(item, &self.hash_builder))
+    }
+
+    /// Check if the byte slice has been inserted into this filter.
+    #[inline(always)]
+    pub fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_fingerprint(self.hash_builder.hash_one_128(item))
+    }
+
+    /// Check if the fingerprint has been inserted into this filter.
+    #[inline]
+    pub fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        let (start, key) = self.locate(fingerprint);
+        let block = &self.words[start..start + self.num_hashes as usize];
+        #[cfg(all(feature = "simd", any(target_arch = "x86_64", target_arch = "aarch64")))]
+        if self.vectorized {
+            // SAFETY: as for `insert_fingerprint`.
+            return unsafe { simd::contains(block, key) };
+        }
+        block
+            .iter()
+            .zip(SALTS)
+            .all(|(word, salt)| word & lane_bit(key, salt) != 0)
+    }
+
+    /// Remove all values from this PartitionedBloomFilter
+    #[inline(always)]
+    pub fn clear(&mut self) {
+        self.words.fill(0);
+    }
+
+    /// The first word of the fingerprint's block, and the key that
+    /// picks its bit in each partition.
+    #[inline(always)]
+    fn locate(&self, fingerprint: BloomFingerprint) -> (usize, u32) {
+        let num_blocks = (self.words.len() / self.num_hashes as usize) as u64;
+        let block = fastrange(fingerprint.h1, num_blocks) as usize;
+        (block * self.num_hashes as usize, fingerprint.h2 as u32)
+    }
+}
+
+/// The bit `key` sets in the partition salted with `salt`.  The top 5
+/// bits of the product index a 32-bit lane.
+#[inline(always)]
+fn lane_bit(key: u32, salt: u32) -> u32 {
+    1 << (key.wrapping_mul(salt) >> 27)
+}
+
+/// Whether filters with `num_hashes` partitions can use the vector
+/// path on this CPU.
+fn simd_available(num_hashes: u32) -> bool {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    let available = std::arch::is_x86_feature_detected!("avx2");
+    #[cfg(all(feature = "simd", target_arch = "aarch64"))]
+    let available = std::arch::is_aarch64_feature_detected!("neon");
+    #[cfg(not(all(feature = "simd", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+    let available = false;
+    num_hashes == MAX_PARTITIONS && available
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod simd {
+    use core::arch::x86_64::*;
+
+    use super::SALTS;
+
+    /// The bit `key` sets in each of the 8 lanes, as in `lane_bit`.
+    #[target_feature(enable = "avx2")]
+    unsafe fn mask(key: u32) -> __m256i {
+        let salts = _mm256_loadu_si256(SALTS.as_ptr().cast());
+        let product = _mm256_mullo_epi32(_mm256_set1_epi32(key as i32), salts);
+        _mm256_sllv_epi32(_mm256_set1_epi32(1), _mm256_srli_epi32::<27>(product))
+    }
+
+    /// Set `key`'s bits in `block`.
+    ///
+    /// # Safety
+    /// The CPU must support AVX2 and `block` must have 8 lanes.
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn insert(block: &mut [u32], key: u32) {
+        debug_assert_eq!(block.len(), SALTS.len());
+        let ptr = block.as_mut_ptr().cast::<__m256i>();
+        _mm256_storeu_si256(ptr, _mm256_or_si256(_mm256_loadu_si256(ptr), mask(key)));
+    }
+
+    /// Whether all of `key`'s bits are set in `block`.
+    ///
+    /// # Safety
+    /// As for `insert`.
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn contains(block: &[u32], key: u32) -> bool {
+        debug_assert_eq!(block.len(), SALTS.len());
+        _mm256_testc_si256(_mm256_loadu_si256(block.as_ptr().cast()), mask(key)) != 0
+    }
+}
+
+#[cfg(all(feature = "simd", target_arch = "aarch64"))]
+mod simd {
+    use core::arch::aarch64::*;
+
+    use super::SALTS;
+
+    /// The bits `key` sets in the 4 lanes salted with `salts`, as in
+    /// `lane_bit`.
+    #[target_feature(enable = "neon")]
+    unsafe fn mask(key: u32, salts: *const u32) -> uint32x4_t {
+        let shift = vshrq_n_u32::<27>(vmulq_u32(vdupq_n_u32(key), vld1q_u32(salts)));
+        vshlq_u32(vdupq_n_u32(1), vreinterpretq_s32_u32(shift))
+    }
+
+    /// Set `key`'s bits in `block`.
+    ///
+    /// # Safety
+    /// The CPU must support NEON and `block` must have 8 lanes.
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn insert(block: &mut [u32], key: u32) {
+        debug_assert_eq!(block.len(), SALTS.len());
+        let ptr = block.as_mut_ptr();
+        for half in [0, 4] {
+            let lanes = ptr.add(half);
+            vst1q_u32(
+                lanes,
+                vorrq_u32(vld1q_u32(lanes), mask(key, SALTS.as_ptr().add(half))),
+            );
+        }
+    }
+
+    /// Whether all of `key`'s bits are set in `block`.
+    ///
+    /// # Safety
+    /// As for `insert`.
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn contains(block: &[u32], key: u32) -> bool {
+        debug_assert_eq!(block.len(), SALTS.len());
+        let ptr = block.as_ptr();
+        let low = mask(key, SALTS.as_ptr());
+        let high = mask(key, SALTS.as_ptr().add(4));
+        let hit = vandq_u32(
+            vceqq_u32(vandq_u32(vld1q_u32(ptr), low), low),
+            vceqq_u32(vandq_u32(vld1q_u32(ptr.add(4)), high), high),
+        );
+        vminvq_u32(hit) != 0
+    }
+}
+
+impl<H> Intersectable for PartitionedBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Calculates the intersection of two PartitionedBloomFilters.
+    ///
+    /// # Panics
+    /// Panics if the filters are not using the same number of bits
+    fn intersect(&mut self, other: &PartitionedBloomFilter<H>) {
+        assert_eq!(self.words.len(), other.words.len());
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= other;
+        }
+    }
+}
+
+impl<H> Unionable for PartitionedBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Calculates the union of two PartitionedBloomFilters.
+    ///
+    /// # Panics
+    /// Panics if the filters are not using the same number of bits
+    fn union(&mut self, other: &PartitionedBloomFilter<H>) {
+        assert_eq!(self.words.len(), other.words.len());
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PartitionedBloomFilter, MAX_PARTITIONS};
+    use crate::{BloomBuildHasher, ByteKey, Intersectable, RandomXxh3State, Unionable};
+
+    #[test]
+    fn insert_contains() {
+        let mut filter = PartitionedBloomFilter::with_rate_and_hasher(
+            0.01,
+            10_000,
+            RandomXxh3State::from_seed(5),
+        );
+        for i in 0..10_000u32 {
+            filter.insert(&i);
+        }
+        assert!((0..10_000u32).all(|i| filter.contains(&i)));
+        let false_pos = (10_000..110_000u32).filter(|i| filter.contains(i)).count();
+        assert!(false_pos < 3000, "{} false positives", false_pos);
+
+        filter.insert_slice(b"bytes");
+        assert!(filter.contains(&ByteKey(b"bytes")));
+        let fp = filter.hash_builder().hash_one_128(b"bytes");
+        assert!(filter.contains_fingerprint(fp));
+
+        filter.clear();
+        assert!(!filter.contains(&0u32));
+    }
+
+    #[test]
+    fn rounds_up_to_blocks() {
+        assert_eq!(PartitionedBloomFilter::with_size(0, 8).num_bits(), 256);
+        assert_eq!(PartitionedBloomFilter::with_size(100, 3).num_bits(), 192);
+        let mut filter = PartitionedBloomFilter::with_size(1, 1);
+        filter.insert(&1);
+        assert!(filter.contains(&1));
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_hashes() {
+        PartitionedBloomFilter::with_size(256, 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn too_many_hashes() {
+        PartitionedBloomFilter::with_size(256, MAX_PARTITIONS + 1);
+    }
+
+    #[test]
+    fn vectorized_matches_scalar() {
+        let mut vector =
+            PartitionedBloomFilter::with_size_and_hasher(1 << 14, 8, RandomXxh3State::from_seed(1));
+        let mut scalar = PartitionedBloomFilter::combinable_with(&vector).scalar();
+        assert!(!scalar.is_vectorized());
+        for i in 0..1000u32 {
+            vector.insert(&i);
+            scalar.insert(&i);
+        }
+        assert_eq!(vector.words, scalar.words);
+        assert!((0..100_000u32).all(|i| vector.contains(&i) == scalar.contains(&i)));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn vectorized_with_avx2() {
+        let filter = PartitionedBloomFilter::with_size(1 << 14, 8);
+        assert_eq!(
+            filter.is_vectorized(),
+            cfg!(feature = "simd") && is_x86_feature_detected!("avx2")
+        );
+        assert!(!PartitionedBloomFilter::with_size(1 << 14, 7).is_vectorized());
+    }
+
+    #[test]
+    fn union_intersect() {
+        let mut a = PartitionedBloomFilter::with_rate(0.01, 100);
+        let mut b = PartitionedBloomFilter::combinable_with(&a);
+        a.insert(&1);
+        b.insert(&2);
+        let mut both = PartitionedBloomFilter::combinable_with(&a);
+        both.union(&a);
+        both.intersect(&b);
+        assert!(!both.contains(&1));
+        a.union(&b);
+        assert!(a.contains(&1));
+        assert!(a.contains(&2));
+    }
+}
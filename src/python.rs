@@ -0,0 +1,208 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+//! Python bindings, enabled with the `python` feature.  The `python`
+//! directory builds them into the `xx_bloom` extension module with
+//! maturin.
+//!
+//! Keys are `bytes`, hashed like `insert_slice`, so a Python lookup
+//! finds what Rust inserted with the slice methods.  Filters built with
+//! a `seed` hash like ones built in Rust with `with_rate_seeded` (or a
+//! `RandomXxh3State::from_seed` hasher), and `BloomFilter.to_bytes` is
+//! the `serialize` format, so files written on either side can be read
+//! on the other.  That format doesn't hold the hasher, so only seeded
+//! filters can be written: reading one back needs the seed.
+//!
+//! `CountingBloomFilter.to_bytes` is the filter's `serde` form encoded
+//! with `bincode` 1, secret included, so any counting filter can be
+//! written and read back without a seed.
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::hashing::HashIter;
+use crate::{BloomFilter, CountingBloomFilter, RandomXxh3State, ASMS};
+
+fn hasher(seed: Option<u64>) -> RandomXxh3State {
+    seed.map_or_else(RandomXxh3State::new, RandomXxh3State::from_seed)
+}
+
+fn value_error(err: impl ToString) -> PyErr {
+    PyValueError::new_err(err.to_string())
+}
+
+/// A Bloom filter over byte strings.
+#[pyclass(name = "BloomFilter", module = "xx_bloom")]
+pub struct PyBloomFilter {
+    inner: BloomFilter<RandomXxh3State>,
+    // Needed to read back what `to_bytes` writes.
+    seed: Option<u64>,
+}
+
+#[pymethods]
+impl PyBloomFilter {
+    /// Create a filter sized for `items` items at a false positive rate
+    /// of `rate`.  Filters with the same parameters and `seed` hash
+    /// alike; without a seed the secret is random.
+    #[new]
+    #[pyo3(signature = (rate = 0.01, items = 1000, seed = None))]
+    fn new(rate: f32, items: u32, seed: Option<u64>) -> PyResult<Self> {
+        BloomFilter::try_with_rate_and_hasher(rate, items, hasher(seed))
+            .map(|inner| PyBloomFilter { inner, seed })
+            .map_err(value_error)
+    }
+
+    /// Read a filter written by `to_bytes` (or by Rust's `to_bytes`),
+    /// hashing with the secret derived from `seed`.
+    #[staticmethod]
+    fn from_bytes(data: &[u8], seed: u64) -> PyResult<Self> {
+        BloomFilter::from_bytes(data, RandomXxh3State::from_seed(seed))
+            .map(|inner| PyBloomFilter {
+                inner,
+                seed: Some(seed),
+            })
+            .map_err(value_error)
+    }
+
+    /// The filter in the crate's binary format, little endian.  Raises
+    /// `ValueError` if the filter was built without a seed, since its
+    /// random secret isn't part of the format and it couldn't be read
+    /// back.
+    fn to_bytes<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        if self.seed.is_none() {
+            return Err(PyValueError::new_err(
+                "only filters built with a seed can be written",
+            ));
+        }
+        Ok(PyBytes::new(py, &self.inner.to_bytes_le()))
+    }
+
+    /// Insert `key`.  Returns True if it wasn't already (probably)
+    /// present.
+    fn insert(&mut self, key: &[u8]) -> bool {
+        let h_iter = HashIter::from_slice(key, self.inner.num_hashes(), self.inner.hash_builder());
+        self.inner.insert_new_hash_iter(h_iter)
+    }
+
+    /// Insert every key in `keys`, without holding the GIL while
+    /// hashing them.  Other threads keep running meanwhile, but one
+    /// that uses this filter before the insert finishes gets a
+    /// `RuntimeError`.
+    fn insert_many(&mut self, py: Python<'_>, keys: Vec<Bound<'_, PyBytes>>) {
+        // The list holds references to the (immutable) bytes, so the
+        // slices stay valid while the GIL is released.
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_bytes()).collect();
+        let inner = &mut self.inner;
+        py.detach(|| keys.iter().for_each(|key| inner.insert_slice(key)));
+    }
+
+    fn __contains__(&self, key: &[u8]) -> bool {
+        self.inner.contains_slice(key)
+    }
+
+    /// Add everything in `other`, which must have the same parameters
+    /// and seed.
+    fn union(&mut self, other: &PyBloomFilter) -> PyResult<()> {
+        self.inner.union_many(&[&other.inner]).map_err(value_error)
+    }
+
+    #[getter]
+    fn num_bits(&self) -> usize {
+        self.inner.num_bits()
+    }
+
+    #[getter]
+    fn num_hashes(&self) -> u32 {
+        self.inner.num_hashes()
+    }
+}
+
+/// A counting Bloom filter over byte strings, supporting removal and
+/// count estimates.
+#[pyclass(name = "CountingBloomFilter", module = "xx_bloom")]
+pub struct PyCountingBloomFilter {
+    inner: CountingBloomFilter<RandomXxh3State>,
+}
+
+#[pymethods]
+impl PyCountingBloomFilter {
+    /// Create a filter with `bits_per_entry` bit counters sized for
+    /// `items` items at a false positive rate of `rate`.
+    #[new]
+    #[pyo3(signature = (rate = 0.01, items = 1000, bits_per_entry = 4, seed = None))]
+    fn new(rate: f32, items: u32, bits_per_entry: usize, seed: Option<u64>) -> PyResult<Self> {
+        CountingBloomFilter::try_with_rate_and_hasher(bits_per_entry, rate, items, hasher(seed))
+            .map(|inner| PyCountingBloomFilter { inner })
+            .map_err(value_error)
+    }
+
+    /// Read a filter written by `to_bytes`.
+    #[staticmethod]
+    fn from_bytes(data: &[u8]) -> PyResult<Self> {
+        bincode::deserialize(data)
+            .map(|inner| PyCountingBloomFilter { inner })
+            .map_err(value_error)
+    }
+
+    /// The filter, counters and secret included.
+    fn to_bytes<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let bytes = bincode::serialize(&self.inner).map_err(value_error)?;
+        Ok(PyBytes::new(py, &bytes))
+    }
+
+    /// Insert `key`, returning its estimated count before the insert.
+    fn insert(&mut self, key: &[u8]) -> u32 {
+        self.inner.insert_get_count_slice(key)
+    }
+
+    /// Insert every key in `keys`, without holding the GIL while
+    /// hashing them.
+    fn insert_many(&mut self, py: Python<'_>, keys: Vec<Bound<'_, PyBytes>>) {
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_bytes()).collect();
+        let inner = &mut self.inner;
+        py.detach(|| keys.iter().for_each(|key| inner.insert_slice(key)));
+    }
+
+    /// Remove one insert of `key`, returning its estimated count before
+    /// the removal.
+    fn remove(&mut self, key: &[u8]) -> u32 {
+        self.inner.remove_slice(key)
+    }
+
+    /// The estimated number of times `key` was inserted.
+    fn count(&self, key: &[u8]) -> u32 {
+        self.inner.estimate_count_slice(key)
+    }
+
+    fn __contains__(&self, key: &[u8]) -> bool {
+        self.inner.contains_slice(key)
+    }
+
+    /// Add the counts in `other`, which must have the same parameters
+    /// and seed.
+    fn union(&mut self, other: &PyCountingBloomFilter) -> PyResult<()> {
+        self.inner.merge_sum(&other.inner).map_err(value_error)
+    }
+}
+
+/// The `xx_bloom` Python module.
+#[pymodule]
+#[pyo3(name = "xx_bloom")]
+pub fn xx_bloom_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyBloomFilter>()?;
+    m.add_class::<PyCountingBloomFilter>()?;
+    Ok(())
+}
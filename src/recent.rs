@@ -0,0 +1,52 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+//! The fingerprints most recently inserted into a filter, kept with the
+//! `debug-tracking` feature.  Without the feature the reservoir is zero
+//! sized and recording is a no-op.
+
+use crate::BloomFingerprint;
+#[cfg(feature = "debug-tracking")]
+use alloc::vec::Vec;
+
+/// How many of the latest inserted fingerprints are kept.
+#[cfg(feature = "debug-tracking")]
+pub(crate) const RECENT_CAPACITY: usize = 64;
+
+#[derive(Default)]
+pub(crate) struct RecentInserts {
+    #[cfg(feature = "debug-tracking")]
+    fingerprints: Vec<BloomFingerprint>,
+}
+
+impl RecentInserts {
+    #[inline(always)]
+    pub(crate) fn record(&mut self, _fp: BloomFingerprint) {
+        #[cfg(feature = "debug-tracking")]
+        {
+            if self.fingerprints.len() == RECENT_CAPACITY {
+                self.fingerprints.copy_within(1.., 0);
+                self.fingerprints.pop();
+            }
+            self.fingerprints.push(_fp);
+        }
+    }
+
+    /// The recorded fingerprints, oldest first.
+    #[cfg(feature = "debug-tracking")]
+    pub(crate) fn as_slice(&self) -> &[BloomFingerprint] {
+        &self.fingerprints
+    }
+}
@@ -0,0 +1,346 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+//! `serde` support, enabled with the `serde` feature.
+//!
+//! Counters are written packed, as the raw bytes of a `ValueVec` plus
+//! its bit width, rather than one integer per counter.  Hash builders
+//! are written as the secret or seed they hash with, so a filter read
+//! back hashes exactly like the one that was written.
+
+use std::collections::HashMap;
+use core::fmt;
+
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+
+use crate::bloom::check_size;
+use crate::counting::KeySpill;
+use crate::error::ParameterError;
+use crate::xxh_helper::DEFAULT_SECRET_SIZE;
+use crate::{
+    BloomFingerprint, CountingBloomFilter, RandomXxh3State, SecretBasedXxh3Builder,
+    SeededXxh3State, ValueVec,
+};
+
+// Writes a byte slice with `serialize_bytes`, which formats with a
+// native byte string type store compactly.
+struct Bytes<'a>(&'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+// Reads what `Bytes` wrote, as a byte string or a sequence of bytes.
+struct ByteBuf(Vec<u8>);
+
+impl<'de> Deserialize<'de> for ByteBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ByteBuf, D::Error> {
+        struct ByteBufVisitor;
+
+        impl<'de> Visitor<'de> for ByteBufVisitor {
+            type Value = ByteBuf;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a byte array")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<ByteBuf, E> {
+                Ok(ByteBuf(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<ByteBuf, E> {
+                Ok(ByteBuf(v))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ByteBuf, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(ByteBuf(bytes))
+            }
+        }
+
+        deserializer.deserialize_byte_buf(ByteBufVisitor)
+    }
+}
+
+fn secret_from<E: de::Error>(bytes: ByteBuf) -> Result<[u8; DEFAULT_SECRET_SIZE], E> {
+    let len = bytes.0.len();
+    bytes
+        .0
+        .try_into()
+        .map_err(|_| E::invalid_length(len, &"a 192 byte secret"))
+}
+
+impl Serialize for RandomXxh3State {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Bytes(&self.secret).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RandomXxh3State {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<RandomXxh3State, D::Error> {
+        let secret = secret_from(ByteBuf::deserialize(deserializer)?)?;
+        Ok(RandomXxh3State { secret })
+    }
+}
+
+impl Serialize for SecretBasedXxh3Builder {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Bytes(&self.secret).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretBasedXxh3Builder {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<SecretBasedXxh3Builder, D::Error> {
+        let secret = secret_from(ByteBuf::deserialize(deserializer)?)?;
+        Ok(SecretBasedXxh3Builder::with_secret(secret))
+    }
+}
+
+impl Serialize for SeededXxh3State {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.seed().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SeededXxh3State {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SeededXxh3State, D::Error> {
+        Ok(SeededXxh3State::from_seed(u64::deserialize(deserializer)?))
+    }
+}
+
+#[derive(Serialize)]
+struct ValueVecRef<'a> {
+    bits_per_val: usize,
+    count: usize,
+    bytes: Bytes<'a>,
+}
+
+#[derive(Deserialize)]
+struct ValueVecOwned {
+    bits_per_val: usize,
+    count: usize,
+    bytes: ByteBuf,
+}
+
+impl Serialize for ValueVec {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let bits_per_val = self.bits_per_val();
+        ValueVecRef {
+            bits_per_val,
+            count: self.len().checked_div(bits_per_val).unwrap_or(0),
+            bytes: Bytes(&self.to_packed_bytes()),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ValueVec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ValueVec, D::Error> {
+        let owned = ValueVecOwned::deserialize(deserializer)?;
+        ValueVec::from_packed_bytes(owned.bits_per_val, owned.count, &owned.bytes.0)
+            .ok_or_else(|| de::Error::custom("value width over 32 bits or wrong number of bytes"))
+    }
+}
+
+// Spilled keys are written as `(h1, h2, count)` triples, since formats
+// like JSON only allow string map keys.
+#[derive(Serialize, Deserialize)]
+struct KeySpillRepr {
+    capacity: usize,
+    counts: Vec<(u64, u64, u64)>,
+}
+
+#[derive(Serialize)]
+struct CountingRef<'a, H> {
+    num_entries: u64,
+    num_hashes: u32,
+    counters: &'a ValueVec,
+    overflow: Option<Vec<(usize, u32)>>,
+    spill: Option<KeySpillRepr>,
+    total_increments: u64,
+    hash_builder: &'a H,
+}
+
+#[derive(Deserialize)]
+struct CountingOwned<H> {
+    num_entries: u64,
+    num_hashes: u32,
+    counters: ValueVec,
+    overflow: Option<Vec<(usize, u32)>>,
+    spill: Option<KeySpillRepr>,
+    #[serde(default)]
+    total_increments: u64,
+    hash_builder: H,
+}
+
+impl<H: Serialize> Serialize for CountingBloomFilter<H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Sorted so the same filter always serializes the same way.
+        let overflow = self.overflow.as_ref().map(|o| {
+            let mut o: Vec<_> = o.iter().map(|(&idx, &extra)| (idx, extra)).collect();
+            o.sort_unstable();
+            o
+        });
+        let spill = self.spill.as_ref().map(|s| {
+            let mut counts: Vec<_> = s
+                .counts
+                .iter()
+                .map(|(fp, &count)| (fp.h1, fp.h2, count))
+                .collect();
+            counts.sort_unstable();
+            KeySpillRepr {
+                capacity: s.capacity,
+                counts,
+            }
+        });
+        CountingRef {
+            num_entries: self.num_entries,
+            num_hashes: self.num_hashes,
+            counters: &self.counters,
+            overflow,
+            spill,
+            total_increments: self.total_increments,
+            hash_builder: &self.hash_builder,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, H: Deserialize<'de>> Deserialize<'de> for CountingBloomFilter<H> {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<CountingBloomFilter<H>, D::Error> {
+        let owned = CountingOwned::deserialize(deserializer)?;
+        let num_entries = usize::try_from(owned.num_entries)
+            .map_err(|_| de::Error::custom(ParameterError::TooLarge))?;
+        check_size(num_entries, owned.num_hashes).map_err(de::Error::custom)?;
+        let bits_per_val = owned.counters.bits_per_val();
+        if owned.counters.len() as u64 != owned.num_entries.saturating_mul(bits_per_val as u64) {
+            return Err(de::Error::custom(
+                "number of counters doesn't match num_entries",
+            ));
+        }
+        if let Some(overflow) = &owned.overflow {
+            if overflow
+                .iter()
+                .any(|&(idx, _)| idx as u64 >= owned.num_entries)
+            {
+                return Err(de::Error::custom("overflow counter out of bounds"));
+            }
+        }
+        Ok(CountingBloomFilter {
+            counters: owned.counters,
+            overflow: owned
+                .overflow
+                .map(|o| o.into_iter().collect::<HashMap<_, _>>()),
+            spill: owned.spill.map(|s| KeySpill {
+                capacity: s.capacity,
+                counts: s
+                    .counts
+                    .into_iter()
+                    .map(|(h1, h2, count)| (BloomFingerprint::new(h1, h2), count))
+                    .collect(),
+            }),
+            total_increments: owned.total_increments,
+            num_entries: owned.num_entries,
+            num_hashes: owned.num_hashes,
+            hash_builder: owned.hash_builder,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        CountingBloomFilter, RandomXxh3State, SecretBasedXxh3Builder, SeededXxh3State, ValueVec,
+        ASMS,
+    };
+
+    #[test]
+    fn value_vec_packed() {
+        for bits in [1, 3, 4, 7, 32] {
+            let mut vv = ValueVec::new(bits, 37);
+            for i in 0..37 {
+                vv.set(i, (i as u32).wrapping_mul(2_654_435_761) & vv.max_value());
+            }
+            let json = serde_json::to_value(&vv).unwrap();
+            // One byte string, not an integer per value.
+            let bytes = json["bytes"].as_array().unwrap();
+            assert_eq!(bytes.len(), (bits * 37).div_ceil(8));
+            let read: ValueVec = serde_json::from_value(json).unwrap();
+            assert!((0..37).all(|i| read.get(i) == vv.get(i)));
+        }
+
+        let bad = serde_json::json!({"bits_per_val": 4, "count": 10, "bytes": [0, 0]});
+        assert!(serde_json::from_value::<ValueVec>(bad).is_err());
+        let wide = serde_json::json!({"bits_per_val": 33, "count": 0, "bytes": []});
+        assert!(serde_json::from_value::<ValueVec>(wide).is_err());
+    }
+
+    #[test]
+    fn counting_round_trip() {
+        // Seeded, so that "hot" shares no counter with the other items
+        // and its exact count can be checked.
+        let mut cbf =
+            CountingBloomFilter::with_rate_and_hasher(4, 0.01, 100, RandomXxh3State::from_seed(1))
+                .with_spill(4);
+        for i in 0..100u32 {
+            for _ in 0..i % 5 {
+                cbf.insert(&i);
+            }
+        }
+        for _ in 0..1000 {
+            cbf.insert(&"hot");
+        }
+
+        let json = serde_json::to_string(&cbf).unwrap();
+        let read: CountingBloomFilter = serde_json::from_str(&json).unwrap();
+        for i in 0..1000u32 {
+            assert_eq!(read.estimate_count(&i), cbf.estimate_count(&i));
+        }
+        assert_eq!(read.estimate_count(&"hot"), 1000);
+        assert_eq!(read.num_spilled_keys(), cbf.num_spilled_keys());
+        assert_eq!(serde_json::to_string(&read).unwrap(), json);
+
+        let mut overflow =
+            CountingBloomFilter::with_rate_and_hasher(2, 0.01, 10, SeededXxh3State::from_seed(3))
+                .with_overflow_spill();
+        for _ in 0..10 {
+            overflow.insert(&1);
+        }
+        let read: CountingBloomFilter<SeededXxh3State> =
+            serde_json::from_str(&serde_json::to_string(&overflow).unwrap()).unwrap();
+        assert_eq!(read.estimate_count(&1), 10);
+        assert_eq!(read.num_spilled_counters(), overflow.num_spilled_counters());
+    }
+
+    #[test]
+    fn hashers() {
+        let builder = SecretBasedXxh3Builder::with_secret([7; 192]);
+        let read: SecretBasedXxh3Builder =
+            serde_json::from_str(&serde_json::to_string(&builder).unwrap()).unwrap();
+        assert_eq!(read.secret(), builder.secret());
+        assert!(serde_json::from_str::<RandomXxh3State>("[1, 2, 3]").is_err());
+    }
+}
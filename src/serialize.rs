@@ -0,0 +1,1075 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+//! A framed, self-describing binary format for `BloomFilter`.
+//!
+//! The format is meant to be easy to read from other languages.  All
+//! integers, including the words of the bit array, are written in the
+//! byte order chosen by the writer and recorded in the header:
+//!
+//! | offset | size | field                                        |
+//! |--------|------|----------------------------------------------|
+//! | 0      | 4    | magic, the ASCII bytes `XXBF`                |
+//! | 4      | 1    | format version, currently 5                  |
+//! | 5      | 1    | byte order: 0 for little, 1 for big endian   |
+//! | 6      | 1    | index layout: 0 modulo, 1 fastrange, 2 mask  |
+//! | 7      | 1    | reserved, zero                               |
+//! | 8      | 8    | number of bits `m`, as a u64                 |
+//! | 16     | 4    | number of hashes, as a u32                   |
+//! | 20     | 4    | reserved, zero                               |
+//! | 24     | 8    | domain, as a u64                             |
+//! | 32     | 8    | approximate insertions, as a u64             |
+//! | 40     | 8    | configured capacity as a u64, 0 if none      |
+//! | 48     | 8*w  | `w = ceil(m / 64)` u64 words of bits         |
+//!
+//! The index layout selects how probe hashes map onto bits (see
+//! `IndexLayout`).  Versions 1 to 3 set bits with an older probe
+//! sequence, so their bits would give false negatives under the current
+//! one, and are rejected as unsupported.  Version 4 is version 5
+//! without the insertions and capacity, so its words start at offset
+//! 32.  It is still read, estimating the insertions from the bits.
+//!
+//! Bit `i` of the filter is bit `i % 64` of word `i / 64`, counting
+//! from the least significant bit, i.e. `(word[i / 64] >> (i % 64)) & 1`.
+//! Bits past `m` in the final word are zero.
+//!
+//! The hasher is not part of the format.  Filters are only meaningful
+//! when read back with the same hash builder (e.g. a
+//! `SecretBasedXxh3Builder` with the same secret) they were written
+//! with.
+//!
+//! `BloomFilter::diff_bytes` writes a delta instead: only the bits set
+//! since an older copy of the filter, for `apply_diff_bytes` to merge
+//! into that copy.  Its integers are always little endian:
+//!
+//! | offset | size | field                                        |
+//! |--------|------|----------------------------------------------|
+//! | 0      | 4    | magic, the ASCII bytes `XXBD`                |
+//! | 4      | 1    | delta format version, currently 1            |
+//! | 5      | 1    | index layout, as in a filter                 |
+//! | 6      | 2    | reserved, zero                               |
+//! | 8      | 8    | number of bits `m`, as a u64                 |
+//! | 16     | 4    | number of hashes, as a u32                   |
+//! | 20     | 4    | reserved, zero                               |
+//! | 24     | 8    | domain, as a u64                             |
+//! | 32     | 8    | insertions since the older copy, as a u64    |
+//! | 40     | 8    | number of bits `n` in the delta, as a u64    |
+//! | 48     |      | `n` LEB128 varints                           |
+//!
+//! The bit indices are written in increasing order, each as its gap
+//! from the previous one: the first index as is, then one less than
+//! the distance from the previous index for the rest.  A filter that
+//! grows by a few hundred items a round thus ships a couple of bytes
+//! per new bit instead of all `m / 8` bytes of the bit array.
+
+#[cfg(feature = "std")]
+use std::io;
+
+use crate::bits::Bits;
+use crate::bloom::check_size;
+
+use crate::error::{BitOutOfRange, DeserializeError, ParameterError};
+use crate::{BloomBuildHasher, BloomFilter, IndexLayout};
+use alloc::vec::Vec;
+
+const MAGIC: &[u8; 4] = b"XXBF";
+const VERSION: u8 = 5;
+const V4: u8 = 4;
+pub(crate) const HEADER_LEN: usize = 48;
+const V4_HEADER_LEN: usize = 32;
+const DELTA_MAGIC: &[u8; 4] = b"XXBD";
+const DELTA_VERSION: u8 = 1;
+const DELTA_HEADER_LEN: usize = 48;
+
+/// The byte order used for the integers of a serialized filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+impl Endianness {
+    fn flag(self) -> u8 {
+        match self {
+            Endianness::Little => 0,
+            Endianness::Big => 1,
+        }
+    }
+
+    fn from_flag(flag: u8) -> Result<Endianness, DeserializeError> {
+        match flag {
+            0 => Ok(Endianness::Little),
+            1 => Ok(Endianness::Big),
+            _ => Err(DeserializeError::UnknownEndianness(flag)),
+        }
+    }
+
+    fn u64_bytes(self, v: u64) -> [u8; 8] {
+        match self {
+            Endianness::Little => v.to_le_bytes(),
+            Endianness::Big => v.to_be_bytes(),
+        }
+    }
+
+    fn u32_bytes(self, v: u32) -> [u8; 4] {
+        match self {
+            Endianness::Little => v.to_le_bytes(),
+            Endianness::Big => v.to_be_bytes(),
+        }
+    }
+
+    pub(crate) fn read_u64(self, bytes: &[u8]) -> u64 {
+        let bytes = bytes[..8].try_into().unwrap();
+        match self {
+            Endianness::Little => u64::from_le_bytes(bytes),
+            Endianness::Big => u64::from_be_bytes(bytes),
+        }
+    }
+
+    fn read_u32(self, bytes: &[u8]) -> u32 {
+        let bytes = bytes[..4].try_into().unwrap();
+        match self {
+            Endianness::Little => u32::from_le_bytes(bytes),
+            Endianness::Big => u32::from_be_bytes(bytes),
+        }
+    }
+}
+
+fn layout_flag(layout: IndexLayout) -> u8 {
+    match layout {
+        IndexLayout::Modulo => 0,
+        IndexLayout::FastRange => 1,
+        IndexLayout::Mask => 2,
+    }
+}
+
+fn layout_from_flag(flag: u8) -> Result<IndexLayout, DeserializeError> {
+    match flag {
+        0 => Ok(IndexLayout::Modulo),
+        1 => Ok(IndexLayout::FastRange),
+        2 => Ok(IndexLayout::Mask),
+        _ => Err(DeserializeError::UnknownLayout(flag)),
+    }
+}
+
+/// Serialize a filter described by `header`, `words` being its bit
+/// array.
+pub(crate) fn write_filter<I: ExactSizeIterator<Item = u64>>(header: &Header, words: I) -> Vec<u8> {
+    let endianness = header.endianness;
+    let mut out = Vec::with_capacity(HEADER_LEN + words.len() * 8);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&[VERSION, endianness.flag(), layout_flag(header.layout), 0]);
+    out.extend_from_slice(&endianness.u64_bytes(header.num_bits as u64));
+    out.extend_from_slice(&endianness.u32_bytes(header.num_hashes));
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&endianness.u64_bytes(header.domain));
+    out.extend_from_slice(&endianness.u64_bytes(header.insertions.unwrap_or(0)));
+    out.extend_from_slice(&endianness.u64_bytes(header.capacity.unwrap_or(0)));
+    for word in words {
+        out.extend_from_slice(&endianness.u64_bytes(word));
+    }
+    out
+}
+
+impl<H> BloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Serialize this filter using the given byte order.  See the
+    /// `serialize` module for the exact layout.
+    pub fn to_bytes(&self, endianness: Endianness) -> Vec<u8> {
+        let header = Header {
+            endianness,
+            layout: self.layout(),
+            num_bits: self.num_bits(),
+            num_hashes: self.num_hashes(),
+            domain: self.domain(),
+            insertions: Some(self.approximate_insertions()),
+            capacity: self.configured_capacity(),
+            len: HEADER_LEN,
+        };
+        write_filter(&header, self.bits().words().iter().copied())
+    }
+
+    /// Shorthand for `to_bytes(Endianness::Little)`.
+    #[inline(always)]
+    pub fn to_bytes_le(&self) -> Vec<u8> {
+        self.to_bytes(Endianness::Little)
+    }
+
+    /// Shorthand for `to_bytes(Endianness::Big)`.
+    #[inline(always)]
+    pub fn to_bytes_be(&self) -> Vec<u8> {
+        self.to_bytes(Endianness::Big)
+    }
+
+    /// Read a filter written by `to_bytes` in either byte order,
+    /// hashing with `hash_builder`.  Any bytes after the filter are
+    /// ignored.
+    pub fn from_bytes(bytes: &[u8], hash_builder: H) -> Result<BloomFilter<H>, DeserializeError> {
+        let header = Header::parse(bytes)?;
+        let words = header.payload(bytes)?.chunks_exact(8);
+        let bits = Bits::from_words(
+            header.num_bits,
+            words.map(|word| header.endianness.read_u64(word)),
+        );
+        let mut filter = BloomFilter::from_parts(
+            bits,
+            header.num_hashes,
+            hash_builder,
+            header.domain,
+            header.layout,
+        )
+        .recount();
+        // The emptiness stays counted from the bits, so a header
+        // claiming no insertions can't hide any.
+        let occupancy = filter.occupancy_mut();
+        if let Some(insertions) = header.insertions {
+            occupancy.insertions = insertions;
+        }
+        occupancy.capacity = header.capacity;
+        Ok(filter)
+    }
+
+    /// Union the filter serialized in `bytes` into this one, without
+    /// building it first.  The words are ORed straight from `bytes` into
+    /// this filter's bits.  Returns true if this filter changed.
+    ///
+    /// The serialized filter must have the same number of bits, hashes,
+    /// domain and layout as this one, and like `union` must have been
+    /// built with the same hasher, which can't be checked.  On any error
+    /// this filter is left untouched.
+    pub fn union_from_bytes(&mut self, bytes: &[u8]) -> Result<bool, DeserializeError> {
+        let header = Header::parse(bytes)?;
+        header.check_matches(self)?;
+        let payload = header.payload(bytes)?;
+        let last_mask = last_word_mask(self.num_bits());
+        let mut changed = false;
+        let words = self.bits_mut().words_mut();
+        let num_words = words.len();
+        for (i, (word, src)) in words.iter_mut().zip(payload.chunks_exact(8)).enumerate() {
+            let mut src = header.endianness.read_u64(src);
+            if i + 1 == num_words {
+                src &= last_mask;
+            }
+            changed |= src & !*word != 0;
+            *word |= src;
+        }
+        header.merge_occupancy(self);
+        Ok(changed)
+    }
+
+    /// Like `union_from_bytes`, but reading the serialized filter from
+    /// `r` a chunk at a time, so it never needs to be held in memory.
+    /// Any bytes after the filter are left unread.
+    ///
+    /// Header errors are returned as `io::ErrorKind::InvalidData`
+    /// wrapping a `DeserializeError`, and a stream that ends early as
+    /// `io::ErrorKind::UnexpectedEof`.  Either way, and on any other
+    /// read error, this filter is left untouched: the words already
+    /// merged are restored from a log of the ones that changed.
+    #[cfg(feature = "std")]
+    pub fn union_from_reader<R: io::Read>(&mut self, mut r: R) -> io::Result<bool> {
+        let invalid = |err: DeserializeError| io::Error::new(io::ErrorKind::InvalidData, err);
+        let mut header = [0; HEADER_LEN];
+        r.read_exact(&mut header[..V4_HEADER_LEN])?;
+        let len = header_len(header[4]);
+        r.read_exact(&mut header[V4_HEADER_LEN..len])?;
+        let header = Header::parse(&header[..len]).map_err(invalid)?;
+        header.check_matches(self).map_err(invalid)?;
+
+        let last_mask = last_word_mask(self.num_bits());
+        let words = self.bits_mut().words_mut();
+        let num_words = words.len();
+        let mut undo = Vec::new();
+        let mut buf = [0; STREAM_CHUNK];
+        let mut start = 0;
+        while start < num_words {
+            let len = (num_words - start).min(STREAM_CHUNK / 8);
+            if let Err(err) = r.read_exact(&mut buf[..len * 8]) {
+                for (i, word) in undo {
+                    words[i] = word;
+                }
+                return Err(err);
+            }
+            for (j, src) in buf[..len * 8].chunks_exact(8).enumerate() {
+                let i = start + j;
+                let mut src = header.endianness.read_u64(src);
+                if i + 1 == num_words {
+                    src &= last_mask;
+                }
+                if src & !words[i] != 0 {
+                    undo.push((i, words[i]));
+                    words[i] |= src;
+                }
+            }
+            start += len;
+        }
+        header.merge_occupancy(self);
+        Ok(!undo.is_empty())
+    }
+
+    /// Encode the bits set in this filter but not in `base`, an older
+    /// copy of it, so that `base.apply_diff_bytes` catches the copy up.
+    /// See the `serialize` module for the format.  The delta's size
+    /// grows with `bits_added_since(base)` rather than with the filter,
+    /// which makes it the cheap way to replicate a slowly growing
+    /// filter.
+    ///
+    /// # Panics
+    /// Panics if the filters' sizes, number of hashes, domains, layouts
+    /// or hashers differ.
+    pub fn diff_bytes(&self, base: &BloomFilter<H>) -> Vec<u8> {
+        self.check_compatible(&[base])
+            .expect("can't diff incompatible filters");
+        let added = self.bits_added_since(base);
+        let mut out = Vec::with_capacity(DELTA_HEADER_LEN + added * 2);
+        out.extend_from_slice(DELTA_MAGIC);
+        out.extend_from_slice(&[DELTA_VERSION, layout_flag(self.layout()), 0, 0]);
+        out.extend_from_slice(&(self.num_bits() as u64).to_le_bytes());
+        out.extend_from_slice(&self.num_hashes().to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&self.domain().to_le_bytes());
+        let insertions = self
+            .approximate_insertions()
+            .saturating_sub(base.approximate_insertions());
+        out.extend_from_slice(&insertions.to_le_bytes());
+        out.extend_from_slice(&(added as u64).to_le_bytes());
+        let mut next = 0;
+        for bit in self.bits().ones_and_not(base.bits()) {
+            write_varint(&mut out, (bit - next) as u64);
+            next = bit + 1;
+        }
+        out
+    }
+
+    /// Merge a delta written by `diff_bytes` into this filter, which
+    /// should be the `base` it was taken against, or a copy that has
+    /// since grown further.  Returns true if this filter changed.
+    ///
+    /// The delta's insertions are only counted when it sets new bits,
+    /// so applying the same delta again, e.g. when retrying a transfer,
+    /// doesn't inflate `approximate_insertions`.
+    ///
+    /// The delta must have the same number of bits, hashes, domain and
+    /// layout as this filter, and like `union` must come from a filter
+    /// with the same hasher, which can't be checked.  On any error this
+    /// filter is left untouched.
+    pub fn apply_diff_bytes(&mut self, diff: &[u8]) -> Result<bool, DeserializeError> {
+        if diff.len() >= 4 && &diff[..4] != DELTA_MAGIC {
+            return Err(DeserializeError::BadMagic);
+        }
+        match diff.get(4) {
+            Some(&DELTA_VERSION) | None => {}
+            Some(&version) => return Err(DeserializeError::UnsupportedVersion(version)),
+        }
+        if diff.len() < DELTA_HEADER_LEN {
+            return Err(DeserializeError::Truncated {
+                expected: DELTA_HEADER_LEN,
+                actual: diff.len(),
+            });
+        }
+        let le = Endianness::Little;
+        let layout = layout_from_flag(diff[5])?;
+        if le.read_u64(&diff[8..]) != self.num_bits() as u64
+            || le.read_u32(&diff[16..]) != self.num_hashes()
+            || le.read_u64(&diff[24..]) != self.domain()
+            || layout != self.layout()
+        {
+            return Err(DeserializeError::Incompatible);
+        }
+        let insertions = le.read_u64(&diff[32..]);
+        let count = le.read_u64(&diff[40..]);
+
+        // Decode every index before setting any, so a bad delta leaves
+        // the filter as it was.
+        let num_bits = self.num_bits();
+        let mut rest = &diff[DELTA_HEADER_LEN..];
+        let mut bits = Vec::with_capacity((count as usize).min(rest.len()));
+        let mut next = 0u64;
+        for _ in 0..count {
+            let gap = read_varint(&mut rest, diff.len())?;
+            let bit = next.saturating_add(gap);
+            if bit >= num_bits as u64 {
+                return Err(DeserializeError::BitOutOfRange(BitOutOfRange {
+                    bit: usize::try_from(bit).unwrap_or(usize::MAX),
+                    num_bits,
+                }));
+            }
+            bits.push(bit as usize);
+            next = bit + 1;
+        }
+
+        let filter_bits = self.bits_mut();
+        let mut changed = false;
+        for bit in bits {
+            changed |= !filter_bits.get(bit);
+            filter_bits.set(bit);
+        }
+        if changed {
+            let occupancy = self.occupancy_mut();
+            occupancy.insertions = occupancy.insertions.saturating_add(insertions);
+        }
+        Ok(changed)
+    }
+}
+
+/// Append `v` to `out` as an LEB128 varint.
+fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+/// Read an LEB128 varint from the front of `bytes`, advancing past it.
+/// `len` is the length of the whole buffer, for reporting truncation.
+fn read_varint(bytes: &mut &[u8], len: usize) -> Result<u64, DeserializeError> {
+    let mut v = 0u64;
+    for (i, &byte) in bytes.iter().enumerate() {
+        // Saturate on overflow, so the index is rejected as out of
+        // range rather than silently wrapped.
+        let shift = 7 * i as u32;
+        let part = u64::from(byte & 0x7f);
+        v = match part.checked_shl(shift) {
+            Some(shifted) if shifted >> shift == part => v | shifted,
+            _ => u64::MAX,
+        };
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[i + 1..];
+            return Ok(v);
+        }
+    }
+    Err(DeserializeError::Truncated {
+        expected: len + 1,
+        actual: len,
+    })
+}
+
+/// The bytes read from a stream at a time by `union_from_reader`.
+const STREAM_CHUNK: usize = 4096;
+
+/// The mask of the bits of the last word that are part of a filter of
+/// `num_bits` bits.
+fn last_word_mask(num_bits: usize) -> u64 {
+    match num_bits % 64 {
+        0 => u64::MAX,
+        extra => (1 << extra) - 1,
+    }
+}
+
+// The length of the header of a filter of format `version`.  Unknown
+// versions are rejected by `Header::parse` before that matters.
+fn header_len(version: u8) -> usize {
+    match version {
+        V4 => V4_HEADER_LEN,
+        _ => HEADER_LEN,
+    }
+}
+
+/// The decoded header of a serialized filter.
+pub(crate) struct Header {
+    pub(crate) endianness: Endianness,
+    pub(crate) layout: IndexLayout,
+    pub(crate) num_bits: usize,
+    pub(crate) num_hashes: u32,
+    pub(crate) domain: u64,
+    /// None for version 4, which didn't record them.
+    pub(crate) insertions: Option<u64>,
+    pub(crate) capacity: Option<u64>,
+    /// Where the words start.
+    pub(crate) len: usize,
+}
+
+impl Header {
+    pub(crate) fn parse(bytes: &[u8]) -> Result<Header, DeserializeError> {
+        if bytes.len() >= 4 && &bytes[..4] != MAGIC {
+            return Err(DeserializeError::BadMagic);
+        }
+        let version = match bytes.get(4) {
+            Some(&version @ (VERSION | V4)) => version,
+            None => VERSION,
+            Some(&version) => return Err(DeserializeError::UnsupportedVersion(version)),
+        };
+        let len = header_len(version);
+        if bytes.len() < len {
+            return Err(DeserializeError::Truncated {
+                expected: len,
+                actual: bytes.len(),
+            });
+        }
+        let endianness = Endianness::from_flag(bytes[5])?;
+        let layout = layout_from_flag(bytes[6])?;
+        // A 32-bit usize can't hold every u64, so don't let a header
+        // claiming more bits wrap around to a small filter.
+        let num_bits = usize::try_from(endianness.read_u64(&bytes[8..]))
+            .map_err(|_| DeserializeError::InvalidParameters(ParameterError::TooLarge))?;
+        if !layout.supports(num_bits) {
+            return Err(DeserializeError::NotPowerOfTwo(num_bits as u64));
+        }
+        let num_hashes = endianness.read_u32(&bytes[16..]);
+        check_size(num_bits, num_hashes).map_err(DeserializeError::InvalidParameters)?;
+        let domain = endianness.read_u64(&bytes[24..]);
+        let (insertions, capacity) = match version {
+            V4 => (None, None),
+            _ => (
+                Some(endianness.read_u64(&bytes[32..])),
+                Some(endianness.read_u64(&bytes[40..])).filter(|&capacity| capacity > 0),
+            ),
+        };
+        Ok(Header {
+            endianness,
+            layout,
+            num_bits,
+            num_hashes,
+            domain,
+            insertions,
+            capacity,
+            len,
+        })
+    }
+
+    /// The words of the filter this header starts in `bytes`.
+    pub(crate) fn payload<'a>(&self, bytes: &'a [u8]) -> Result<&'a [u8], DeserializeError> {
+        let expected = self
+            .num_bits
+            .div_ceil(64)
+            .checked_mul(8)
+            .and_then(|len| len.checked_add(self.len))
+            .unwrap_or(usize::MAX);
+        if bytes.len() < expected {
+            return Err(DeserializeError::Truncated {
+                expected,
+                actual: bytes.len(),
+            });
+        }
+        Ok(&bytes[self.len..expected])
+    }
+
+    // Account for the serialized filter's items after unioning it into
+    // `filter`.
+    fn merge_occupancy<H: BloomBuildHasher>(&self, filter: &mut BloomFilter<H>) {
+        let occupancy = filter.occupancy_mut();
+        occupancy.insertions = occupancy.insertions.saturating_add(self.insertions.unwrap_or(0));
+    }
+
+    fn check_matches<H: BloomBuildHasher>(
+        &self,
+        filter: &BloomFilter<H>,
+    ) -> Result<(), DeserializeError> {
+        if self.num_bits != filter.num_bits()
+            || self.num_hashes != filter.num_hashes()
+            || self.domain != filter.domain()
+            || self.layout != filter.layout()
+        {
+            return Err(DeserializeError::Incompatible);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::Endianness;
+    use crate::error::{DeserializeError, ParameterError};
+    use crate::{BloomFilter, IndexLayout, RandomXxh3State, Unionable, ASMS, MAX_NUM_HASHES};
+    use rand::Rng;
+    use std::io;
+
+    fn golden_filter() -> BloomFilter {
+        // Bits 0, 1, 9, 63, 64 and 69 of a 70 bit filter.
+        let raw = [0xc0, 0x40, 0, 0, 0, 0, 0, 0x01, 0x84];
+        BloomFilter::from_raw_bits(&raw, 70, 3, RandomXxh3State::from_seed(1)).unwrap()
+    }
+
+    // The golden filter as written by version 4, before insertions and
+    // capacity were recorded.
+    const GOLDEN_V4_LE: [&[u8]; 6] = [
+        b"XXBF",
+        &[4, 0, 1, 0],
+        &[70, 0, 0, 0, 0, 0, 0, 0],
+        &[3, 0, 0, 0, 0, 0, 0, 0],
+        &[0, 0, 0, 0, 0, 0, 0, 0],
+        &[0x03, 0x02, 0, 0, 0, 0, 0, 0x80, 0x21, 0, 0, 0, 0, 0, 0, 0],
+    ];
+
+    #[test]
+    fn golden_le() {
+        // Six bits set by three hashes estimate two insertions.
+        let filter = golden_filter();
+        assert_eq!(filter.approximate_insertions(), 2);
+        let expected: Vec<u8> = [
+            &b"XXBF"[..],
+            &[5, 0, 1, 0],
+            &[70, 0, 0, 0, 0, 0, 0, 0],
+            &[3, 0, 0, 0, 0, 0, 0, 0],
+            &[0, 0, 0, 0, 0, 0, 0, 0],
+            &[2, 0, 0, 0, 0, 0, 0, 0],
+            &[0, 0, 0, 0, 0, 0, 0, 0],
+            &[0x03, 0x02, 0, 0, 0, 0, 0, 0x80],
+            &[0x21, 0, 0, 0, 0, 0, 0, 0],
+        ]
+        .concat();
+        assert_eq!(filter.to_bytes_le(), expected);
+    }
+
+    #[test]
+    fn golden_be() {
+        let expected: Vec<u8> = [
+            &b"XXBF"[..],
+            &[5, 1, 1, 0],
+            &[0, 0, 0, 0, 0, 0, 0, 70],
+            &[0, 0, 0, 3, 0, 0, 0, 0],
+            &[0, 0, 0, 0, 0, 0, 0, 0],
+            &[0, 0, 0, 0, 0, 0, 0, 2],
+            &[0, 0, 0, 0, 0, 0, 0, 0],
+            &[0x80, 0, 0, 0, 0, 0, 0x02, 0x03],
+            &[0, 0, 0, 0, 0, 0, 0, 0x21],
+        ]
+        .concat();
+        assert_eq!(golden_filter().to_bytes_be(), expected);
+    }
+
+    #[test]
+    fn read_v4() {
+        let hasher = RandomXxh3State::from_seed(1);
+        let bytes = GOLDEN_V4_LE.concat();
+        let read = BloomFilter::from_bytes(&bytes, hasher).unwrap();
+        assert_eq!(read.raw_bits(), golden_filter().raw_bits());
+        assert_eq!(read.approximate_insertions(), 2);
+        assert_eq!(read.configured_capacity(), None);
+        assert_eq!(
+            BloomFilter::from_bytes(&bytes[..30], hasher).err(),
+            Some(DeserializeError::Truncated {
+                expected: 32,
+                actual: 30
+            })
+        );
+
+        let mut filter = BloomFilter::with_size_and_hasher(70, 3, hasher);
+        assert!(filter.union_from_bytes(&bytes).unwrap());
+        assert!(!filter.union_from_reader(&bytes[..]).unwrap());
+        assert_eq!(filter.raw_bits(), read.raw_bits());
+    }
+
+    #[test]
+    fn occupancy_round_trip() {
+        let hasher = RandomXxh3State::from_seed(7);
+        let mut filter = BloomFilter::with_rate_and_hasher(0.01, 100, hasher);
+        for i in 0..150u32 {
+            filter.insert(&i);
+        }
+        let insertions = filter.approximate_insertions();
+        assert!(insertions > 140 && insertions <= 150, "{}", insertions);
+        for endianness in [Endianness::Little, Endianness::Big] {
+            let read = BloomFilter::from_bytes(&filter.to_bytes(endianness), hasher).unwrap();
+            assert_eq!(read.approximate_insertions(), insertions);
+            assert_eq!(read.configured_capacity(), Some(100));
+            assert!(read.over_capacity());
+        }
+
+        // A header claiming no insertions doesn't make the filter empty.
+        let mut bytes = filter.to_bytes_le();
+        bytes[32..40].fill(0);
+        let read = BloomFilter::from_bytes(&bytes, hasher).unwrap();
+        assert_eq!(read.approximate_insertions(), 0);
+        assert!((0..150u32).all(|i| read.contains(&i)));
+
+        let mut empty =
+            BloomFilter::with_size_and_hasher(filter.num_bits(), filter.num_hashes(), hasher);
+        assert!(!empty.contains(&1));
+        empty.union_from_bytes(&filter.to_bytes_le()).unwrap();
+        assert_eq!(empty.approximate_insertions(), insertions);
+        assert!((0..150u32).all(|i| empty.contains(&i)));
+    }
+
+    #[test]
+    fn round_trip() {
+        let hasher = RandomXxh3State::from_seed(7);
+        for num_bits in [0, 1, 31, 32, 33, 64, 100, 1000] {
+            let mut filter = BloomFilter::with_size_and_hasher(num_bits.max(1), 4, hasher);
+            for i in 0..num_bits / 4 {
+                filter.insert(&i);
+            }
+            for endianness in [Endianness::Little, Endianness::Big] {
+                let bytes = filter.to_bytes(endianness);
+                let read = BloomFilter::from_bytes(&bytes, hasher).unwrap();
+                assert_eq!(read.num_bits(), filter.num_bits());
+                assert_eq!(read.num_hashes(), 4);
+                assert_eq!(read.raw_bits(), filter.raw_bits());
+                assert!((0..num_bits / 4).all(|i| read.contains(&i)));
+            }
+        }
+    }
+
+    #[test]
+    fn domain() {
+        let hasher = RandomXxh3State::from_seed(7);
+        let mut filter = BloomFilter::with_domain(0.01, 100, hasher, 0x0102_0304_0506_0708);
+        filter.insert(&1);
+        let bytes = filter.to_bytes_be();
+        assert_eq!(&bytes[24..32], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        let read = BloomFilter::from_bytes(&bytes, hasher).unwrap();
+        assert_eq!(read.domain(), filter.domain());
+        assert!(read.contains(&1));
+    }
+
+    #[test]
+    fn reject_old_versions() {
+        // Written with an older probe sequence, so they can't be read.
+        let mut bytes = golden_filter().to_bytes_le();
+        for version in 1..=3 {
+            bytes[4] = version;
+            assert_eq!(
+                BloomFilter::from_bytes(&bytes, RandomXxh3State::from_seed(1)).err(),
+                Some(DeserializeError::UnsupportedVersion(version))
+            );
+        }
+    }
+
+    #[test]
+    fn layout_round_trip() {
+        let hasher = RandomXxh3State::from_seed(7);
+        for layout in [
+            IndexLayout::Modulo,
+            IndexLayout::FastRange,
+            IndexLayout::Mask,
+        ] {
+            let mut filter = BloomFilter::with_size_and_layout(1024, 4, hasher, layout);
+            filter.insert(&1);
+            let read = BloomFilter::from_bytes(&filter.to_bytes_le(), hasher).unwrap();
+            assert_eq!(read.layout(), layout);
+            assert!(read.contains(&1));
+        }
+    }
+
+    #[test]
+    fn bad_input() {
+        let hasher = RandomXxh3State::from_seed(7);
+        let bytes = golden_filter().to_bytes_le();
+        assert_eq!(
+            BloomFilter::from_bytes(&bytes[..56], hasher).err(),
+            Some(DeserializeError::Truncated {
+                expected: 64,
+                actual: 56
+            })
+        );
+        assert_eq!(
+            BloomFilter::from_bytes(&bytes[..40], hasher).err(),
+            Some(DeserializeError::Truncated {
+                expected: 48,
+                actual: 40
+            })
+        );
+        let mut bad = bytes.clone();
+        bad[0] = b'Y';
+        assert_eq!(
+            BloomFilter::from_bytes(&bad, hasher).err(),
+            Some(DeserializeError::BadMagic)
+        );
+        let mut bad = bytes.clone();
+        bad[4] = 9;
+        assert_eq!(
+            BloomFilter::from_bytes(&bad, hasher).err(),
+            Some(DeserializeError::UnsupportedVersion(9))
+        );
+        let mut bad = bytes.clone();
+        bad[5] = 2;
+        assert_eq!(
+            BloomFilter::from_bytes(&bad, hasher).err(),
+            Some(DeserializeError::UnknownEndianness(2))
+        );
+        let mut bad = bytes.clone();
+        bad[6] = 3;
+        assert_eq!(
+            BloomFilter::from_bytes(&bad, hasher).err(),
+            Some(DeserializeError::UnknownLayout(3))
+        );
+        let mut bad = bytes.clone();
+        bad[6] = 2;
+        assert_eq!(
+            BloomFilter::from_bytes(&bad, hasher).err(),
+            Some(DeserializeError::NotPowerOfTwo(70))
+        );
+        let mut bad = bytes.clone();
+        bad[8..16].copy_from_slice(&0u64.to_le_bytes());
+        assert_eq!(
+            BloomFilter::from_bytes(&bad, hasher).err(),
+            Some(DeserializeError::InvalidParameters(ParameterError::ZeroBits))
+        );
+        for (num_hashes, err) in [
+            (0, ParameterError::ZeroHashes),
+            (MAX_NUM_HASHES + 1, ParameterError::TooManyHashes(MAX_NUM_HASHES + 1)),
+        ] {
+            let mut bad = bytes.clone();
+            bad[16..20].copy_from_slice(&num_hashes.to_le_bytes());
+            assert_eq!(
+                BloomFilter::from_bytes(&bad, hasher).err(),
+                Some(DeserializeError::InvalidParameters(err))
+            );
+        }
+    }
+
+    // Decodes randomly corrupted and truncated filters, and random
+    // bytes behind a valid magic and version, checking that decoding
+    // never panics and whatever decodes can be used.
+    #[test]
+    fn corrupt_input() {
+        let mut rng = rand::thread_rng();
+        let hasher = RandomXxh3State::from_seed(7);
+        let mut filter = BloomFilter::with_size_and_hasher(1000, 4, hasher);
+        (0..100).for_each(|i| filter.insert(&i));
+        let valid = [filter.to_bytes_le(), filter.to_bytes_be()];
+        for _ in 0..10_000 {
+            let mut bytes = valid[rng.gen_range(0, 2)].clone();
+            match rng.gen_range(0, 3) {
+                0 => {
+                    for _ in 0..rng.gen_range(1, 4) {
+                        let i = rng.gen_range(0, bytes.len());
+                        bytes[i] ^= 1 << rng.gen_range(0, 8);
+                    }
+                }
+                1 => bytes.truncate(rng.gen_range(0, bytes.len())),
+                _ => {
+                    let len = rng.gen_range(5, 200);
+                    bytes.truncate(5);
+                    bytes.extend((5..len).map(|_| rng.gen::<u8>()));
+                }
+            }
+            if let Ok(mut read) = BloomFilter::from_bytes(&bytes, hasher) {
+                read.insert(&-1);
+                assert!(read.contains(&-1));
+            }
+            let mut into = BloomFilter::combinable_with(&filter);
+            if into.union_from_bytes(&bytes).is_err() {
+                assert_eq!(into.count_ones(), 0);
+            }
+            let mut into = BloomFilter::combinable_with(&filter);
+            let _ = into.union_from_reader(&bytes[..]);
+        }
+    }
+
+    #[test]
+    fn union_from_bytes() {
+        let hasher = RandomXxh3State::from_seed(7);
+        let shards: Vec<Vec<u8>> = (0..10u32)
+            .map(|shard| {
+                let mut filter = BloomFilter::with_size_and_hasher(10_007, 4, hasher);
+                for i in 0..200 {
+                    filter.insert(&(shard * 1000 + i));
+                }
+                let endianness = if shard % 2 == 0 {
+                    Endianness::Little
+                } else {
+                    Endianness::Big
+                };
+                filter.to_bytes(endianness)
+            })
+            .collect();
+
+        let mut expected = BloomFilter::with_size_and_hasher(10_007, 4, hasher);
+        let mut from_bytes = BloomFilter::with_size_and_hasher(10_007, 4, hasher);
+        let mut from_reader = BloomFilter::with_size_and_hasher(10_007, 4, hasher);
+        for shard in &shards {
+            expected.union(&BloomFilter::from_bytes(shard, hasher).unwrap());
+            assert!(from_bytes.union_from_bytes(shard).unwrap());
+            assert!(from_reader.union_from_reader(&shard[..]).unwrap());
+        }
+        assert_eq!(from_bytes.bits().words(), expected.bits().words());
+        assert_eq!(from_reader.bits().words(), expected.bits().words());
+        assert!(!from_bytes.union_from_bytes(&shards[3]).unwrap());
+        assert!(!from_reader.union_from_reader(&shards[3][..]).unwrap());
+        assert!((0..10u32).all(|shard| from_bytes.contains(&(shard * 1000 + 199))));
+    }
+
+    #[test]
+    fn union_from_bytes_errors() {
+        let hasher = RandomXxh3State::from_seed(7);
+        let mut other = BloomFilter::with_size_and_hasher(1000, 4, hasher);
+        (0..100).for_each(|i| other.insert(&i));
+        let bytes = other.to_bytes_le();
+        let mut filter = BloomFilter::with_size_and_hasher(1000, 4, hasher);
+        filter.insert(&1000);
+        let before = filter.raw_bits();
+
+        let wrong_hashes = BloomFilter::with_size_and_hasher(1000, 5, hasher);
+        let wrong_bytes = wrong_hashes.to_bytes_le();
+        assert_eq!(
+            filter.union_from_bytes(&wrong_bytes),
+            Err(DeserializeError::Incompatible)
+        );
+        let err = filter.union_from_reader(&wrong_bytes[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(matches!(
+            filter.union_from_bytes(truncated),
+            Err(DeserializeError::Truncated { .. })
+        ));
+        let err = filter.union_from_reader(truncated).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(filter.raw_bits(), before);
+
+        // A stream spanning several chunks that fails after some words
+        // were merged.
+        let mut big = BloomFilter::with_size_and_hasher(100_000, 4, hasher);
+        (0..10_000).for_each(|i| big.insert(&i));
+        let bytes = big.to_bytes_le();
+        let mut filter = BloomFilter::with_size_and_hasher(100_000, 4, hasher);
+        filter.insert(&-1);
+        let before = filter.raw_bits();
+        assert!(filter.union_from_reader(&bytes[..10_000]).is_err());
+        assert_eq!(filter.raw_bits(), before);
+        assert!(filter.union_from_reader(&bytes[..]).unwrap());
+        assert!(filter.contains(&9999));
+    }
+
+    #[test]
+    fn num_bits_beyond_usize() {
+        let mut bytes = BloomFilter::with_size(64, 3).to_bytes_le();
+        bytes[8..16].copy_from_slice(&((1u64 << 32) + 64).to_le_bytes());
+        let err = BloomFilter::from_bytes(&bytes, RandomXxh3State::from_seed(1)).err();
+        if cfg!(target_pointer_width = "32") {
+            assert_eq!(
+                err,
+                Some(DeserializeError::InvalidParameters(ParameterError::TooLarge))
+            );
+        } else {
+            assert!(matches!(err, Some(DeserializeError::Truncated { .. })), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn diff_round_trip() {
+        let hasher = RandomXxh3State::from_seed(21);
+        let mut primary = BloomFilter::with_rate_and_hasher(0.01, 5000, hasher);
+        let mut replica = BloomFilter::combinable_with(&primary);
+        let mut base = primary.fork();
+        let mut rng = rand::thread_rng();
+        for batch in 0..5u32 {
+            for _ in 0..200 {
+                primary.insert(&rng.gen::<u64>());
+            }
+            let diff = primary.diff_bytes(&base);
+            let added = primary.bits_added_since(&base);
+            assert!(added > 0);
+            assert!(diff.len() < primary.to_bytes_le().len() / 2, "batch {}", batch);
+            assert!(replica.apply_diff_bytes(&diff).unwrap());
+            assert_eq!(replica.bits().words(), primary.bits().words());
+            assert_eq!(
+                replica.approximate_insertions(),
+                primary.approximate_insertions()
+            );
+            // Applying it again changes nothing, not even the count.
+            assert!(!replica.apply_diff_bytes(&diff).unwrap());
+            assert_eq!(
+                replica.approximate_insertions(),
+                primary.approximate_insertions()
+            );
+            replica = primary.fork();
+            base = primary.fork();
+        }
+
+        let empty = primary.diff_bytes(&base);
+        assert_eq!(empty.len(), 48);
+        assert!(!replica.apply_diff_bytes(&empty).unwrap());
+
+        // Bits past the first word's worth of gap need multi-byte varints.
+        let mut sparse = BloomFilter::with_size_and_hasher(100_000, 1, hasher);
+        let fresh = BloomFilter::combinable_with(&sparse);
+        sparse.insert(&1u32);
+        sparse.insert(&2u32);
+        let mut copy = BloomFilter::combinable_with(&sparse);
+        assert!(copy.apply_diff_bytes(&sparse.diff_bytes(&fresh)).unwrap());
+        assert_eq!(copy.bits().words(), sparse.bits().words());
+    }
+
+    #[test]
+    #[should_panic]
+    fn diff_incompatible() {
+        let a = BloomFilter::with_size_and_hasher(1000, 4, RandomXxh3State::from_seed(1));
+        let b = BloomFilter::with_size_and_hasher(1000, 3, RandomXxh3State::from_seed(1));
+        a.diff_bytes(&b);
+    }
+
+    #[test]
+    fn apply_diff_errors() {
+        let hasher = RandomXxh3State::from_seed(22);
+        let mut a = BloomFilter::with_size_and_hasher(1000, 4, hasher);
+        let base = a.fork();
+        for i in 0..20u32 {
+            a.insert(&i);
+        }
+        let diff = a.diff_bytes(&base);
+        let mut target = base.fork();
+
+        let mut bad = diff.clone();
+        bad[0] = b'Y';
+        assert_eq!(target.apply_diff_bytes(&bad), Err(DeserializeError::BadMagic));
+        assert_eq!(
+            target.apply_diff_bytes(&a.to_bytes_le()),
+            Err(DeserializeError::BadMagic)
+        );
+        let mut bad = diff.clone();
+        bad[4] = 2;
+        assert_eq!(
+            target.apply_diff_bytes(&bad),
+            Err(DeserializeError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            target.apply_diff_bytes(&diff[..47]),
+            Err(DeserializeError::Truncated {
+                expected: 48,
+                actual: 47
+            })
+        );
+        assert!(matches!(
+            target.apply_diff_bytes(&diff[..diff.len() - 1]),
+            Err(DeserializeError::Truncated { .. })
+        ));
+
+        let mut other = BloomFilter::with_size_and_hasher(1000, 3, hasher);
+        assert_eq!(
+            other.apply_diff_bytes(&diff),
+            Err(DeserializeError::Incompatible)
+        );
+
+        // One bit at 1000, just past the end.
+        let mut bad = diff[..48].to_vec();
+        bad[40..48].copy_from_slice(&1u64.to_le_bytes());
+        bad.extend_from_slice(&[0xe8, 0x07]);
+        assert_eq!(
+            target.apply_diff_bytes(&bad),
+            Err(DeserializeError::BitOutOfRange(crate::error::BitOutOfRange {
+                bit: 1000,
+                num_bits: 1000
+            }))
+        );
+        // An overlong varint saturates rather than wrapping into range.
+        let mut bad = diff[..48].to_vec();
+        bad[40..48].copy_from_slice(&1u64.to_le_bytes());
+        bad.extend_from_slice(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x02]);
+        assert!(matches!(
+            target.apply_diff_bytes(&bad),
+            Err(DeserializeError::BitOutOfRange(_))
+        ));
+
+        assert_eq!(target.bits().words(), base.bits().words());
+        assert_eq!(target.approximate_insertions(), 0);
+        assert!(target.apply_diff_bytes(&diff).unwrap());
+        assert_eq!(target.bits().words(), a.bits().words());
+    }
+}
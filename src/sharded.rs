@@ -0,0 +1,225 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+use core::ops::{Deref, DerefMut};
+
+use crate::hashing::fingerprint;
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher, BloomFilter, BloomFingerprint, Unionable, ASMS};
+
+/// A BloomFilter split into identically configured shards so that
+/// several threads can insert without sharing any state.
+///
+/// Every shard is sized for the total number of items and shares one
+/// hasher, so the shards can be unioned into a single filter with
+/// `merge` once the workers are done.  Until then lookups probe every
+/// shard.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::{ASMS,ShardedBloomFilter};
+///
+/// let mut sharded = ShardedBloomFilter::new(0.01, 1000, 4);
+/// std::thread::scope(|s| {
+///     for (i, mut shard) in sharded.handles().into_iter().enumerate() {
+///         s.spawn(move || shard.insert(&i));
+///     }
+/// });
+/// assert!(sharded.contains(&3usize));
+/// let merged = sharded.merge();
+/// assert!(merged.contains(&0usize));
+/// ```
+pub struct ShardedBloomFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    shards: Vec<BloomFilter<H>>,
+}
+
+/// Exclusive access to one shard of a `ShardedBloomFilter`, which can
+/// be sent to the worker thread that fills it.
+pub struct ShardHandle<'a, H>
+where
+    H: BloomBuildHasher,
+{
+    index: usize,
+    shard: &'a mut BloomFilter<H>,
+}
+
+impl<H> ShardHandle<'_, H>
+where
+    H: BloomBuildHasher,
+{
+    /// The index of this shard.
+    #[inline(always)]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl<H> Deref for ShardHandle<'_, H>
+where
+    H: BloomBuildHasher,
+{
+    type Target = BloomFilter<H>;
+
+    fn deref(&self) -> &BloomFilter<H> {
+        self.shard
+    }
+}
+
+impl<H> DerefMut for ShardHandle<'_, H>
+where
+    H: BloomBuildHasher,
+{
+    fn deref_mut(&mut self) -> &mut BloomFilter<H> {
+        self.shard
+    }
+}
+
+impl ShardedBloomFilter<RandomXxh3State> {
+    /// Create `num_shards` shards that together expect to hold
+    /// `expected_num_items`.  The merged filter will have a false
+    /// positive rate of the value specified in `rate`.
+    pub fn new(
+        rate: f32,
+        expected_num_items: u32,
+        num_shards: usize,
+    ) -> ShardedBloomFilter<RandomXxh3State> {
+        ShardedBloomFilter::with_hasher(
+            rate,
+            expected_num_items,
+            num_shards,
+            RandomXxh3State::new(),
+        )
+    }
+}
+
+impl<H> ShardedBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Like `new`, but every shard hashes items using `hash_builder`.
+    ///
+    /// # Panics
+    /// Panics if `num_shards` is 0.
+    pub fn with_hasher(
+        rate: f32,
+        expected_num_items: u32,
+        num_shards: usize,
+        hash_builder: H,
+    ) -> ShardedBloomFilter<H> {
+        assert!(num_shards > 0, "need at least one shard");
+        let first = BloomFilter::with_rate_and_hasher(rate, expected_num_items, hash_builder);
+        let mut shards = Vec::with_capacity(num_shards);
+        shards.extend((1..num_shards).map(|_| BloomFilter::combinable_with(&first)));
+        shards.insert(0, first);
+        ShardedBloomFilter { shards }
+    }
+
+    /// The number of shards.
+    #[inline(always)]
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Get a handle to every shard, in index order, to hand out to
+    /// workers.
+    pub fn handles(&mut self) -> Vec<ShardHandle<'_, H>> {
+        self.shards
+            .iter_mut()
+            .enumerate()
+            .map(|(index, shard)| ShardHandle { index, shard })
+            .collect()
+    }
+
+    /// Get a handle to the shard at `index`.
+    ///
+    /// # Panics
+    /// Panics if `index` is out of bounds.
+    pub fn shard_mut(&mut self, index: usize) -> ShardHandle<'_, H> {
+        ShardHandle {
+            index,
+            shard: &mut self.shards[index],
+        }
+    }
+
+    /// Check if any shard contains `item`.
+    #[inline(always)]
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_fingerprint(fingerprint(item, self.shards[0].hash_builder()))
+    }
+
+    /// Check if any shard contains the byte slice `item`.
+    #[inline(always)]
+    pub fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_fingerprint(self.shards[0].hash_builder().hash_one_128(item))
+    }
+
+    /// Check if any shard contains the fingerprint.
+    pub fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        self.shards
+            .iter()
+            .any(|shard| shard.contains_fingerprint(fingerprint))
+    }
+
+    /// Union every shard into a single filter.
+    pub fn merge(self) -> BloomFilter<H> {
+        let mut shards = self.shards.into_iter();
+        let mut merged = shards.next().unwrap();
+        for shard in shards {
+            merged.union(&shard);
+        }
+        merged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShardedBloomFilter;
+    use crate::ASMS;
+
+    #[test]
+    fn threads_fill_shards() {
+        let shards = 8u32;
+        let per_shard = 10_000u32;
+        let mut sharded = ShardedBloomFilter::new(0.01, shards * per_shard, shards as usize);
+        std::thread::scope(|s| {
+            for mut shard in sharded.handles() {
+                s.spawn(move || {
+                    let start = shard.index() as u32 * per_shard;
+                    for i in start..start + per_shard {
+                        shard.insert(&i);
+                    }
+                });
+            }
+        });
+
+        assert!((0..shards * per_shard).all(|i| sharded.contains(&i)));
+        let merged = sharded.merge();
+        assert!((0..shards * per_shard).all(|i| merged.contains(&i)));
+    }
+
+    #[test]
+    fn shard_mut() {
+        let mut sharded = ShardedBloomFilter::new(0.01, 100, 2);
+        sharded.shard_mut(1).insert_slice(b"hello");
+        assert!(sharded.contains_slice(b"hello"));
+        assert!(!sharded.contains_slice(b"world"));
+        assert_eq!(sharded.num_shards(), 2);
+    }
+}
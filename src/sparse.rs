@@ -0,0 +1,319 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use std::collections::HashMap;
+use core::hash::Hash;
+use core::mem;
+
+use crate::bits::Bits;
+use crate::hashing::{fingerprint, probeable, HashIter};
+use crate::xxh_helper::RandomXxh3State;
+use crate::{
+    needed_bits, optimal_num_hashes, BloomBuildHasher, BloomFilter, BloomFingerprint, IndexLayout,
+    ASMS,
+};
+
+// 4 KiB of bits per block.
+const BLOCK_WORDS: usize = 4096 / 8;
+const BLOCK_BITS: usize = BLOCK_WORDS * 64;
+
+type Block = Box<[u64; BLOCK_WORDS]>;
+
+/// A BloomFilter for huge filters that stay mostly empty, which only
+/// allocates the 4 KiB blocks of bits that have a bit set.
+///
+/// Bits are addressed exactly like a `BloomFilter` of the same size,
+/// number of hashes and hasher, so `densify` turns it into one that
+/// answers every lookup the same way.  Lookups whose probes land in a
+/// block that was never written answer false without touching memory
+/// beyond the block table.  Each block costs 4 KiB plus a table entry,
+/// so once more than a few percent of blocks are written a dense filter
+/// is smaller; `memory_usage` reports the current cost.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::{ASMS,SparseBloomFilter};
+///
+/// // 8 GiB of bits if dense.
+/// let mut filter = SparseBloomFilter::with_size(1 << 36, 7);
+/// filter.insert(&1);
+/// assert!(filter.contains(&1));
+/// assert!(!filter.contains(&2));
+/// assert!(filter.num_blocks() <= 7);
+/// ```
+pub struct SparseBloomFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    blocks: HashMap<usize, Block>,
+    num_bits: usize,
+    num_hashes: u32,
+    hash_builder: H,
+}
+
+impl SparseBloomFilter<RandomXxh3State> {
+    /// Create a new SparseBloomFilter with the specified number of bits,
+    /// and hashes
+    pub fn with_size(num_bits: usize, num_hashes: u32) -> SparseBloomFilter<RandomXxh3State> {
+        SparseBloomFilter::with_size_and_hasher(num_bits, num_hashes, RandomXxh3State::new())
+    }
+
+    /// create a SparseBloomFilter that expects to hold
+    /// `expected_num_items`.  The filter will be sized to have a
+    /// false positive rate of the value specified in `rate`.
+    pub fn with_rate(rate: f32, expected_num_items: u32) -> SparseBloomFilter<RandomXxh3State> {
+        SparseBloomFilter::with_rate_and_hasher(rate, expected_num_items, RandomXxh3State::new())
+    }
+}
+
+impl<H> SparseBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Create a new SparseBloomFilter with the specified number of bits,
+    /// hashes, and HashBuilder.
+    pub fn with_size_and_hasher(
+        num_bits: usize,
+        num_hashes: u32,
+        hash_builder: H,
+    ) -> SparseBloomFilter<H> {
+        SparseBloomFilter {
+            blocks: HashMap::new(),
+            num_bits,
+            num_hashes,
+            hash_builder,
+        }
+    }
+
+    /// Create a SparseBloomFilter that expects to hold
+    /// `expected_num_items` with a false positive rate of `rate`,
+    /// hashing items with `hash_builder`.
+    pub fn with_rate_and_hasher(
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> SparseBloomFilter<H> {
+        let bits = needed_bits(rate, expected_num_items);
+        SparseBloomFilter::with_size_and_hasher(
+            bits,
+            optimal_num_hashes(bits, expected_num_items),
+            hash_builder,
+        )
+    }
+
+    /// Get the number of bits this SparseBloomFilter is using, whether
+    /// or not they are allocated.
+    #[inline(always)]
+    pub fn num_bits(&self) -> usize {
+        self.num_bits
+    }
+
+    /// Get the number of hash functions this SparseBloomFilter is using
+    #[inline(always)]
+    pub fn num_hashes(&self) -> u32 {
+        self.num_hashes
+    }
+
+    /// Get the hash builder this SparseBloomFilter is using
+    #[inline(always)]
+    pub fn hash_builder(&self) -> &H {
+        &self.hash_builder
+    }
+
+    /// The number of 4 KiB blocks that have been allocated.
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Roughly how many bytes the filter is using: its allocated blocks
+    /// plus the table that finds them.
+    pub fn memory_usage(&self) -> usize {
+        let entry = mem::size_of::<(usize, Block)>() + 1;
+        self.blocks.len() * mem::size_of::<[u64; BLOCK_WORDS]>() + self.blocks.capacity() * entry
+    }
+
+    /// The number of bytes a dense `BloomFilter` of the same size would
+    /// use for its bits.
+    pub fn dense_memory_usage(&self) -> usize {
+        self.num_bits.div_ceil(64) * 8
+    }
+
+    /// Copy the filter into a dense `BloomFilter` that answers every
+    /// lookup the same way.
+    pub fn densify(&self) -> BloomFilter<H> {
+        let mut bits = Bits::new(self.num_bits);
+        let words = bits.words_mut();
+        for (&block, data) in &self.blocks {
+            let start = block * BLOCK_WORDS;
+            let end = (start + BLOCK_WORDS).min(words.len());
+            words[start..end].copy_from_slice(&data[..end - start]);
+        }
+        BloomFilter::from_parts(
+            bits,
+            self.num_hashes,
+            self.hash_builder.clone(),
+            0,
+            IndexLayout::default(),
+        )
+        .recount()
+    }
+
+    #[inline(always)]
+    fn index(&self, h: u64) -> usize {
+        IndexLayout::default().index(h, self.num_bits)
+    }
+
+    #[inline(always)]
+    fn probeable(&self) -> bool {
+        probeable(self.num_bits as u64)
+    }
+
+    fn insert_hash_iter(&mut self, h_iter: HashIter) {
+        if !self.probeable() {
+            return;
+        }
+        for h in h_iter {
+            let idx = self.index(h);
+            let block = self
+                .blocks
+                .entry(idx / BLOCK_BITS)
+                .or_insert_with(|| Box::new([0; BLOCK_WORDS]));
+            let bit = idx % BLOCK_BITS;
+            block[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    fn contains_hash_iter(&self, mut h_iter: HashIter) -> bool {
+        if !self.probeable() {
+            return false;
+        }
+        h_iter.all(|h| {
+            let idx = self.index(h);
+            let bit = idx % BLOCK_BITS;
+            self.blocks
+                .get(&(idx / BLOCK_BITS))
+                .is_some_and(|block| block[bit / 64] & (1 << (bit % 64)) != 0)
+        })
+    }
+}
+
+impl<H> ASMS for SparseBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Insert item into this SparseBloomFilter.
+    #[inline(always)]
+    fn insert<T: Hash>(&mut self, item: &T) {
+        self.insert_hash_iter(HashIter::from_fingerprint(
+            fingerprint(item, &self.hash_builder),
+            self.num_hashes,
+        ))
+    }
+
+    /// Insert the byte slice `item` into this SparseBloomFilter.
+    #[inline(always)]
+    fn insert_slice(&mut self, item: &[u8]) {
+        self.insert_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Insert a fingerprint into this SparseBloomFilter.
+    #[inline(always)]
+    fn insert_fingerprint(&mut self, fingerprint: BloomFingerprint) {
+        self.insert_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Check if the item has been inserted into this SparseBloomFilter.
+    #[inline(always)]
+    fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Check if the byte slice `item` has been inserted into this
+    /// SparseBloomFilter.
+    #[inline(always)]
+    fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Check if the fingerprint has been inserted into this
+    /// SparseBloomFilter.
+    #[inline(always)]
+    fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        self.contains_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Remove all values from this SparseBloomFilter, freeing every
+    /// block.
+    fn clear(&mut self) {
+        self.blocks = HashMap::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SparseBloomFilter;
+    use crate::{BloomBuildHasher, BloomFilter, RandomXxh3State, ASMS};
+
+    #[test]
+    fn densify_matches_dense() {
+        let hasher = RandomXxh3State::from_seed(9);
+        // Not a multiple of the block size, so the last block is partial.
+        let num_bits = (1 << 28) + 12_345;
+        let mut sparse = SparseBloomFilter::with_size_and_hasher(num_bits, 7, hasher);
+        let mut dense = BloomFilter::with_size_and_hasher(num_bits, 7, hasher);
+        for i in 0..100u32 {
+            sparse.insert(&i);
+            dense.insert(&i);
+        }
+        sparse.insert_fingerprint(hasher.hash_one_128(b"fp"));
+        dense.insert_fingerprint(hasher.hash_one_128(b"fp"));
+
+        assert!(sparse.num_blocks() <= 707);
+        assert!(sparse.memory_usage() < sparse.dense_memory_usage() / 8);
+        for i in 0..100_000u32 {
+            assert_eq!(sparse.contains(&i), dense.contains(&i));
+        }
+        assert!(sparse.contains_slice(b"fp"));
+        assert!(!sparse.contains_slice(b"other"));
+
+        let densified = sparse.densify();
+        assert_eq!(densified.layout(), dense.layout());
+        assert_eq!(densified.bits().words(), dense.bits().words());
+
+        sparse.clear();
+        assert_eq!(sparse.num_blocks(), 0);
+        assert!(!sparse.contains(&1u32));
+    }
+
+    #[test]
+    fn last_bit() {
+        let hasher = RandomXxh3State::from_seed(10);
+        let mut sparse = SparseBloomFilter::with_size_and_hasher(100, 100, hasher);
+        let mut dense = BloomFilter::with_size_and_hasher(100, 100, hasher);
+        sparse.insert(&1u32);
+        dense.insert(&1u32);
+        assert_eq!(sparse.num_blocks(), 1);
+        assert_eq!(sparse.densify().bits().words(), dense.bits().words());
+    }
+}
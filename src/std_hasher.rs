@@ -1,6 +1,7 @@
 use std::{
     collections::hash_map::RandomState,
     hash::{BuildHasher, Hasher},
+    io,
 };
 
 use crate::{BloomBuildHasher, BloomFingerprint, BloomHasher};
@@ -165,4 +166,12 @@ where
     fn hash_one_128(&self, k: &[u8]) -> BloomFingerprint {
         BloomFingerprint::new(self.h1.hash_one(k), self.h2.hash_one(k))
     }
+
+    // `hash_one` prefixes a slice with its length, so the contents have
+    // to be read in full before hashing can start.
+    fn hash_reader_128<Rd: io::Read>(&self, mut r: Rd) -> io::Result<BloomFingerprint> {
+        let mut contents = Vec::new();
+        r.read_to_end(&mut contents)?;
+        Ok(self.hash_one_128(&contents))
+    }
 }
@@ -0,0 +1,195 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+//! Where a `BloomFilter` keeps its bits.
+//!
+//! A filter is generic over its bit storage so the same insert and
+//! lookup code can run over other backends, e.g. lookups served from
+//! memory it doesn't own, such as a memory mapped file (see `MmapBits`
+//! behind the `memmap2` feature).  `BitStorage` is all lookups need;
+//! storage that can also be written implements `BitStorageMut`, which
+//! is what inserting needs.  Filters default to the in-memory `Bits`.
+
+pub use crate::bits::Bits;
+
+/// Read access to a fixed size array of bits.
+pub trait BitStorage {
+    /// The number of bits.
+    fn len(&self) -> usize;
+
+    /// Returns true if there are no bits.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get bit `i`.
+    ///
+    /// # Panics
+    /// Panics if `i` is out of bounds.
+    fn get(&self, i: usize) -> bool;
+
+    /// Get bit `i`, skipping the bounds check where the storage can.
+    ///
+    /// # Safety
+    /// `i` must be less than `len()`.
+    #[inline(always)]
+    unsafe fn get_unchecked(&self, i: usize) -> bool {
+        self.get(i)
+    }
+
+    /// The number of bits that are set.  The default checks every bit
+    /// in turn; storage that can count a word at a time should override
+    /// it.
+    fn count_ones(&self) -> usize {
+        (0..self.len()).filter(|&i| self.get(i)).count()
+    }
+}
+
+/// Write access to a fixed size array of bits.
+pub trait BitStorageMut: BitStorage {
+    /// Set bit `i`.
+    ///
+    /// # Panics
+    /// Panics if `i` is out of bounds.
+    fn set(&mut self, i: usize);
+
+    /// Set bit `i`, skipping the bounds check where the storage can.
+    ///
+    /// # Safety
+    /// `i` must be less than `len()`.
+    #[inline(always)]
+    unsafe fn set_unchecked(&mut self, i: usize) {
+        self.set(i)
+    }
+
+    /// Set bit `i`, returning whether it was already set, and skipping
+    /// the bounds check where the storage can.
+    ///
+    /// # Safety
+    /// `i` must be less than `len()`.
+    #[inline(always)]
+    unsafe fn replace_unchecked(&mut self, i: usize) -> bool {
+        // SAFETY: the caller guarantees `i < len`.
+        unsafe {
+            let old = self.get_unchecked(i);
+            self.set_unchecked(i);
+            old
+        }
+    }
+
+    /// Clear every bit.
+    fn clear(&mut self);
+}
+
+impl BitStorage for Bits {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        Bits::len(self)
+    }
+
+    #[inline(always)]
+    fn get(&self, i: usize) -> bool {
+        Bits::get(self, i)
+    }
+
+    #[inline(always)]
+    unsafe fn get_unchecked(&self, i: usize) -> bool {
+        // SAFETY: the caller guarantees `i < len`.
+        unsafe { Bits::get_unchecked(self, i) }
+    }
+
+    #[inline(always)]
+    fn count_ones(&self) -> usize {
+        Bits::count_ones(self)
+    }
+}
+
+impl BitStorageMut for Bits {
+    #[inline(always)]
+    fn set(&mut self, i: usize) {
+        Bits::set(self, i)
+    }
+
+    #[inline(always)]
+    unsafe fn set_unchecked(&mut self, i: usize) {
+        // SAFETY: the caller guarantees `i < len`.
+        unsafe { Bits::set_unchecked(self, i) }
+    }
+
+    #[inline(always)]
+    unsafe fn replace_unchecked(&mut self, i: usize) -> bool {
+        // SAFETY: the caller guarantees `i < len`.
+        unsafe { Bits::replace_unchecked(self, i) }
+    }
+
+    #[inline(always)]
+    fn clear(&mut self) {
+        Bits::clear(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BitStorage, BitStorageMut};
+    use crate::{BloomFilter, RandomXxh3State, ASMS};
+
+    // A deliberately naive backend that only has the required methods.
+    struct BoolVec(Vec<bool>);
+
+    impl BitStorage for BoolVec {
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn get(&self, i: usize) -> bool {
+            self.0[i]
+        }
+    }
+
+    impl BitStorageMut for BoolVec {
+        fn set(&mut self, i: usize) {
+            self.0[i] = true;
+        }
+
+        fn clear(&mut self) {
+            self.0.fill(false);
+        }
+    }
+
+    #[test]
+    fn custom_storage() {
+        let hasher = RandomXxh3State::from_seed(5);
+        let mut bits = BloomFilter::with_size_and_hasher(1000, 4, hasher);
+        let mut custom =
+            BloomFilter::with_storage_and_hasher(BoolVec(vec![false; 1000]), 4, hasher);
+        for i in 0..100u32 {
+            bits.insert(&i);
+            custom.insert(&i);
+        }
+        custom.insert_slice(b"slice");
+        bits.insert_slice(b"slice");
+
+        assert_eq!(custom.count_ones(), bits.count_ones());
+        assert_eq!(custom.estimate_cardinality(), bits.estimate_cardinality());
+        for i in 0..1000u32 {
+            assert_eq!(custom.contains(&i), bits.contains(&i));
+        }
+        assert!(custom.contains_slice(b"slice"));
+
+        custom.clear();
+        assert_eq!(custom.count_ones(), 0);
+        assert!(!custom.contains(&1));
+    }
+}
@@ -0,0 +1,506 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::bloom::{needed_bits, optimal_num_hashes};
+use crate::hashing::{fastrange, probeable, HashIter};
+use crate::valuevec::ValueVec;
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher, BloomFingerprint};
+
+/// A CountingBloomFilter shared between threads by dividing its counters
+/// into lock-protected stripes.
+///
+/// Counter `i` lives in stripe `i % num_stripes`.  Every operation
+/// locks just the stripes its probe positions fall into, always in
+/// ascending stripe order so that concurrent operations can't deadlock,
+/// and then applies all of its updates while holding them.
+///
+/// Compared to `AtomicCountingBloomFilter`, whose compare-exchange
+/// loops can spin for a long time when many threads hammer the counters
+/// of the same hot key, threads waiting on a stripe block and make
+/// progress in turn.  Each operation is also atomic as a whole, so e.g.
+/// a `remove` can't interleave with an `insert` of another key sharing
+/// a counter.  The cost is taking up to `num_hashes` locks per
+/// operation, which is slower when contention is low; more stripes
+/// means less contention but more memory for the locks.
+///
+/// Addressing matches `CountingBloomFilter`, so a filter built with the
+/// same parameters and hasher reports the same estimates once all
+/// updates have finished.  Saturated counters likewise stay saturated
+/// when items are removed.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::StripedCountingBloomFilter;
+///
+/// let filter = StripedCountingBloomFilter::with_rate(4, 0.01, 1000, 16);
+/// std::thread::scope(|s| {
+///     s.spawn(|| filter.insert(&1));
+///     s.spawn(|| filter.insert(&1));
+/// });
+/// assert_eq!(filter.estimate_count(&1), 2);
+/// assert_eq!(filter.remove(&1), 2);
+/// assert_eq!(filter.estimate_count(&1), 1);
+/// ```
+pub struct StripedCountingBloomFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    stripes: Box<[Mutex<ValueVec>]>,
+    num_entries: u64,
+    max_value: u32,
+    num_hashes: u32,
+    hash_builder: H,
+}
+
+/// The stripes touched by one operation, locked, along with where each
+/// probe lands: `(index into guards, index within the stripe)`.
+struct Locked<'a> {
+    guards: Vec<MutexGuard<'a, ValueVec>>,
+    probes: Vec<(usize, usize)>,
+}
+
+impl Locked<'_> {
+    fn min_counter(&self) -> u32 {
+        self.probes
+            .iter()
+            .map(|&(g, i)| self.guards[g].get(i))
+            .fold(u32::MAX, |min, cur| min.min(cur))
+    }
+}
+
+impl StripedCountingBloomFilter<RandomXxh3State> {
+    /// Create a new StripedCountingBloomFilter that will hold
+    /// `num_entries` items, uses `bits_per_entry` per item,
+    /// `num_hashes` hashes and `num_stripes` locks
+    pub fn with_size(
+        num_entries: usize,
+        bits_per_entry: usize,
+        num_hashes: u32,
+        num_stripes: usize,
+    ) -> StripedCountingBloomFilter<RandomXxh3State> {
+        StripedCountingBloomFilter::with_size_and_hasher(
+            num_entries,
+            bits_per_entry,
+            num_hashes,
+            num_stripes,
+            RandomXxh3State::new(),
+        )
+    }
+
+    /// create a StripedCountingBloomFilter that uses `bits_per_entry`
+    /// entries, `num_stripes` locks and expects to hold
+    /// `expected_num_items`.  The filter will be sized to have a false
+    /// positive rate of the value specified in `rate`.
+    pub fn with_rate(
+        bits_per_entry: usize,
+        rate: f32,
+        expected_num_items: u32,
+        num_stripes: usize,
+    ) -> StripedCountingBloomFilter<RandomXxh3State> {
+        StripedCountingBloomFilter::with_rate_and_hasher(
+            bits_per_entry,
+            rate,
+            expected_num_items,
+            num_stripes,
+            RandomXxh3State::new(),
+        )
+    }
+}
+
+impl<H> StripedCountingBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Create a new StripedCountingBloomFilter with the specified
+    /// number of entries, bits per entry, hashes, stripes and hash
+    /// builder.
+    ///
+    /// # Panics
+    /// Panics if `num_stripes` is 0 or `bits_per_entry` is 0 or larger
+    /// than 32.
+    pub fn with_size_and_hasher(
+        num_entries: usize,
+        bits_per_entry: usize,
+        num_hashes: u32,
+        num_stripes: usize,
+        hash_builder: H,
+    ) -> StripedCountingBloomFilter<H> {
+        assert!(num_stripes > 0, "need at least one stripe");
+        assert!(
+            (1..=32).contains(&bits_per_entry),
+            "bits_per_entry must be between 1 and 32"
+        );
+        let num_stripes = num_stripes.min(num_entries.max(1));
+        let stripes = (0..num_stripes)
+            .map(|s| {
+                let len = (num_entries - s).div_ceil(num_stripes);
+                Mutex::new(ValueVec::new(bits_per_entry, len))
+            })
+            .collect();
+        StripedCountingBloomFilter {
+            stripes,
+            num_entries: num_entries as u64,
+            max_value: u32::MAX >> (32 - bits_per_entry),
+            num_hashes,
+            hash_builder,
+        }
+    }
+
+    /// Create a StripedCountingBloomFilter that expects to hold
+    /// `expected_num_items`.  The filter will be sized to have a
+    /// false positive rate of the value specified in `rate`.  Items
+    /// will be hashed using the Hasher produced by `hash_builder`.
+    pub fn with_rate_and_hasher(
+        bits_per_entry: usize,
+        rate: f32,
+        expected_num_items: u32,
+        num_stripes: usize,
+        hash_builder: H,
+    ) -> StripedCountingBloomFilter<H> {
+        let entries = needed_bits(rate, expected_num_items);
+        StripedCountingBloomFilter::with_size_and_hasher(
+            entries,
+            bits_per_entry,
+            optimal_num_hashes(entries, expected_num_items),
+            num_stripes,
+            hash_builder,
+        )
+    }
+
+    /// The number of stripes the counters are divided into.
+    #[inline(always)]
+    pub fn num_stripes(&self) -> usize {
+        self.stripes.len()
+    }
+
+    /// The maximum value each counter can hold
+    #[inline(always)]
+    pub fn max_value(&self) -> u32 {
+        self.max_value
+    }
+
+    /// Get the hash builder this filter is using
+    #[inline(always)]
+    pub fn hash_builder(&self) -> &H {
+        &self.hash_builder
+    }
+
+    /// Lock every stripe `h_iter` probes, in ascending order.
+    fn lock(&self, h_iter: HashIter) -> Locked<'_> {
+        let num_stripes = self.stripes.len() as u64;
+        let positions: Vec<(usize, usize)> = h_iter
+            .map(|h| {
+                let idx = fastrange(h, self.num_entries);
+                ((idx % num_stripes) as usize, (idx / num_stripes) as usize)
+            })
+            .collect();
+        let mut stripes: Vec<usize> = positions.iter().map(|&(s, _)| s).collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        let guards = stripes
+            .iter()
+            .map(|&s| self.stripes[s].lock().unwrap())
+            .collect();
+        let probes = positions
+            .into_iter()
+            .map(|(s, i)| (stripes.binary_search(&s).unwrap(), i))
+            .collect();
+        Locked { guards, probes }
+    }
+
+    #[inline(always)]
+    fn probeable(&self) -> bool {
+        probeable(self.num_entries)
+    }
+
+    fn insert_hash_iter(&self, h_iter: HashIter) -> u32 {
+        if !self.probeable() {
+            return 0;
+        }
+        let mut locked = self.lock(h_iter);
+        let Locked { guards, probes } = &mut locked;
+        probes
+            .iter()
+            .map(|&(g, i)| {
+                let cur = guards[g].get(i);
+                if cur < self.max_value {
+                    guards[g].set(i, cur + 1);
+                }
+                cur
+            })
+            .fold(u32::MAX, |min, cur| min.min(cur))
+    }
+
+    fn remove_hash_iter(&self, h_iter: HashIter) -> u32 {
+        if !self.probeable() {
+            return 0;
+        }
+        let mut locked = self.lock(h_iter);
+        if locked.min_counter() == 0 {
+            return 0;
+        }
+        let Locked { guards, probes } = &mut locked;
+        probes
+            .iter()
+            .map(|&(g, i)| {
+                let cur = guards[g].get(i);
+                if cur > 0 && cur < self.max_value {
+                    guards[g].set(i, cur - 1);
+                }
+                cur
+            })
+            .fold(u32::MAX, |min, cur| min.min(cur))
+    }
+
+    fn estimate_count_hash_iter(&self, h_iter: HashIter) -> u32 {
+        if !self.probeable() {
+            return 0;
+        }
+        self.lock(h_iter).min_counter()
+    }
+
+    /// Inserts an item, returns the estimated count of the number of
+    /// times this item had previously been inserted (not counting
+    /// this insertion)
+    #[inline(always)]
+    pub fn insert<T: Hash>(&self, item: &T) -> u32 {
+        self.insert_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Like `insert`, but a fast-path for when the item is a byte slice.
+    #[inline(always)]
+    pub fn insert_slice(&self, item: &[u8]) -> u32 {
+        self.insert_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Like `insert`, but a fast-path that lets you amortize hashing
+    /// across multiple filters sharing the same hash algorithm.
+    #[inline(always)]
+    pub fn insert_fingerprint(&self, fingerprint: BloomFingerprint) -> u32 {
+        self.insert_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Remove an item.  Returns an upper bound of the number of times
+    /// this item had been inserted previously (i.e. the count before
+    /// this remove).  Returns 0 if item was never inserted.
+    #[inline(always)]
+    pub fn remove<T: Hash>(&self, item: &T) -> u32 {
+        self.remove_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Like `remove`, but a fast-path for when the item is a byte slice.
+    #[inline(always)]
+    pub fn remove_slice(&self, item: &[u8]) -> u32 {
+        self.remove_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Like `remove`, but a fast-path for fingerprints.
+    #[inline(always)]
+    pub fn remove_fingerprint(&self, fingerprint: BloomFingerprint) -> u32 {
+        self.remove_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Return an estimate of the number of times `item` has been
+    /// inserted into the filter.  Estimate is a upper bound on the
+    /// count, meaning the item has been inserted *at most* this many
+    /// times, but possibly fewer.
+    #[inline(always)]
+    pub fn estimate_count<T: Hash>(&self, item: &T) -> u32 {
+        self.estimate_count_hash_iter(HashIter::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Like `estimate_count`, but a fast-path for when the item is a
+    /// byte slice.
+    #[inline(always)]
+    pub fn estimate_count_slice(&self, item: &[u8]) -> u32 {
+        self.estimate_count_hash_iter(HashIter::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Like `estimate_count`, but a fast-path for fingerprints.
+    #[inline(always)]
+    pub fn estimate_count_fingerprint(&self, fingerprint: BloomFingerprint) -> u32 {
+        self.estimate_count_hash_iter(HashIter::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Check if the item has been inserted into this filter.
+    #[inline(always)]
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.estimate_count(item) > 0
+    }
+
+    /// Like `contains`, but a fast-path for when the item is a byte slice.
+    #[inline(always)]
+    pub fn contains_slice(&self, item: &[u8]) -> bool {
+        self.estimate_count_slice(item) > 0
+    }
+
+    /// Like `contains`, but a fast-path for fingerprints.
+    #[inline(always)]
+    pub fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        self.estimate_count_fingerprint(fingerprint) > 0
+    }
+
+    /// Reset all counters to 0.
+    pub fn clear(&self) {
+        for stripe in self.stripes.iter() {
+            stripe.lock().unwrap().clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StripedCountingBloomFilter;
+    use crate::hashing::{fastrange, HashIter};
+    use crate::{CountingBloomFilter, RandomXxh3State, ASMS};
+
+    #[test]
+    fn simple() {
+        let hasher = RandomXxh3State::from_seed(1);
+        let filter = StripedCountingBloomFilter::with_rate_and_hasher(4, 0.01, 100, 8, hasher);
+        assert_eq!(filter.insert(&1), 0);
+        assert_eq!(filter.insert(&1), 1);
+        filter.insert_slice(b"hello");
+        assert_eq!(filter.estimate_count(&1), 2);
+        assert!(filter.contains_slice(b"hello"));
+        assert!(!filter.contains(&2));
+        assert_eq!(filter.remove(&2), 0);
+        assert_eq!(filter.remove(&1), 2);
+        assert_eq!(filter.remove(&1), 1);
+        assert!(!filter.contains(&1));
+        filter.clear();
+        assert!(!filter.contains_slice(b"hello"));
+    }
+
+    #[test]
+    fn more_stripes_than_entries() {
+        let filter = StripedCountingBloomFilter::with_size(3, 4, 2, 16);
+        assert_eq!(filter.num_stripes(), 3);
+        filter.insert(&1);
+        assert!(filter.contains(&1));
+    }
+
+    /// Generates `len` keys in `0..num_keys` following a Zipf
+    /// distribution with exponent 1, from a splitmix64 stream seeded
+    /// with `seed`.
+    fn zipf_keys(seed: u64, num_keys: u32, len: usize) -> Vec<u32> {
+        let cdf: Vec<f64> = (1..=num_keys)
+            .scan(0.0, |total, rank| {
+                *total += 1.0 / rank as f64;
+                Some(*total)
+            })
+            .collect();
+        let total = cdf[cdf.len() - 1];
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                let mut z = state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                let u = (z ^ (z >> 31)) as f64 / u64::MAX as f64 * total;
+                cdf.partition_point(|&c| c < u).min(num_keys as usize - 1) as u32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn zipf_stress_matches_sequential() {
+        let threads = 16u64;
+        let keys = 1_000u32;
+        let per_thread = 20_000;
+        let hasher = RandomXxh3State::from_seed(0x5eed);
+        let filter = StripedCountingBloomFilter::with_rate_and_hasher(4, 0.01, keys, 8, hasher);
+        let mut oracle = CountingBloomFilter::with_rate_and_hasher(4, 0.01, keys, hasher);
+
+        let traces: Vec<Vec<u32>> = (0..threads)
+            .map(|t| zipf_keys(t, keys, per_thread))
+            .collect();
+        std::thread::scope(|s| {
+            for trace in &traces {
+                let filter = &filter;
+                s.spawn(move || {
+                    for key in trace {
+                        filter.insert(key);
+                    }
+                });
+            }
+        });
+
+        let mut counts = vec![0u32; keys as usize];
+        for key in traces.iter().flatten() {
+            oracle.insert(key);
+            counts[*key as usize] += 1;
+        }
+        // Saturating increments commute, so the final counters match the
+        // sequential oracle exactly.
+        for key in 0..keys * 2 {
+            assert_eq!(filter.estimate_count(&key), oracle.estimate_count(&key));
+        }
+        for key in 0..keys {
+            let count = counts[key as usize].min(filter.max_value());
+            assert!(filter.estimate_count(&key) >= count);
+        }
+    }
+
+    #[test]
+    fn saturated_remove() {
+        let hasher = RandomXxh3State::from_seed(1);
+        let max = CountingBloomFilter::bits_for_max(3);
+        let cbf = StripedCountingBloomFilter::with_size_and_hasher(64, max, 3, 4, hasher);
+        // Find a key sharing a counter with key 0.
+        let counters = |key: u32| -> Vec<usize> {
+            HashIter::from(key, 3, &hasher).map(|h| fastrange(h, 64) as usize).collect()
+        };
+        let shared = (1..)
+            .find(|key| counters(*key).iter().any(|c| counters(0).contains(c)))
+            .unwrap();
+
+        for _ in 0..20 {
+            cbf.insert(&0);
+        }
+        cbf.insert(&shared);
+        for _ in 0..20 {
+            cbf.remove(&0);
+        }
+        assert!(cbf.contains(&shared));
+        // Key 0 is over-counted rather than going to zero.
+        assert_eq!(cbf.estimate_count(&0), 3);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "empty filter")]
+    fn no_entries() {
+        StripedCountingBloomFilter::with_size(0, 4, 3, 2).insert(&1);
+    }
+}
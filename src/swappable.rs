@@ -0,0 +1,255 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::sync::atomic::Ordering;
+use std::sync::{Mutex, PoisonError};
+
+use crossbeam_epoch::{self as epoch, Atomic, Guard, Owned};
+
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher, BloomFilter, BloomFingerprint, ASMS};
+
+/// A read-only BloomFilter that can be replaced while other threads are
+/// querying it.
+///
+/// Readers never block: `load` pins the current epoch and returns a
+/// guard through which the current filter can be queried for as long as
+/// the guard is held.  A writer replacing the filter with `swap` or
+/// `rotate_with` only swaps a pointer, and the old filter is freed once
+/// every reader that could still see it has dropped its guard.  Writers
+/// are serialized with each other but never wait on readers.
+///
+/// This fits e.g. a dedup window that is periodically rotated: build
+/// the next filter off to the side and swap it in.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::{ASMS,BloomFilter,SwappableFilter};
+///
+/// let mut first = BloomFilter::with_rate(0.01, 100);
+/// first.insert(&1);
+/// let filter = SwappableFilter::new(first);
+/// assert!(filter.load().contains(&1));
+///
+/// filter.rotate_with(|old| {
+///     let mut next = BloomFilter::combinable_with(old);
+///     next.insert(&2);
+///     next
+/// });
+/// assert!(filter.contains(&2));
+/// assert!(!filter.contains(&1));
+/// ```
+pub struct SwappableFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    current: Atomic<BloomFilter<H>>,
+    // Serializes writers.  It guards no data, so a `rotate_with` that
+    // panicked leaves nothing for the next writer to distrust and
+    // poisoning is ignored.
+    writer: Mutex<()>,
+}
+
+/// Keeps the filter that was current when `SwappableFilter::load` was
+/// called alive, and derefs to it.
+pub struct FilterGuard<'a, H>
+where
+    H: BloomBuildHasher,
+{
+    filter: *const BloomFilter<H>,
+    _guard: Guard,
+    _marker: PhantomData<&'a BloomFilter<H>>,
+}
+
+impl<H> Deref for FilterGuard<'_, H>
+where
+    H: BloomBuildHasher,
+{
+    type Target = BloomFilter<H>;
+
+    fn deref(&self) -> &BloomFilter<H> {
+        // SAFETY: the filter is only destroyed after every guard pinned
+        // while it was current has been dropped.
+        unsafe { &*self.filter }
+    }
+}
+
+impl<H> SwappableFilter<H>
+where
+    H: BloomBuildHasher + Send + 'static,
+{
+    /// Start out serving `filter`.
+    pub fn new(filter: BloomFilter<H>) -> SwappableFilter<H> {
+        SwappableFilter {
+            current: Atomic::new(filter),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Get the current filter.  It stays valid (and unchanged) while the
+    /// guard is held, even if it is swapped out in the meantime, so hold
+    /// guards briefly to let old filters be freed.
+    pub fn load(&self) -> FilterGuard<'_, H> {
+        let guard = epoch::pin();
+        let filter = self.current.load(Ordering::Acquire, &guard).as_raw();
+        FilterGuard {
+            filter,
+            _guard: guard,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Replace the current filter with `filter`.
+    pub fn swap(&self, filter: BloomFilter<H>) {
+        let _writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        self.replace(Owned::new(filter));
+    }
+
+    /// Replace the current filter with the one `rotate` builds from it.
+    /// Readers keep using the old filter while `rotate` runs, and keep
+    /// it if `rotate` panics.
+    pub fn rotate_with<F>(&self, rotate: F)
+    where
+        F: FnOnce(&BloomFilter<H>) -> BloomFilter<H>,
+    {
+        let _writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let next = rotate(&self.load());
+        self.replace(Owned::new(next));
+    }
+
+    fn replace(&self, filter: Owned<BloomFilter<H>>) {
+        let guard = epoch::pin();
+        let old = self.current.swap(filter, Ordering::AcqRel, &guard);
+        // SAFETY: `old` is no longer reachable from `current`, so only
+        // readers pinned before the swap can still see it.
+        unsafe { guard.defer_destroy(old) };
+    }
+
+    /// Check if the current filter contains `item`.
+    #[inline(always)]
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.load().contains(item)
+    }
+
+    /// Check if the current filter contains the byte slice `item`.
+    #[inline(always)]
+    pub fn contains_slice(&self, item: &[u8]) -> bool {
+        self.load().contains_slice(item)
+    }
+
+    /// Check if the current filter contains the fingerprint.
+    #[inline(always)]
+    pub fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        self.load().contains_fingerprint(fingerprint)
+    }
+}
+
+impl<H> Drop for SwappableFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    fn drop(&mut self) {
+        // SAFETY: guards borrow the SwappableFilter, so none are left
+        // and the current filter can be freed right away.
+        unsafe {
+            drop(
+                self.current
+                    .load(Ordering::Relaxed, epoch::unprotected())
+                    .into_owned(),
+            )
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use super::SwappableFilter;
+    use crate::{BloomFilter, RandomXxh3State, ASMS};
+
+    // Generation `g` of the filter has `1000 + g` bits and contains
+    // `("gen", g)` as well as `"always"`, so a reader can tell which
+    // generation it is looking at and check that it is intact.
+    fn generation(g: usize) -> BloomFilter {
+        let mut filter =
+            BloomFilter::with_size_and_hasher(1000 + g, 4, RandomXxh3State::from_seed(1));
+        filter.insert(&("gen", g));
+        filter.insert(&"always");
+        filter
+    }
+
+    #[test]
+    fn swap() {
+        let filter = SwappableFilter::new(generation(0));
+        let old = filter.load();
+        filter.swap(generation(1));
+        assert_eq!(old.num_bits(), 1000);
+        assert!(old.contains(&("gen", 0usize)));
+        assert_eq!(filter.load().num_bits(), 1001);
+        assert!(filter.contains(&("gen", 1usize)));
+    }
+
+    #[test]
+    fn panicking_rotation() {
+        let filter = SwappableFilter::new(generation(0));
+        let rotated = std::panic::catch_unwind(|| filter.rotate_with(|_| panic!("rotate failed")));
+        assert!(rotated.is_err());
+        assert_eq!(filter.load().num_bits(), 1000);
+        filter.rotate_with(|_| generation(1));
+        assert_eq!(filter.load().num_bits(), 1001);
+    }
+
+    #[test]
+    fn concurrent_rotation() {
+        let filter = SwappableFilter::new(generation(0));
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut last = 0;
+                    let mut loads = 0;
+                    while !done.load(Ordering::Relaxed) || loads == 0 {
+                        let current = filter.load();
+                        let g = current.num_bits() - 1000;
+                        assert!(g >= last, "generations went backwards");
+                        assert!(current.contains(&"always"));
+                        assert!(current.contains(&("gen", g)));
+                        last = g;
+                        loads += 1;
+                    }
+                });
+            }
+            s.spawn(|| {
+                for g in 1..=500 {
+                    if g % 2 == 0 {
+                        filter.swap(generation(g));
+                    } else {
+                        filter.rotate_with(|old| {
+                            assert_eq!(old.num_bits(), 1000 + g - 1);
+                            generation(g)
+                        });
+                    }
+                }
+                done.store(true, Ordering::Relaxed);
+            });
+        });
+        assert_eq!(filter.load().num_bits(), 1500);
+    }
+}
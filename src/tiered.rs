@@ -0,0 +1,328 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+
+use crate::hashing::fingerprint;
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher, BloomFilter, BloomFingerprint, CountingBloomFilter, ASMS};
+
+/// A two level BloomFilter for skewed query distributions.
+///
+/// All items live in a full size "cold" filter.  Keys that are queried
+/// often get promoted into a small "hot" filter that is sized to stay
+/// in cache, and lookups check the hot filter first so that the common
+/// case only touches a few cache lines.  Both levels share one hasher so
+/// a key is hashed once regardless of how many levels are probed.
+///
+/// Keys are only promoted if the cold filter contains them, so the hot
+/// filter is (modulo its own false positives) a subset of the cold one.
+/// The false positive rate of the tiered filter is therefore roughly
+/// the sum of the rates of both levels.
+///
+/// Promotion happens explicitly via `promote` or automatically via
+/// `query` once a key has hit the cold filter `promote_after` times in
+/// the current generation.  After `generation_len` promotions the hot
+/// filter is cleared and a new generation starts, so keys that stopped
+/// being hot are eventually evicted.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::{ASMS,TieredBloomFilter};
+///
+/// let mut filter = TieredBloomFilter::with_rate(0.01, 10_000, 100);
+/// filter.insert(&1);
+/// assert!(filter.query(&1));
+/// assert!(filter.query(&1)); /* promoted to the hot filter */
+/// assert!(filter.hot().contains(&1));
+/// ```
+pub struct TieredBloomFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    hot: BloomFilter<H>,
+    cold: BloomFilter<H>,
+    hits: CountingBloomFilter<H>,
+    promote_after: u32,
+    generation_len: u64,
+    promotions: u64,
+    generation: u64,
+}
+
+impl TieredBloomFilter<RandomXxh3State> {
+    /// Create a TieredBloomFilter that expects to hold
+    /// `expected_num_items` of which roughly `hot_items` are queried
+    /// frequently.  Both levels are sized to have a false positive rate
+    /// of the value specified in `rate`.
+    pub fn with_rate(
+        rate: f32,
+        expected_num_items: u32,
+        hot_items: u32,
+    ) -> TieredBloomFilter<RandomXxh3State> {
+        TieredBloomFilter::with_rate_and_hasher(
+            rate,
+            expected_num_items,
+            hot_items,
+            RandomXxh3State::new(),
+        )
+    }
+}
+
+impl<H> TieredBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Create a TieredBloomFilter that expects to hold
+    /// `expected_num_items` of which roughly `hot_items` are queried
+    /// frequently, with both levels hashing via `hash_builder`.
+    pub fn with_rate_and_hasher(
+        rate: f32,
+        expected_num_items: u32,
+        hot_items: u32,
+        hash_builder: H,
+    ) -> TieredBloomFilter<H> {
+        let promote_after = 2;
+        let hot = BloomFilter::with_rate_and_hasher(rate, hot_items, hash_builder.clone());
+        let hits = CountingBloomFilter::with_size_and_hasher(
+            hot.num_bits(),
+            CountingBloomFilter::bits_for_max(promote_after),
+            hot.num_hashes(),
+            hash_builder.clone(),
+        );
+        TieredBloomFilter {
+            hot,
+            cold: BloomFilter::with_rate_and_hasher(rate, expected_num_items, hash_builder),
+            hits,
+            promote_after,
+            generation_len: hot_items as u64,
+            promotions: 0,
+            generation: 0,
+        }
+    }
+
+    /// Set how many cold hits within a generation promote a key during
+    /// `query`.
+    ///
+    /// # Panics
+    /// Panics if `promote_after` is 0.
+    pub fn set_promote_after(&mut self, promote_after: u32) {
+        assert!(promote_after > 0, "promote_after must be at least 1");
+        self.promote_after = promote_after;
+        self.hits = CountingBloomFilter::with_size_and_hasher(
+            self.hot.num_bits(),
+            CountingBloomFilter::bits_for_max(promote_after),
+            self.hot.num_hashes(),
+            self.hot.hash_builder().clone(),
+        );
+    }
+
+    /// Set how many promotions happen before the hot filter is cleared
+    /// and a new generation starts.
+    pub fn set_generation_len(&mut self, generation_len: u64) {
+        self.generation_len = generation_len;
+    }
+
+    /// The small filter holding promoted keys.
+    #[inline(always)]
+    pub fn hot(&self) -> &BloomFilter<H> {
+        &self.hot
+    }
+
+    /// The full size filter holding every inserted key.
+    #[inline(always)]
+    pub fn cold(&self) -> &BloomFilter<H> {
+        &self.cold
+    }
+
+    /// The current generation.  Starts at 0 and increments every time
+    /// the hot filter is cleared.
+    #[inline(always)]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Clear the hot filter and start a new generation.
+    pub fn advance_generation(&mut self) {
+        self.hot.clear();
+        self.hits.clear();
+        self.promotions = 0;
+        self.generation += 1;
+    }
+
+    /// Promote `item` into the hot filter if the cold filter contains
+    /// it.  Returns true if the item was promoted.
+    #[inline(always)]
+    pub fn promote<T: Hash>(&mut self, item: &T) -> bool {
+        self.promote_fingerprint(fingerprint(item, self.cold.hash_builder()))
+    }
+
+    /// Promote a byte slice into the hot filter if the cold filter
+    /// contains it.  Returns true if the item was promoted.
+    #[inline(always)]
+    pub fn promote_slice(&mut self, item: &[u8]) -> bool {
+        self.promote_fingerprint(self.cold.hash_builder().hash_one_128(item))
+    }
+
+    /// Promote a fingerprint into the hot filter if the cold filter
+    /// contains it.  Returns true if the item was promoted.
+    pub fn promote_fingerprint(&mut self, fp: BloomFingerprint) -> bool {
+        if !self.cold.contains_fingerprint(fp) {
+            return false;
+        }
+        self.promote_unchecked(fp);
+        true
+    }
+
+    fn promote_unchecked(&mut self, fp: BloomFingerprint) {
+        if self.generation_len > 0 && self.promotions >= self.generation_len {
+            self.advance_generation();
+        }
+        self.hot.insert_fingerprint(fp);
+        self.promotions += 1;
+    }
+
+    /// Check if the item has been inserted, promoting it into the hot
+    /// filter once it has been found in the cold filter
+    /// `promote_after` times this generation.
+    #[inline(always)]
+    pub fn query<T: Hash>(&mut self, item: &T) -> bool {
+        self.query_fingerprint(fingerprint(item, self.cold.hash_builder()))
+    }
+
+    /// Check if the byte slice has been inserted, promoting it the same
+    /// way `query` does.
+    #[inline(always)]
+    pub fn query_slice(&mut self, item: &[u8]) -> bool {
+        self.query_fingerprint(self.cold.hash_builder().hash_one_128(item))
+    }
+
+    /// Check if the fingerprint has been inserted, promoting it the same
+    /// way `query` does.
+    pub fn query_fingerprint(&mut self, fp: BloomFingerprint) -> bool {
+        if self.hot.contains_fingerprint(fp) {
+            return true;
+        }
+        if !self.cold.contains_fingerprint(fp) {
+            return false;
+        }
+        if self.hits.insert_get_count_fingerprint(fp) + 1 >= self.promote_after {
+            self.promote_unchecked(fp);
+        }
+        true
+    }
+}
+
+impl<H> ASMS for TieredBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Insert an item into the cold filter.
+    #[inline(always)]
+    fn insert<T: Hash>(&mut self, item: &T) {
+        self.cold.insert(item)
+    }
+
+    #[inline(always)]
+    fn insert_slice(&mut self, item: &[u8]) {
+        self.cold.insert_slice(item)
+    }
+
+    #[inline(always)]
+    fn insert_fingerprint(&mut self, fingerprint: BloomFingerprint) {
+        self.cold.insert_fingerprint(fingerprint)
+    }
+
+    /// Check the hot filter and then the cold filter for the item.
+    /// This never promotes; use `query` for that.
+    #[inline(always)]
+    fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_fingerprint(fingerprint(item, self.cold.hash_builder()))
+    }
+
+    #[inline(always)]
+    fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_fingerprint(self.cold.hash_builder().hash_one_128(item))
+    }
+
+    #[inline(always)]
+    fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        self.hot.contains_fingerprint(fingerprint) || self.cold.contains_fingerprint(fingerprint)
+    }
+
+    /// Remove all values from both levels.
+    fn clear(&mut self) {
+        self.advance_generation();
+        self.cold.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TieredBloomFilter;
+    use crate::{RandomXxh3State, ASMS};
+
+    #[test]
+    fn promotion() {
+        let mut filter =
+            TieredBloomFilter::with_rate_and_hasher(0.01, 1000, 10, RandomXxh3State::from_seed(1));
+        filter.set_promote_after(3);
+        filter.insert(&1);
+        assert!(!filter.promote(&2));
+
+        assert!(filter.query(&1));
+        assert!(filter.query(&1));
+        assert!(!filter.hot().contains(&1));
+        assert!(filter.query(&1));
+        assert!(filter.hot().contains(&1));
+        assert!(!filter.query(&2));
+        assert!(!filter.hot().contains(&2));
+    }
+
+    #[test]
+    fn generations() {
+        let mut filter = TieredBloomFilter::with_rate(0.01, 1000, 10);
+        filter.set_generation_len(2);
+        for i in 0..3 {
+            filter.insert(&i);
+        }
+        assert!(filter.promote(&0));
+        assert!(filter.promote(&1));
+        assert_eq!(filter.generation(), 0);
+        assert!(filter.promote(&2));
+        assert_eq!(filter.generation(), 1);
+        assert!(!filter.hot().contains(&0));
+        assert!(filter.hot().contains(&2));
+        for i in 0..3 {
+            assert!(filter.contains(&i));
+        }
+    }
+
+    #[test]
+    fn matches_cold_filter() {
+        let mut filter = TieredBloomFilter::with_rate(0.01, 1000, 10);
+        for i in 0..1000 {
+            filter.insert(&i);
+        }
+        for _ in 0..3 {
+            for i in 0..2000 {
+                assert_eq!(filter.query(&(i % 20)), filter.cold().contains(&(i % 20)));
+            }
+        }
+        for i in 0..1000 {
+            assert!(filter.query(&i));
+        }
+    }
+}
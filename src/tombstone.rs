@@ -0,0 +1,211 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+
+use crate::hashing::fingerprint;
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher, BloomFilter, BloomFingerprint, ASMS};
+
+/// A BloomFilter that supports approximate removal by pairing it with
+/// a second filter of removed items.
+///
+/// Inserts go into one filter and removals into the other, and an item
+/// is contained if the first filter has it and the second doesn't.
+/// This is far cheaper than a `CountingBloomFilter` when removals are
+/// rare, at the cost of different failure modes:
+///
+/// * A false positive in the removal filter hides an item that was
+///   never removed, so unlike a plain BloomFilter this one can give
+///   false negatives.  Their rate is the false positive rate of the
+///   removal filter.
+/// * Removal is permanent.  Inserting an item again after removing it
+///   doesn't make it visible again.
+///
+/// Both filters share one hasher so an item is hashed once per
+/// operation.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::{ASMS,TombstoneBloomFilter};
+///
+/// let mut filter = TombstoneBloomFilter::with_rate(0.01, 1000, 100);
+/// filter.insert(&1);
+/// filter.insert(&2);
+/// filter.remove(&1);
+/// assert!(!filter.contains(&1));
+/// assert!(filter.contains(&2));
+/// ```
+pub struct TombstoneBloomFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher,
+{
+    inserted: BloomFilter<H>,
+    removed: BloomFilter<H>,
+}
+
+impl TombstoneBloomFilter<RandomXxh3State> {
+    /// Create a TombstoneBloomFilter that expects to hold
+    /// `expected_num_items` and have `expected_num_removals` of them
+    /// removed.  Both filters are sized to have a false positive rate
+    /// of the value specified in `rate`.
+    pub fn with_rate(
+        rate: f32,
+        expected_num_items: u32,
+        expected_num_removals: u32,
+    ) -> TombstoneBloomFilter<RandomXxh3State> {
+        TombstoneBloomFilter::with_rate_and_hasher(
+            rate,
+            expected_num_items,
+            expected_num_removals,
+            RandomXxh3State::new(),
+        )
+    }
+}
+
+impl<H> TombstoneBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Like `with_rate`, but both filters hash items using
+    /// `hash_builder`.
+    pub fn with_rate_and_hasher(
+        rate: f32,
+        expected_num_items: u32,
+        expected_num_removals: u32,
+        hash_builder: H,
+    ) -> TombstoneBloomFilter<H> {
+        TombstoneBloomFilter {
+            removed: BloomFilter::with_rate_and_hasher(
+                rate,
+                expected_num_removals,
+                hash_builder.clone(),
+            ),
+            inserted: BloomFilter::with_rate_and_hasher(rate, expected_num_items, hash_builder),
+        }
+    }
+
+    /// The filter holding every inserted item.
+    #[inline(always)]
+    pub fn inserted(&self) -> &BloomFilter<H> {
+        &self.inserted
+    }
+
+    /// The filter holding every removed item.
+    #[inline(always)]
+    pub fn removed(&self) -> &BloomFilter<H> {
+        &self.removed
+    }
+
+    /// Remove `item`, so that `contains` no longer finds it.
+    #[inline(always)]
+    pub fn remove<T: Hash>(&mut self, item: &T) {
+        self.remove_fingerprint(fingerprint(item, self.inserted.hash_builder()))
+    }
+
+    /// Remove the byte slice `item`.
+    #[inline(always)]
+    pub fn remove_slice(&mut self, item: &[u8]) {
+        self.remove_fingerprint(self.inserted.hash_builder().hash_one_128(item))
+    }
+
+    /// Remove the fingerprint.
+    #[inline(always)]
+    pub fn remove_fingerprint(&mut self, fingerprint: BloomFingerprint) {
+        self.removed.insert_fingerprint(fingerprint)
+    }
+}
+
+impl<H> ASMS for TombstoneBloomFilter<H>
+where
+    H: BloomBuildHasher,
+{
+    /// Insert an item.  This has no visible effect if the item has
+    /// been removed before.
+    #[inline(always)]
+    fn insert<T: Hash>(&mut self, item: &T) {
+        self.inserted.insert(item)
+    }
+
+    #[inline(always)]
+    fn insert_slice(&mut self, item: &[u8]) {
+        self.inserted.insert_slice(item)
+    }
+
+    #[inline(always)]
+    fn insert_fingerprint(&mut self, fingerprint: BloomFingerprint) {
+        self.inserted.insert_fingerprint(fingerprint)
+    }
+
+    /// Check if the item has been inserted and not removed.
+    #[inline(always)]
+    fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_fingerprint(fingerprint(item, self.inserted.hash_builder()))
+    }
+
+    #[inline(always)]
+    fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_fingerprint(self.inserted.hash_builder().hash_one_128(item))
+    }
+
+    #[inline(always)]
+    fn contains_fingerprint(&self, fingerprint: BloomFingerprint) -> bool {
+        self.inserted.contains_fingerprint(fingerprint)
+            && !self.removed.contains_fingerprint(fingerprint)
+    }
+
+    /// Remove all values from both filters.
+    fn clear(&mut self) {
+        self.inserted.clear();
+        self.removed.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TombstoneBloomFilter;
+    use crate::{RandomXxh3State, ASMS};
+
+    #[test]
+    fn remove() {
+        let mut filter = TombstoneBloomFilter::with_rate_and_hasher(
+            0.01,
+            1000,
+            100,
+            RandomXxh3State::from_seed(1),
+        );
+        for i in 0..1000 {
+            filter.insert(&i);
+        }
+        for i in 0..100 {
+            filter.remove(&i);
+        }
+        assert!((0..100).all(|i| !filter.contains(&i)));
+        let false_negatives = (100..1000).filter(|i| !filter.contains(i)).count();
+        assert!(false_negatives < 30, "{}", false_negatives);
+
+        // Removal is permanent.
+        filter.insert(&0);
+        assert!(!filter.contains(&0));
+        filter.remove_slice(b"x");
+        filter.insert_slice(b"x");
+        assert!(!filter.contains_slice(b"x"));
+
+        filter.clear();
+        filter.insert(&0);
+        assert!(filter.contains(&0));
+    }
+}
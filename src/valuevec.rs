@@ -13,28 +13,37 @@
 // Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
 // 02110-1301, USA.
 
-extern crate bit_vec;
-extern crate core;
-
-use bit_vec::BitVec;
+use alloc::boxed::Box;
+use alloc::vec;
 
 /// A ValueVec is a bit vector that holds fixed sized unsigned integer
 /// values.
 pub struct ValueVec {
     bits_per_val: usize,
     mask: u32,
-    bits: BitVec,
+    blocks: Box<[u32]>,
+    num_bits: usize,
+    // True if values never straddle two blocks (the width divides 32),
+    // so they can be read and written with a single shift and mask.
+    aligned: bool,
 }
 
 impl ValueVec {
     /// Create a ValueVec that holds values with `bits_per_val` bits and
     /// space to hold `count` values.
+    ///
+    /// # Panics
+    /// Panics if the total number of bits doesn't fit in a usize.
     pub fn new(bits_per_val: usize, count: usize) -> ValueVec {
-        let bits = bits_per_val * count;
+        let bits = bits_per_val
+            .checked_mul(count)
+            .expect("ValueVec needs more bits than this platform can address");
         ValueVec {
-            bits_per_val: bits_per_val,
-            mask: 2u32.pow(bits_per_val as u32) - 1,
-            bits: BitVec::from_elem(bits, false),
+            bits_per_val,
+            mask: ((1u64 << bits_per_val) - 1) as u32,
+            blocks: vec![0; bits.div_ceil(32)].into_boxed_slice(),
+            num_bits: bits,
+            aligned: bits_per_val > 0 && 32 % bits_per_val == 0,
         }
     }
 
@@ -73,11 +82,10 @@ impl ValueVec {
 
     /// Resets all values to 0 in this ValueVec
     pub fn clear(&mut self) {
-        self.bits.clear();
+        self.blocks.fill(0);
     }
 
     fn set_bits(&mut self, idx: usize, val: u32, num_bits: usize) {
-        let blocks = unsafe { self.bits.storage_mut() };
         let blockidx = idx / 32;
         let shift = 32 - (idx % 32) - num_bits;
         let mask = if num_bits == self.bits_per_val {
@@ -85,29 +93,72 @@ impl ValueVec {
         } else {
             2u32.pow(num_bits as u32) - 1
         } << shift;
-        let block = blocks[blockidx];
+        let block = self.blocks[blockidx];
 
         // this will be the value with all bits in our value set to zero
         let zeroed = (block ^ mask) & block;
         // or in the new val
-        blocks[blockidx] = zeroed | (val << shift);
+        self.blocks[blockidx] = zeroed | (val << shift);
     }
 
     fn get_bits(&self, idx: usize, num_bits: usize) -> u32 {
-        let blocks = self.bits.storage();
         let shift = 32 - (idx % 32) - num_bits;
         let mask = if num_bits == self.bits_per_val {
             self.mask
         } else {
             2u32.pow(num_bits as u32) - 1
         } << shift;
-        let val = blocks[idx / 32] & mask;
+        let val = self.blocks[idx / 32] & mask;
         val >> shift
     }
 
     /// Get the total number of bits this valuevec is using
     pub fn len(&self) -> usize {
-        self.bits.len()
+        self.num_bits
+    }
+
+    /// Returns true if this ValueVec has no space for any values
+    pub fn is_empty(&self) -> bool {
+        self.num_bits == 0
+    }
+
+    /// The values as one big endian bit stream: value `i` is bits
+    /// `i * bits_per_val..(i + 1) * bits_per_val`, most significant bit
+    /// first, padded with zero bits to a whole byte.
+    #[cfg(feature = "serde")]
+    pub(crate) fn to_packed_bytes(&self) -> Vec<u8> {
+        self.blocks
+            .iter()
+            .flat_map(|block| block.to_be_bytes())
+            .take(self.num_bits.div_ceil(8))
+            .collect()
+    }
+
+    /// The inverse of `to_packed_bytes`, or None if `bits_per_val` is
+    /// more than 32 or `bytes` isn't exactly long enough for `count`
+    /// values.
+    #[cfg(feature = "serde")]
+    pub(crate) fn from_packed_bytes(
+        bits_per_val: usize,
+        count: usize,
+        bytes: &[u8],
+    ) -> Option<ValueVec> {
+        let num_bits = bits_per_val.checked_mul(count)?;
+        if bits_per_val > 32 || bytes.len() != num_bits.div_ceil(8) {
+            return None;
+        }
+        let mut vv = ValueVec::new(bits_per_val, count);
+        for (block, chunk) in vv.blocks.iter_mut().zip(bytes.chunks(4)) {
+            let mut be = [0; 4];
+            be[..chunk.len()].copy_from_slice(chunk);
+            *block = u32::from_be_bytes(be);
+        }
+        if num_bits % 32 != 0 {
+            if let Some(last) = vv.blocks.last_mut() {
+                *last &= !(u32::MAX >> (num_bits % 32));
+            }
+        }
+        Some(vv)
     }
 
     /// Set value at index `i` to value `val`.
@@ -116,6 +167,7 @@ impl ValueVec {
     ///
     /// Panics if `val` needs more bits to store than the number of
     /// bits this vec is using per value
+    #[inline]
     pub fn set(&mut self, i: usize, val: u32) {
         if val > self.mask {
             panic!(
@@ -123,8 +175,18 @@ impl ValueVec {
                 val, self.mask
             );
         }
+        if self.aligned {
+            let idx = i * self.bits_per_val;
+            let shift = 32 - self.bits_per_val - idx % 32;
+            let block = &mut self.blocks[idx / 32];
+            *block = (*block & !(self.mask << shift)) | (val << shift);
+        } else {
+            self.set_packed(i, val)
+        }
+    }
+
+    fn set_packed(&mut self, i: usize, val: u32) {
         let idx = i * self.bits_per_val;
-        //println!("idx is: {}",idx);
         let rem = 32 - (idx % 32);
         if rem < self.bits_per_val {
             // rem is how many bits needed in the lower part
@@ -142,7 +204,17 @@ impl ValueVec {
     }
 
     /// Get the value in this ValueVec stored at index `i`
+    #[inline]
     pub fn get(&self, i: usize) -> u32 {
+        if self.aligned {
+            let idx = i * self.bits_per_val;
+            (self.blocks[idx / 32] >> (32 - self.bits_per_val - idx % 32)) & self.mask
+        } else {
+            self.get_packed(i)
+        }
+    }
+
+    fn get_packed(&self, i: usize) -> u32 {
         let idx = i * self.bits_per_val;
         let rem = 32 - (idx % 32);
         if rem < self.bits_per_val {
@@ -160,6 +232,27 @@ impl ValueVec {
 mod tests {
     use crate::valuevec::ValueVec;
 
+    // The aligned widths must lay values out exactly like the packed
+    // code, so both are interchangeable.
+    #[test]
+    fn aligned_matches_packed() {
+        for bits in [1, 2, 4, 8, 16, 32] {
+            let mut aligned = ValueVec::new(bits, 100);
+            let mut packed = ValueVec::new(bits, 100);
+            for round in 0..3u32 {
+                for i in 0..100 {
+                    let val = (i as u32 + round).wrapping_mul(0x9E37_79B9) & aligned.max_value();
+                    aligned.set(i, val);
+                    packed.set_packed(i, val);
+                    assert_eq!(aligned.get(i), val);
+                    assert_eq!(packed.get_packed(i), val);
+                }
+                assert_eq!(aligned.blocks, packed.blocks, "{} bits", bits);
+            }
+        }
+        assert_eq!(ValueVec::new(32, 1).max_value(), u32::MAX);
+    }
+
     #[test]
     fn set_get_no_overlap() {
         let mut vv = ValueVec::new(4, 12);
@@ -0,0 +1,256 @@
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License as
+// published by the Free Software Foundation; either version 2 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+// 02110-1301, USA.
+
+use core::hash::Hash;
+
+use crate::bits::Bits;
+use crate::bloom::{needed_bits, optimal_num_hashes};
+use crate::hashing::HashIter256;
+use crate::xxh_helper::RandomXxh3State;
+use crate::{BloomBuildHasher256, BloomFingerprint256, Intersectable, Unionable};
+
+/// A BloomFilter whose probe positions are derived from a 256-bit
+/// fingerprint.
+///
+/// `BloomFilter` derives every probe from the two 64-bit halves of a
+/// 128-bit fingerprint.  That is plenty for typical false positive
+/// rates, but the very low rates some applications need call for
+/// dozens of hash functions, and at that point keys whose halves
+/// happen to line up share most of their probe positions.  This filter
+/// uses all four lanes of a `BloomFingerprint256` instead, at the cost
+/// of hashing twice as much per item.  It only accepts hash builders
+/// implementing `BloomBuildHasher256`.
+///
+/// # Example Usage
+///
+/// ```rust
+/// use xx_bloom::WideBloomFilter;
+///
+/// let mut filter = WideBloomFilter::with_rate(1e-15, 1000);
+/// assert!(filter.num_hashes() > 40);
+/// filter.insert(&1);
+/// assert!(filter.contains(&1));
+/// assert!(!filter.contains(&2));
+/// ```
+pub struct WideBloomFilter<H = RandomXxh3State>
+where
+    H: BloomBuildHasher256,
+{
+    bits: Bits,
+    num_hashes: u32,
+    hash_builder: H,
+}
+
+impl WideBloomFilter<RandomXxh3State> {
+    /// Create a new WideBloomFilter with the specified number of bits,
+    /// and hashes
+    pub fn with_size(num_bits: usize, num_hashes: u32) -> WideBloomFilter<RandomXxh3State> {
+        WideBloomFilter::with_size_and_hasher(num_bits, num_hashes, RandomXxh3State::new())
+    }
+
+    /// Create a WideBloomFilter that expects to hold
+    /// `expected_num_items`.  The filter will be sized to have a
+    /// false positive rate of the value specified in `rate`.
+    pub fn with_rate(rate: f32, expected_num_items: u32) -> WideBloomFilter<RandomXxh3State> {
+        WideBloomFilter::with_rate_and_hasher(rate, expected_num_items, RandomXxh3State::new())
+    }
+}
+
+impl<H> WideBloomFilter<H>
+where
+    H: BloomBuildHasher256,
+{
+    /// Create a new WideBloomFilter with the specified number of bits,
+    /// hashes, and HashBuilder.
+    pub fn with_size_and_hasher(
+        num_bits: usize,
+        num_hashes: u32,
+        hash_builder: H,
+    ) -> WideBloomFilter<H> {
+        WideBloomFilter {
+            bits: Bits::new(num_bits),
+            num_hashes,
+            hash_builder,
+        }
+    }
+
+    /// Create a WideBloomFilter that expects to hold
+    /// `expected_num_items` with a false positive rate of `rate`,
+    /// hashing items with `hash_builder`.
+    pub fn with_rate_and_hasher(
+        rate: f32,
+        expected_num_items: u32,
+        hash_builder: H,
+    ) -> WideBloomFilter<H> {
+        let bits = needed_bits(rate, expected_num_items);
+        WideBloomFilter::with_size_and_hasher(
+            bits,
+            optimal_num_hashes(bits, expected_num_items),
+            hash_builder,
+        )
+    }
+
+    /// Create a new WideBloomFilter with the exact same parameters as
+    /// the other, so the two can be intersected and unioned.
+    pub fn combinable_with(other: &WideBloomFilter<H>) -> Self {
+        WideBloomFilter::with_size_and_hasher(
+            other.num_bits(),
+            other.num_hashes,
+            other.hash_builder.clone(),
+        )
+    }
+
+    /// Get the number of bits this WideBloomFilter is using
+    #[inline(always)]
+    pub fn num_bits(&self) -> usize {
+        self.bits.len()
+    }
+
+    /// Get the number of hash functions this WideBloomFilter is using
+    #[inline(always)]
+    pub fn num_hashes(&self) -> u32 {
+        self.num_hashes
+    }
+
+    /// Get the hash builder this WideBloomFilter is using
+    #[inline(always)]
+    pub fn hash_builder(&self) -> &H {
+        &self.hash_builder
+    }
+
+    /// Insert item into this WideBloomFilter.
+    #[inline(always)]
+    pub fn insert<T: Hash>(&mut self, item: &T) {
+        self.insert_hash_iter(HashIter256::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Insert a byte slice into this WideBloomFilter.
+    #[inline(always)]
+    pub fn insert_slice(&mut self, item: &[u8]) {
+        self.insert_hash_iter(HashIter256::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Insert a fingerprint into this WideBloomFilter.
+    #[inline(always)]
+    pub fn insert_fingerprint(&mut self, fingerprint: BloomFingerprint256) {
+        self.insert_hash_iter(HashIter256::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Check if the item has been inserted into this filter.  This
+    /// function can return false positives, but not false negatives.
+    #[inline(always)]
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_hash_iter(HashIter256::from(item, self.num_hashes, &self.hash_builder))
+    }
+
+    /// Check if the byte slice has been inserted into this filter.
+    #[inline(always)]
+    pub fn contains_slice(&self, item: &[u8]) -> bool {
+        self.contains_hash_iter(HashIter256::from_slice(
+            item,
+            self.num_hashes,
+            &self.hash_builder,
+        ))
+    }
+
+    /// Check if the fingerprint has been inserted into this filter.
+    #[inline(always)]
+    pub fn contains_fingerprint(&self, fingerprint: BloomFingerprint256) -> bool {
+        self.contains_hash_iter(HashIter256::from_fingerprint(fingerprint, self.num_hashes))
+    }
+
+    /// Remove all values from this WideBloomFilter
+    #[inline(always)]
+    pub fn clear(&mut self) {
+        self.bits.clear();
+    }
+
+    fn insert_hash_iter(&mut self, h_iter: HashIter256) {
+        let num_bits = self.num_bits() as u64;
+        h_iter.for_each(|h| self.bits.set((h % num_bits) as usize));
+    }
+
+    fn contains_hash_iter(&self, mut h_iter: HashIter256) -> bool {
+        let num_bits = self.num_bits() as u64;
+        h_iter.all(|h| self.bits.get((h % num_bits) as usize))
+    }
+}
+
+impl<H> Intersectable for WideBloomFilter<H>
+where
+    H: BloomBuildHasher256,
+{
+    /// Calculates the intersection of two WideBloomFilters.
+    ///
+    /// # Panics
+    /// Panics if the filters are not using the same number of bits
+    fn intersect(&mut self, other: &WideBloomFilter<H>) {
+        self.bits.and(&other.bits);
+    }
+}
+
+impl<H> Unionable for WideBloomFilter<H>
+where
+    H: BloomBuildHasher256,
+{
+    /// Calculates the union of two WideBloomFilters.
+    ///
+    /// # Panics
+    /// Panics if the filters are not using the same number of bits
+    fn union(&mut self, other: &WideBloomFilter<H>) {
+        self.bits.or(&other.bits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WideBloomFilter;
+    use crate::{BloomBuildHasher256, ByteKey, RandomXxh3State, Unionable};
+
+    #[test]
+    fn insert_contains() {
+        let mut filter =
+            WideBloomFilter::with_rate_and_hasher(1e-12, 1000, RandomXxh3State::from_seed(3));
+        assert!(filter.num_hashes() >= 40);
+        for i in 0..1000u32 {
+            filter.insert(&i);
+        }
+        assert!((0..1000u32).all(|i| filter.contains(&i)));
+        assert!((1000..100_000u32).all(|i| !filter.contains(&i)));
+
+        filter.insert_slice(b"bytes");
+        assert!(filter.contains(&ByteKey(b"bytes")));
+        let fp = filter.hash_builder().hash_one_256(b"bytes");
+        assert!(filter.contains_fingerprint(fp));
+
+        filter.clear();
+        assert!(!filter.contains(&0u32));
+    }
+
+    #[test]
+    fn union() {
+        let mut a = WideBloomFilter::with_rate(1e-9, 100);
+        let mut b = WideBloomFilter::combinable_with(&a);
+        a.insert(&1);
+        b.insert(&2);
+        a.union(&b);
+        assert!(a.contains(&1));
+        assert!(a.contains(&2));
+    }
+}
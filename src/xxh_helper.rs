@@ -1,8 +1,17 @@
-use std::mem::MaybeUninit;
+use alloc::boxed::Box;
+#[cfg(feature = "std")]
+use core::cell::Cell;
+#[cfg(feature = "std")]
+use core::mem::MaybeUninit;
 
-use xxhash_rust::xxh3::{Xxh3, Xxh3Builder};
+use xxhash_rust::xxh3::{xxh3_128_with_seed, xxh3_64_with_seed, Xxh3, Xxh3Builder};
 
-use crate::{BloomBuildHasher, BloomFingerprint, BloomHasher};
+use core::hash::{BuildHasher, Hasher};
+
+use crate::{
+    BloomBuildHasher, BloomBuildHasher256, BloomFingerprint, BloomFingerprint256, BloomHasher,
+    BloomHasher256,
+};
 
 pub(crate) const DEFAULT_SECRET_SIZE: usize = 192;
 
@@ -12,38 +21,191 @@ impl BloomHasher for Xxh3 {
     }
 }
 
+/// A pair of Xxh3 hashers keyed with different secrets, producing a
+/// 256-bit digest from their two 128-bit digests.
+#[derive(Clone)]
+pub struct Xxh3Pair {
+    first: Xxh3,
+    second: Xxh3,
+}
+
+impl Xxh3Pair {
+    fn with_secret(secret: &[u8; DEFAULT_SECRET_SIZE]) -> Self {
+        Self {
+            first: Xxh3::with_secret(*secret),
+            second: Xxh3::with_secret(second_secret(secret)),
+        }
+    }
+}
+
+impl Hasher for Xxh3Pair {
+    #[inline(always)]
+    fn write(&mut self, bytes: &[u8]) {
+        self.first.write(bytes);
+        self.second.write(bytes);
+    }
+
+    #[inline(always)]
+    fn finish(&self) -> u64 {
+        self.first.finish()
+    }
+}
+
+impl BloomHasher for Xxh3Pair {
+    fn finish_128(&self) -> BloomFingerprint {
+        self.first.finish_128()
+    }
+}
+
+impl BloomHasher256 for Xxh3Pair {
+    fn finish_256(&self) -> BloomFingerprint256 {
+        BloomFingerprint256::new_256(self.first.digest128(), self.second.digest128())
+    }
+}
+
+/// The secret for the second half of a 256-bit hash: the original secret
+/// rotated by half its length, so both halves are keyed with the same
+/// random bytes paired up differently.
+fn second_secret(secret: &[u8; DEFAULT_SECRET_SIZE]) -> [u8; DEFAULT_SECRET_SIZE] {
+    let mut second = *secret;
+    second.rotate_left(DEFAULT_SECRET_SIZE / 2);
+    second
+}
+
+#[inline(always)]
+fn xxh3_256_with_secret(k: &[u8], secret: &[u8; DEFAULT_SECRET_SIZE]) -> BloomFingerprint256 {
+    BloomFingerprint256::new_256(
+        xxhash_rust::xxh3::xxh3_128_with_secret(k, secret),
+        xxhash_rust::xxh3::xxh3_128_with_secret(k, &second_secret(secret)),
+    )
+}
+
+/// The `Hasher` the xxh3 builders hand out through
+/// `core::hash::BuildHasher`, e.g. to a `HashMap`.  It hashes exactly
+/// like their `BloomBuildHasher` hasher, and `finish` returns the low 64
+/// bits of the 128-bit digest, i.e. the `h2` half of the item's
+/// `BloomFingerprint`, so a map and a filter sharing a builder agree on
+/// every key's digest.
+#[derive(Clone)]
+pub struct Xxh3MapHasher(Xxh3);
+
+impl Hasher for Xxh3MapHasher {
+    #[inline(always)]
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+    }
+
+    #[inline(always)]
+    fn finish(&self) -> u64 {
+        self.0.digest128() as u64
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct RandomXxh3State {
-    secret: [u8; DEFAULT_SECRET_SIZE],
+    pub(crate) secret: [u8; DEFAULT_SECRET_SIZE],
 }
 
-fn random_secret() -> [u8; DEFAULT_SECRET_SIZE] {
+#[cfg(feature = "std")]
+fn try_random_secret() -> Result<[u8; DEFAULT_SECRET_SIZE], getrandom::Error> {
     let mut v =
         unsafe { MaybeUninit::<[MaybeUninit<u8>; DEFAULT_SECRET_SIZE]>::uninit().assume_init() };
-    getrandom::getrandom_uninit(&mut v)
-        .unwrap()
-        .try_into()
-        .unwrap()
+    Ok(getrandom::getrandom_uninit(&mut v)?.try_into().unwrap())
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn random_secret() -> [u8; DEFAULT_SECRET_SIZE] {
+    try_random_secret().expect(
+        "no entropy source; enable the `wasm` feature in browsers, or use \
+         `RandomXxh3State::from_entropy_or` or `from_seed`",
+    )
+}
+
+/// Deterministically expands `seed` into a secret using splitmix64.
+fn seeded_secret(seed: u64) -> [u8; DEFAULT_SECRET_SIZE] {
+    let mut state = seed;
+    next_seeded_secret(&mut state)
+}
+
+/// The next secret from the splitmix64 stream at `state`, so
+/// consecutive calls give secrets that don't overlap.
+fn next_seeded_secret(state: &mut u64) -> [u8; DEFAULT_SECRET_SIZE] {
+    let mut secret = [0u8; DEFAULT_SECRET_SIZE];
+    for chunk in secret.chunks_exact_mut(8) {
+        *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        chunk.copy_from_slice(&(z ^ (z >> 31)).to_le_bytes());
+    }
+    secret
 }
 
 impl RandomXxh3State {
+    ///Creates an instance whose secret is derived from `seed`.  The same
+    ///seed always produces the same secret on every platform, so filters
+    ///built from it in different processes hash identically and can be
+    ///combined.
+    pub fn from_seed(seed: u64) -> Self {
+        Self {
+            secret: seeded_secret(seed),
+        }
+    }
+
+    ///Creates an instance with a fresh secret from the OS like
+    ///`new_fully_random`, or with `secret` when there's no entropy
+    ///source to draw one from.  This is for code that runs both where
+    ///randomness is available and where it isn't, e.g. wasm32 without
+    ///the `wasm` feature's JavaScript backend.  Without the `std`
+    ///feature there's never an entropy source, so this always uses
+    ///`secret`.
+    pub fn from_entropy_or(secret: [u8; DEFAULT_SECRET_SIZE]) -> Self {
+        #[cfg(feature = "std")]
+        let secret = try_random_secret().unwrap_or(secret);
+        Self { secret }
+    }
+
+    #[cfg(feature = "std")]
     #[inline(always)]
     ///Creates new instance with default params.
+    ///
+    ///Like std's `RandomState`, this doesn't ask the OS for randomness
+    ///every time.  Each thread draws one random 192-byte secret on
+    ///first use.  Every call then returns that secret with its first 8
+    ///bytes, read as a native endian `u64`, increased by a per-thread
+    ///call count.  So instances made on one thread share the other 184
+    ///bytes of their secrets.  They still hash differently, which is
+    ///all `HashMap`-style uses need, but their hashes aren't
+    ///independent: don't rely on two of them to act as unrelated hash
+    ///functions, or to keep one secret if another leaks.  Use
+    ///`new_fully_random` for that.
     pub fn new() -> Self {
-        // From Rust's internals for RandomState
-        thread_local!(static SECRET: std::cell::RefCell<[u8; DEFAULT_SECRET_SIZE]> = {
-            std::cell::RefCell::new(random_secret())
+        // From Rust's internals for RandomState: a random secret per
+        // thread, with its first 8 bytes bumped for every new instance.
+        thread_local! {
+            static SECRET: [u8; DEFAULT_SECRET_SIZE] = random_secret();
+            static COUNTER: Cell<u64> = const { Cell::new(0) };
+        }
+
+        let mut secret = SECRET.with(|secret| *secret);
+        let bump = COUNTER.with(|counter| {
+            let bump = counter.get().wrapping_add(1);
+            counter.set(bump);
+            bump
         });
+        let (first, _) = secret.split_at_mut(8);
+        let randomized_u64 = u64::from_ne_bytes(first.try_into().unwrap()).wrapping_add(bump);
+        first.copy_from_slice(&randomized_u64.to_ne_bytes());
+        Self { secret }
+    }
 
+    ///Creates an instance with a completely fresh secret drawn from the
+    ///OS, independent of every other instance.  This costs a `getrandom`
+    ///call each time, unlike `new`.
+    #[cfg(feature = "std")]
+    pub fn new_fully_random() -> Self {
         Self {
-            secret: SECRET.with(|cell| {
-                let mut secret = cell.borrow_mut();
-                let randomized_u64_bytes = secret.split_at_mut(8).0;
-                let randomized_u64 =
-                    u64::from_ne_bytes(randomized_u64_bytes.try_into().unwrap()).wrapping_add(1);
-                randomized_u64_bytes.copy_from_slice(&randomized_u64.to_ne_bytes());
-                secret.clone()
-            }),
+            secret: random_secret(),
         }
     }
 
@@ -59,6 +221,7 @@ impl RandomXxh3State {
     }
 }
 
+#[cfg(feature = "std")]
 impl Default for RandomXxh3State {
     #[inline(always)]
     fn default() -> Self {
@@ -81,9 +244,32 @@ impl BloomBuildHasher for RandomXxh3State {
     }
 }
 
+impl BuildHasher for RandomXxh3State {
+    type Hasher = Xxh3MapHasher;
+
+    #[inline(always)]
+    fn build_hasher(&self) -> Xxh3MapHasher {
+        Xxh3MapHasher(Xxh3::with_secret(self.secret))
+    }
+}
+
+impl BloomBuildHasher256 for RandomXxh3State {
+    type Hasher256 = Xxh3Pair;
+
+    #[inline(always)]
+    fn build_hasher_256(&self) -> Self::Hasher256 {
+        Xxh3Pair::with_secret(&self.secret)
+    }
+
+    #[inline(always)]
+    fn hash_one_256(&self, k: &[u8]) -> BloomFingerprint256 {
+        xxh3_256_with_secret(k, &self.secret)
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct SecretBasedXxh3Builder {
-    secret: [u8; DEFAULT_SECRET_SIZE],
+    pub(crate) secret: [u8; DEFAULT_SECRET_SIZE],
 }
 
 impl SecretBasedXxh3Builder {
@@ -96,6 +282,11 @@ impl SecretBasedXxh3Builder {
     pub const fn build(&self) -> Xxh3 {
         Xxh3::with_secret(self.secret)
     }
+
+    #[inline(always)]
+    pub const fn secret(&self) -> &[u8] {
+        &self.secret
+    }
 }
 
 impl BloomBuildHasher for SecretBasedXxh3Builder {
@@ -112,3 +303,349 @@ impl BloomBuildHasher for SecretBasedXxh3Builder {
         BloomFingerprint::new_128(h)
     }
 }
+
+impl BuildHasher for SecretBasedXxh3Builder {
+    type Hasher = Xxh3MapHasher;
+
+    #[inline(always)]
+    fn build_hasher(&self) -> Xxh3MapHasher {
+        Xxh3MapHasher(self.build())
+    }
+}
+
+impl BloomBuildHasher256 for SecretBasedXxh3Builder {
+    type Hasher256 = Xxh3Pair;
+
+    #[inline(always)]
+    fn build_hasher_256(&self) -> Self::Hasher256 {
+        Xxh3Pair::with_secret(&self.secret)
+    }
+
+    #[inline(always)]
+    fn hash_one_256(&self, k: &[u8]) -> BloomFingerprint256 {
+        xxh3_256_with_secret(k, &self.secret)
+    }
+}
+
+/// A hasher builder holding one secret per probe, so each of a filter's
+/// probe positions comes from its own xxh3 hash of the item rather than
+/// being derived from two hashes by double hashing.
+///
+/// Double hashing is what `BloomFilter` does, and it's cheap: one
+/// 128-bit hash covers every probe.  But keys whose two halves happen
+/// to line up modulo the filter size share their probes, which can push
+/// the false positive rate above the theoretical one.  At typical rates
+/// the difference is small; the `Independent hashing` benchmark prints
+/// both.  Fully independent hashes follow the theory, at the cost of a
+/// hash per probe on inserts.  Lookups of absent items usually stop after the
+/// first probe or two misses, so they pay much less.  See
+/// `IndependentBloomFilter`, which probes with one hash per lane.
+///
+/// As a `BloomBuildHasher` it hashes with the first secret, exactly
+/// like a `SecretBasedXxh3Builder` with that secret, so it can be used
+/// with any filter.
+#[derive(Clone, PartialEq, Eq)]
+pub struct MultiSecretXxh3State {
+    secrets: Box<[[u8; DEFAULT_SECRET_SIZE]]>,
+}
+
+impl MultiSecretXxh3State {
+    /// Creates an instance with `num_lanes` secrets drawn from the OS.
+    ///
+    /// # Panics
+    /// Panics if `num_lanes` is 0.
+    #[cfg(feature = "std")]
+    pub fn new(num_lanes: u32) -> Self {
+        assert!(num_lanes > 0, "at least one lane is needed");
+        Self {
+            secrets: (0..num_lanes).map(|_| random_secret()).collect(),
+        }
+    }
+
+    /// Creates an instance with `num_lanes` secrets derived from
+    /// `seed`.  The same seed always produces the same secrets, and the
+    /// first is the one `RandomXxh3State::from_seed` uses.
+    ///
+    /// # Panics
+    /// Panics if `num_lanes` is 0.
+    pub fn from_seed(seed: u64, num_lanes: u32) -> Self {
+        assert!(num_lanes > 0, "at least one lane is needed");
+        let mut state = seed;
+        Self {
+            secrets: (0..num_lanes).map(|_| next_seeded_secret(&mut state)).collect(),
+        }
+    }
+
+    /// The number of independent hashes this produces per item.
+    #[inline(always)]
+    pub fn num_lanes(&self) -> u32 {
+        self.secrets.len() as u32
+    }
+
+    /// A hasher for lane `lane`, whose `finish` is that lane's hash.
+    ///
+    /// # Panics
+    /// Panics if `lane` isn't less than `num_lanes`.
+    #[inline(always)]
+    pub fn lane_hasher(&self, lane: u32) -> Xxh3 {
+        Xxh3::with_secret(self.secrets[lane as usize])
+    }
+
+    /// The hash of `k` for lane `lane`.
+    ///
+    /// # Panics
+    /// Panics if `lane` isn't less than `num_lanes`.
+    #[inline(always)]
+    pub fn hash_lane(&self, k: &[u8], lane: u32) -> u64 {
+        xxhash_rust::xxh3::xxh3_64_with_secret(k, &self.secrets[lane as usize])
+    }
+}
+
+impl BloomBuildHasher for MultiSecretXxh3State {
+    type Hasher = Xxh3;
+
+    #[inline(always)]
+    fn build_hasher(&self) -> Self::Hasher {
+        self.lane_hasher(0)
+    }
+
+    #[inline(always)]
+    fn hash_one_128(&self, k: &[u8]) -> BloomFingerprint {
+        let h = xxhash_rust::xxh3::xxh3_128_with_secret(k, &self.secrets[0]);
+        BloomFingerprint::new_128(h)
+    }
+}
+
+/// A hasher builder that keys Xxh3 with an 8-byte seed instead of a
+/// full 192-byte secret, making it cheap to create and copy into many
+/// small filters.
+///
+/// The secret is only derived from the seed when an item is too long
+/// to hash in one shot; shorter items, including every integer, never
+/// need it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SeededXxh3State {
+    seed: u64,
+}
+
+impl SeededXxh3State {
+    /// Creates an instance with a random seed.  As with
+    /// `RandomXxh3State::new`, only the first call on each thread draws
+    /// randomness, and later calls on that thread use consecutive seeds.
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    pub fn new() -> Self {
+        thread_local!(static SEED: Cell<u64> = Cell::new(u64::from_ne_bytes(
+            random_secret()[..8].try_into().unwrap()
+        )));
+
+        Self {
+            seed: SEED.with(|seed| {
+                let next = seed.get().wrapping_add(1);
+                seed.set(next);
+                next
+            }),
+        }
+    }
+
+    /// Creates an instance keyed with `seed`.  Like
+    /// `RandomXxh3State::from_seed`, the same seed always hashes the
+    /// same way, but the two don't hash alike.
+    #[inline(always)]
+    pub const fn from_seed(seed: u64) -> Self {
+        Self { seed }
+    }
+
+    #[inline(always)]
+    pub const fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for SeededXxh3State {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BloomBuildHasher for SeededXxh3State {
+    type Hasher = SeededXxh3Hasher;
+
+    #[inline(always)]
+    fn build_hasher(&self) -> Self::Hasher {
+        SeededXxh3Hasher {
+            seed: self.seed,
+            len: 0,
+            buf: [0; INLINE_INPUT],
+            stream: None,
+        }
+    }
+
+    #[inline(always)]
+    fn hash_one_128(&self, k: &[u8]) -> BloomFingerprint {
+        BloomFingerprint::new_128(xxh3_128_with_seed(k, self.seed))
+    }
+}
+
+/// How much input `SeededXxh3Hasher` collects before switching to a
+/// streaming `Xxh3`.
+const INLINE_INPUT: usize = 64;
+
+/// The hasher built by `SeededXxh3State`.  Input is collected inline and
+/// hashed in one shot when finished, which is far cheaper than setting
+/// up a streaming `Xxh3`; only input longer than 64 bytes pays for one.
+pub struct SeededXxh3Hasher {
+    seed: u64,
+    len: usize,
+    buf: [u8; INLINE_INPUT],
+    stream: Option<Box<Xxh3>>,
+}
+
+impl Hasher for SeededXxh3Hasher {
+    #[inline(always)]
+    fn write(&mut self, bytes: &[u8]) {
+        if let Some(stream) = &mut self.stream {
+            stream.update(bytes);
+        } else if let Some(buf) = self.buf.get_mut(self.len..self.len + bytes.len()) {
+            buf.copy_from_slice(bytes);
+            self.len += bytes.len();
+        } else {
+            let mut stream = Box::new(Xxh3::with_seed(self.seed));
+            stream.update(&self.buf[..self.len]);
+            stream.update(bytes);
+            self.stream = Some(stream);
+        }
+    }
+
+    #[inline(always)]
+    fn finish(&self) -> u64 {
+        match &self.stream {
+            Some(stream) => stream.digest(),
+            None => xxh3_64_with_seed(&self.buf[..self.len], self.seed),
+        }
+    }
+}
+
+impl BloomHasher for SeededXxh3Hasher {
+    #[inline(always)]
+    fn finish_128(&self) -> BloomFingerprint {
+        BloomFingerprint::new_128(match &self.stream {
+            Some(stream) => stream.digest128(),
+            None => xxh3_128_with_seed(&self.buf[..self.len], self.seed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::hash::Hasher;
+
+    #[cfg(feature = "std")]
+    use super::DEFAULT_SECRET_SIZE;
+    use super::{RandomXxh3State, SecretBasedXxh3Builder, SeededXxh3State};
+    use crate::hashing::{fingerprint, fingerprint_256};
+    use crate::{BloomBuildHasher, BloomBuildHasher256, BloomHasher, ByteKey};
+
+    #[test]
+    fn from_seed() {
+        let a = RandomXxh3State::from_seed(42);
+        let b = RandomXxh3State::from_seed(42);
+        let c = RandomXxh3State::from_seed(43);
+        assert_eq!(a.secret(), b.secret());
+        assert_ne!(a.secret(), c.secret());
+        assert_eq!(a.hash_one_128(b"key"), b.hash_one_128(b"key"));
+        assert_eq!(&a.secret()[..8], &0xbdd7_3226_2feb_6e95u64.to_le_bytes());
+    }
+
+    #[test]
+    fn hash_256() {
+        let hasher = RandomXxh3State::from_seed(1);
+        let fp = hasher.hash_one_256(b"key");
+        assert_eq!(fp.truncate(), hasher.hash_one_128(b"key"));
+        assert_ne!(fp.lanes[..2], fp.lanes[2..]);
+        assert_eq!(fingerprint_256(ByteKey(b"key"), &hasher), fp);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn new_differs() {
+        let a = RandomXxh3State::new();
+        let b = RandomXxh3State::new();
+        assert_ne!(a.secret()[..8], b.secret()[..8]);
+        assert_eq!(a.secret()[8..], b.secret()[8..]);
+        assert_ne!(SeededXxh3State::new(), SeededXxh3State::new());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn from_entropy_or() {
+        let fallback = [7; DEFAULT_SECRET_SIZE];
+        let a = RandomXxh3State::from_entropy_or(fallback);
+        let b = RandomXxh3State::from_entropy_or(fallback);
+        assert_ne!(a.secret(), &fallback[..]);
+        assert_ne!(a.secret(), b.secret());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn new_fully_random() {
+        let a = RandomXxh3State::new_fully_random();
+        let b = RandomXxh3State::new_fully_random();
+        assert_ne!(a.secret()[..8], b.secret()[..8]);
+        assert_ne!(a.secret()[8..], b.secret()[8..]);
+        assert_ne!(a.hash_one_128(b"key"), b.hash_one_128(b"key"));
+    }
+
+    #[test]
+    fn seeded() {
+        let hasher = SeededXxh3State::from_seed(42);
+        assert_eq!(hasher, SeededXxh3State::from_seed(42));
+        assert_ne!(
+            hasher.hash_one_128(b"key"),
+            SeededXxh3State::from_seed(43).hash_one_128(b"key")
+        );
+        assert_eq!(fingerprint(ByteKey(b"key"), &hasher), hasher.hash_one_128(b"key"));
+
+        // Straddle the switch from the inline buffer to a streaming hasher.
+        let data: Vec<u8> = (0..200u8).collect();
+        for len in [0, 1, 63, 64, 65, 200] {
+            for split in [0, len / 3, len] {
+                let mut h = hasher.build_hasher();
+                h.write(&data[..split]);
+                h.write(&data[split..len]);
+                assert_eq!(h.finish_128(), hasher.hash_one_128(&data[..len]));
+                assert_eq!(
+                    h.finish(),
+                    xxhash_rust::xxh3::xxh3_64_with_seed(&data[..len], 42)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn std_build_hasher() {
+        use core::hash::BuildHasher;
+        use std::collections::HashMap;
+
+        use crate::{BloomFilter, ASMS};
+
+        let state = RandomXxh3State::from_seed(7);
+        let mut map = HashMap::with_hasher(state);
+        let mut filter = BloomFilter::with_rate_and_hasher(0.01, 100, state);
+        for i in 0..100u32 {
+            map.insert(i, i * 2);
+            filter.insert(&i);
+        }
+        for i in 0..100u32 {
+            assert_eq!(map[&i], i * 2);
+            assert!(filter.contains(&i));
+            assert_eq!(state.hash_one(i), fingerprint(i, &state).h2);
+        }
+
+        let secret = SecretBasedXxh3Builder::with_secret(state.secret);
+        assert_eq!(secret.hash_one("key"), state.hash_one("key"));
+        assert_eq!(secret.hash_one("key"), fingerprint("key", &secret).h2);
+    }
+}